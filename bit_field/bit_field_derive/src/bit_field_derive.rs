@@ -367,7 +367,7 @@ struct FieldSpec<'a> {
 
 // Unwrap ast to get the named fields. We only care about field names and types:
 // "myfield : BitField3" -> ("myfield", Token(BitField3))
-fn get_struct_fields(fields: &FieldsNamed) -> Result<Vec<FieldSpec>> {
+fn get_struct_fields(fields: &FieldsNamed) -> Result<Vec<FieldSpec<'_>>> {
     let mut vec = Vec::new();
 
     for field in &fields.named {
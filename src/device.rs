@@ -1,12 +1,11 @@
 use core::fmt;
-use std::{collections::HashMap, iter::zip, mem};
+use std::{collections::{HashMap, VecDeque}, mem};
 
 use bitflags::bitflags;
-use log::{debug, error, info, trace};
-use pixels::Pixels;
-use unicorn_engine::Unicorn;
+use log::{debug, error, info, trace, warn};
+use unicorn_engine::{RegisterARM, Unicorn, uc_error};
 
-use crate::{exception::{ExceptionType, call_exception_handler}, extdev::{input::{Input, KeyPress, KeyType}, sd::SD}, peripherals::{adc, aic, blt, gpio, rtc, sic, sys, tmr, uart, vpost}};
+use crate::{impl_snapshot, exception::{CPSR_THUMB, ExceptionPolicy, ExceptionType, FaultState, call_exception_handler}, extdev::{input::{Input, KeyPress, KeyType}, sd::{CID_ESD, CID_XSD, SD}}, peripherals::{adc, aic, blt, des, edma, gpio, i2c, i2s, jpg, pwm, rtc, sdram, sic, spi, sys, tmr, uart, videoin, vpost, common::Reset}, render::FrameSink};
 
 #[derive(Default, Debug, PartialEq)]
 pub enum QuitDetail {
@@ -15,6 +14,16 @@ pub enum QuitDetail {
     CPUException,
     CPUHalt,
     HLECallbackFailure,
+    WatchdogReset,
+    /// Unicorn stopped on an error that is not an abort the guest can take.
+    EmulatorError(uc_error),
+}
+
+impl QuitDetail {
+    /// Whether the guest stopped abnormally and the state is worth dumping.
+    pub fn is_crash(&self) -> bool {
+        matches!(self, Self::CPUException | Self::HLECallbackFailure | Self::WatchdogReset | Self::EmulatorError(_))
+    }
 }
 
 impl fmt::Display for QuitDetail {
@@ -24,6 +33,8 @@ impl fmt::Display for QuitDetail {
             Self::CPUException => { write!(f, "CPU exception occurred and user asked us to quit on this type of exception.") }
             Self::CPUHalt => { write!(f, "CPU halted.") }
             Self::HLECallbackFailure => { write!(f, "HLE callback failed to execute.") }
+            Self::WatchdogReset => { write!(f, "Watchdog timer reset the system.") }
+            Self::EmulatorError(err) => { write!(f, "Emulator stopped on Unicorn error {err:?}.") }
         }
     }
 }
@@ -35,6 +46,10 @@ bitflags! {
         const Tick = 1 << 0;
         const FrameStep = 1 << 1;
         const SVC = 1 << 2;
+        /// Guest hit a debugger breakpoint.
+        const Breakpoint = 1 << 3;
+        /// Guest accessed a watched memory range.
+        const Watchpoint = 1 << 4;
     }
 }
 
@@ -46,10 +61,35 @@ pub struct ExtraState {
     pub raw_sdram: Vec<u8>,
     pub stop_reason: StopReason,
     pub quit_detail: Option<QuitDetail>,
+    /// Elapsed CPU cycles, estimated from the number of executed instructions. Peripheral timing is expressed in steps.
     pub steps: u64,
+    /// Average CPU cycles per instruction, scaled by `CPI_SCALE`.
+    pub cycles_per_insn: u64,
+    /// Fraction of a cycle left over from the last block, scaled by `CPI_SCALE`.
+    pub cycle_fraction: u64,
+    /// Step at which the next timed peripheral event is due. See `schedule_next_event()`.
+    pub next_event: u64,
+    /// Step at which the current emulation slice ends and control returns to the device emulator.
+    pub slice_end: u64,
+    /// Breakpoint address to run past once when resuming from the debugger.
+    pub gdb_step_over: Option<u64>,
+    /// Structured trace output, if enabled.
+    pub tracer: Option<crate::trace::Tracer>,
+    /// Registers accessed by the guest, for `--dump-mmio-map`.
+    pub mmio_coverage: crate::trace::MmioCoverage,
+    /// Symbols for naming fault locations, if a symbol map is given.
+    pub symbols: Option<crate::hle::SymbolTable>,
+    pub watch: crate::watch::Watchpoints,
+    pub fault: FaultState,
+    pub exception_policy: ExceptionPolicy,
+    /// Exception vector base inside the mapped boot ROM. The HLE vectors in SRAM are used if no ROM is mapped.
+    pub vector_base: Option<u64>,
+    /// Byte order of the guest, for multi-byte accesses the emulator splits into bytes itself.
+    pub endian: crate::memmap::Endian,
 
     pub store_only: HashMap<u64, u64>,
     pub clk: sys::ClockConfig,
+    pub sdram: sdram::SDRAMConfig,
     pub sic: sic::SICConfig,
     pub gpio: gpio::GPIOConfig,
     pub uart: uart::UARTConfig,
@@ -59,23 +99,62 @@ pub struct ExtraState {
     pub adc: adc::ADCConfig,
     pub vpost: vpost::LCDConfig,
     pub blt: blt::BLTConfig,
+    pub pwm: pwm::PWMConfig,
+    pub i2s: i2s::I2SConfig,
+    pub edma: edma::EDMAConfig,
+    pub jpg: jpg::JPGConfig,
+    pub des: des::DESConfig,
+    pub spi: spi::SPIConfig,
+    pub i2c: i2c::I2CConfig,
+    pub videoin: videoin::VideoInConfig,
 }
 
 /// Peripheral device emulation context.
 ///
 /// Contains the states required to emulate devices, and actual device logic (excluding MMIO, which is considered part
 /// of the emulator state).
-#[derive(Default)]
 pub struct Device {
     pub internal_sd: SD,
     pub external_sd: SD,
     pub input: Input,
+    /// Samples played by the guest through I2S, waiting to be consumed by an audio backend.
+    pub audio_out: VecDeque<u32>,
+    /// Total number of samples played by the guest through I2S.
+    pub audio_frames: u64,
+    /// Samples waiting to be recorded by the ADC microphone input.
+    pub mic_input: VecDeque<i16>,
+    /// Bytes waiting to be received by each UART port.
+    pub uart_input: [VecDeque<u8>; 2],
+    /// Number of frames presented since the emulator started.
+    pub frames: u64,
+}
+
+impl Default for Device {
+    fn default() -> Self {
+        Self {
+            internal_sd: SD::new(&CID_ESD),
+            external_sd: SD::new(&CID_XSD),
+            input: Default::default(),
+            audio_out: Default::default(),
+            audio_frames: Default::default(),
+            mic_input: Default::default(),
+            uart_input: Default::default(),
+            frames: Default::default(),
+        }
+    }
 }
 
+// SDRAM is saved separately since it is mapped directly from `raw_sdram`.
+impl_snapshot!(ExtraState { steps, cycle_fraction, fault, store_only, clk, sdram, sic, gpio, uart, rtc, tmr, aic, adc, vpost, blt, pwm, i2s, edma, jpg, des, spi, i2c, videoin });
+impl_snapshot!(Device { internal_sd, external_sd, audio_frames, mic_input, uart_input, frames });
+
+/// Fixed point scale of `ExtraState::cycles_per_insn`.
+pub const CPI_SCALE: u64 = 256;
+
 pub type UnicornContext<'a> = Unicorn<'a, Box<ExtraState>>;
 
 #[inline]
-/// Defer a stop to right before the next instruction executes, stating the specified reason.
+/// Defer a stop to right before the next translation block executes, stating the specified reason.
 pub fn request_stop(uc: &mut UnicornContext, reason: StopReason) {
     uc.get_data_mut().stop_reason |= reason;
 }
@@ -85,17 +164,96 @@ pub fn request_quit(uc: &mut UnicornContext, detail: QuitDetail) {
     uc.get_data_mut().quit_detail = Some(detail);
 }
 
+/// Recompute the step of the next timed peripheral event. Needs to be called whenever a peripheral may have started,
+/// stopped or reconfigured one of its timed events.
+pub fn schedule_next_event(uc: &mut UnicornContext) {
+    let steps = uc.get_data().steps;
+    let sources = [
+        vpost::next_event, tmr::next_event, pwm::next_event, uart::next_event, sic::next_event, i2s::next_event,
+    ];
+    let next_event = sources.into_iter()
+        .filter_map(|next_event| next_event(uc, steps))
+        .min()
+        .unwrap_or(u64::MAX);
+    uc.get_data_mut().next_event = next_event;
+}
+
+/// Peripherals that can be reset, by the name they are mapped under. The clock and SDRAM controllers are left out,
+/// since the running guest depends on them.
+pub const RESETTABLE_PERIPHERALS: &[&str] = &[
+    "SIC", "GPIO", "RTC", "UART", "TMR", "AIC", "ADC", "VPOST", "PWM", "I2S", "BLT", "EDMA", "JPG", "DES", "SPI", "I2C",
+    "VIDEOIN",
+];
+
+/// Return the registers of the peripheral mapped as `name` to their power-on values, keeping host-side resources like
+/// mounted images. Returns false if no resettable peripheral has that name.
+pub fn reset_peripheral(uc: &mut UnicornContext, name: &str) -> bool {
+    let state = uc.get_data_mut();
+    let (base, size, peripheral): (u64, usize, &mut dyn Reset) = match name {
+        "SIC" => (sic::BASE, sic::SIZE, &mut state.sic),
+        "GPIO" => (gpio::BASE, gpio::SIZE, &mut state.gpio),
+        "RTC" => (rtc::BASE, rtc::SIZE, &mut state.rtc),
+        "UART" => (uart::BASE, uart::SIZE, &mut state.uart),
+        "TMR" => (tmr::BASE, tmr::SIZE, &mut state.tmr),
+        "AIC" => (aic::BASE, aic::SIZE, &mut state.aic),
+        "ADC" => (adc::BASE, adc::SIZE, &mut state.adc),
+        "VPOST" => (vpost::BASE, vpost::SIZE, &mut state.vpost),
+        "PWM" => (pwm::BASE, pwm::SIZE, &mut state.pwm),
+        "I2S" => (i2s::BASE, i2s::SIZE, &mut state.i2s),
+        "BLT" => (blt::BASE, blt::SIZE, &mut state.blt),
+        "EDMA" => (edma::BASE, edma::SIZE, &mut state.edma),
+        "JPG" => (jpg::BASE, jpg::SIZE, &mut state.jpg),
+        "DES" => (des::BASE, des::SIZE, &mut state.des),
+        "SPI" => (spi::BASE, spi::SIZE, &mut state.spi),
+        "I2C" => (i2c::BASE, i2c::SIZE, &mut state.i2c),
+        "VIDEOIN" => (videoin::BASE, videoin::SIZE, &mut state.videoin),
+        _ => return false,
+    };
+    debug!("{name}: Reset");
+    peripheral.reset();
+    // Registers that only hold their value are kept outside of the peripheral.
+    let range = base..base + u64::try_from(size).unwrap();
+    state.store_only.retain(|addr, _| !range.contains(addr));
+    schedule_next_event(uc);
+    true
+}
+
+/// Reset all of `RESETTABLE_PERIPHERALS`.
+pub fn reset_peripherals(uc: &mut UnicornContext) {
+    for name in RESETTABLE_PERIPHERALS {
+        reset_peripheral(uc, name);
+    }
+}
+
 /// Stops the emulator when a peripheral needs attention from the device emulator.
-/// Called before the execution of every instruction.
-pub fn check_stop_condition(uc: &mut UnicornContext, _addr: u64, _size: u32) {
-    uc.get_data_mut().steps += 1;
+/// Called before the execution of every translation block. Timed events that became due while the block was counted
+/// run in order, at the step they were scheduled for.
+pub fn check_stop_condition(uc: &mut UnicornContext, _addr: u64, size: u32) {
+    let thumb = uc.reg_read(RegisterARM::CPSR).is_ok_and(|cpsr| cpsr & CPSR_THUMB != 0);
+    let insn_size = if thumb { 2 } else { 4 };
+    let steps = {
+        let data = uc.get_data_mut();
+        let scaled_cycles = u64::from(size / insn_size).max(1) * data.cycles_per_insn + data.cycle_fraction;
+        data.steps += scaled_cycles / CPI_SCALE;
+        data.cycle_fraction = scaled_cycles % CPI_SCALE;
+        data.steps
+    };
 
-    // TODO emulate actual clock behavior
-    let steps = uc.get_data().steps;
-    vpost::generate_stop_condition(uc, steps);
-    tmr::generate_stop_condition(uc, steps);
+    while uc.get_data().next_event <= steps {
+        let event_step = uc.get_data().next_event;
+        uc.get_data_mut().steps = event_step;
+        vpost::generate_stop_condition(uc, event_step);
+        tmr::generate_stop_condition(uc, event_step);
+        pwm::generate_stop_condition(uc, event_step);
+        uart::generate_stop_condition(uc, event_step);
+        sic::generate_stop_condition(uc, event_step);
+        i2s::generate_stop_condition(uc, event_step);
+        schedule_next_event(uc);
+    }
+    uc.get_data_mut().steps = steps;
 
-    if !uc.get_data().stop_reason.is_empty() {
+    // Block boundaries never fall in the middle of an instruction, so ending the slice here is always safe.
+    if !uc.get_data().stop_reason.is_empty() || steps >= uc.get_data().slice_end {
         uc.emu_stop().unwrap_or_else(|err| {
             error!("Failed to stop emulator: {err:?}");
         });
@@ -109,10 +267,7 @@ pub fn input_tick(uc: &mut UnicornContext, device: &mut Device) {
                 match key_type {
                     KeyType::Home => {
                         debug!("Home pressed");
-                        let gpio = &mut uc.get_data_mut().gpio;
-                        gpio.ports[0].data_in.set_p2(false);
-                        gpio.ports[0].irq_trigger_source.set_p2(true);
-                        gpio.irq_on_frame_step = true;
+                        uc.get_data_mut().gpio.set_input(0, 2, false);
                     }
                     KeyType::Power => {
                         debug!("Power pressed");
@@ -120,16 +275,17 @@ pub fn input_tick(uc: &mut UnicornContext, device: &mut Device) {
                         rtc.power_control.set_power_key(false);
                         rtc.irq_on_frame_step = true;
                     }
+                    KeyType::Gpio { port, pin } => {
+                        debug!("GPIO {port}.{pin} pressed");
+                        uc.get_data_mut().gpio.set_input(port, pin, false);
+                    }
                 }
             },
             KeyPress::Release(key_type) => {
                 match key_type {
                     KeyType::Home => {
                         debug!("Home released");
-                        let gpio = &mut uc.get_data_mut().gpio;
-                        gpio.ports[0].data_in.set_p2(true);
-                        gpio.ports[0].irq_trigger_source.set_p2(true);
-                        gpio.irq_on_frame_step = true;
+                        uc.get_data_mut().gpio.set_input(0, 2, true);
                     }
                     KeyType::Power => {
                         debug!("Power released");
@@ -137,6 +293,10 @@ pub fn input_tick(uc: &mut UnicornContext, device: &mut Device) {
                         rtc.power_control.set_power_key(true);
                         rtc.irq_on_frame_step = true;
                     }
+                    KeyType::Gpio { port, pin } => {
+                        debug!("GPIO {port}.{pin} released");
+                        uc.get_data_mut().gpio.set_input(port, pin, true);
+                    }
                 }
             },
         }
@@ -144,12 +304,28 @@ pub fn input_tick(uc: &mut UnicornContext, device: &mut Device) {
 }
 
 impl Device {
+    /// Queue bytes to be received by a UART port.
+    pub fn push_uart_input(&mut self, port: usize, data: &[u8]) {
+        self.uart_input[port].extend(data);
+    }
+
+    /// Queue 16-bit little endian samples to be recorded by the microphone. A trailing odd byte is ignored.
+    pub fn push_mic_input(&mut self, data: &[u8]) {
+        self.mic_input.extend(data.chunks_exact(2).map(|sample| i16::from_le_bytes([sample[0], sample[1]])));
+    }
+
+    /// Take up to `max` samples played by the guest, oldest first.
+    pub fn take_audio_samples(&mut self, max: usize) -> Vec<u32> {
+        let count = max.min(self.audio_out.len());
+        self.audio_out.drain(..count).collect()
+    }
+
     /// Process MMIO register updates and device state changes.
     ///
-    /// This will modify both the device states and the emulator states associated with it.
-    pub fn tick(&mut self, uc: &mut UnicornContext, render: &mut Pixels) -> bool {
-        let quit_detail = mem::take(&mut uc.get_data_mut().quit_detail);
-        if let Some(reason) = quit_detail {
+    /// This will modify both the device states and the emulator states associated with it. Returns false when the
+    /// emulator should quit, leaving the reason in `ExtraState::quit_detail`.
+    pub fn tick<S: FrameSink>(&mut self, uc: &mut UnicornContext, render: &mut S) -> bool {
+        if let Some(reason) = &uc.get_data().quit_detail {
             info!("Quit condition pre-check: {reason}");
             return false;
         }
@@ -157,20 +333,31 @@ impl Device {
         let reason = mem::take(&mut uc.get_data_mut().stop_reason);
 
         if reason.contains(StopReason::FrameStep) {
+            self.input.frame_step(self.frames);
             adc::frame_step(uc);
             gpio::frame_step(uc);
             rtc::frame_step(uc);
+            videoin::frame_step(uc);
+            uart::tick(uc, self);
             if uc.get_data().vpost.control.get_run() {
                 trace!("Frame copy from 0x{:08x}", uc.get_data().vpost.fb);
-                let a = uc.mem_read_as_vec(uc.get_data().vpost.fb.into(), 320 * 240 * 2).unwrap();
-                for (spx, dpx) in zip(a.chunks_exact(2), render.frame_mut().chunks_exact_mut(4)) {
-                    dpx[0] = spx[1] & 0b11111000;
-                    dpx[1] = ((spx[1] & 0b111) << 5) | ((spx[0] & 0b11100000) >> 3);
-                    dpx[2] = spx[0] << 3;
-                    dpx[3] = 0xff;
+                let vpost = &uc.get_data().vpost;
+                let (width, height) = (vpost.width(), vpost.height());
+                if render.size() != (width, height) {
+                    info!("Panel resolution changed to {width}x{height}");
+                    if let Err(err) = render.resize(width, height) {
+                        error!("Failed to resize render buffer: {err:?}");
+                        request_quit(uc, QuitDetail::HLECallbackFailure);
+                        return false;
+                    }
+                }
+                match uc.mem_read_as_vec(vpost.fb.into(), vpost.fb_size()) {
+                    Ok(a) => vpost::convert_frame(&uc.get_data().vpost.control, &a, render.frame_mut()),
+                    Err(err) => warn!("Skipping frame from unreadable frame buffer 0x{:08x}: {err:?}", vpost.fb),
                 }
             }
-            match render.render() {
+            self.frames += 1;
+            match render.present() {
                 Ok(_) => {}
                 Err(err) => {
                     error!("Failed to render image: {err:?}");
@@ -191,12 +378,17 @@ impl Device {
             rtc::tick(uc);
             sic::tick(uc, self);
             blt::tick(uc);
+            edma::tick(uc);
+            jpg::tick(uc);
+            des::tick(uc);
+            spi::tick(uc);
+            i2c::tick(uc);
             adc::tick(uc, self);
+            i2s::tick(uc, self);
             input_tick(uc, self);
         }
 
-        let quit_detail = mem::take(&mut uc.get_data_mut().quit_detail);
-        if let Some(reason) = quit_detail {
+        if let Some(reason) = &uc.get_data().quit_detail {
             info!("Quit condition post-check: {reason}");
             false
         } else {
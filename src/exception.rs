@@ -1,12 +1,55 @@
-use std::{fs::File, io::Write};
+use std::{fs::{self, File}, io::Write, path::Path};
 
-use log::{error, trace};
+use log::{debug, error, info, trace};
 use unicorn_engine::{MemType, RegisterARM, uc_error};
 
-use crate::{RuntimeError, device::{QuitDetail, StopReason, UnicornContext, request_quit, request_stop}};
+use crate::{RuntimeError, impl_snapshot, memmap, mmu::CP15Register, device::{QuitDetail, StopReason, UnicornContext, request_quit, request_stop}};
+
+/// N3290x likely keeps the exception handler trampolines in bootrom, which is mapped at where the high exception
+/// handlers are normally at. Without a bootrom image the exception handlers are mapped at the start of SRAM instead.
+pub const HLE_VECTOR_BASE: u64 = memmap::SRAM_BASE;
+
+/// Thumb state bit in CPSR.
+pub const CPSR_THUMB: u64 = 1 << 5;
+
+/// Fault status for an external abort on a non-cacheable access, which is what an access to nothing on the bus
+/// turns into.
+const FSR_EXTERNAL_ABORT: u32 = 0b1000;
+
+/// Fault status and address of the last abort, as the guest finds them in the CP15 fault registers.
+///
+/// Unicorn has no hook for coprocessor reads, so the values are written into CP15 when an abort is delivered, where
+/// `MRC p15, 0, <Rd>, c5/c6, ...` picks them up. The ARM926 has no IFAR, so `ifar` is only visible to the host.
+#[derive(Default)]
+pub struct FaultState {
+    pub dfsr: u32,
+    pub dfar: u32,
+    pub ifsr: u32,
+    pub ifar: u32,
+    /// Abort recorded but not handled yet. See `deliver_abort()`.
+    pending: Option<ExceptionType>,
+}
+
+impl FaultState {
+    /// Record an access to unmapped memory. Returns the abort the guest should take.
+    fn record(&mut self, access_type: MemType, addr: u64) -> ExceptionType {
+        if access_type == MemType::FETCH_UNMAPPED {
+            self.ifsr = FSR_EXTERNAL_ABORT;
+            self.ifar = addr as u32;
+            ExceptionType::PrefetchAbort
+        } else {
+            self.dfsr = FSR_EXTERNAL_ABORT;
+            self.dfar = addr as u32;
+            ExceptionType::DataAbort
+        }
+    }
+}
+
+// Pending aborts are always delivered before control returns to the device loop, so they are never saved.
+impl_snapshot!(FaultState { dfsr, dfar, ifsr, ifar });
 
 #[repr(u8)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum ExceptionType {
     Reset = 0x0,
     UndefinedInstruction = 0x4,
@@ -18,15 +61,97 @@ pub enum ExceptionType {
 }
 
 impl ExceptionType {
+    /// Offset from the resume point to the LR value the handler expects, per the ARM ARM. Only the undefined
+    /// instruction exception depends on the instruction set state, since the other handlers return with a fixed
+    /// `SUBS PC, LR, #n` in both states.
+    pub fn lr_offset(self, thumb: bool) -> u64 {
+        match self {
+            Self::Reset => 0,  // Undefined
+            Self::UndefinedInstruction => if thumb { 2 } else { 4 },  // Next instruction
+            Self::SupervisorCall => 0,  // Next instruction (QEMU already gives us next instruction)
+            Self::PrefetchAbort => 4,  // Affected instruction + 4
+            Self::DataAbort => 8,  // Affected instruction + 8
+            Self::IRQ | Self::FIQ => 4,  // Next instruction + 4
+        }
+    }
+
     #[inline]
-    pub fn to_vector_address(self) -> u64 {
-        // N3290x likely keeps the exception handler trampolines in bootrom, which is mapped at where the high
-        // exception handlers are normally at.
-        // We don't emulate the bootrom so the exception handlers will be mapped at 0xff000000 instead.
-        0xff000000u64 + (self as u64)
+    pub fn to_vector_address(self, base: u64) -> u64 {
+        base + (self as u64)
     }
 }
 
+/// QEMU exception numbers reported to the interrupt hook.
+const EXCP_UDEF: u32 = 1;
+const EXCP_SWI: u32 = 2;
+const EXCP_PREFETCH_ABORT: u32 = 3;
+const EXCP_DATA_ABORT: u32 = 4;
+
+/// What to do when the guest raises a CPU exception.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ExceptionAction {
+    /// Run the guest's exception handler.
+    Deliver,
+    /// Skip the instruction that raised the exception and carry on.
+    Skip,
+    /// Stop the emulator.
+    Quit,
+}
+
+/// Action taken for each exception type, indexed by vector offset.
+pub struct ExceptionPolicy {
+    actions: [ExceptionAction; 8],
+}
+
+impl Default for ExceptionPolicy {
+    fn default() -> Self {
+        let mut actions = [ExceptionAction::Deliver; 8];
+        actions[ExceptionType::UndefinedInstruction as usize / 4] = ExceptionAction::Quit;
+        actions[ExceptionType::PrefetchAbort as usize / 4] = ExceptionAction::Quit;
+        actions[ExceptionType::DataAbort as usize / 4] = ExceptionAction::Quit;
+        Self { actions }
+    }
+}
+
+impl ExceptionPolicy {
+    pub fn action(&self, exc_type: ExceptionType) -> ExceptionAction {
+        self.actions[exc_type as usize / 4]
+    }
+
+    pub fn set(&mut self, exc_type: ExceptionType, action: ExceptionAction) {
+        self.actions[exc_type as usize / 4] = action;
+    }
+}
+
+/// Parse an exception policy entry like `undefined=quit`. Exceptions are `undefined`, `svc`, `prefetch` or `data`, and
+/// actions are `deliver`, `skip` or `quit`.
+pub fn parse_policy(value: &str) -> Result<(ExceptionType, ExceptionAction), String> {
+    let (exc_type, action) = value
+        .split_once('=')
+        .ok_or_else(|| format!("Expected <exception>=<action>, got `{value}`"))?;
+    let exc_type = match exc_type.trim().to_ascii_lowercase().as_str() {
+        "undefined" => ExceptionType::UndefinedInstruction,
+        "svc" => ExceptionType::SupervisorCall,
+        "prefetch" => ExceptionType::PrefetchAbort,
+        "data" => ExceptionType::DataAbort,
+        _ => return Err(format!("Unknown exception `{exc_type}`")),
+    };
+    let action = match action.trim().to_ascii_lowercase().as_str() {
+        "deliver" => ExceptionAction::Deliver,
+        "skip" => ExceptionAction::Skip,
+        "quit" => ExceptionAction::Quit,
+        _ => return Err(format!("Unknown action `{action}`")),
+    };
+    Ok((exc_type, action))
+}
+
+/// Move the PC past the current instruction.
+fn skip_instruction(uc: &mut UnicornContext) -> Result<(), uc_error> {
+    let pc = uc.pc_read()?;
+    let thumb = uc.reg_read(RegisterARM::CPSR)? & CPSR_THUMB != 0;
+    uc.set_pc(pc + if thumb { 2 } else { 4 })
+}
+
 pub fn call_exception_handler(uc: &mut UnicornContext, exc_type: ExceptionType) -> Result<(), uc_error> {
     /* Notes on PC:
      * - Exceptions will leave the PC at the unexecuted instruction.
@@ -35,17 +160,8 @@ pub fn call_exception_handler(uc: &mut UnicornContext, exc_type: ExceptionType)
      * In all cases, current_pc will be the resume point.
      */
     let current_pc = uc.pc_read()?;
-    
-    // TODO properly compute offset of next instruction
-    let computed_lr = match exc_type {
-        ExceptionType::Reset => current_pc,  // Undefined
-        ExceptionType::UndefinedInstruction => current_pc + 4,  // Next instruction
-        ExceptionType::SupervisorCall => current_pc,  // Next instruction (QEMU already gives us next instruction)
-        ExceptionType::PrefetchAbort => current_pc + 4,  // Affected instruction + 4
-        ExceptionType::DataAbort => current_pc + 8,  // Affected instruction + 8
-        ExceptionType::IRQ => current_pc + 4,  // Next instruction
-        ExceptionType::FIQ => current_pc + 4,  // Next instruction
-    };
+    let cpsr = uc.reg_read(RegisterARM::CPSR)?;
+    let computed_lr = current_pc + exc_type.lr_offset(cpsr & CPSR_THUMB != 0);
 
     let computed_cpsr_set = match exc_type {
         ExceptionType::Reset => 0b11010011,  // svc, no interrupt
@@ -56,13 +172,15 @@ pub fn call_exception_handler(uc: &mut UnicornContext, exc_type: ExceptionType)
         ExceptionType::FIQ => 0b11010001,  // fiq, no interrupt
     };
 
-    let cpsr = uc.reg_read(RegisterARM::CPSR)?;
+    // Handlers always run in ARM state, which clearing the low bits takes care of.
     let new_cpsr = (cpsr & !0b00111111) | computed_cpsr_set;
-    // Switch mode
+    // Switch mode. Unicorn banks R8-R12 (FIQ) and SP/LR on CPSR writes, so SPSR and LR below land in the new mode's
+    // bank and the interrupted mode's registers are restored by the handler's exception return.
     uc.reg_write(RegisterARM::CPSR, new_cpsr)?;
     uc.reg_write(RegisterARM::SPSR, cpsr)?;
     uc.reg_write(RegisterARM::LR, computed_lr)?;
-    uc.set_pc(exc_type.to_vector_address())?;
+    let vector_base = uc.get_data().vector_base.unwrap_or(HLE_VECTOR_BASE);
+    uc.set_pc(exc_type.to_vector_address(vector_base))?;
     trace!("Exception {exc_type:?} raised @ 0x{current_pc:08x}");
     Ok(())
 }
@@ -70,19 +188,93 @@ pub fn call_exception_handler(uc: &mut UnicornContext, exc_type: ExceptionType)
 pub fn unmapped_access(uc: &mut UnicornContext, access_type: MemType, addr: u64, size: usize, value: i64) -> bool {
     let pc = uc.pc_read().unwrap();
     error!("exception: {access_type:?} of {size} bytes at 0x{addr:08x}, value 0x{value:08x}, by 0x{pc:08x}.");
+    if let Some(symbols) = &uc.get_data().symbols {
+        crate::hle::log_backtrace(uc, symbols);
+    }
+    crate::trace::record_unmapped(uc, addr, size, value);
+    let data = uc.get_data_mut();
+    let abort = data.fault.record(access_type, addr);
+    if data.exception_policy.action(abort) != ExceptionAction::Quit {
+        data.fault.pending = Some(abort);
+    }
+    // Unicorn can't retry an access that is still unmapped, so stop here and let `deliver_abort()` redirect the
+    // guest once emulation has stopped at the faulting instruction.
     false
 }
 
+/// Handle the abort recorded by `unmapped_access()`, if any, according to the exception policy. Returns whether
+/// emulation can resume.
+pub fn deliver_abort(uc: &mut UnicornContext) -> Result<bool, uc_error> {
+    let data = uc.get_data_mut();
+    let Some(abort) = data.fault.pending.take() else {
+        return Ok(false);
+    };
+    let (dfsr, dfar, ifsr) = (data.fault.dfsr, data.fault.dfar, data.fault.ifsr);
+    match data.exception_policy.action(abort) {
+        ExceptionAction::Deliver => {
+            CP15Register::DataFaultStatus.write(uc, dfsr)?;
+            CP15Register::InstructionFaultStatus.write(uc, ifsr)?;
+            CP15Register::FaultAddress.write(uc, dfar)?;
+            call_exception_handler(uc, abort)?;
+            debug!("{abort:?} delivered (DFAR=0x{dfar:08x})");
+        }
+        ExceptionAction::Skip => skip_instruction(uc)?,
+        ExceptionAction::Quit => return Ok(false),
+    }
+    Ok(true)
+}
+
 pub fn intr(uc: &mut UnicornContext, intno: u32) {
-    if intno == 2 {
-        request_stop(uc, StopReason::SVC);
-    } else {
-        error!("Not int2 ({intno}). This should not have happened.");
+    let exc_type = match intno {
+        EXCP_UDEF => ExceptionType::UndefinedInstruction,
+        EXCP_SWI => ExceptionType::SupervisorCall,
+        EXCP_PREFETCH_ABORT => ExceptionType::PrefetchAbort,
+        EXCP_DATA_ABORT => ExceptionType::DataAbort,
+        _ => {
+            error!("Unexpected CPU exception {intno}.");
+            request_quit(uc, QuitDetail::CPUException);
+            return;
+        }
+    };
+
+    let result = match uc.get_data().exception_policy.action(exc_type) {
+        // SVCs are delivered from the device loop so HLE hooks get a chance to run first.
+        ExceptionAction::Deliver if intno == EXCP_SWI => {
+            request_stop(uc, StopReason::SVC);
+            Ok(())
+        }
+        ExceptionAction::Deliver => call_exception_handler(uc, exc_type),
+        // QEMU already moved past the SVC instruction.
+        ExceptionAction::Skip if intno == EXCP_SWI => Ok(()),
+        ExceptionAction::Skip => skip_instruction(uc),
+        ExceptionAction::Quit => {
+            error!("{exc_type:?} raised at 0x{:08x}.", uc.pc_read().unwrap_or_default());
+            request_quit(uc, QuitDetail::CPUException);
+            Ok(())
+        }
+    };
+    if let Err(err) = result {
+        error!("Failed to handle {exc_type:?}: {err:?}.");
         request_quit(uc, QuitDetail::CPUException);
     }
 }
 
-pub fn dump_data(uc: &UnicornContext) -> Result<(), RuntimeError> {
+fn mode_name(cpsr: u64) -> &'static str {
+    match cpsr & 0b11111 {
+        0x10 => "usr",
+        0x11 => "fiq",
+        0x12 => "irq",
+        0x13 => "svc",
+        0x17 => "abt",
+        0x1b => "und",
+        0x1f => "sys",
+        _ => "invalid",
+    }
+}
+
+/// Log the CPU state and write a crash report with SDRAM and SRAM dumps into `dir`. File names carry the PC so
+/// successive crashes don't overwrite each other.
+pub fn dump_data(uc: &UnicornContext, dir: &Path, reason: &str) -> Result<(), RuntimeError> {
     let regs: Vec<u64> = uc.reg_read_batch(&[
         RegisterARM::R0,
         RegisterARM::R1,
@@ -103,15 +295,108 @@ pub fn dump_data(uc: &UnicornContext) -> Result<(), RuntimeError> {
         RegisterARM::CPSR,
         RegisterARM::SPSR,
     ], 18)?.iter().map(|val| val & 0xffffffff).collect();
-    error!("R0=0x{:08x} R1=0x{:08x} R2=0x{:08x} R3=0x{:08x}", regs[0], regs[1], regs[2], regs[3]);
-    error!("R4=0x{:08x} R5=0x{:08x} R6=0x{:08x} R7=0x{:08x}", regs[4], regs[5], regs[6], regs[7]);
-    error!("R8=0x{:08x} R9=0x{:08x} R10=0x{:08x} R11=0x{:08x}", regs[8], regs[9], regs[10], regs[11]);
-    error!("R12=0x{:08x} SP=0x{:08x} LR=0x{:08x} PC=0x{:08x}", regs[12], regs[13], regs[14], regs[15]);
-    error!("CPSR=0x{:08x} SPSR=0x{:08x}", regs[16], regs[17]);
-    let mut sdram_dump = File::options().write(true).create(true).open("sdram.bin")?;
-    sdram_dump.write(&uc.get_data().raw_sdram)?;
-    let mut sram_dump = File::options().write(true).create(true).open("sram.bin")?;
-    let sram_data = uc.mem_read_as_vec(0xff000000, 8192)?;
-    sram_dump.write(&sram_data)?;
+    let (pc, cpsr) = (regs[15], regs[16]);
+    let fault = &uc.get_data().fault;
+    let state = if cpsr & CPSR_THUMB != 0 { "thumb" } else { "arm" };
+    let lines = [
+        format!("Reason: {reason}"),
+        format!("Step: {}", uc.get_data().steps),
+        format!("Mode: {} ({state}), PC=0x{pc:08x}, LR=0x{:08x}", mode_name(cpsr), regs[14]),
+        format!("R0=0x{:08x} R1=0x{:08x} R2=0x{:08x} R3=0x{:08x}", regs[0], regs[1], regs[2], regs[3]),
+        format!("R4=0x{:08x} R5=0x{:08x} R6=0x{:08x} R7=0x{:08x}", regs[4], regs[5], regs[6], regs[7]),
+        format!("R8=0x{:08x} R9=0x{:08x} R10=0x{:08x} R11=0x{:08x}", regs[8], regs[9], regs[10], regs[11]),
+        format!("R12=0x{:08x} SP=0x{:08x} LR=0x{:08x} PC=0x{:08x}", regs[12], regs[13], regs[14], pc),
+        format!("CPSR=0x{cpsr:08x} SPSR=0x{:08x}", regs[17]),
+        format!(
+            "DFSR=0x{:08x} DFAR=0x{:08x} IFSR=0x{:08x} IFAR=0x{:08x}",
+            fault.dfsr, fault.dfar, fault.ifsr, fault.ifar,
+        ),
+        format!(
+            "SCTLR=0x{:08x} TTBR=0x{:08x} DACR=0x{:08x} CTR=0x{:08x}",
+            CP15Register::Control.read(uc)?, CP15Register::TranslationTableBase.read(uc)?,
+            CP15Register::DomainAccessControl.read(uc)?, CP15Register::CacheType.read(uc)?,
+        ),
+    ];
+    for line in &lines {
+        error!("{line}");
+    }
+
+    fs::create_dir_all(dir)?;
+    let mut report = File::create(dir.join(format!("crash-{pc:08x}.txt")))?;
+    for line in &lines {
+        writeln!(report, "{line}")?;
+    }
+    File::create(dir.join(format!("sdram-{pc:08x}.bin")))?.write_all(&uc.get_data().raw_sdram)?;
+    let sram_data = uc.mem_read_as_vec(memmap::SRAM_BASE, memmap::SRAM_SIZE)?;
+    File::create(dir.join(format!("sram-{pc:08x}.bin")))?.write_all(&sram_data)?;
+    info!("Crash dump written to {}", dir.display());
     Ok(())
 }
+
+#[test]
+fn test_lr_offset() {
+    assert_eq!(ExceptionType::UndefinedInstruction.lr_offset(false), 4);
+    assert_eq!(ExceptionType::UndefinedInstruction.lr_offset(true), 2);
+    for thumb in [false, true] {
+        assert_eq!(ExceptionType::SupervisorCall.lr_offset(thumb), 0);
+        assert_eq!(ExceptionType::PrefetchAbort.lr_offset(thumb), 4);
+        assert_eq!(ExceptionType::DataAbort.lr_offset(thumb), 8);
+        assert_eq!(ExceptionType::IRQ.lr_offset(thumb), 4);
+        assert_eq!(ExceptionType::FIQ.lr_offset(thumb), 4);
+    }
+}
+
+#[test]
+fn test_parse_policy() {
+    assert!(matches!(parse_policy("undefined=quit"), Ok((ExceptionType::UndefinedInstruction, ExceptionAction::Quit))));
+    assert!(matches!(parse_policy("Data=Deliver"), Ok((ExceptionType::DataAbort, ExceptionAction::Deliver))));
+    assert!(matches!(parse_policy("svc=skip"), Ok((ExceptionType::SupervisorCall, ExceptionAction::Skip))));
+    assert!(parse_policy("irq=quit").is_err());
+    assert!(parse_policy("prefetch").is_err());
+    assert!(parse_policy("prefetch=ignore").is_err());
+}
+
+#[test]
+fn test_fault_record() {
+    let mut fault = FaultState::default();
+    assert!(matches!(fault.record(MemType::WRITE_UNMAPPED, 0xc0001234), ExceptionType::DataAbort));
+    assert!(matches!(fault.record(MemType::FETCH_UNMAPPED, 0xc0005678), ExceptionType::PrefetchAbort));
+    assert_eq!((fault.dfsr, fault.dfar), (FSR_EXTERNAL_ABORT, 0xc0001234));
+    assert_eq!((fault.ifsr, fault.ifar), (FSR_EXTERNAL_ABORT, 0xc0005678));
+}
+
+#[test]
+fn test_fiq_banked_registers() {
+    use unicorn_engine::{Arch, ArmCpuModel, Mode, Permission, Unicorn};
+    use crate::device::ExtraState;
+
+    const RESUME_PC: u64 = 0x100;
+    // mov r8, #0x22; mov r12, #0x33; mov sp, #0x8000; subs pc, lr, #4
+    const HANDLER: [u32; 4] = [0xe3a08022, 0xe3a0c033, 0xe3a0d902, 0xe25ef004];
+
+    let mut uc = Unicorn::new_with_data(Arch::ARM, Mode::LITTLE_ENDIAN, Box::new(ExtraState::default())).unwrap();
+    uc.ctl_set_cpu_model(ArmCpuModel::UC_CPU_ARM_926.into()).unwrap();
+    uc.mem_map(0x00000000, 0x1000, Permission::ALL).unwrap();
+    uc.mem_map(0xff000000, 0x1000, Permission::ALL).unwrap();
+    let handler: Vec<u8> = HANDLER.iter().flat_map(|insn| insn.to_le_bytes()).collect();
+    uc.mem_write(ExceptionType::FIQ.to_vector_address(HLE_VECTOR_BASE), &handler).unwrap();
+
+    // User mode state that the handler must not clobber.
+    uc.reg_write(RegisterARM::CPSR, 0b10000).unwrap();
+    uc.reg_write(RegisterARM::R8, 0x11).unwrap();
+    uc.reg_write(RegisterARM::R12, 0x12).unwrap();
+    uc.reg_write(RegisterARM::SP, 0x3000).unwrap();
+    uc.reg_write(RegisterARM::LR, 0x4444).unwrap();
+    uc.set_pc(RESUME_PC).unwrap();
+
+    call_exception_handler(&mut uc, ExceptionType::FIQ).unwrap();
+    let vector = uc.pc_read().unwrap();
+    uc.emu_start(vector, RESUME_PC, 0, HANDLER.len()).unwrap();
+
+    assert_eq!(uc.pc_read().unwrap(), RESUME_PC);
+    assert_eq!(uc.reg_read(RegisterARM::CPSR).unwrap() & 0b11111, 0b10000);
+    assert_eq!(uc.reg_read(RegisterARM::R8).unwrap(), 0x11);
+    assert_eq!(uc.reg_read(RegisterARM::R12).unwrap(), 0x12);
+    assert_eq!(uc.reg_read(RegisterARM::SP).unwrap(), 0x3000);
+    assert_eq!(uc.reg_read(RegisterARM::LR).unwrap(), 0x4444);
+}
@@ -0,0 +1,76 @@
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+
+use log::{debug, warn};
+
+use crate::RuntimeError;
+
+/// Y, U and V of the 75% color bars, from left to right: white, yellow, cyan, green, magenta, red, blue and black.
+const COLOR_BARS: [[u8; 3]; 8] = [
+    [180, 128, 128],
+    [162, 44, 142],
+    [131, 156, 44],
+    [112, 72, 58],
+    [84, 184, 198],
+    [65, 100, 212],
+    [35, 212, 114],
+    [16, 128, 128],
+];
+
+/// Image sensor feeding the video capture engine. Frames are packed YUV 4:2:2 (YUYV).
+///
+/// Without an image file, the sensor shows color bars. Image files hold raw frames of the size the guest captures,
+/// and are played in a loop.
+#[derive(Default)]
+pub struct Camera {
+    image_file: Option<fs::File>,
+}
+
+impl Camera {
+    pub fn open(path: &str) -> Result<Self, RuntimeError> {
+        let image_file = fs::File::open(path)?;
+        debug!("Camera: {} bytes of frames", image_file.metadata()?.len());
+        Ok(Self { image_file: Some(image_file) })
+    }
+
+    /// Capture a frame of `width` by `height` pixels. `width` is rounded up to an even number of pixels.
+    pub fn capture(&mut self, width: usize, height: usize) -> Vec<u8> {
+        let width = width.next_multiple_of(2);
+        let mut frame = vec![0u8; width * height * 2];
+        if let Some(image_file) = self.image_file.as_mut() {
+            // Rewind once at the end of the file.
+            let result = image_file.read_exact(&mut frame)
+                .or_else(|_| image_file.seek(SeekFrom::Start(0)).and_then(|_| image_file.read_exact(&mut frame)));
+            match result {
+                Ok(()) => return frame,
+                Err(err) => {
+                    warn!("Camera: No {width}x{height} frame in the image ({err:?}), showing color bars");
+                    self.image_file = None;
+                }
+            }
+        }
+        color_bars(&mut frame, width);
+        frame
+    }
+}
+
+/// Fill a YUYV frame with vertical color bars.
+fn color_bars(frame: &mut [u8], width: usize) {
+    for row in frame.chunks_exact_mut(width * 2) {
+        for (pair, pixels) in row.chunks_exact_mut(4).enumerate() {
+            let [y, u, v] = COLOR_BARS[pair * 2 * COLOR_BARS.len() / width];
+            pixels.copy_from_slice(&[y, u, y, v]);
+        }
+    }
+}
+
+#[test]
+fn test_color_bars() {
+    let mut camera = Camera::default();
+    let frame = camera.capture(15, 2);
+    assert_eq!(frame.len(), 16 * 2 * 2);
+    // 2 pixels per bar.
+    assert_eq!(frame[..8], [180, 128, 180, 128, 162, 44, 162, 142]);
+    assert_eq!(frame[28..32], [16, 128, 16, 128]);
+    assert_eq!(frame[..32], frame[32..]);
+}
@@ -0,0 +1,163 @@
+use std::fs;
+use std::io::{Seek, SeekFrom, Write};
+
+use log::{debug, error, trace};
+
+use crate::{RuntimeError, extdev::i2c::I2CSlave, impl_snapshot};
+
+/// 24C01, the smallest part.
+const MIN_SIZE: usize = 128;
+/// 24C512, the largest part with 2 address bytes.
+const MAX_SIZE: usize = 0x10000;
+/// Parts up to 24C16 take one address byte and use the low bits of the device address as the block number.
+const BLOCK_SIZE: usize = 256;
+
+/// A 24Cxx I2C EEPROM backed by an image file. The part is picked from the image size.
+///
+/// The image is kept in memory and written bytes are written back to the file as they change.
+#[derive(Default)]
+pub struct EEPROM {
+    image_file: Option<fs::File>,
+    data: Vec<u8>,
+    /// Lowest device address of the part.
+    base: u8,
+    /// Address of the next byte read or written.
+    pointer: usize,
+    /// Address bytes still expected in the current write transaction.
+    address_pending: usize,
+    /// Page that started the current write, which later bytes wrap around in.
+    page: usize,
+    /// Data bytes written in the current transaction.
+    written: usize,
+}
+
+impl EEPROM {
+    pub fn open(path: &str, base: u8) -> Result<Self, RuntimeError> {
+        let data = fs::read(path)?;
+        let size = data.len();
+        if !size.is_power_of_two() || !(MIN_SIZE..=MAX_SIZE).contains(&size) {
+            error!("EEPROM image size {size} is not a power of 2 between {MIN_SIZE} and {MAX_SIZE} bytes.");
+            return Err(RuntimeError::EEPROMInvalidImage);
+        }
+        let image_file = fs::OpenOptions::new().write(true).open(path)?;
+        debug!("EEPROM: {size} bytes at 0x{base:02x}");
+        Ok(Self { image_file: Some(image_file), data, base, ..Default::default() })
+    }
+
+    /// Device addresses the part answers to.
+    pub fn addresses(&self) -> Vec<u8> {
+        let blocks = u8::try_from(self.blocks()).unwrap();
+        (self.base..self.base + blocks).collect()
+    }
+
+    /// Number of 256 byte blocks selected through the device address.
+    fn blocks(&self) -> usize {
+        if self.data.len() > BLOCK_SIZE * 8 { 1 } else { self.data.len().div_ceil(BLOCK_SIZE) }
+    }
+
+    fn address_bytes(&self) -> usize {
+        if self.data.len() > BLOCK_SIZE * 8 { 2 } else { 1 }
+    }
+
+    /// Page write buffer size of the part.
+    fn page_size(&self) -> usize {
+        match self.data.len().ilog2() {
+            ..=8 => 8,
+            9..=11 => 16,
+            12..=13 => 32,
+            14..=15 => 64,
+            _ => 128,
+        }
+    }
+
+    fn write_back(&mut self, offset: usize) {
+        let Some(image_file) = self.image_file.as_mut() else {
+            return;
+        };
+        let result = image_file.seek(SeekFrom::Start(u64::try_from(offset).unwrap()))
+            .and_then(|_| image_file.write_all(&self.data[offset..=offset]));
+        result.unwrap_or_else(|err| {
+            error!("EEPROM: Writing 0x{offset:04x} failed: {err:?}");
+        });
+    }
+}
+
+impl I2CSlave for EEPROM {
+    fn start(&mut self, addr: u8, read: bool) -> bool {
+        if !read {
+            self.address_pending = self.address_bytes();
+            self.written = 0;
+            self.pointer = usize::from(addr - self.base) * BLOCK_SIZE;
+        }
+        true
+    }
+
+    fn write(&mut self, data: u8) -> bool {
+        if self.address_pending > 0 {
+            self.address_pending -= 1;
+            // The block number from the device address is in the upper bits already.
+            let pointer = if self.address_bytes() == 1 { self.pointer } else { self.pointer << 8 };
+            self.pointer = (pointer | usize::from(data)) % self.data.len();
+            self.page = self.pointer & !(self.page_size() - 1);
+            return true;
+        }
+
+        // Writes past the end of the page wrap around to its start.
+        let offset = self.page | ((self.pointer + self.written) % self.page_size());
+        trace!("EEPROM: Write 0x{offset:04x} = 0x{data:02x}");
+        self.data[offset] = data;
+        self.written += 1;
+        self.write_back(offset);
+        true
+    }
+
+    fn read(&mut self) -> u8 {
+        let data = self.data[self.pointer];
+        self.pointer = (self.pointer + 1) % self.data.len();
+        data
+    }
+
+    fn stop(&mut self) {
+        if self.written > 0 {
+            self.pointer = self.page | ((self.pointer + self.written) % self.page_size());
+            self.written = 0;
+        }
+        self.address_pending = 0;
+    }
+}
+
+impl_snapshot!(EEPROM { pointer, address_pending, page, written });
+
+#[test]
+fn test_eeprom() {
+    // 24C04: 2 blocks of 256 bytes with 16 byte pages.
+    let path = crate::extdev::make_test_file("eeprom.bin", 512);
+    let mut eeprom = EEPROM::open(path.to_str().unwrap(), 0x50).unwrap();
+    assert_eq!(eeprom.addresses(), [0x50, 0x51]);
+
+    // Random read from block 1.
+    assert!(eeprom.start(0x51, false));
+    assert!(eeprom.write(0x02));
+    assert!(eeprom.start(0x51, true));
+    assert_eq!([eeprom.read(), eeprom.read()], [(258 % 251) as u8, (259 % 251) as u8]);
+    eeprom.stop();
+
+    // Page write wrapping around the end of the page, then a current address read.
+    eeprom.start(0x50, false);
+    eeprom.write(0x1f);
+    eeprom.write(0xaa);
+    eeprom.write(0xbb);
+    eeprom.stop();
+    eeprom.start(0x50, true);
+    assert_eq!(eeprom.read(), 0x11);
+    eeprom.stop();
+    eeprom.start(0x50, false);
+    eeprom.write(0x1f);
+    eeprom.start(0x50, true);
+    assert_eq!(eeprom.read(), 0xaa);
+    eeprom.stop();
+
+    let data = fs::read(&path).unwrap();
+    assert_eq!((data[0x1f], data[0x10]), (0xaa, 0xbb));
+    fs::remove_file(&path).unwrap();
+}
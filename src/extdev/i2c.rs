@@ -0,0 +1,59 @@
+use std::collections::BTreeMap;
+
+use crate::{RuntimeError, snapshot::Snapshot};
+
+/// A device on the I2C bus, addressed by the master with a 7-bit address.
+pub trait I2CSlave: Snapshot {
+    /// Start condition followed by one of the slave's addresses. Returns whether the slave acknowledges.
+    fn start(&mut self, addr: u8, read: bool) -> bool;
+    /// Byte written by the master. Returns whether the slave acknowledges.
+    fn write(&mut self, data: u8) -> bool;
+    /// Byte read by the master.
+    fn read(&mut self) -> u8;
+    /// Stop condition.
+    fn stop(&mut self) {}
+}
+
+/// Slaves attached to an I2C bus, keyed by address. A slave may answer to several addresses.
+#[derive(Default)]
+pub struct I2CBus {
+    slaves: Vec<Box<dyn I2CSlave>>,
+    /// Index into `slaves` of each address.
+    addresses: BTreeMap<u8, usize>,
+}
+
+impl I2CBus {
+    /// Attach a slave answering to `addresses`.
+    pub fn attach(&mut self, addresses: &[u8], slave: Box<dyn I2CSlave>) -> Result<(), RuntimeError> {
+        if let Some(&addr) = addresses.iter().find(|&addr| *addr > 0x7f || self.addresses.contains_key(addr)) {
+            return Err(RuntimeError::I2CAddressUnavailable(addr));
+        }
+        let index = self.slaves.len();
+        self.slaves.push(slave);
+        self.addresses.extend(addresses.iter().map(|&addr| (addr, index)));
+        Ok(())
+    }
+
+    /// The slave answering to an address.
+    pub fn slave(&mut self, addr: u8) -> Option<&mut dyn I2CSlave> {
+        let index = *self.addresses.get(&addr)?;
+        Some(self.slaves[index].as_mut())
+    }
+}
+
+/// Slaves are saved in the order they were attached, so they need to be attached the same way before loading.
+impl Snapshot for I2CBus {
+    fn save(&self, out: &mut Vec<u8>) {
+        self.slaves.len().save(out);
+        self.slaves.iter().for_each(|slave| slave.save(out));
+    }
+
+    fn load(&mut self, input: &mut &[u8]) -> Result<(), RuntimeError> {
+        let mut len = 0usize;
+        len.load(input)?;
+        if len != self.slaves.len() {
+            return Err(RuntimeError::SnapshotInvalid);
+        }
+        self.slaves.iter_mut().try_for_each(|slave| slave.load(input))
+    }
+}
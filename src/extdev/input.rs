@@ -1,37 +1,133 @@
 use std::collections::VecDeque;
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum KeyType {
     Home,
     Power,
+    /// Active-low button wired to a GPIO pin.
+    Gpio { port: usize, pin: usize },
 }
 
+#[derive(Debug, PartialEq)]
 pub enum KeyPress {
     Press(KeyType),
     Release(KeyType),
 }
 
-#[derive(Default)]
+/// Named buttons of the board. Home is wired to GPA2 and Power to the RTC power key input.
+pub const BOARD_BUTTONS: &[(&str, KeyType)] = &[
+    ("Home", KeyType::Home),
+    ("Power", KeyType::Power),
+];
+
+/// Parse a device button name. Either one of `BOARD_BUTTONS` or a GPIO pin like `GPB5`.
+pub fn parse_key_type(name: &str) -> Option<KeyType> {
+    if let Some((_, key)) = BOARD_BUTTONS.iter().find(|(button, _)| button.eq_ignore_ascii_case(name)) {
+        return Some(*key);
+    }
+    let lower = name.to_ascii_lowercase();
+    let rest = lower.strip_prefix("gp")?;
+    let port = usize::from(rest.bytes().next()?.checked_sub(b'a')?);
+    let pin = rest.get(1..)?.parse().ok()?;
+    (port < 5 && pin < 16).then_some(KeyType::Gpio { port, pin })
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ScriptEvent {
+    TouchMove((usize, usize)),
+    TouchRelease,
+    Key(KeyPress),
+}
+
+/// Timed input events, keyed by the frame they are delivered on.
+pub type InputScript = Vec<(u64, ScriptEvent)>;
+
+/// Parse an input script.
+///
+/// Each line is `<frame> <event>`, where event is one of `move <x> <y>` (touch or drag to a panel pixel), `lift`
+/// (release the touchscreen), `press <button>` or `release <button>`. Empty lines and `#` comments are ignored. Events
+/// on the same frame are delivered in file order.
+pub fn parse_input_script(text: &str) -> Result<InputScript, String> {
+    let mut script = InputScript::new();
+    for (lineno, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let err = |msg: &str| format!("Line {}: {msg}: {line}", lineno + 1);
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let frame = fields[0].parse::<u64>().map_err(|_| err("Invalid frame number"))?;
+        let coord = |s: &str| s.parse::<usize>().map_err(|_| err("Invalid coordinate"));
+        let key = |s: &str| parse_key_type(s).ok_or_else(|| err("Unknown device button"));
+        let event = match fields[1..] {
+            ["move", x, y] => ScriptEvent::TouchMove((coord(x)?, coord(y)?)),
+            ["lift"] => ScriptEvent::TouchRelease,
+            ["press", name] => ScriptEvent::Key(KeyPress::Press(key(name)?)),
+            ["release", name] => ScriptEvent::Key(KeyPress::Release(key(name)?)),
+            _ => return Err(err("Invalid event")),
+        };
+        script.push((frame, event));
+    }
+    // Stable, so same-frame events keep their order.
+    script.sort_by_key(|(frame, _)| *frame);
+    Ok(script)
+}
+
+/// Default number of pending touch updates kept before intermediate moves are dropped.
+pub const DEFAULT_TOUCH_QUEUE_LEN: usize = 32;
+
 pub struct Input {
     touch: VecDeque<Option<(usize, usize)>>,
+    touch_limit: usize,
     keys: VecDeque<KeyPress>,
+    script: VecDeque<(u64, ScriptEvent)>,
+}
+
+impl Default for Input {
+    fn default() -> Self {
+        Self {
+            touch: Default::default(),
+            touch_limit: DEFAULT_TOUCH_QUEUE_LEN,
+            keys: Default::default(),
+            script: Default::default(),
+        }
+    }
 }
 
 impl Input {
+    /// Set how many touch updates may be pending before the oldest intermediate moves are dropped.
+    pub fn set_touch_queue_len(&mut self, len: usize) {
+        self.touch_limit = len.max(1);
+    }
+
     pub fn touch_move(&mut self, xy: (usize, usize)) {
         if let Some(last_touch) = self.touch.back() {
             if last_touch.is_none() {
-                self.touch.push_back(Some(xy));
+                self.push_touch(Some(xy));
             } else if let Some(prev_xy) = last_touch && *prev_xy != xy {
-                self.touch.push_back(Some(xy));
+                self.push_touch(Some(xy));
             }
         } else {
-            self.touch.push_back(Some(xy));
+            self.push_touch(Some(xy));
+        }
+    }
+
+    /// Queue a touch update, then trim the queue back to its limit by dropping moves that are superseded by a later
+    /// move of the same stroke, so taps survive. Once only releases and final positions are left, the oldest updates are
+    /// dropped.
+    fn push_touch(&mut self, update: Option<(usize, usize)>) {
+        self.touch.push_back(update);
+        while self.touch.len() > self.touch_limit {
+            let i = (0..self.touch.len() - 1)
+                .find(|&i| self.touch[i].is_some() && self.touch[i + 1].is_some())
+                .unwrap_or(0);
+            self.touch.remove(i);
         }
     }
 
     #[inline]
     pub fn touch_release(&mut self) {
-        self.touch.push_back(None);
+        self.push_touch(None);
     }
 
     #[inline]
@@ -53,4 +149,69 @@ impl Input {
     pub fn check_key(&mut self) -> Option<KeyPress> {
         self.keys.pop_front()
     }
+
+    /// Queue a script to be replayed. Its frame numbers are relative to the frame counter passed to `frame_step`.
+    pub fn load_script(&mut self, script: InputScript) {
+        self.script = script.into();
+    }
+
+    /// Deliver the scripted events due on or before `frame`.
+    pub fn frame_step(&mut self, frame: u64) {
+        while let Some((_, event)) = self.script.pop_front_if(|(at, _)| *at <= frame) {
+            match event {
+                ScriptEvent::TouchMove(xy) => self.touch_move(xy),
+                ScriptEvent::TouchRelease => self.touch_release(),
+                ScriptEvent::Key(key) => self.keys.push_back(key),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_input_script() {
+    let text = "# drag then tap Home\n2 move 10 20\n2 move 30 20\n\n4 lift\n3 press Home  # out of order\n\
+        5 release GPB5\n";
+    let script = parse_input_script(text).unwrap();
+    assert_eq!(script, vec![
+        (2, ScriptEvent::TouchMove((10, 20))),
+        (2, ScriptEvent::TouchMove((30, 20))),
+        (3, ScriptEvent::Key(KeyPress::Press(KeyType::Home))),
+        (4, ScriptEvent::TouchRelease),
+        (5, ScriptEvent::Key(KeyPress::Release(KeyType::Gpio { port: 1, pin: 5 }))),
+    ]);
+    assert!(parse_input_script("1 move 10").is_err());
+    assert!(parse_input_script("1 press gpé").is_err());
+    assert!(parse_input_script("x lift").is_err());
+    assert!(parse_input_script("1 press GPF0").is_err());
+
+    let mut input = Input::default();
+    input.load_script(script);
+    input.frame_step(1);
+    assert_eq!(input.check_touch(), None);
+    input.frame_step(2);
+    assert_eq!(input.check_touch(), Some(Some((10, 20))));
+    assert_eq!(input.check_touch(), Some(Some((30, 20))));
+    assert_eq!(input.check_key(), None);
+    // Catch up on everything that was due while frames were skipped.
+    input.frame_step(5);
+    assert_eq!(input.check_key(), Some(KeyPress::Press(KeyType::Home)));
+    assert_eq!(input.check_touch(), Some(None));
+    assert_eq!(input.check_key(), Some(KeyPress::Release(KeyType::Gpio { port: 1, pin: 5 })));
+}
+
+#[test]
+fn test_touch_queue_limit() {
+    let mut input = Input::default();
+    input.set_touch_queue_len(4);
+    input.touch_move((0, 0));
+    input.touch_release();
+    for x in 1..=10 {
+        input.touch_move((x, 0));
+    }
+    input.touch_release();
+    input.touch_move((20, 0));
+    // The drag collapses to its last position, then the new stroke pushes out the oldest update.
+    assert_eq!(input.touch, [None, Some((10, 0)), None, Some((20, 0))]);
+    input.touch_move((21, 0));
+    assert_eq!(input.touch, [None, Some((10, 0)), None, Some((21, 0))]);
 }
@@ -1,2 +1,15 @@
+pub mod camera;
+pub mod eeprom;
+pub mod i2c;
 pub mod input;
+pub mod nand;
 pub mod sd;
+pub mod spi_flash;
+
+/// Create a temporary image file of `len` bytes, filled with a repeating pattern. `name` must be unique among tests.
+#[cfg(test)]
+pub fn make_test_file(name: &str, len: usize) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("lle-test-{}-{name}", std::process::id()));
+    std::fs::write(&path, (0..len).map(|i| (i % 251) as u8).collect::<Vec<_>>()).unwrap();
+    path
+}
@@ -0,0 +1,397 @@
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use log::{debug, error, trace, warn};
+
+use crate::{RuntimeError, impl_snapshot, snapshot::{Snapshot, load_new}};
+
+/*
+Supported commands (large page, 8-bit bus):
+
+0xff        Reset
+0x90        Read ID (1 address cycle)
+0x70        Read status
+0x00 / 0x30 Read page (2 column + 3 row address cycles)
+0x80 / 0x10 Program page (2 column + 3 row address cycles)
+0x60 / 0xd0 Erase block (3 row address cycles)
+*/
+
+pub const PAGE_SIZE: usize = 2048;
+pub const SPARE_SIZE: usize = 64;
+/// Size of a page including its spare area, which is how pages are laid out in the backing image.
+pub const RAW_PAGE_SIZE: usize = PAGE_SIZE + SPARE_SIZE;
+pub const PAGES_PER_BLOCK: u64 = 64;
+
+const CMD_READ: u8 = 0x00;
+const CMD_READ_CONFIRM: u8 = 0x30;
+const CMD_PROGRAM: u8 = 0x80;
+const CMD_PROGRAM_CONFIRM: u8 = 0x10;
+const CMD_ERASE: u8 = 0x60;
+const CMD_ERASE_CONFIRM: u8 = 0xd0;
+const CMD_READ_STATUS: u8 = 0x70;
+const CMD_READ_ID: u8 = 0x90;
+const CMD_RESET: u8 = 0xff;
+
+const STATUS_FAIL: u8 = 1 << 0;
+const STATUS_READY: u8 = 1 << 6;
+const STATUS_NOT_PROTECTED: u8 = 1 << 7;
+
+const MAKER_SAMSUNG: u8 = 0xec;
+
+/// Device codes of 3.3V x8 large page parts, indexed by log2(blocks / 1024).
+const DEVICE_CODES: [u8; 4] = [0xf1, 0xda, 0xdc, 0xd3];
+
+/// What the data port returns when read.
+#[derive(Default, Debug, PartialEq)]
+enum DataOut {
+    #[default]
+    None,
+    Id,
+    Status,
+    Page,
+}
+
+/// A raw NAND flash chip backed by an image file.
+///
+/// The image holds each page followed by its spare area, i.e. `RAW_PAGE_SIZE` bytes per page.
+#[derive(Default)]
+pub struct NAND {
+    image_file: Option<fs::File>,
+    pages: u64,
+    /// Last command latched into the chip.
+    command: u8,
+    /// Address cycles received after the last command.
+    address: Vec<u8>,
+    /// Page register, which holds the page being read or programmed.
+    page_buf: Vec<u8>,
+    column: usize,
+    data_out: DataOut,
+    id_index: usize,
+    status: u8,
+    /// An operation that pulls R/B# low has finished since the last call to `take_ready()`.
+    ready_edge: bool,
+}
+
+impl NAND {
+    pub fn mount(&mut self, path: &str) -> Result<(), RuntimeError> {
+        if self.image_file.is_some() {
+            return Err(RuntimeError::NANDAlreadyMounted)
+        }
+        let file = fs::OpenOptions::new().read(true).write(true).open(path)?;
+        let size = file.metadata()?.len();
+        let block_size = RAW_PAGE_SIZE as u64 * PAGES_PER_BLOCK;
+        if size == 0 || size % block_size != 0 || Self::device_code(size / block_size).is_none() {
+            error!("NAND image size {size} is not 1024, 2048, 4096 or 8192 blocks of {block_size} bytes.");
+            return Err(RuntimeError::NANDInvalidImage);
+        }
+
+        self.image_file = Some(file);
+        self.pages = size / RAW_PAGE_SIZE as u64;
+        debug!("NAND: {} blocks, ID {:02x?}", self.pages / PAGES_PER_BLOCK, self.id());
+        self.reset();
+        Ok(())
+    }
+
+    pub fn unmount(&mut self) {
+        self.flush();
+        self.image_file = None;
+        self.pages = 0;
+    }
+
+    pub fn is_mounted(&self) -> bool {
+        self.image_file.is_some()
+    }
+
+    fn device_code(blocks: u64) -> Option<u8> {
+        let index = blocks.checked_ilog2()?.checked_sub(10)?;
+        if blocks.is_power_of_two() {
+            DEVICE_CODES.get(usize::try_from(index).ok()?).copied()
+        } else {
+            None
+        }
+    }
+
+    /// Read ID bytes: maker, device, and the 3rd to 5th bytes describing a 2KiB page, 128KiB block, x8 part.
+    fn id(&self) -> [u8; 5] {
+        let device = Self::device_code(self.pages / PAGES_PER_BLOCK).unwrap_or(0);
+        [MAKER_SAMSUNG, device, 0x00, 0x95, 0x40]
+    }
+
+    fn reset(&mut self) {
+        self.command = CMD_RESET;
+        self.address.clear();
+        self.page_buf.clear();
+        self.column = 0;
+        self.data_out = DataOut::None;
+        self.status = STATUS_READY | STATUS_NOT_PROTECTED;
+        self.ready_edge = true;
+    }
+
+    /// Whether R/B# went from busy back to ready since the last call.
+    pub fn take_ready(&mut self) -> bool {
+        std::mem::take(&mut self.ready_edge)
+    }
+
+    /// Latch a command byte.
+    pub fn command(&mut self, cmd: u8) {
+        if !self.is_mounted() {
+            return;
+        }
+        trace!("NAND: Command 0x{cmd:02x}");
+        match cmd {
+            CMD_RESET => self.reset(),
+            CMD_READ_STATUS => self.data_out = DataOut::Status,
+            CMD_READ | CMD_PROGRAM | CMD_ERASE | CMD_READ_ID => {
+                self.command = cmd;
+                self.address.clear();
+                self.data_out = DataOut::None;
+                if cmd == CMD_PROGRAM {
+                    self.page_buf = vec![0xff; RAW_PAGE_SIZE];
+                }
+            }
+            CMD_READ_CONFIRM if self.command == CMD_READ => {
+                if let Some(page) = self.row(2) {
+                    self.page_buf = self.read_page(page);
+                    self.column = self.column_address();
+                    self.data_out = DataOut::Page;
+                    self.ready_edge = true;
+                }
+            }
+            CMD_PROGRAM_CONFIRM if self.command == CMD_PROGRAM => {
+                let ok = self.row(2).is_some_and(|page| self.program_page(page));
+                self.set_result(ok);
+            }
+            CMD_ERASE_CONFIRM if self.command == CMD_ERASE => {
+                let ok = self.row(0).is_some_and(|page| self.erase_block(page / PAGES_PER_BLOCK));
+                self.set_result(ok);
+            }
+            _ => warn!("NAND: Unsupported command 0x{cmd:02x} after 0x{:02x}", self.command),
+        }
+    }
+
+    /// Latch an address byte.
+    pub fn address(&mut self, addr: u8) {
+        if !self.is_mounted() {
+            return;
+        }
+        self.address.push(addr);
+        match self.command {
+            CMD_READ_ID => {
+                self.id_index = 0;
+                self.data_out = DataOut::Id;
+            }
+            CMD_PROGRAM if self.address.len() == 2 => self.column = self.column_address(),
+            _ => {}
+        }
+    }
+
+    /// Read from the data port.
+    pub fn read_data(&mut self, buf: &mut [u8]) {
+        match self.data_out {
+            DataOut::None => {
+                warn!("NAND: Data read without a pending read command");
+                buf.fill(0xff);
+            }
+            DataOut::Id => {
+                let id = self.id();
+                for b in buf {
+                    *b = id.get(self.id_index).copied().unwrap_or(0);
+                    self.id_index += 1;
+                }
+            }
+            DataOut::Status => buf.fill(self.status),
+            DataOut::Page => {
+                for b in buf {
+                    *b = self.page_buf.get(self.column).copied().unwrap_or(0xff);
+                    self.column += 1;
+                }
+            }
+        }
+    }
+
+    /// Write to the data port. Only meaningful between the two cycles of a program command.
+    pub fn write_data(&mut self, buf: &[u8]) {
+        if self.command != CMD_PROGRAM || self.page_buf.is_empty() {
+            warn!("NAND: Data written without a pending program command");
+            return;
+        }
+        let start = self.column.min(RAW_PAGE_SIZE);
+        let end = (self.column + buf.len()).min(RAW_PAGE_SIZE);
+        self.page_buf[start..end].copy_from_slice(&buf[..end - start]);
+        self.column += buf.len();
+    }
+
+    /// Column address from the first two address cycles.
+    fn column_address(&self) -> usize {
+        usize::from(self.address.first().copied().unwrap_or(0)) |
+            usize::from(self.address.get(1).copied().unwrap_or(0)) << 8
+    }
+
+    /// Page address from the 3 address cycles starting at `start`, if it's within the chip.
+    fn row(&self, start: usize) -> Option<u64> {
+        let page = self.address.iter().skip(start).take(3).rev().fold(0u64, |acc, b| (acc << 8) | u64::from(*b));
+        if page >= self.pages {
+            warn!("NAND: Page {page} is beyond the end of the chip");
+            return None;
+        }
+        Some(page)
+    }
+
+    fn set_result(&mut self, ok: bool) {
+        self.status = STATUS_READY | STATUS_NOT_PROTECTED | if ok { 0 } else { STATUS_FAIL };
+        self.data_out = DataOut::None;
+        self.ready_edge = true;
+    }
+
+    fn seek(&mut self, page: u64) -> Option<&mut fs::File> {
+        let image_file = self.image_file.as_mut()?;
+        match image_file.seek(SeekFrom::Start(page * RAW_PAGE_SIZE as u64)) {
+            Ok(_) => Some(image_file),
+            Err(err) => {
+                error!("NAND: Seeking to page {page} failed: {err:?}");
+                None
+            }
+        }
+    }
+
+    fn read_page(&mut self, page: u64) -> Vec<u8> {
+        let mut buf = vec![0xff; RAW_PAGE_SIZE];
+        if let Some(image_file) = self.seek(page) {
+            image_file.read_exact(&mut buf).unwrap_or_else(|err| {
+                error!("NAND: Reading page {page} failed: {err:?}");
+            });
+        }
+        trace!("NAND: Read page {page}");
+        buf
+    }
+
+    /// Program the page register into `page`. Like on a real chip, programming can only clear bits.
+    fn program_page(&mut self, page: u64) -> bool {
+        let mut data = self.read_page(page);
+        for (old, new) in data.iter_mut().zip(&self.page_buf) {
+            *old &= new;
+        }
+        trace!("NAND: Program page {page}");
+        self.write_pages(page, &data)
+    }
+
+    fn erase_block(&mut self, block: u64) -> bool {
+        trace!("NAND: Erase block {block}");
+        let data = vec![0xff; RAW_PAGE_SIZE * PAGES_PER_BLOCK as usize];
+        self.write_pages(block * PAGES_PER_BLOCK, &data)
+    }
+
+    fn write_pages(&mut self, page: u64, data: &[u8]) -> bool {
+        let Some(image_file) = self.seek(page) else {
+            return false;
+        };
+        image_file.write_all(data).map_err(|err| {
+            error!("NAND: Writing page {page} failed: {err:?}");
+        }).is_ok()
+    }
+
+    pub fn flush(&mut self) {
+        if let Some(image_file) = self.image_file.as_mut() {
+            image_file.flush().unwrap_or_else(|err| {
+                error!("NAND: Flushing the image failed: {err:?}");
+            });
+        }
+    }
+}
+
+impl Snapshot for DataOut {
+    fn save(&self, out: &mut Vec<u8>) {
+        let value: u8 = match self {
+            Self::None => 0,
+            Self::Id => 1,
+            Self::Status => 2,
+            Self::Page => 3,
+        };
+        value.save(out);
+    }
+
+    fn load(&mut self, input: &mut &[u8]) -> Result<(), RuntimeError> {
+        *self = match load_new::<u8>(input)? {
+            0 => Self::None,
+            1 => Self::Id,
+            2 => Self::Status,
+            3 => Self::Page,
+            _ => return Err(RuntimeError::SnapshotInvalid),
+        };
+        Ok(())
+    }
+}
+
+impl_snapshot!(NAND { command, address, page_buf, column, data_out, id_index, status });
+
+#[cfg(test)]
+fn make_test_nand(name: &str) -> std::path::PathBuf {
+    // Sparse 1024 block image with a pattern in page 0 and the rest of block 1 erased.
+    let path = crate::extdev::make_test_file(&format!("{name}.nand"), RAW_PAGE_SIZE);
+    let mut image = fs::OpenOptions::new().write(true).open(&path).unwrap();
+    image.seek(SeekFrom::Start(RAW_PAGE_SIZE as u64 * PAGES_PER_BLOCK)).unwrap();
+    image.write_all(&vec![0xff; RAW_PAGE_SIZE * PAGES_PER_BLOCK as usize]).unwrap();
+    image.set_len(RAW_PAGE_SIZE as u64 * PAGES_PER_BLOCK * 1024).unwrap();
+    path
+}
+
+#[cfg(test)]
+fn send_address(nand: &mut NAND, column: u16, page: u32) {
+    for b in column.to_le_bytes() {
+        nand.address(b);
+    }
+    for b in &page.to_le_bytes()[..3] {
+        nand.address(*b);
+    }
+}
+
+#[test]
+fn test_nand_commands() {
+    let path = make_test_nand("commands");
+    let mut nand = NAND::default();
+    nand.mount(path.to_str().unwrap()).unwrap();
+
+    nand.command(CMD_READ_ID);
+    nand.address(0);
+    let mut id = [0u8; 5];
+    nand.read_data(&mut id);
+    assert_eq!(id, [0xec, 0xf1, 0x00, 0x95, 0x40]);
+
+    // Read page 0 starting from column 4.
+    nand.command(CMD_READ);
+    send_address(&mut nand, 4, 0);
+    nand.command(CMD_READ_CONFIRM);
+    assert!(nand.take_ready());
+    let mut buf = [0u8; 4];
+    nand.read_data(&mut buf);
+    assert_eq!(buf, [4, 5, 6, 7]);
+
+    // Program clears bits of page 65 (block 1), then erase restores it.
+    nand.command(CMD_PROGRAM);
+    send_address(&mut nand, 0, 65);
+    nand.write_data(&[0x12, 0x34]);
+    nand.command(CMD_PROGRAM_CONFIRM);
+    nand.command(CMD_READ_STATUS);
+    nand.read_data(&mut buf[..1]);
+    assert_eq!(buf[0], STATUS_READY | STATUS_NOT_PROTECTED);
+
+    nand.command(CMD_READ);
+    send_address(&mut nand, 0, 65);
+    nand.command(CMD_READ_CONFIRM);
+    nand.read_data(&mut buf);
+    assert_eq!(buf, [0x12, 0x34, 0xff, 0xff]);
+
+    nand.command(CMD_ERASE);
+    for b in &65u32.to_le_bytes()[..3] {
+        nand.address(*b);
+    }
+    nand.command(CMD_ERASE_CONFIRM);
+    nand.command(CMD_READ);
+    send_address(&mut nand, 0, 64);
+    nand.command(CMD_READ_CONFIRM);
+    nand.read_data(&mut buf);
+    assert_eq!(buf, [0xff; 4]);
+
+    nand.unmount();
+    fs::remove_file(&path).unwrap();
+}
@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::{fmt::Display};
 use std::fs;
@@ -9,7 +10,9 @@ use std::os::windows::fs::MetadataExt;
 use bit_field::{B1, B2, B3, B4, B5, B6, B7, B8, B12, B22, bitfield};
 use log::{debug, error, trace, warn};
 
-use crate::RuntimeError;
+use crate::{RuntimeError, impl_snapshot, impl_snapshot_bitfield, peripherals::common::Reset, snapshot::{Snapshot, load_new}};
+#[cfg(test)]
+use crate::extdev::make_test_file;
 
 /*
 Commands directly used by BSP:
@@ -24,17 +27,29 @@ CMD8
 CMD9
 CMD10
 CMD12
+CMD13 (polled by some guests after writes)
 CMD16
+CMD17 (not used by BSP, but used by some recovery tools)
 CMD18
+CMD24 (not used by BSP, but used by some recovery tools)
 CMD25
+CMD32 (used by guests that erase before formatting)
+CMD33
+CMD38
+CMD42 (used by guests that password-protect removable cards)
 CMD55
     ACMD6
+    ACMD13 (queried by some guests for the speed class)
     ACMD41
     ACMD51
 */
 
-pub const CID_ESD: [u8; 16] = [0x00, 0x45, 0x6d, 0x49, 0x6e, 0x74, 0x53, 0x44, 0x10, 0xde, 0xad, 0xbe, 0xef, 0x00, 0xe1, 0x6f];
-pub const CID_XSD: [u8; 16] = [0x00, 0x45, 0x6d, 0x45, 0x78, 0x74, 0x53, 0x44, 0x10, 0xde, 0xad, 0xbe, 0xef, 0x00, 0xe1, 0x65];
+pub const CID_ESD: CardIdentification = CardIdentification {
+    manufacturer: 0x00, oem: *b"Em", product: *b"IntSD", revision: 0x10, serial: 0x45534430, year: 2014, month: 1,
+};
+pub const CID_XSD: CardIdentification = CardIdentification {
+    manufacturer: 0x00, oem: *b"Em", product: *b"ExtSD", revision: 0x10, serial: 0x58534430, year: 2014, month: 1,
+};
 // SD spec V2.00, erases to 0, no security, 1-and-4-bit interface, no optional command support.
 const SCR: [u8; 8] = [0x02, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
 // From 6 to 1
@@ -48,6 +63,88 @@ const CARD_FUNC: [u16; 6] = [
 ];
 
 const SDSC_MAX_CAPACITY: u64 = 0x80000000;
+/// Supply voltage field of CMD8 accepted by the card (2.7-3.6V).
+const VHS_2V7_3V6: u8 = 0b0001;
+
+// CMD42 data block flags.
+const LOCK_SET_PWD: u8 = 1 << 0;
+const LOCK_CLR_PWD: u8 = 1 << 1;
+const LOCK_LOCK_UNLOCK: u8 = 1 << 2;
+const LOCK_ERASE: u8 = 1 << 3;
+const MAX_PASSWORD_LEN: usize = 16;
+/// Capacity granularity of the CSD C_SIZE field.
+const C_SIZE_UNIT: u64 = 512 * 1024;
+
+// SD Status fields. Class 4, 4MiB allocation units, erase timeout of 1 second per AU.
+const SPEED_CLASS_4: u8 = 0x02;
+const PERFORMANCE_MOVE: u8 = 4;  // 4MB/s
+const AU_SIZE_4MIB: u8 = 0x9;
+const ERASE_SIZE: u16 = 1;  // AUs
+const ERASE_TIMEOUT: u8 = 1;  // Seconds
+
+/// Sectors zeroed per write when erasing the image.
+const ERASE_CHUNK_SECTORS: u64 = 128;
+
+/// Fields of the CID register (see 5.2).
+pub struct CardIdentification {
+    pub manufacturer: u8,
+    pub oem: [u8; 2],
+    pub product: [u8; 5],
+    /// Product revision in BCD, e.g. 0x10 for 1.0.
+    pub revision: u8,
+    pub serial: u32,
+    /// Manufacturing date. Years range from 2000 to 2255.
+    pub year: u16,
+    pub month: u8,
+}
+
+impl CardIdentification {
+    /// Encode the register, most significant byte first, including the CRC7 and the end bit.
+    pub fn as_bytes(&self) -> [u8; 16] {
+        let mut cid = [0u8; 16];
+        cid[0] = self.manufacturer;
+        cid[1..3].copy_from_slice(&self.oem);
+        cid[3..8].copy_from_slice(&self.product);
+        cid[8] = self.revision;
+        cid[9..13].copy_from_slice(&self.serial.to_be_bytes());
+        // MDT[19:8] holds the year since 2000 followed by the month, after 4 reserved bits.
+        let mdt = (self.year.saturating_sub(2000).min(0xff) << 4) | u16::from(self.month & 0xf);
+        cid[13..15].copy_from_slice(&mdt.to_be_bytes());
+        cid[15] = (crc7(&cid[..15]) << 1) | 1;
+        cid
+    }
+}
+
+/// Compute the CRC7 (G(x) = x^7 + x^3 + 1) used by the CMD channel and CID/CSD registers.
+pub fn crc7(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in data {
+        for bit in (0..8).rev() {
+            let msb = ((crc >> 6) ^ (byte >> bit)) & 1;
+            crc = (crc << 1) & 0x7f;
+            if msb != 0 {
+                crc ^= 0x09;
+            }
+        }
+    }
+    crc
+}
+
+/// Compute the CRC16-CCITT (G(x) = x^16 + x^12 + x^5 + 1) used by the DAT channel.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for byte in data {
+        crc ^= u16::from(*byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
 
 #[bitfield]
 #[derive(Default, Debug, PartialEq)]
@@ -150,17 +247,21 @@ pub struct CardStatus {
     out_of_range: bool,  // 31
 }
 
+/// Status bits that are only reported once (table 4-42).
+///
+/// This covers the bits with clear condition C (cleared by read): OUT_OF_RANGE, ADDRESS_ERROR, BLOCK_LEN_ERROR,
+/// ERASE_SEQ_ERROR, ERASE_PARAM, WP_VIOLATION, LOCK_UNLOCK_FAILED, CARD_ECC_FAILED, CC_ERROR, ERROR, CSD_OVERWRITE,
+/// WP_ERASE_SKIP, ERASE_RESET, APP_CMD and AKE_SEQ_ERROR. It also covers COM_CRC_ERROR and ILLEGAL_COMMAND (clear
+/// condition B), since the offending command gets no response and the bits are reported by the next one instead.
+///
+/// CARD_IS_LOCKED, CARD_ECC_DISABLED, CURRENT_STATE, READY_FOR_DATA and FX_EVENT follow the card state and are kept.
+const STATUS_CLEAR_ON_READ: u64 = 0xfdf9a028;
+
 impl CardStatus {
+    /// Take the status to report in a response, clearing the bits that are only reported once.
     pub fn after_read(&mut self) -> CardStatus {
-        // TODO cross-check with table 4-43
-        let before_clear = self.clone();
-        self.set(26, 6, 0);
-        self.set_lock_unlock_failed(false);
-        self.set(19, 3, 0);
-        self.set(15, 2, 0);
-        self.set_erased_reset(false);
-        self.set_app_command(false);
-        self.set_ake_seq_error(false);
+        let before_clear = *self;
+        self.set(0, 32, self.get(0, 32) & !STATUS_CLEAR_ON_READ);
         before_clear
     }
 }
@@ -169,16 +270,21 @@ impl CardStatus {
 pub enum SendAction {
     #[default]
     None,
-    FTLWrite{sector_index: u64},
+    /// Write to the backing image. `single` terminates the transfer after one block (CMD24).
+    FTLWrite{sector_index: u64, single: bool},
+    /// Receive the CMD42 data block.
+    LockUnlock,
 }
 
 #[derive(Default, Debug)]
 pub enum RecvAction {
     #[default]
     None,
-    FTLRead{sector_index: u64},
+    /// Read from the backing image. `single` terminates the transfer after one block (CMD17).
+    FTLRead{sector_index: u64, single: bool},
     SCRRead,
     FunctionStatus{ arg: u32 },
+    SDStatusRead,
 }
 
 #[bitfield]
@@ -330,16 +436,32 @@ impl CardSpecific {
         }
     }
 
-    pub fn init_with_size(size: u64) -> Self {
-        // TODO this cannot be unwrap
+    /// Build a CSD describing a card of `size` bytes, which needs to be a non-zero multiple of `C_SIZE_UNIT`.
+    pub fn init_with_size(size: u64) -> Result<Self, RuntimeError> {
+        if size == 0 {
+            return Err(RuntimeError::SDImageEmpty);
+        }
+        if !size.is_multiple_of(C_SIZE_UNIT) {
+            return Err(RuntimeError::SDImageUnaligned);
+        }
+        let c_size = size / C_SIZE_UNIT - 1;
         if size > SDSC_MAX_CAPACITY {
             let mut result = CardSpecificHC::default();
-            result.set_c_size(u32::try_from(size / 1024 / 512).unwrap() - 1);
-            Self::HC(result)
+            let c_size = u32::try_from(c_size).ok().filter(|c_size| *c_size < 1 << 22);
+            result.set_c_size(c_size.ok_or(RuntimeError::SDImageTooLarge)?);
+            Ok(Self::HC(result))
         } else {
             let mut result = CardSpecificSC::default();
-            result.set_c_size(u16::try_from(size / 1024 / 512).unwrap() - 1);
-            Self::SC(result)
+            // Fits in 12 bits, since SDSC capacity is capped at 2GiB with 512x C_SIZE_MULT and 1KiB blocks.
+            result.set_c_size(u16::try_from(c_size).unwrap());
+            Ok(Self::SC(result))
+        }
+    }
+
+    pub fn set_perm_write_protect(&mut self, protect: bool) {
+        match self {
+            CardSpecific::SC(csd) => csd.set_perm_write_protect(protect),
+            CardSpecific::HC(csd) => csd.set_perm_write_protect(protect),
         }
     }
 
@@ -363,27 +485,70 @@ pub struct SD {
     rca: u16,
     selected_functions: u32,
     io_size: u32,
+    /// 4-bit bus width selected by ACMD6.
+    wide_bus: bool,
+    /// Card password set through CMD42. Empty when the card has no password.
+    password: Vec<u8>,
+    /// The host sent a valid CMD8, i.e. it's a v2.00 host that can handle high capacity cards.
+    if_cond_received: bool,
+    /// Number of SIC ticks DAT0 is held low after a write or erase.
+    busy_ticks: u32,
+    /// SIC ticks left until the card releases DAT0.
+    busy_remaining: u32,
+    /// First and last sector of the erase range set by CMD32 and CMD33.
+    erase_start: Option<u64>,
+    erase_end: Option<u64>,
     image_file: Option<fs::File>,
+    image_size: u64,
+    /// Sectors written by the host but not yet written back to the image, indexed by sector.
+    write_cache: BTreeMap<u64, Vec<u8>>,
+    /// Number of dirty sectors that triggers a write back. 0 writes through.
+    write_cache_limit: usize,
+    read_only: bool,
+    /// Card is physically removed from the slot while keeping the image mounted.
+    ejected: bool,
+    /// Emulate CRC checksums on the CMD and DAT channels.
+    crc_enabled: bool,
+    /// CRC16 of the last block read from the DAT channel. Only populated when CRC emulation is enabled.
+    data_crc: Option<u16>,
     send_action: SendAction,
     recv_action: RecvAction,
 }
 
 impl SD {
+    /// Create an empty slot for a card identified by `cid`.
+    pub fn new(cid: &CardIdentification) -> Self {
+        Self { cid: cid.as_bytes(), ..Default::default() }
+    }
+
     pub fn mount(&mut self, path: &str) -> Result<(), RuntimeError> {
+        self.mount_with_options(path, false)
+    }
+
+    /// Mount an image as a permanently write-protected card.
+    pub fn mount_ro(&mut self, path: &str) -> Result<(), RuntimeError> {
+        self.mount_with_options(path, true)
+    }
+
+    fn mount_with_options(&mut self, path: &str, read_only: bool) -> Result<(), RuntimeError> {
         if self.image_file.is_some() {
             return Err(RuntimeError::SDAlreadyMounted)
         }
-        let file = fs::OpenOptions::new().read(true).write(true).open(path)?;
-        self.image_file = Some(file);
+        let file = fs::OpenOptions::new().read(true).write(!read_only).open(path)?;
 
         #[cfg(target_os = "linux")]
-        let size = self.image_file.as_ref().unwrap().metadata()?.size();
+        let size = file.metadata()?.size();
         #[cfg(target_os = "windows")]
-        let size = self.image_file.as_ref().unwrap().metadata()?.file_size();
+        let size = file.metadata()?.file_size();
 
-        let csd_inner = CardSpecific::init_with_size(size);
+        // Only take the image once it's known to fit in a CSD.
+        let mut csd_inner = CardSpecific::init_with_size(size)?;
+        csd_inner.set_perm_write_protect(read_only);
         debug!("Emulated CSD: {}", &csd_inner);
 
+        self.image_file = Some(file);
+        self.read_only = read_only;
+        self.image_size = size;
         self.csd = Some(csd_inner);
 
         self.send_action = SendAction::None;
@@ -393,7 +558,10 @@ impl SD {
     }
 
     pub fn unmount(&mut self) {
+        self.flush();
         self.image_file = None;
+        self.image_size = 0;
+        self.read_only = false;
         self.csd = None;
     }
 
@@ -401,9 +569,57 @@ impl SD {
         self.image_file.is_some()
     }
 
+    /// Whether a card with a mounted image is currently present in the slot.
+    pub fn is_inserted(&self) -> bool {
+        self.is_mounted() && !self.ejected
+    }
+
+    /// Put the card back into the slot.
+    ///
+    /// The card will be powered on in the `Idle` state, and the host needs to initialize it again.
+    pub fn insert(&mut self) {
+        if !self.ejected {
+            return;
+        }
+        debug!("Card inserted");
+        self.ejected = false;
+    }
+
+    /// Remove the card from the slot without unmounting the image.
+    ///
+    /// Any pending transfers are dropped as the card loses power.
+    pub fn eject(&mut self) {
+        if self.ejected {
+            return;
+        }
+        debug!("Card ejected");
+        self.flush();
+        self.ejected = true;
+        self.card_status = CardStatus::default();
+        // Cards with a password power up locked.
+        self.card_status.set_card_is_locked(!self.password.is_empty());
+        self.rca = 0;
+        self.busy_remaining = 0;
+        self.send_action = SendAction::None;
+        self.recv_action = RecvAction::None;
+    }
+
+    /// Drop the data transfer in progress, as when the host resets its SD engine in the middle of it.
+    ///
+    /// Blocks already written are kept, and the card goes back to the `Transfer` state.
+    pub fn abort_transfer(&mut self) {
+        if matches!(self.card_status.get_current_state(), CurrentState::SendingData | CurrentState::ReceivingData) {
+            debug!("Data transfer aborted");
+            self.flush();
+            self.card_status.set_current_state(CurrentState::Transfer);
+        }
+        self.send_action = SendAction::None;
+        self.recv_action = RecvAction::None;
+    }
+
     /// Make a request on the CMD channel.
     pub fn make_request(&mut self, cmd: u8, arg: u32) -> Response {
-        if !self.is_mounted() {
+        if !self.is_inserted() {
             return Response::RNone;
         }
         if self.card_status.get_app_command() {
@@ -414,6 +630,17 @@ impl SD {
                 6 => {
                     if self.card_status.get_current_state() == CurrentState::Transfer {
                         debug!("arg=0x{arg:08x}");
+                        self.wide_bus = arg & 0b11 == 0b10;
+                        let status = self.card_status.after_read();
+                        Response::R1(ResponseType1 { cmd, status, busy: false })
+                    } else {
+                        self.term_illegal()
+                    }
+                }
+                13 => {
+                    if self.card_status.get_current_state() == CurrentState::Transfer {
+                        self.recv_action = RecvAction::SDStatusRead;
+                        self.card_status.set_current_state(CurrentState::SendingData);
                         let status = self.card_status.after_read();
                         Response::R1(ResponseType1 { cmd, status, busy: false })
                     } else {
@@ -422,11 +649,14 @@ impl SD {
                 }
                 41 => {
                     if self.card_status.get_current_state() == CurrentState::Idle {
-                        let is_sdhc = match &self.csd {
-                            Some(csdd) => csdd.is_sdhc(),
-                            None => false,
-                        };
-                        if arg & 0x00ffffff == 0 {
+                        // CCS is only meaningful to hosts that went through CMD8.
+                        let is_sdhc = self.is_sdhc() && self.if_cond_received;
+                        if self.is_sdhc() && !self.if_cond_received {
+                            // High capacity cards never finish powering up for v1.x hosts.
+                            warn!("High capacity card initialized without CMD8.");
+                            self.card_status.after_read();
+                            Response::R3(ResponseType3 { ocr: 0x00ffff00, is_sdhc, power_up: false })
+                        } else if arg & 0x00ffffff == 0 {
                             debug!("query");
                             self.card_status.after_read();
                             Response::R3(ResponseType3 { ocr: 0x00ffff00, is_sdhc, power_up: false })
@@ -458,16 +688,19 @@ impl SD {
             };
         }
         trace!("CMD{cmd} arg=0x{arg:08x}");
+        if self.card_status.get_card_is_locked() && matches!(cmd, 6 | 17 | 18 | 24 | 25 | 32 | 33 | 38) {
+            warn!("Rejecting CMD{cmd} on a locked card.");
+            return self.term_illegal();
+        }
         match cmd {
             0 => {
-                self.card_status.set(0, 32, 0u64);
-                self.rca = 0;
+                self.reset();
                 Response::R1(ResponseType1 { cmd, status: self.card_status, busy: false })
             }
             2 => {
                 if self.card_status.get_current_state() == CurrentState::Ready {
                     self.card_status.set_current_state(CurrentState::Identification);
-                    Response::R2(ResponseType2 { cid_csd: self.cid.clone() })
+                    Response::R2(ResponseType2 { cid_csd: self.cid })
                 } else {
                     self.term_illegal()
                 }
@@ -528,8 +761,15 @@ impl SD {
             }
             8 => {
                 if self.card_status.get_current_state() == CurrentState::Idle {
+                    let voltage = u8::try_from((arg >> 8) & 0xf).unwrap();
+                    if voltage != VHS_2V7_3V6 {
+                        // Cards that don't support the voltage range stay silent and remain idle.
+                        warn!("Unsupported supply voltage 0b{voltage:04b} in CMD8.");
+                        return Response::RNone;
+                    }
+                    self.if_cond_received = true;
                     Response::R7(ResponseType7 {
-                        voltage_accepted: u8::try_from((arg >> 8) & 0xf).unwrap(),
+                        voltage_accepted: voltage,
                         check: u8::try_from(arg & 0xff).unwrap(),
                     })
                 } else {
@@ -551,7 +791,7 @@ impl SD {
             10 => {
                 if self.rca == u16::try_from((arg >> 16) & 0xffff).unwrap() {
                     if self.card_status.get_current_state() == CurrentState::StandBy {
-                        Response::R2(ResponseType2 { cid_csd: CID_ESD.clone() })
+                        Response::R2(ResponseType2 { cid_csd: self.cid })
                     } else {
                         self.term_illegal()
                     }
@@ -563,12 +803,39 @@ impl SD {
             12 => {
                 match self.card_status.get_current_state() {
                     CurrentState::SendingData | CurrentState::ReceivingData => {
-                        // TODO: ReceivingData technically needs to wait until the write buffer has been flushed.
-                        // We don't implement asychronous IO operations yet so switching directly to Transfer is good enough for now.
+                        // Writes are flushed synchronously, but the card still goes through Programming and holds DAT0
+                        // low for a while, like a real card draining its write buffer.
+                        let busy = matches!(self.send_action, SendAction::FTLWrite { .. });
+                        if busy {
+                            self.flush();
+                        }
                         trace!("Continuous data IO end");
                         self.recv_action = RecvAction::None;
                         self.send_action = SendAction::None;
                         self.card_status.set_current_state(CurrentState::Transfer);
+                        if busy {
+                            self.start_busy();
+                        }
+                        let status = self.card_status.after_read();
+                        Response::R1(ResponseType1 { cmd, status, busy })
+                    }
+                    _ => self.term_illegal(),
+                }
+            }
+            13 => {
+                let rca = u16::try_from(arg >> 16).unwrap();
+                match self.card_status.get_current_state() {
+                    CurrentState::StandBy |
+                    CurrentState::Transfer |
+                    CurrentState::SendingData |
+                    CurrentState::ReceivingData |
+                    CurrentState::Programming => {
+                        if rca != self.rca {
+                            warn!("RCA does not match, ignoring request.");
+                            return Response::RNone;
+                        }
+                        let ready_for_data = !self.is_busy();
+                        self.card_status.set_ready_for_data(ready_for_data);
                         let status = self.card_status.after_read();
                         Response::R1(ResponseType1 { cmd, status, busy: false })
                     }
@@ -577,24 +844,28 @@ impl SD {
             }
             16 => {
                 if self.card_status.get_current_state() == CurrentState::Transfer {
-                    self.card_status.after_read();
                     if arg == 0 || arg > 512 {
                         warn!("New IO size of {arg} bytes is out of range 1..=512.");
                         self.card_status.set_block_len_error(true);
-                        Response::R1(ResponseType1 { cmd, status: self.card_status, busy: false })
                     } else {
                         self.io_size = arg;
-                        self.card_status.set_block_len_error(false);
                         debug!("IO size (block length) changed to {} bytes.", self.io_size);
-                        Response::R1(ResponseType1 { cmd, status: self.card_status, busy: false })
                     }
+                    let status = self.card_status.after_read();
+                    Response::R1(ResponseType1 { cmd, status, busy: false })
                 } else {
                     self.term_illegal()
                 }
             }
-            18 => {
-                if self.card_status.get_current_state() == CurrentState::Transfer {
-                    self.recv_action = RecvAction::FTLRead { sector_index: arg.into() };
+            17 | 18 => {
+                if self.card_status.get_current_state() == CurrentState::Transfer && self.is_misaligned(arg) {
+                    warn!("Rejecting read from misaligned byte address 0x{arg:08x}.");
+                    self.card_status.set_address_error(true);
+                    let status = self.card_status.after_read();
+                    Response::R1(ResponseType1 { cmd, status, busy: false })
+                } else if self.card_status.get_current_state() == CurrentState::Transfer {
+                    let sector_index = self.arg_to_sector(arg);
+                    self.recv_action = RecvAction::FTLRead { sector_index, single: cmd == 17 };
                     self.card_status.set_current_state(CurrentState::SendingData);
                     let status = self.card_status.after_read();
                     Response::R1(ResponseType1 { cmd, status, busy: false })
@@ -602,9 +873,90 @@ impl SD {
                     self.term_illegal()
                 }
             }
-            25 => {
+            24 | 25 => {
+                if self.card_status.get_current_state() == CurrentState::Transfer && self.read_only {
+                    warn!("Rejecting write to sector {} on a read-only card.", self.arg_to_sector(arg));
+                    self.card_status.set_wp_violation(true);
+                    let status = self.card_status.after_read();
+                    Response::R1(ResponseType1 { cmd, status, busy: false })
+                } else if self.card_status.get_current_state() == CurrentState::Transfer && self.is_misaligned(arg) {
+                    warn!("Rejecting write to misaligned byte address 0x{arg:08x}.");
+                    self.card_status.set_address_error(true);
+                    let status = self.card_status.after_read();
+                    Response::R1(ResponseType1 { cmd, status, busy: false })
+                } else if self.card_status.get_current_state() == CurrentState::Transfer &&
+                    self.arg_to_sector(arg) >= self.image_size / 512
+                {
+                    warn!("Rejecting write to sector {} beyond the end of the image.", self.arg_to_sector(arg));
+                    self.card_status.set_out_of_range(true);
+                    let status = self.card_status.after_read();
+                    Response::R1(ResponseType1 { cmd, status, busy: false })
+                } else if self.card_status.get_current_state() == CurrentState::Transfer {
+                    let sector_index = self.arg_to_sector(arg);
+                    self.send_action = SendAction::FTLWrite { sector_index, single: cmd == 24 };
+                    self.card_status.set_current_state(CurrentState::ReceivingData);
+                    let status = self.card_status.after_read();
+                    Response::R1(ResponseType1 { cmd, status, busy: false })
+                } else {
+                    self.term_illegal()
+                }
+            }
+            32 | 33 => {
+                if self.card_status.get_current_state() != CurrentState::Transfer {
+                    return self.term_illegal();
+                }
+                // SDSC cards ignore the byte offset within the sector.
+                let sector_index = self.arg_to_sector(arg);
+                if sector_index >= self.image_size / 512 {
+                    warn!("Erase address {sector_index} is beyond the end of the image.");
+                    self.card_status.set_out_of_range(true);
+                    self.erase_start = None;
+                    self.erase_end = None;
+                } else if cmd == 32 {
+                    self.erase_start = Some(sector_index);
+                    self.erase_end = None;
+                } else if self.erase_start.is_some() {
+                    self.erase_end = Some(sector_index);
+                } else {
+                    warn!("Erase end set before the erase start.");
+                    self.card_status.set_erase_seq_error(true);
+                }
+                let status = self.card_status.after_read();
+                Response::R1(ResponseType1 { cmd, status, busy: false })
+            }
+            38 => {
+                if self.card_status.get_current_state() != CurrentState::Transfer {
+                    return self.term_illegal();
+                }
+                // Only an erase that actually runs keeps the card busy.
+                let busy = match (self.erase_start.take(), self.erase_end.take()) {
+                    (Some(start), Some(end)) if start > end => {
+                        warn!("Erase range {start}..={end} is reversed.");
+                        self.card_status.set_erase_param(true);
+                        false
+                    }
+                    (Some(_), Some(_)) if self.read_only => {
+                        warn!("Skipping erase on a read-only card.");
+                        self.card_status.set_wp_erase_skip(true);
+                        false
+                    }
+                    (Some(start), Some(end)) => {
+                        self.erase_sectors(start, end);
+                        self.start_busy();
+                        true
+                    }
+                    _ => {
+                        warn!("Erase requested without a complete erase range.");
+                        self.card_status.set_erase_seq_error(true);
+                        false
+                    }
+                };
+                let status = self.card_status.after_read();
+                Response::R1(ResponseType1 { cmd, status, busy })
+            }
+            42 => {
                 if self.card_status.get_current_state() == CurrentState::Transfer {
-                    self.send_action = SendAction::FTLWrite { sector_index: arg.into() };
+                    self.send_action = SendAction::LockUnlock;
                     self.card_status.set_current_state(CurrentState::ReceivingData);
                     let status = self.card_status.after_read();
                     Response::R1(ResponseType1 { cmd, status, busy: false })
@@ -613,9 +965,11 @@ impl SD {
                 }
             }
             55 => {
-                self.card_status.after_read();
+                // APP_CMD also tells the card to interpret the next command as an ACMD, so it's set after the read.
+                let mut status = self.card_status.after_read();
                 self.card_status.set_app_command(true);
-                Response::R1(ResponseType1 { cmd, status: self.card_status, busy: false })
+                status.set_app_command(true);
+                Response::R1(ResponseType1 { cmd, status, busy: false })
             }
             _ => {
                 warn!("Unhandled SD card command {cmd}");
@@ -624,46 +978,138 @@ impl SD {
         }
     }
 
+    /// Whether the card is in the data phase `state` with an action waiting for the data. Otherwise the host moves data
+    /// without a data command before it, which is most likely a bug of the guest driver, so the data is dropped.
+    fn in_data_phase(&self, state: CurrentState, has_action: bool, direction: &str) -> bool {
+        let current = self.card_status.get_current_state();
+        if self.is_inserted() && current == state && has_action {
+            return true;
+        }
+        warn!("Dropping data {direction} the card in {current:?} state without a data command. \
+               This is likely a bug of either the emulator or the guest program.");
+        false
+    }
+
     /// Send data to the emulated SD card through the DAT channel.
-    pub fn send_data(&mut self, data: &[u8]) {
+    ///
+    /// Returns whether the card took the data. Data sent outside of a write data phase is dropped.
+    pub fn send_data(&mut self, data: &[u8]) -> bool {
+        let has_action = !matches!(self.send_action, SendAction::None);
+        if !self.in_data_phase(CurrentState::ReceivingData, has_action, "sent to") {
+            return false;
+        }
         match self.send_action {
-            SendAction::None => {
-                warn!("Data provided by SIC but no send_action defined here. \
-                       This is likely a bug of either the emulator or the guest program.");
+            SendAction::None => return false,
+            SendAction::LockUnlock => {
+                if !self.lock_unlock(data) {
+                    self.card_status.set_lock_unlock_failed(true);
+                }
+                self.card_status.set_current_state(CurrentState::Transfer);
+                self.send_action = SendAction::None;
+                self.start_busy();
             },
-            SendAction::FTLWrite { sector_index } => {
-                if data.len() % 512 != 0 {
-                    warn!("Buffer size is not multiple of sectors");
+            SendAction::FTLWrite { sector_index, single } => {
+                // Single block writes only consume one block and ignore the rest.
+                let data = if single && data.len() > self.block_len() {
+                    &data[..self.block_len()]
+                } else {
+                    data
+                };
+
+                if self.read_only {
+                    warn!("Refusing to write {} bytes to sector {} on a read-only card.", data.len(), sector_index);
+                    self.card_status.set_wp_violation(true);
+                    return true;
                 }
-                let image_file = self.image_file.as_mut().unwrap();
-                image_file.seek(SeekFrom::Start(512 * sector_index)).unwrap_or_else(|err| {
-                    error!("Seeking to sector {sector_index} failed: {err:?}");
-                    0u64
-                });
-                image_file.write_all(data).unwrap_or_else(|err| {
-                    error!("Writing {} bytes to sector {} failed: {:?}", data.len(), sector_index, err);
-                });
-                trace!("Wrote {} bytes to sector {}", data.len(), sector_index);
-                let new_sector_index = sector_index + u64::try_from(data.len()).unwrap() / 512;
-                self.send_action = SendAction::FTLWrite { sector_index: new_sector_index };
+
+                // Pad partial sectors with zeroes so the backing image is always written in whole sectors.
+                let padded_len = data.len().next_multiple_of(512);
+                let mut padded_buf = Vec::new();
+                let write_buf = if padded_len != data.len() {
+                    warn!("Buffer size {} is not multiple of sectors. Padding to {} bytes.", data.len(), padded_len);
+                    padded_buf.extend_from_slice(data);
+                    padded_buf.resize(padded_len, 0u8);
+                    &padded_buf[..]
+                } else {
+                    data
+                };
+
+                // Sectors past the end of the image are dropped, so the backing image never grows.
+                let sector_count = self.image_size / 512;
+                let fit_len = write_buf.len()
+                    .min(usize::try_from(sector_count.saturating_sub(sector_index) * 512).unwrap_or(usize::MAX));
+                if fit_len < write_buf.len() {
+                    warn!("Dropping {} bytes written beyond the end of the image.", write_buf.len() - fit_len);
+                    self.card_status.set_out_of_range(true);
+                }
+                let write_buf = &write_buf[..fit_len];
+
+                for (i, sector) in write_buf.chunks(512).enumerate() {
+                    self.write_cache.insert(sector_index + i as u64, sector.to_vec());
+                }
+                trace!("Wrote {} bytes to sector {}", write_buf.len(), sector_index);
+                if self.write_cache.len() >= self.write_cache_limit {
+                    self.write_back();
+                }
+                let new_sector_index = sector_index + u64::try_from(write_buf.len()).unwrap() / 512;
+                if single {
+                    trace!("Single block write end");
+                    self.flush();
+                    self.card_status.set_current_state(CurrentState::Transfer);
+                    self.send_action = SendAction::None;
+                } else {
+                    self.send_action = SendAction::FTLWrite { sector_index: new_sector_index, single };
+                }
+                // The card is busy after every block, while staying in ReceivingData during multiple block writes.
+                self.start_busy();
             },
         }
+        true
     }
 
     /// Receive data from the emulated SD card through the DAT channel.
-    pub fn recv_data(&mut self, data: &mut [u8]) {
+    ///
+    /// Returns the number of bytes actually sent by the card, which may be shorter than the buffer when the card runs
+    /// out of data (e.g. reaching the end of the image, or the end of a single block transfer). Outside of a read data
+    /// phase, the card sends nothing and the buffer is zeroed.
+    pub fn recv_data(&mut self, data: &mut [u8]) -> usize {
+        let has_action = !matches!(self.recv_action, RecvAction::None);
+        if !self.in_data_phase(CurrentState::SendingData, has_action, "requested from") {
+            data.fill(0);
+            self.data_crc = None;
+            return 0;
+        }
         match self.recv_action {
-            RecvAction::None => {
-                warn!("Data requested by SIC but no recv_action defined here. \
-                       This is likely a bug of either the emulator or the guest program.");
-            },
-            RecvAction::FTLRead { sector_index } => {
+            RecvAction::None => 0,
+            RecvAction::FTLRead { sector_index, single } => {
+                // Single block reads only fill one block and leave the rest of the buffer untouched.
+                let block_len = self.block_len();
+                let data = if single && data.len() > block_len {
+                    &mut data[..block_len]
+                } else {
+                    data
+                };
+
                 if data.len() % 512 != 0 {
                     warn!("Buffer size is not multiple of sectors");
                 }
 
+                let offset = 512 * sector_index;
+                if offset >= self.image_size {
+                    warn!("Sector {sector_index} is beyond the end of the image.");
+                    self.card_status.set_out_of_range(true);
+                    return 0;
+                }
+                let available = self.image_size - offset;
+                let data = if u64::try_from(data.len()).unwrap() > available {
+                    warn!("Short read of {available} bytes at the end of the image.");
+                    &mut data[..usize::try_from(available).unwrap()]
+                } else {
+                    data
+                };
+
                 let image_file = self.image_file.as_mut().unwrap();
-                image_file.seek(SeekFrom::Start(512 * sector_index)).unwrap_or_else(|err| {
+                image_file.seek(SeekFrom::Start(offset)).unwrap_or_else(|err| {
                     error!("Seeking to sector {sector_index} failed: {err:?}");
                     0u64
                 });
@@ -672,15 +1118,34 @@ impl SD {
                     error!("Reading {} bytes from sector {} failed: {:?}", data.len(), sector_index, err);
                 });
 
+                // Sectors that haven't been written back yet take precedence over the image.
+                let end_sector = sector_index + u64::try_from(data.len().div_ceil(512)).unwrap();
+                for (cached_index, sector) in self.write_cache.range(sector_index..end_sector) {
+                    let offset = usize::try_from(cached_index - sector_index).unwrap() * 512;
+                    let len = sector.len().min(data.len() - offset);
+                    data[offset..offset + len].copy_from_slice(&sector[..len]);
+                }
+
                 trace!("Read {} bytes from sector {}", data.len(), sector_index);
 
+                if self.crc_enabled {
+                    self.data_crc = Some(crc16(data));
+                }
+
                 let new_sector_index = sector_index + u64::try_from(data.len()).unwrap() / 512;
-                self.recv_action = RecvAction::FTLRead { sector_index: new_sector_index };
+                if single {
+                    trace!("Single block read end");
+                    self.card_status.set_current_state(CurrentState::Transfer);
+                    self.recv_action = RecvAction::None;
+                } else {
+                    self.recv_action = RecvAction::FTLRead { sector_index: new_sector_index, single };
+                }
+                data.len()
             },
             RecvAction::FunctionStatus{arg} => {
                 if data.len() < 64 {
                     error!("Buffer is too small for Function Status");
-                    return;
+                    return 0;
                 }
 
                 // 200mA
@@ -728,20 +1193,148 @@ impl SD {
 
                 self.card_status.set_current_state(CurrentState::Transfer);
                 self.recv_action = RecvAction::None;
+                64
             },
             RecvAction::SCRRead => {
                 if data.len() < 8 {
                     error!("Buffer is too small for SCR");
-                    return;
+                    return 0;
                 }
                 debug!("SCR={SCR:02x?}");
                 data[..8].clone_from_slice(&SCR);
                 self.card_status.set_current_state(CurrentState::Transfer);
                 self.recv_action = RecvAction::None;
+                SCR.len()
+            },
+            RecvAction::SDStatusRead => {
+                if data.len() < 64 {
+                    error!("Buffer is too small for SD Status");
+                    return 0;
+                }
+                data[..64].clone_from_slice(&self.sd_status());
+                debug!("SD Status: {:02x?}", &data[..64]);
+                self.card_status.set_current_state(CurrentState::Transfer);
+                self.recv_action = RecvAction::None;
+                64
             },
         }
     }
 
+    /// Process a CMD42 data block (see 4.3.7). Returns false if the operation failed.
+    fn lock_unlock(&mut self, data: &[u8]) -> bool {
+        let Some(&flags) = data.first() else {
+            return false;
+        };
+        let locked = self.card_status.get_card_is_locked();
+
+        if flags & LOCK_ERASE != 0 {
+            // Forced erase is only allowed on its own, and on a locked card.
+            if flags != LOCK_ERASE || !locked {
+                warn!("Rejecting forced erase (flags=0x{flags:02x}, locked={locked}).");
+                return false;
+            }
+            if self.read_only {
+                warn!("Rejecting forced erase on a read-only card.");
+                return false;
+            }
+            debug!("Forced erase: clearing the password and wiping the card");
+            self.password.clear();
+            self.card_status.set_card_is_locked(false);
+            let sectors = self.image_size / 512;
+            if sectors > 0 {
+                self.erase_sectors(0, sectors - 1);
+            }
+            return true;
+        }
+
+        let Some(pwd) = data.get(1).and_then(|len| data.get(2..2 + usize::from(*len))) else {
+            warn!("CMD42 password does not fit in the data block.");
+            return false;
+        };
+        let lock = flags & LOCK_LOCK_UNLOCK != 0;
+
+        if flags & LOCK_SET_PWD != 0 {
+            if flags & LOCK_CLR_PWD != 0 {
+                return false;
+            }
+            // The block holds the current password followed by the new one.
+            let Some(new_pwd) = pwd.strip_prefix(self.password.as_slice()) else {
+                warn!("CMD42 current password mismatch.");
+                return false;
+            };
+            if new_pwd.is_empty() || new_pwd.len() > MAX_PASSWORD_LEN {
+                warn!("CMD42 new password has an invalid length of {} bytes.", new_pwd.len());
+                return false;
+            }
+            debug!("Password set");
+            self.password = new_pwd.to_vec();
+            if lock {
+                self.card_status.set_card_is_locked(true);
+            }
+            return true;
+        }
+
+        if self.password.is_empty() || pwd != self.password.as_slice() {
+            warn!("CMD42 password mismatch.");
+            return false;
+        }
+        if flags & LOCK_CLR_PWD != 0 {
+            if lock {
+                return false;
+            }
+            debug!("Password cleared");
+            self.password.clear();
+            self.card_status.set_card_is_locked(false);
+        } else {
+            debug!("Card {}", if lock { "locked" } else { "unlocked" });
+            self.card_status.set_card_is_locked(lock);
+        }
+        true
+    }
+
+    /// Erase sectors `start..=end` of the backing image. Erased sectors read back as zeroes, as reported by the SCR.
+    /// Image errors are reported with `ERASE_SEQ_ERROR`.
+    fn erase_sectors(&mut self, start: u64, end: u64) {
+        // Pending writes to the erased range are dropped.
+        self.write_cache.retain(|sector_index, _| !(start..=end).contains(sector_index));
+        let Some(image_file) = self.image_file.as_mut() else {
+            return;
+        };
+        if let Err(err) = image_file.seek(SeekFrom::Start(512 * start)) {
+            error!("Seeking to sector {start} failed: {err:?}");
+            self.card_status.set_erase_seq_error(true);
+            return;
+        }
+        let zeroes = [0u8; 512 * ERASE_CHUNK_SECTORS as usize];
+        for chunk_start in (start..=end).step_by(ERASE_CHUNK_SECTORS as usize) {
+            let sectors = (end - chunk_start + 1).min(ERASE_CHUNK_SECTORS);
+            if let Err(err) = image_file.write_all(&zeroes[..512 * usize::try_from(sectors).unwrap()]) {
+                error!("Erasing sectors from {chunk_start} failed: {err:?}");
+                self.card_status.set_erase_seq_error(true);
+                break;
+            }
+        }
+        trace!("Erased sectors {start}..={end}");
+        self.flush();
+    }
+
+    /// Build the 512-bit SD Status register (see 4.10.2), most significant byte first.
+    fn sd_status(&self) -> [u8; 64] {
+        let mut status = [0u8; 64];
+        // DAT_BUS_WIDTH[511:510], SECURED_MODE[509] is never set.
+        status[0] = if self.wide_bus { 0b10 << 6 } else { 0 };
+        // SD_CARD_TYPE[495:480] is 0 (regular read/write card), SIZE_OF_PROTECTED_AREA[479:448] is 0.
+        status[8] = SPEED_CLASS_4;
+        status[9] = PERFORMANCE_MOVE;
+        // AU_SIZE[431:428]
+        status[10] = AU_SIZE_4MIB << 4;
+        // ERASE_SIZE[423:408]
+        status[11..13].clone_from_slice(&ERASE_SIZE.to_be_bytes());
+        // ERASE_TIMEOUT[407:402], ERASE_OFFSET[401:400]
+        status[13] = ERASE_TIMEOUT << 2;
+        status
+    }
+
     /// Set the `ILLEGAL_COMMAND` status bit and respond with a no response. Should always use with a return.
     #[inline(always)]
     fn term_illegal(&mut self) -> Response {
@@ -749,7 +1342,716 @@ impl SD {
         Response::RNone
     }
 
-    pub fn set_cid(&mut self, cid: &[u8]) {
-        self.cid.clone_from_slice(cid);
+    #[inline]
+    fn is_sdhc(&self) -> bool {
+        self.csd.as_ref().is_some_and(CardSpecific::is_sdhc)
+    }
+
+    /// Sector addressed by the argument of a data command. SDHC cards are addressed in 512-byte blocks, while SDSC
+    /// cards take a byte address.
+    #[inline]
+    fn arg_to_sector(&self, arg: u32) -> u64 {
+        if self.is_sdhc() {
+            arg.into()
+        } else {
+            u64::from(arg) / 512
+        }
+    }
+
+    /// Whether the byte address of a read or write on an SDSC card does not start on a sector.
+    #[inline]
+    fn is_misaligned(&self, arg: u32) -> bool {
+        !self.is_sdhc() && !arg.is_multiple_of(512)
+    }
+
+    /// Hold DAT0 low for the configured number of ticks. The card goes from `Transfer` to `Programming` until then.
+    fn start_busy(&mut self) {
+        if self.busy_ticks == 0 {
+            return;
+        }
+        if self.card_status.get_current_state() == CurrentState::Transfer {
+            self.card_status.set_current_state(CurrentState::Programming);
+        }
+        self.card_status.set_ready_for_data(false);
+        self.busy_remaining = self.busy_ticks;
+    }
+
+    /// Whether the card is holding DAT0 low while programming or erasing.
+    #[inline]
+    pub fn is_busy(&self) -> bool {
+        self.busy_remaining > 0
+    }
+
+    /// Advance the busy period by one SIC tick. Returns true when the card just released DAT0.
+    pub fn tick(&mut self) -> bool {
+        if self.busy_remaining == 0 {
+            return false;
+        }
+        self.busy_remaining -= 1;
+        if self.busy_remaining > 0 {
+            return false;
+        }
+        trace!("Busy end");
+        if self.card_status.get_current_state() == CurrentState::Programming {
+            self.card_status.set_current_state(CurrentState::Transfer);
+        }
+        self.card_status.set_ready_for_data(true);
+        true
+    }
+
+    /// Set how many SIC ticks the card stays busy after writes and erases. 0 disables the busy period.
+    pub fn set_busy_ticks(&mut self, ticks: u32) {
+        self.busy_ticks = ticks;
+    }
+
+    /// Current block length in bytes, as configured by CMD16. Defaults to 512 bytes.
+    #[inline]
+    fn block_len(&self) -> usize {
+        if self.io_size == 0 {
+            512
+        } else {
+            usize::try_from(self.io_size).unwrap()
+        }
+    }
+
+    /// Write the cached sectors back to the image, one contiguous run at a time.
+    fn write_back(&mut self) {
+        let cache = std::mem::take(&mut self.write_cache);
+        let Some(image_file) = self.image_file.as_mut() else {
+            return;
+        };
+        let mut runs: Vec<(u64, Vec<u8>)> = vec![];
+        for (sector_index, sector) in cache {
+            match runs.last_mut() {
+                Some((start, run)) if *start + u64::try_from(run.len() / 512).unwrap() == sector_index => {
+                    run.extend_from_slice(&sector);
+                }
+                _ => runs.push((sector_index, sector)),
+            }
+        }
+        for (sector_index, run) in runs {
+            let result = image_file.seek(SeekFrom::Start(512 * sector_index))
+                .and_then(|_| image_file.write_all(&run));
+            if let Err(err) = result {
+                error!("Writing {} bytes to sector {} failed: {:?}", run.len(), sector_index, err);
+            }
+        }
+    }
+
+    /// Write back cached sectors and flush pending writes to the backing image.
+    pub fn flush(&mut self) {
+        self.write_back();
+        if let Some(image_file) = self.image_file.as_mut() {
+            image_file.flush().unwrap_or_else(|err| {
+                error!("Flushing SD image failed: {err:?}");
+            });
+        }
+    }
+
+    /// Keep up to `size` bytes of written sectors in memory before writing them back to the image. Cached sectors are
+    /// also written back on CMD12, at the end of single block writes and on unmount.
+    pub fn set_write_cache_size(&mut self, size: usize) {
+        self.write_cache_limit = size / 512;
+    }
+
+    pub fn set_crc_enabled(&mut self, enabled: bool) {
+        self.crc_enabled = enabled;
+    }
+
+    #[inline]
+    pub fn is_crc_enabled(&self) -> bool {
+        self.crc_enabled
+    }
+
+    /// Take the CRC16 of the last block read from the DAT channel, if any.
+    #[inline]
+    pub fn take_data_crc(&mut self) -> Option<u16> {
+        self.data_crc.take()
+    }
+}
+
+#[cfg(test)]
+pub fn select_test_card(sd: &mut SD) {
+    let _ = sd.make_request(0, 0);
+    let _ = sd.make_request(8, 0x1aa);
+    let _ = sd.make_request(55, 0);
+    let _ = sd.make_request(41, 0x00ff8000);
+    let _ = sd.make_request(2, 0);
+    let _ = sd.make_request(3, 0);
+    let _ = sd.make_request(7, 1 << 16);
+    assert_eq!(sd.card_status.get_current_state(), CurrentState::Transfer);
+}
+
+impl_snapshot_bitfield!(CardStatus);
+
+impl Snapshot for SendAction {
+    fn save(&self, out: &mut Vec<u8>) {
+        match self {
+            Self::None => 0u8.save(out),
+            Self::FTLWrite { sector_index, single } => {
+                1u8.save(out);
+                sector_index.save(out);
+                single.save(out);
+            }
+            Self::LockUnlock => 2u8.save(out),
+        }
+    }
+
+    fn load(&mut self, input: &mut &[u8]) -> Result<(), RuntimeError> {
+        *self = match load_new::<u8>(input)? {
+            0 => Self::None,
+            1 => Self::FTLWrite { sector_index: load_new(input)?, single: load_new(input)? },
+            2 => Self::LockUnlock,
+            _ => return Err(RuntimeError::SnapshotInvalid),
+        };
+        Ok(())
+    }
+}
+
+impl Snapshot for RecvAction {
+    fn save(&self, out: &mut Vec<u8>) {
+        match self {
+            Self::None => 0u8.save(out),
+            Self::FTLRead { sector_index, single } => {
+                1u8.save(out);
+                sector_index.save(out);
+                single.save(out);
+            }
+            Self::SCRRead => 2u8.save(out),
+            Self::FunctionStatus { arg } => {
+                3u8.save(out);
+                arg.save(out);
+            }
+            Self::SDStatusRead => 4u8.save(out),
+        }
     }
+
+    fn load(&mut self, input: &mut &[u8]) -> Result<(), RuntimeError> {
+        *self = match load_new::<u8>(input)? {
+            0 => Self::None,
+            1 => Self::FTLRead { sector_index: load_new(input)?, single: load_new(input)? },
+            2 => Self::SCRRead,
+            3 => Self::FunctionStatus { arg: load_new(input)? },
+            4 => Self::SDStatusRead,
+            _ => return Err(RuntimeError::SnapshotInvalid),
+        };
+        Ok(())
+    }
+}
+
+/// Going idle through CMD0. The image stays mounted, and the password and lock state are kept, since they are only
+/// reapplied on power-up.
+impl Reset for SD {
+    fn reset(&mut self) {
+        let locked = self.card_status.get_card_is_locked();
+        self.card_status.set(0, 32, 0u64);
+        self.card_status.set_card_is_locked(locked);
+        self.rca = 0;
+        self.wide_bus = false;
+        self.if_cond_received = false;
+        self.busy_remaining = 0;
+        self.erase_start = None;
+        self.erase_end = None;
+        self.send_action = SendAction::None;
+        self.recv_action = RecvAction::None;
+    }
+}
+
+// The image and the CSD derived from it come from the mount, so only the protocol state is saved.
+impl_snapshot!(SD {
+    cid, card_status, rca, selected_functions, io_size, wide_bus, password, if_cond_received, busy_remaining, erase_start, erase_end,
+    ejected, crc_enabled, data_crc, send_action, recv_action,
+});
+
+#[test]
+fn test_abort_transfer() {
+    let path = make_test_file("abort.img", 512 * 1024);
+    let mut sd = SD::default();
+    sd.mount(path.to_str().unwrap()).unwrap();
+    select_test_card(&mut sd);
+
+    // The host resets its SD engine in the middle of a multiple block read.
+    let _ = sd.make_request(18, 0);
+    sd.abort_transfer();
+    assert_eq!(sd.card_status.get_current_state(), CurrentState::Transfer);
+    assert_eq!(sd.recv_data(&mut [0u8; 512]), 0);
+
+    // CMD0 drops the transfer too, and the image stays mounted.
+    let _ = sd.make_request(17, 0);
+    let _ = sd.make_request(0, 0);
+    assert_eq!(sd.card_status.get_current_state(), CurrentState::Idle);
+    assert_eq!(sd.recv_data(&mut [0u8; 512]), 0);
+    assert!(sd.is_mounted());
+
+    sd.unmount();
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_out_of_phase_data() {
+    let path = make_test_file("phase.img", 512 * 1024);
+    let expected = fs::read(&path).unwrap();
+    let mut sd = SD::default();
+    sd.mount(path.to_str().unwrap()).unwrap();
+    select_test_card(&mut sd);
+
+    // DMA without a data command in the transfer state.
+    let mut buf = [0xaau8; 512];
+    assert_eq!(sd.recv_data(&mut buf), 0);
+    assert!(buf.iter().all(|b| *b == 0));
+    assert!(!sd.send_data(&[0x5a; 512]));
+    assert_eq!(sd.card_status.get_current_state(), CurrentState::Transfer);
+
+    // Writing during a read, and reading during a write.
+    let _ = sd.make_request(17, 0);
+    assert!(!sd.send_data(&[0x5a; 512]));
+    assert_eq!(sd.recv_data(&mut buf), 512);
+    let _ = sd.make_request(24, 0);
+    assert_eq!(sd.recv_data(&mut buf), 0);
+    assert!(sd.send_data(&[0x5a; 512]));
+
+    // Only the block of the write command reaches the image.
+    sd.unmount();
+    let image = fs::read(&path).unwrap();
+    assert!(image[..512].iter().all(|b| *b == 0x5a));
+    assert_eq!(image[512..], expected[512..]);
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_single_block_read() {
+    let path = make_test_file("cmd17.img", 512 * 1024);
+    let mut sd = SD::default();
+    sd.mount(path.to_str().unwrap()).unwrap();
+    select_test_card(&mut sd);
+
+    let _ = sd.make_request(17, 0);
+    assert_eq!(sd.card_status.get_current_state(), CurrentState::SendingData);
+    let mut buf = [0u8; 1024];
+    assert_eq!(sd.recv_data(&mut buf), 512);
+    assert_eq!(sd.card_status.get_current_state(), CurrentState::Transfer);
+
+    let expected = fs::read(&path).unwrap();
+    assert_eq!(buf[..512], expected[..512]);
+    assert!(buf[512..].iter().all(|b| *b == 0));
+
+    sd.unmount();
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_crc7() {
+    // Examples from SD Physical Layer Simplified Specification 4.5
+    assert_eq!(crc7(&[0x40, 0x00, 0x00, 0x00, 0x00]), 0x4a);  // CMD0
+    assert_eq!(crc7(&[0x51, 0x00, 0x00, 0x00, 0x00]), 0x2a);  // CMD17
+    assert_eq!(crc7(&[0x11, 0x00, 0x00, 0x09, 0x00]), 0x33);  // Response of CMD17
+}
+
+#[test]
+fn test_crc16() {
+    // Example from SD Physical Layer Simplified Specification 4.5
+    assert_eq!(crc16(&[0xff; 512]), 0x7fa1);
+}
+
+#[test]
+fn test_sd_status() {
+    let path = make_test_file("acmd13.img", 512 * 1024);
+    let mut sd = SD::default();
+    sd.mount(path.to_str().unwrap()).unwrap();
+    select_test_card(&mut sd);
+
+    let _ = sd.make_request(55, 1 << 16);
+    let _ = sd.make_request(6, 0b10);
+    let _ = sd.make_request(55, 1 << 16);
+    let _ = sd.make_request(13, 0);
+    assert_eq!(sd.card_status.get_current_state(), CurrentState::SendingData);
+    let mut buf = [0u8; 64];
+    assert_eq!(sd.recv_data(&mut buf), 64);
+    assert_eq!(sd.card_status.get_current_state(), CurrentState::Transfer);
+
+    assert_eq!(buf[0] >> 6, 0b10);
+    assert_eq!(buf[8], SPEED_CLASS_4);
+    assert_eq!(buf[10] >> 4, AU_SIZE_4MIB);
+    assert_eq!(buf[11..13], ERASE_SIZE.to_be_bytes());
+    assert_eq!(buf[13], ERASE_TIMEOUT << 2);
+
+    sd.unmount();
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_erase() {
+    let path = make_test_file("erase.img", 512 * 1024);
+    let mut sd = SD::default();
+    sd.mount(path.to_str().unwrap()).unwrap();
+    select_test_card(&mut sd);
+
+    // Only an erase that runs is busy.
+    let status = |sd: &mut SD, cmd, arg, busy| match sd.make_request(cmd, arg) {
+        Response::R1(resp) => {
+            assert_eq!(resp.busy, busy);
+            resp.status
+        }
+        _ => panic!("CMD{cmd} did not return R1"),
+    };
+
+    // Erase without a range, with a reversed range and past the end of the image.
+    assert!(status(&mut sd, 38, 0, false).get_erase_seq_error());
+    let _ = status(&mut sd, 32, 3 * 512, false);
+    let _ = status(&mut sd, 33, 2 * 512, false);
+    assert!(status(&mut sd, 38, 0, false).get_erase_param());
+    assert!(status(&mut sd, 32, 1024 * 512, false).get_out_of_range());
+
+    let _ = status(&mut sd, 32, 2 * 512, false);
+    let _ = status(&mut sd, 33, 3 * 512, false);
+    let erase_status = status(&mut sd, 38, 0, true);
+    assert!(!erase_status.get_erase_param() && !erase_status.get_erase_seq_error());
+
+    let _ = sd.make_request(18, 512);
+    let mut buf = vec![0u8; 512 * 4];
+    assert_eq!(sd.recv_data(&mut buf), buf.len());
+    let _ = sd.make_request(12, 0);
+    let pattern: Vec<u8> = (512..512 * 5).map(|i| (i % 251) as u8).collect();
+    assert_eq!(buf[..512], pattern[..512]);
+    assert!(buf[512..1536].iter().all(|b| *b == 0));
+    assert_eq!(buf[1536..], pattern[1536..]);
+
+    sd.unmount();
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_busy_after_write() {
+    let path = make_test_file("busy.img", 512 * 1024);
+    let mut sd = SD::default();
+    sd.mount(path.to_str().unwrap()).unwrap();
+    sd.set_busy_ticks(2);
+    select_test_card(&mut sd);
+
+    let _ = sd.make_request(24, 0);
+    sd.send_data(&[0x5a; 512]);
+    assert!(sd.is_busy());
+    assert_eq!(sd.card_status.get_current_state(), CurrentState::Programming);
+    let Response::R1(resp) = sd.make_request(13, 1 << 16) else {
+        panic!("CMD13 did not return R1");
+    };
+    assert!(!resp.status.get_ready_for_data());
+
+    assert!(!sd.tick());
+    assert!(sd.tick());
+    assert!(!sd.is_busy());
+    assert_eq!(sd.card_status.get_current_state(), CurrentState::Transfer);
+    assert!(sd.card_status.get_ready_for_data());
+
+    // Multiple block writes are busy after CMD12.
+    let _ = sd.make_request(25, 512);
+    sd.send_data(&[0xa5; 1024]);
+    assert_eq!(sd.card_status.get_current_state(), CurrentState::ReceivingData);
+    while sd.is_busy() {
+        sd.tick();
+    }
+    let Response::R1(resp) = sd.make_request(12, 0) else {
+        panic!("CMD12 did not return R1b");
+    };
+    assert!(resp.busy);
+    assert!(sd.is_busy());
+
+    sd.unmount();
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_write_out_of_range() {
+    let path = make_test_file("range.img", 512 * 1024);
+    let mut sd = SD::default();
+    sd.mount(path.to_str().unwrap()).unwrap();
+    select_test_card(&mut sd);
+    let last = 1023 * 512;
+
+    // Starting past the end is rejected up front.
+    let Response::R1(resp) = sd.make_request(24, last + 512) else {
+        panic!("CMD24 did not return R1");
+    };
+    assert!(resp.status.get_out_of_range());
+    assert_eq!(sd.card_status.get_current_state(), CurrentState::Transfer);
+
+    // The last sector itself is fine.
+    let _ = sd.make_request(24, last);
+    sd.send_data(&[0x5a; 512]);
+    assert!(!sd.card_status.get_out_of_range());
+    while sd.is_busy() {
+        sd.tick();
+    }
+
+    // A multiple block write across the end keeps what fits.
+    let _ = sd.make_request(25, last);
+    sd.send_data(&[0xa5; 1024]);
+    assert!(sd.card_status.get_out_of_range());
+    while sd.is_busy() {
+        sd.tick();
+    }
+    let _ = sd.make_request(12, 0);
+
+    sd.unmount();
+    let image = fs::read(&path).unwrap();
+    assert_eq!(image.len(), 512 * 1024);
+    assert_eq!(image[usize::try_from(last).unwrap()..], [0xa5; 512]);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_addressing_modes() {
+    let read_sector = |sd: &mut SD, arg: u32| {
+        let Response::R1(resp) = sd.make_request(17, arg) else {
+            panic!("CMD17 did not return R1");
+        };
+        let mut buf = [0u8; 512];
+        let len = if resp.status.get_address_error() { 0 } else { sd.recv_data(&mut buf) };
+        (len, buf)
+    };
+    let path = make_test_file("addressing.img", 512 * 1024);
+    let expected = fs::read(&path).unwrap();
+
+    // SDSC cards are addressed by byte.
+    let mut sd = SD::default();
+    sd.mount(path.to_str().unwrap()).unwrap();
+    assert!(!sd.is_sdhc());
+    select_test_card(&mut sd);
+    let (len, buf) = read_sector(&mut sd, 3 * 512);
+    assert_eq!(len, 512);
+    assert_eq!(buf[..], expected[3 * 512..4 * 512]);
+    assert_eq!(read_sector(&mut sd, 100).0, 0);
+    assert_eq!(sd.card_status.get_current_state(), CurrentState::Transfer);
+    sd.unmount();
+
+    // SDHC cards are addressed by block. Grow the image past the SDSC capacity without writing it out.
+    fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(SDSC_MAX_CAPACITY * 2).unwrap();
+    let mut sd = SD::default();
+    sd.mount(path.to_str().unwrap()).unwrap();
+    assert!(sd.is_sdhc());
+    select_test_card(&mut sd);
+    let (len, buf) = read_sector(&mut sd, 3);
+    assert_eq!(len, 512);
+    assert_eq!(buf[..], expected[3 * 512..4 * 512]);
+    sd.unmount();
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_init_with_size() {
+    assert!(matches!(CardSpecific::init_with_size(0), Err(RuntimeError::SDImageEmpty)));
+    assert!(matches!(CardSpecific::init_with_size(C_SIZE_UNIT + 512), Err(RuntimeError::SDImageUnaligned)));
+    assert!(matches!(CardSpecific::init_with_size(C_SIZE_UNIT << 22), Ok(CardSpecific::HC(_))));
+    let too_large = CardSpecific::init_with_size((C_SIZE_UNIT << 22) + C_SIZE_UNIT);
+    assert!(matches!(too_large, Err(RuntimeError::SDImageTooLarge)));
+    assert!(matches!(CardSpecific::init_with_size(SDSC_MAX_CAPACITY), Ok(CardSpecific::SC(_))));
+
+    // A bad image leaves the card unmounted.
+    let path = make_test_file("unaligned.img", 512 * 1023);
+    let mut sd = SD::default();
+    assert!(sd.mount(path.to_str().unwrap()).is_err());
+    assert!(!sd.is_mounted());
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_interface_condition() {
+    let path = make_test_file("cmd8.img", 512 * 1024);
+    fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(SDSC_MAX_CAPACITY * 2).unwrap();
+    let mut sd = SD::default();
+    sd.mount(path.to_str().unwrap()).unwrap();
+
+    let acmd41 = |sd: &mut SD| {
+        let _ = sd.make_request(55, 0);
+        match sd.make_request(41, 0x40ff8000) {
+            Response::R3(resp) => (resp.power_up, resp.is_sdhc),
+            _ => panic!("ACMD41 did not return R3"),
+        }
+    };
+
+    // v1.x host: no CMD8, so the high capacity card never becomes ready.
+    let _ = sd.make_request(0, 0);
+    assert_eq!(acmd41(&mut sd), (false, false));
+    assert_eq!(sd.card_status.get_current_state(), CurrentState::Idle);
+
+    // Unsupported voltage is ignored.
+    assert!(matches!(sd.make_request(8, 0x2aa), Response::RNone));
+
+    let Response::R7(resp) = sd.make_request(8, 0x15a) else {
+        panic!("CMD8 did not return R7");
+    };
+    assert_eq!((resp.voltage_accepted, resp.check), (VHS_2V7_3V6, 0x5a));
+    assert_eq!(acmd41(&mut sd), (true, true));
+    assert_eq!(sd.card_status.get_current_state(), CurrentState::Ready);
+
+    sd.unmount();
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_lock_unlock() {
+    let path = make_test_file("cmd42.img", 512 * 1024);
+    let mut sd = SD::default();
+    sd.mount(path.to_str().unwrap()).unwrap();
+    select_test_card(&mut sd);
+
+    let lock_unlock = |sd: &mut SD, flags: u8, pwd: &[u8]| {
+        let _ = sd.make_request(42, 0);
+        let mut block = vec![0u8; 512];
+        block[0] = flags;
+        block[1] = pwd.len() as u8;
+        block[2..2 + pwd.len()].copy_from_slice(pwd);
+        sd.send_data(&block);
+        assert_eq!(sd.card_status.get_current_state(), CurrentState::Transfer);
+        let Response::R1(resp) = sd.make_request(13, 1 << 16) else {
+            panic!("CMD13 did not return R1");
+        };
+        (resp.status.get_card_is_locked(), resp.status.get_lock_unlock_failed())
+    };
+
+    // Set the password and lock in one go, then data access is rejected.
+    assert_eq!(lock_unlock(&mut sd, LOCK_SET_PWD | LOCK_LOCK_UNLOCK, b"hunter2"), (true, false));
+    assert!(matches!(sd.make_request(17, 0), Response::RNone));
+    // Wrong password, then the right one.
+    assert_eq!(lock_unlock(&mut sd, 0, b"hunter3"), (true, true));
+    assert_eq!(lock_unlock(&mut sd, 0, b"hunter2"), (false, false));
+    assert!(matches!(sd.make_request(17, 0), Response::R1(_)));
+    let mut buf = [0u8; 512];
+    assert_eq!(sd.recv_data(&mut buf), 512);
+
+    // Change the password with old + new, and lock again.
+    assert_eq!(lock_unlock(&mut sd, LOCK_SET_PWD, b"hunter2letmein"), (false, false));
+    assert_eq!(lock_unlock(&mut sd, LOCK_LOCK_UNLOCK, b"hunter2"), (false, true));
+    assert_eq!(lock_unlock(&mut sd, LOCK_LOCK_UNLOCK, b"letmein"), (true, false));
+
+    // Forced erase wipes the card and removes the password.
+    assert_eq!(lock_unlock(&mut sd, LOCK_ERASE, &[]), (false, false));
+    assert!(sd.password.is_empty());
+    assert!(fs::read(&path).unwrap().iter().all(|b| *b == 0));
+    assert_eq!(lock_unlock(&mut sd, LOCK_ERASE, &[]), (false, true));
+
+    sd.unmount();
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_write_cache() {
+    let path = make_test_file("cache.img", 512 * 1024);
+    let mut sd = SD::default();
+    sd.mount(path.to_str().unwrap()).unwrap();
+    sd.set_write_cache_size(512 * 4);
+    select_test_card(&mut sd);
+
+    // Sectors stay in the cache until CMD12.
+    let _ = sd.make_request(25, 512);
+    sd.send_data(&[0x5a; 1024]);
+    assert_eq!(sd.write_cache.len(), 2);
+    assert_ne!(fs::read(&path).unwrap()[512..1536], [0x5a; 1024]);
+    let _ = sd.make_request(12, 0);
+    assert!(sd.write_cache.is_empty());
+    assert_eq!(fs::read(&path).unwrap()[512..1536], [0x5a; 1024]);
+
+    // Reaching the limit writes back in the middle of a transfer.
+    let _ = sd.make_request(25, 512 * 16);
+    sd.send_data(&[0x3c; 512 * 3]);
+    assert_eq!(sd.write_cache.len(), 3);
+    sd.send_data(&[0x3c; 512]);
+    assert!(sd.write_cache.is_empty());
+    assert_eq!(fs::read(&path).unwrap()[512 * 16..512 * 20], [0x3c; 512 * 4]);
+
+    // Reads see sectors that haven't been written back yet.
+    sd.send_data(&[0xa5; 512]);
+    assert_eq!(sd.write_cache.len(), 1);
+    sd.card_status.set_current_state(CurrentState::SendingData);
+    sd.recv_action = RecvAction::FTLRead { sector_index: 19, single: false };
+    let mut buf = vec![0u8; 512 * 2];
+    assert_eq!(sd.recv_data(&mut buf), buf.len());
+    assert!(buf[..512].iter().all(|b| *b == 0x3c));
+    assert!(buf[512..].iter().all(|b| *b == 0xa5));
+
+    // Unmounting writes back the rest.
+    sd.unmount();
+    assert_eq!(fs::read(&path).unwrap()[512 * 20..512 * 21], [0xa5; 512]);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_status_clear_on_read() {
+    let path = make_test_file("status.img", 512 * 1024);
+    let mut sd = SD::default();
+    sd.mount(path.to_str().unwrap()).unwrap();
+    select_test_card(&mut sd);
+
+    let status = |sd: &mut SD| match sd.make_request(13, 1 << 16) {
+        Response::R1(resp) => resp.status,
+        _ => panic!("CMD13 did not return R1"),
+    };
+
+    // Errors raised during a transfer are reported once, by the next response.
+    let _ = sd.make_request(18, 1024 * 512);
+    assert_eq!(sd.recv_data(&mut [0u8; 512]), 0);
+    sd.card_status.set_card_is_locked(true);
+    let Response::R1(resp) = sd.make_request(12, 0) else {
+        panic!("CMD12 did not return R1");
+    };
+    assert!(resp.status.get_out_of_range());
+    assert!(resp.status.get_card_is_locked());
+    let next = status(&mut sd);
+    assert!(!next.get_out_of_range());
+    assert!(next.get_card_is_locked());
+    assert_eq!(next.get_current_state(), CurrentState::Transfer);
+    sd.card_status.set_card_is_locked(false);
+
+    // An illegal command gets no response, so the next one reports it.
+    assert!(matches!(sd.make_request(2, 0), Response::RNone));
+    assert!(status(&mut sd).get_illegal_command());
+    assert!(!status(&mut sd).get_illegal_command());
+
+    // Block length errors are no longer sticky, and APP_CMD is reported by CMD55 itself.
+    let Response::R1(resp) = sd.make_request(16, 1024) else {
+        panic!("CMD16 did not return R1");
+    };
+    assert!(resp.status.get_block_len_error());
+    assert!(!status(&mut sd).get_block_len_error());
+    let Response::R1(resp) = sd.make_request(55, 1 << 16) else {
+        panic!("CMD55 did not return R1");
+    };
+    assert!(resp.status.get_app_command());
+
+    sd.unmount();
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_cid() {
+    let esd = CID_ESD.as_bytes();
+    let xsd = CID_XSD.as_bytes();
+    assert_ne!(esd[9..13], xsd[9..13]);
+    assert_eq!(&esd[3..8], b"IntSD");
+    // 2014-01
+    assert_eq!(esd[13..15], [0x00, 0xe1]);
+    assert_eq!(esd[15] >> 1, crc7(&esd[..15]));
+    assert_eq!(esd[15] & 1, 1);
+
+    let path = make_test_file("cid.img", 512 * 1024);
+    let mut sd = SD::new(&CID_XSD);
+    sd.mount(path.to_str().unwrap()).unwrap();
+    let _ = sd.make_request(0, 0);
+    let _ = sd.make_request(8, 0x1aa);
+    let _ = sd.make_request(55, 0);
+    let _ = sd.make_request(41, 0x00ff8000);
+    let Response::R2(resp) = sd.make_request(2, 0) else {
+        panic!("CMD2 did not return R2");
+    };
+    assert_eq!(resp.cid_csd, xsd);
+    let _ = sd.make_request(3, 0);
+    let Response::R2(resp) = sd.make_request(10, 1 << 16) else {
+        panic!("CMD10 did not return R2");
+    };
+    assert_eq!(resp.cid_csd, xsd);
+
+    sd.unmount();
+    fs::remove_file(&path).unwrap();
 }
@@ -0,0 +1,257 @@
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use log::{debug, error, trace, warn};
+
+use crate::{RuntimeError, impl_snapshot};
+
+/*
+Supported commands (3 byte addresses):
+
+0x9f        Read JEDEC ID
+0x05        Read status
+0x06 / 0x04 Write enable / disable
+0x03        Read
+0x0b        Fast read (1 dummy byte)
+0x02        Page program
+0x20        Sector erase (4KiB)
+0xd8        Block erase (64KiB)
+0xc7 / 0x60 Chip erase
+*/
+
+const CMD_READ_ID: u8 = 0x9f;
+const CMD_READ_STATUS: u8 = 0x05;
+const CMD_WRITE_ENABLE: u8 = 0x06;
+const CMD_WRITE_DISABLE: u8 = 0x04;
+const CMD_READ: u8 = 0x03;
+const CMD_FAST_READ: u8 = 0x0b;
+const CMD_PAGE_PROGRAM: u8 = 0x02;
+const CMD_SECTOR_ERASE: u8 = 0x20;
+const CMD_BLOCK_ERASE: u8 = 0xd8;
+const CMD_CHIP_ERASE: u8 = 0xc7;
+const CMD_CHIP_ERASE_ALT: u8 = 0x60;
+
+const STATUS_WEL: u8 = 1 << 1;
+
+const MAKER_WINBOND: u8 = 0xef;
+const MEMORY_TYPE: u8 = 0x40;
+
+const PAGE_SIZE: u64 = 256;
+const SECTOR_SIZE: u64 = 0x1000;
+const BLOCK_SIZE: u64 = 0x10000;
+/// Largest chip 3 byte addresses can reach.
+const MAX_SIZE: u64 = 0x1000000;
+
+/// An SPI NOR flash chip backed by an image file.
+///
+/// The image is kept in memory and programmed or erased ranges are written back to the file as they change.
+#[derive(Default)]
+pub struct SPIFlash {
+    image_file: Option<fs::File>,
+    data: Vec<u8>,
+    /// Whether chip select is asserted.
+    selected: bool,
+    /// Command byte of the current transaction, if received.
+    command: Option<u8>,
+    /// Bytes received after the command byte.
+    received: usize,
+    address: u32,
+    write_enable: bool,
+}
+
+impl SPIFlash {
+    pub fn mount(&mut self, path: &str) -> Result<(), RuntimeError> {
+        if self.image_file.is_some() {
+            return Err(RuntimeError::SPIFlashAlreadyMounted)
+        }
+        let mut file = fs::OpenOptions::new().read(true).write(true).open(path)?;
+        let size = file.metadata()?.len();
+        if !size.is_power_of_two() || !(BLOCK_SIZE..=MAX_SIZE).contains(&size) {
+            error!("SPI flash image size {size} is not a power of 2 between {BLOCK_SIZE} and {MAX_SIZE} bytes.");
+            return Err(RuntimeError::SPIFlashInvalidImage);
+        }
+
+        let mut data = vec![];
+        file.read_to_end(&mut data)?;
+        self.image_file = Some(file);
+        self.data = data;
+        debug!("SPI flash: {size} bytes, ID {:02x?}", self.id());
+        Ok(())
+    }
+
+    pub fn unmount(&mut self) {
+        self.flush();
+        self.image_file = None;
+        self.data = vec![];
+    }
+
+    pub fn is_mounted(&self) -> bool {
+        self.image_file.is_some()
+    }
+
+    /// JEDEC ID: maker, memory type and log2 of the capacity.
+    fn id(&self) -> [u8; 3] {
+        let capacity = u8::try_from(self.data.len().checked_ilog2().unwrap_or(0)).unwrap();
+        [MAKER_WINBOND, MEMORY_TYPE, capacity]
+    }
+
+    /// Drive chip select. Erase commands run when chip select is released, like on a real chip.
+    pub fn select(&mut self, selected: bool) {
+        if self.selected && !selected {
+            self.finish();
+        }
+        if selected != self.selected {
+            self.command = None;
+            self.received = 0;
+            self.address = 0;
+        }
+        self.selected = selected;
+    }
+
+    /// Exchange one byte. Returns 0xff (MISO pulled up) when the chip is not selected or not driving the bus.
+    pub fn transfer(&mut self, mosi: u8) -> u8 {
+        if !self.selected || !self.is_mounted() {
+            return 0xff;
+        }
+        let Some(command) = self.command else {
+            trace!("SPI flash: Command 0x{mosi:02x}");
+            self.command = Some(mosi);
+            match mosi {
+                CMD_WRITE_ENABLE => self.write_enable = true,
+                CMD_WRITE_DISABLE => self.write_enable = false,
+                CMD_READ_ID | CMD_READ_STATUS | CMD_READ | CMD_FAST_READ | CMD_PAGE_PROGRAM | CMD_SECTOR_ERASE |
+                    CMD_BLOCK_ERASE | CMD_CHIP_ERASE | CMD_CHIP_ERASE_ALT => {}
+                _ => warn!("SPI flash: Unsupported command 0x{mosi:02x}"),
+            }
+            return 0xff;
+        };
+
+        let index = self.received;
+        self.received += 1;
+        match command {
+            CMD_READ_ID => self.id().get(index).copied().unwrap_or(0xff),
+            CMD_READ_STATUS => if self.write_enable { STATUS_WEL } else { 0 },
+            CMD_READ | CMD_FAST_READ | CMD_PAGE_PROGRAM | CMD_SECTOR_ERASE | CMD_BLOCK_ERASE if index < 3 => {
+                self.address = self.address << 8 | u32::from(mosi);
+                0xff
+            }
+            // Dummy byte.
+            CMD_FAST_READ if index == 3 => 0xff,
+            CMD_READ | CMD_FAST_READ => {
+                let offset = self.offset(u64::from(self.address));
+                self.address = self.address.wrapping_add(1);
+                self.data[offset]
+            }
+            CMD_PAGE_PROGRAM if self.write_enable => {
+                // Addresses wrap around within the page.
+                let page = u64::from(self.address) & !(PAGE_SIZE - 1);
+                let column = (u64::from(self.address) + u64::try_from(index - 3).unwrap()) % PAGE_SIZE;
+                let offset = self.offset(page + column);
+                // Programming can only clear bits.
+                self.data[offset] &= mosi;
+                self.write_back(offset, 1);
+                0xff
+            }
+            _ => 0xff,
+        }
+    }
+
+    /// Run the erase command of the transaction that just ended.
+    fn finish(&mut self) {
+        let address = u64::from(self.address);
+        let (start, len) = match self.command {
+            Some(CMD_SECTOR_ERASE) if self.received >= 3 => (address & !(SECTOR_SIZE - 1), SECTOR_SIZE),
+            Some(CMD_BLOCK_ERASE) if self.received >= 3 => (address & !(BLOCK_SIZE - 1), BLOCK_SIZE),
+            Some(CMD_CHIP_ERASE | CMD_CHIP_ERASE_ALT) => (0, u64::try_from(self.data.len()).unwrap()),
+            Some(CMD_PAGE_PROGRAM) => {
+                self.write_enable = false;
+                return;
+            }
+            _ => return,
+        };
+        if !self.write_enable {
+            warn!("SPI flash: Erase without write enable");
+            return;
+        }
+        trace!("SPI flash: Erase 0x{start:06x} ({len} bytes)");
+        let offset = self.offset(start);
+        let len = usize::try_from(len).unwrap();
+        self.data[offset..offset + len].fill(0xff);
+        self.write_back(offset, len);
+        self.write_enable = false;
+    }
+
+    /// Offset in the image of an address. The address space wraps around on chips smaller than 16MiB.
+    fn offset(&self, addr: u64) -> usize {
+        usize::try_from(addr).unwrap() % self.data.len()
+    }
+
+    fn write_back(&mut self, offset: usize, len: usize) {
+        let Some(image_file) = self.image_file.as_mut() else {
+            return;
+        };
+        let result = image_file.seek(SeekFrom::Start(u64::try_from(offset).unwrap()))
+            .and_then(|_| image_file.write_all(&self.data[offset..offset + len]));
+        result.unwrap_or_else(|err| {
+            error!("SPI flash: Writing 0x{offset:06x} failed: {err:?}");
+        });
+    }
+
+    pub fn flush(&mut self) {
+        if let Some(image_file) = self.image_file.as_mut() {
+            image_file.flush().unwrap_or_else(|err| {
+                error!("SPI flash: Flushing the image failed: {err:?}");
+            });
+        }
+    }
+}
+
+impl_snapshot!(SPIFlash { selected, command, received, address, write_enable });
+
+#[cfg(test)]
+pub fn make_test_spi_flash(name: &str) -> std::path::PathBuf {
+    crate::extdev::make_test_file(&format!("{name}.spi"), 2 * BLOCK_SIZE as usize)
+}
+
+#[cfg(test)]
+fn transaction(flash: &mut SPIFlash, out: &[u8]) -> Vec<u8> {
+    flash.select(true);
+    let result = out.iter().map(|&b| flash.transfer(b)).collect();
+    flash.select(false);
+    result
+}
+
+#[test]
+fn test_spi_flash_commands() {
+    let path = make_test_spi_flash("commands");
+    let mut flash = SPIFlash::default();
+    flash.mount(path.to_str().unwrap()).unwrap();
+
+    assert_eq!(transaction(&mut flash, &[CMD_READ_ID, 0, 0, 0]), [0xff, 0xef, 0x40, 0x11]);
+    assert_eq!(transaction(&mut flash, &[CMD_READ, 0x00, 0x01, 0x00, 0, 0]), [0xff, 0xff, 0xff, 0xff, 5, 6]);
+    assert_eq!(transaction(&mut flash, &[CMD_FAST_READ, 0x00, 0x00, 0x02, 0, 0]), [0xff, 0xff, 0xff, 0xff, 0xff, 2]);
+
+    // Programming needs write enable, and only clears bits.
+    transaction(&mut flash, &[CMD_PAGE_PROGRAM, 0x00, 0x00, 0x01, 0x00]);
+    assert_eq!(transaction(&mut flash, &[CMD_READ, 0, 0, 1, 0])[4], 1);
+    transaction(&mut flash, &[CMD_WRITE_ENABLE]);
+    assert_eq!(transaction(&mut flash, &[CMD_READ_STATUS, 0]), [0xff, STATUS_WEL]);
+    transaction(&mut flash, &[CMD_PAGE_PROGRAM, 0x00, 0x01, 0xff, 0x0f, 0x04]);
+    assert_eq!(transaction(&mut flash, &[CMD_READ, 0x00, 0x01, 0xff, 0])[4], 9);
+    // The second byte wraps around to the start of the page.
+    assert_eq!(transaction(&mut flash, &[CMD_READ, 0x00, 0x01, 0x00, 0, 0])[4..], [4, 6]);
+    assert_eq!(transaction(&mut flash, &[CMD_READ_STATUS, 0]), [0xff, 0]);
+
+    // Erase the second sector.
+    transaction(&mut flash, &[CMD_WRITE_ENABLE]);
+    transaction(&mut flash, &[CMD_SECTOR_ERASE, 0x00, 0x10, 0x00]);
+    assert_eq!(transaction(&mut flash, &[CMD_READ, 0x00, 0x1f, 0xfe, 0, 0])[4..], [0xff, 0xff]);
+    assert_eq!(transaction(&mut flash, &[CMD_READ, 0x00, 0x20, 0x00, 0])[4], (0x2000 % 251) as u8);
+
+    flash.unmount();
+    // Written back to the image.
+    let data = fs::read(&path).unwrap();
+    assert_eq!(data[0x100], 4);
+    assert_eq!(data[0x1000], 0xff);
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,509 @@
+use std::{collections::{HashMap, hash_map::Entry}, io::{self, ErrorKind, Read, Write}, net::{TcpListener, TcpStream}, time::Duration};
+
+use log::{debug, error, info, warn};
+use unicorn_engine::{RegisterARM, UcHookId};
+
+use crate::{device::{QuitDetail, StopReason, UnicornContext, request_quit, request_stop, reset_peripheral, reset_peripherals}, mmu, watch::{self, WatchKind, Watchpoint}};
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/// Largest memory transfer served in a single packet.
+const MAX_TRANSFER: usize = 0x1000;
+
+/// How long to wait for packets while the guest is halted.
+const HALTED_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Core registers in the order of the default GDB ARM register layout.
+const CORE_REGS: [RegisterARM; 16] = [
+    RegisterARM::R0,
+    RegisterARM::R1,
+    RegisterARM::R2,
+    RegisterARM::R3,
+    RegisterARM::R4,
+    RegisterARM::R5,
+    RegisterARM::R6,
+    RegisterARM::R7,
+    RegisterARM::R8,
+    RegisterARM::R9,
+    RegisterARM::R10,
+    RegisterARM::R11,
+    RegisterARM::R12,
+    RegisterARM::SP,
+    RegisterARM::LR,
+    RegisterARM::PC,
+];
+
+/// GDB register numbers of the legacy FPA registers, which are reported as zero.
+const FPA_REGS: std::ops::Range<usize> = 16..24;
+const FPS_REG: usize = 24;
+const CPSR_REG: usize = 25;
+/// Size in bytes of a FPA register.
+const FPA_REG_SIZE: usize = 12;
+
+/// What the emulator should do after handling GDB packets.
+#[derive(Debug, PartialEq)]
+pub enum GdbAction {
+    /// The guest is halted. Do not run it.
+    Halt,
+    /// Run a single instruction.
+    Step,
+    /// Run until something stops the guest.
+    Continue,
+}
+
+#[derive(Debug, PartialEq)]
+enum Packet {
+    Command(Vec<u8>),
+    Interrupt,
+    Corrupt,
+}
+
+/// GDB remote serial protocol stub.
+pub struct GdbStub {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+    halted: bool,
+    stepping: bool,
+    detached: bool,
+    breakpoints: HashMap<u64, UcHookId>,
+    /// Watchpoint IDs, keyed by GDB watchpoint type, address and length.
+    watchpoints: HashMap<(u8, u64, usize), usize>,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn from_hex(s: &[u8]) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    s.chunks(2).map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()).collect()
+}
+
+fn parse_u64(s: &[u8]) -> Option<u64> {
+    u64::from_str_radix(std::str::from_utf8(s).ok()?, 16).ok()
+}
+
+/// Parse `addr,len`.
+fn parse_range(s: &[u8]) -> Option<(u64, usize)> {
+    let comma = s.iter().position(|c| *c == b',')?;
+    let addr = parse_u64(&s[..comma])?;
+    let len = usize::try_from(parse_u64(&s[comma + 1..])?).ok()?;
+    Some((addr, len))
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |acc, b| acc.wrapping_add(*b))
+}
+
+fn encode_packet(data: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(data.len() + 4);
+    packet.push(b'$');
+    packet.extend_from_slice(data);
+    packet.extend_from_slice(format!("#{:02x}", checksum(data)).as_bytes());
+    packet
+}
+
+/// Take the next packet out of `buffer`. Returns `None` if no complete packet is available yet.
+fn take_packet(buffer: &mut Vec<u8>) -> Option<Packet> {
+    loop {
+        match buffer.first()? {
+            0x03 => {
+                buffer.remove(0);
+                return Some(Packet::Interrupt);
+            }
+            b'$' => {
+                let hash = buffer.iter().position(|c| *c == b'#')?;
+                if buffer.len() < hash + 3 {
+                    return None;
+                }
+                let packet: Vec<u8> = buffer.drain(..hash + 3).collect();
+                let data = &packet[1..hash];
+                let expected = from_hex(&packet[hash + 1..]).and_then(|c| c.first().copied());
+                return Some(if expected == Some(checksum(data)) {
+                    Packet::Command(data.to_vec())
+                } else {
+                    Packet::Corrupt
+                });
+            }
+            // Acks and line noise.
+            _ => {
+                buffer.remove(0);
+            }
+        }
+    }
+}
+
+fn breakpoint_hook(uc: &mut UnicornContext, addr: u64, _size: u32) {
+    if uc.get_data_mut().gdb_step_over.take() == Some(addr) {
+        return;
+    }
+    request_stop(uc, StopReason::Breakpoint);
+    uc.emu_stop().unwrap_or_else(|err| {
+        error!("Failed to stop emulator: {err:?}");
+    });
+}
+
+impl GdbStub {
+    /// Wait for GDB to connect on `port`. The guest starts halted.
+    pub fn listen(port: u16) -> io::Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        info!("Waiting for GDB connection on port {port}...");
+        let (stream, peer) = listener.accept()?;
+        info!("GDB connected from {peer}");
+        stream.set_nodelay(true)?;
+        Ok(Self {
+            stream,
+            buffer: vec![],
+            halted: true,
+            stepping: false,
+            detached: false,
+            breakpoints: HashMap::new(),
+            watchpoints: HashMap::new(),
+        })
+    }
+
+    /// Handle pending packets and decide how the emulator should run next.
+    ///
+    /// Returns an error when the connection is closed or GDB detached. Call `close` afterwards.
+    pub fn poll(&mut self, uc: &mut UnicornContext) -> io::Result<GdbAction> {
+        self.receive()?;
+        while let Some(packet) = take_packet(&mut self.buffer) {
+            match packet {
+                Packet::Command(command) => {
+                    self.stream.write_all(b"+")?;
+                    self.handle(uc, &command)?;
+                }
+                Packet::Interrupt => {
+                    if !self.halted {
+                        self.halted = true;
+                        self.send(format!("S{SIGINT:02x}").as_bytes())?;
+                    }
+                }
+                Packet::Corrupt => self.stream.write_all(b"-")?,
+            }
+        }
+
+        if self.detached {
+            return Err(io::Error::new(ErrorKind::ConnectionAborted, "GDB detached"));
+        }
+
+        Ok(if self.halted {
+            GdbAction::Halt
+        } else if self.stepping {
+            GdbAction::Step
+        } else {
+            GdbAction::Continue
+        })
+    }
+
+    /// Report the guest stopping to GDB, if it stopped because of a breakpoint or a single step. Must be called after
+    /// the emulator stops, before the stop reasons are consumed.
+    pub fn check_stop(&mut self, uc: &mut UnicornContext) -> io::Result<()> {
+        let hit = uc.get_data_mut().watch.take_hits().pop();
+        let stop_reason = uc.get_data().stop_reason;
+        if self.halted || !(self.stepping || stop_reason.intersects(StopReason::Breakpoint | StopReason::Watchpoint)) {
+            return Ok(());
+        }
+        self.stepping = false;
+        self.halted = true;
+        let watch = hit.and_then(|hit| {
+            let (&(kind, _, _), _) = self.watchpoints.iter().find(|(_, id)| **id == hit.id)?;
+            let name = match kind {
+                b'2' => "watch",
+                b'3' => "rwatch",
+                _ => "awatch",
+            };
+            Some(format!("{name}:{:x};", hit.addr))
+        });
+        match watch {
+            Some(watch) => self.send(format!("T{SIGTRAP:02x}{watch}").as_bytes()),
+            None => self.send(format!("S{SIGTRAP:02x}").as_bytes()),
+        }
+    }
+
+    /// Remove all breakpoints and let the guest run freely.
+    pub fn close(mut self, uc: &mut UnicornContext) {
+        for (addr, hook) in self.breakpoints.drain() {
+            uc.remove_hook(hook).unwrap_or_else(|err| {
+                error!("Failed to remove breakpoint at 0x{addr:08x}: {err:?}");
+            });
+        }
+        for ((_, addr, _), id) in self.watchpoints.drain() {
+            watch::remove_watchpoint(uc, id).unwrap_or_else(|err| {
+                error!("Failed to remove watchpoint at 0x{addr:08x}: {err:?}");
+            });
+        }
+        uc.get_data_mut().gdb_step_over = None;
+    }
+
+    fn receive(&mut self) -> io::Result<()> {
+        // Block for a little while when halted so the event loop does not spin.
+        self.stream.set_nonblocking(!self.halted)?;
+        self.stream.set_read_timeout(Some(HALTED_POLL_INTERVAL))?;
+        let mut tmp = [0u8; 4096];
+        loop {
+            match self.stream.read(&mut tmp) {
+                Ok(0) => return Err(io::Error::new(ErrorKind::ConnectionAborted, "GDB disconnected")),
+                Ok(len) => {
+                    self.buffer.extend_from_slice(&tmp[..len]);
+                    self.stream.set_nonblocking(true)?;
+                }
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => return Ok(()),
+                Err(err) if err.kind() == ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        self.stream.write_all(&encode_packet(data))
+    }
+
+    fn handle(&mut self, uc: &mut UnicornContext, command: &[u8]) -> io::Result<()> {
+        debug!("GDB: {}", String::from_utf8_lossy(command));
+        let Some((&kind, args)) = command.split_first() else {
+            return self.send(b"");
+        };
+
+        let reply = match kind {
+            b'?' => format!("S{SIGTRAP:02x}").into_bytes(),
+            b'g' => self.read_registers(uc),
+            b'G' => self.write_registers(uc, args),
+            b'p' => parse_u64(args).and_then(|reg| self.read_register(uc, reg as usize)).unwrap_or_else(|| b"E00".to_vec()),
+            b'P' => self.write_register(uc, args),
+            b'm' => self.read_memory(uc, args),
+            b'M' => self.write_memory(uc, args),
+            b'c' | b's' => {
+                if let Some(addr) = parse_u64(args) {
+                    uc.set_pc(addr).map_err(|err| io::Error::other(format!("{err:?}")))?;
+                }
+                self.resume(uc, kind == b's');
+                return Ok(());
+            }
+            b'Z' | b'z' => self.update_breakpoint(uc, kind == b'Z', args),
+            b'D' => {
+                self.detached = true;
+                b"OK".to_vec()
+            }
+            b'k' => {
+                request_quit(uc, QuitDetail::UserSpecified);
+                self.detached = true;
+                return Ok(());
+            }
+            b'H' => b"OK".to_vec(),
+            b'q' => match args {
+                a if a.starts_with(b"Supported") => format!("PacketSize={:x}", MAX_TRANSFER * 2 + 16).into_bytes(),
+                b"Attached" => b"1".to_vec(),
+                b"C" => b"QC1".to_vec(),
+                b"fThreadInfo" => b"m1".to_vec(),
+                b"sThreadInfo" => b"l".to_vec(),
+                a if a.starts_with(b"Rcmd,") => self.monitor(uc, &a[5..]),
+                _ => vec![],
+            },
+            _ => vec![],
+        };
+        self.send(&reply)
+    }
+
+    /// Run a `monitor` command. `reset` resets all peripherals, and `reset <name>` the one mapped as `name`.
+    fn monitor(&mut self, uc: &mut UnicornContext, args: &[u8]) -> Vec<u8> {
+        let Some(command) = from_hex(args).and_then(|command| String::from_utf8(command).ok()) else {
+            return b"E00".to_vec();
+        };
+        let words: Vec<_> = command.split_whitespace().collect();
+        match words[..] {
+            ["reset"] => reset_peripherals(uc),
+            ["reset", name] if reset_peripheral(uc, &name.to_uppercase()) => (),
+            _ => {
+                warn!("GDB: Unknown monitor command `{command}`");
+                return b"E01".to_vec();
+            }
+        }
+        b"OK".to_vec()
+    }
+
+    fn resume(&mut self, uc: &mut UnicornContext, step: bool) {
+        self.halted = false;
+        self.stepping = step;
+        // Do not stop again at the breakpoint we are sitting on.
+        let pc = uc.pc_read().unwrap_or_default();
+        uc.get_data_mut().gdb_step_over = self.breakpoints.contains_key(&pc).then_some(pc);
+    }
+
+    fn read_register(&self, uc: &UnicornContext, reg: usize) -> Option<Vec<u8>> {
+        let value = match reg {
+            r if r < CORE_REGS.len() => uc.reg_read(CORE_REGS[r]).ok()? as u32,
+            r if FPA_REGS.contains(&r) => return Some(vec![b'0'; FPA_REG_SIZE * 2]),
+            FPS_REG => 0,
+            CPSR_REG => uc.reg_read(RegisterARM::CPSR).ok()? as u32,
+            _ => return None,
+        };
+        // Registers are sent in target byte order.
+        Some(to_hex(&uc.get_data().endian.u32_to_bytes(value)).into_bytes())
+    }
+
+    fn read_registers(&self, uc: &UnicornContext) -> Vec<u8> {
+        let mut reply = vec![];
+        for reg in 0..=CPSR_REG {
+            match self.read_register(uc, reg) {
+                Some(value) => reply.extend(value),
+                None => return b"E00".to_vec(),
+            }
+        }
+        reply
+    }
+
+    fn write_register(&self, uc: &mut UnicornContext, args: &[u8]) -> Vec<u8> {
+        let Some(eq) = args.iter().position(|c| *c == b'=') else {
+            return b"E00".to_vec();
+        };
+        let (Some(reg), Some(value)) = (parse_u64(&args[..eq]), from_hex(&args[eq + 1..])) else {
+            return b"E00".to_vec();
+        };
+        let Ok(value) = <[u8; 4]>::try_from(value.as_slice()) else {
+            // FPA registers.
+            return b"OK".to_vec();
+        };
+        let value = uc.get_data().endian.u32_from_bytes(value).into();
+        let result = match reg as usize {
+            r if r < CORE_REGS.len() => uc.reg_write(CORE_REGS[r], value),
+            CPSR_REG => uc.reg_write(RegisterARM::CPSR, value),
+            FPS_REG => Ok(()),
+            _ => return b"E00".to_vec(),
+        };
+        if result.is_ok() { b"OK".to_vec() } else { b"E00".to_vec() }
+    }
+
+    fn write_registers(&self, uc: &mut UnicornContext, args: &[u8]) -> Vec<u8> {
+        let Some(values) = from_hex(args) else {
+            return b"E00".to_vec();
+        };
+        let cpsr_offset = 4 * CORE_REGS.len() + FPA_REG_SIZE * FPA_REGS.len() + 4;
+        for (i, reg) in CORE_REGS.iter().enumerate() {
+            if let Some(value) = values.get(4 * i..4 * i + 4) {
+                let value = uc.get_data().endian.u32_from_bytes(value.try_into().unwrap());
+                if uc.reg_write(*reg, value.into()).is_err() {
+                    return b"E00".to_vec();
+                }
+            }
+        }
+        if let Some(value) = values.get(cpsr_offset..cpsr_offset + 4) {
+            let value = uc.get_data().endian.u32_from_bytes(value.try_into().unwrap());
+            if uc.reg_write(RegisterARM::CPSR, value.into()).is_err() {
+                return b"E00".to_vec();
+            }
+        }
+        b"OK".to_vec()
+    }
+
+    fn read_memory(&self, uc: &UnicornContext, args: &[u8]) -> Vec<u8> {
+        let Some((addr, len)) = parse_range(args) else {
+            return b"E00".to_vec();
+        };
+        let mut data = vec![0u8; len.min(MAX_TRANSFER)];
+        match mmu::read_virtual(uc, addr, &mut data) {
+            Ok(()) => to_hex(&data).into_bytes(),
+            Err(_) => b"E14".to_vec(),
+        }
+    }
+
+    fn write_memory(&self, uc: &mut UnicornContext, args: &[u8]) -> Vec<u8> {
+        let Some(colon) = args.iter().position(|c| *c == b':') else {
+            return b"E00".to_vec();
+        };
+        let (Some((addr, len)), Some(data)) = (parse_range(&args[..colon]), from_hex(&args[colon + 1..])) else {
+            return b"E00".to_vec();
+        };
+        if data.len() != len {
+            return b"E00".to_vec();
+        }
+        match mmu::write_virtual(uc, addr, &data) {
+            Ok(()) => b"OK".to_vec(),
+            Err(_) => b"E14".to_vec(),
+        }
+    }
+
+    fn update_breakpoint(&mut self, uc: &mut UnicornContext, insert: bool, args: &[u8]) -> Vec<u8> {
+        // Only software breakpoints and watchpoints are supported.
+        if let [kind @ (b'2' | b'3' | b'4'), b',', args @ ..] = args {
+            return self.update_watchpoint(uc, insert, *kind, args);
+        }
+        let Some(args) = args.strip_prefix(b"0,") else {
+            return vec![];
+        };
+        let Some((addr, _kind)) = parse_range(args) else {
+            return b"E00".to_vec();
+        };
+
+        if insert {
+            if let Entry::Vacant(entry) = self.breakpoints.entry(addr) {
+                match uc.add_code_hook(addr, addr, breakpoint_hook) {
+                    Ok(hook) => {
+                        entry.insert(hook);
+                    }
+                    Err(err) => {
+                        warn!("Failed to add breakpoint at 0x{addr:08x}: {err:?}");
+                        return b"E00".to_vec();
+                    }
+                }
+            }
+        } else if let Some(hook) = self.breakpoints.remove(&addr) && let Err(err) = uc.remove_hook(hook) {
+            warn!("Failed to remove breakpoint at 0x{addr:08x}: {err:?}");
+            return b"E00".to_vec();
+        }
+        b"OK".to_vec()
+    }
+
+    fn update_watchpoint(&mut self, uc: &mut UnicornContext, insert: bool, kind: u8, args: &[u8]) -> Vec<u8> {
+        let Some((addr, len)) = parse_range(args) else {
+            return b"E00".to_vec();
+        };
+
+        let key = (kind, addr, len);
+        if insert {
+            if let Entry::Vacant(entry) = self.watchpoints.entry(key) {
+                let kind = match kind {
+                    b'2' => WatchKind::Write,
+                    b'3' => WatchKind::Read,
+                    _ => WatchKind::Access,
+                };
+                let len = u64::try_from(len).unwrap();
+                match watch::add_watchpoint(uc, Watchpoint { addr, len, kind }) {
+                    Ok(id) => {
+                        entry.insert(id);
+                    }
+                    Err(err) => {
+                        warn!("Failed to add watchpoint at 0x{addr:08x}: {err:?}");
+                        return b"E00".to_vec();
+                    }
+                }
+            }
+        } else if let Some(id) = self.watchpoints.remove(&key) && let Err(err) = watch::remove_watchpoint(uc, id) {
+            warn!("Failed to remove watchpoint at 0x{addr:08x}: {err:?}");
+            return b"E00".to_vec();
+        }
+        b"OK".to_vec()
+    }
+}
+
+#[test]
+fn test_take_packet() {
+    let mut buffer = b"+$m0,4#fd\x03$g#00".to_vec();
+    assert_eq!(take_packet(&mut buffer), Some(Packet::Command(b"m0,4".to_vec())));
+    assert_eq!(take_packet(&mut buffer), Some(Packet::Interrupt));
+    assert_eq!(take_packet(&mut buffer), Some(Packet::Corrupt));
+    assert_eq!(take_packet(&mut buffer), None);
+
+    let mut buffer = b"$g#6".to_vec();
+    assert_eq!(take_packet(&mut buffer), None);
+    buffer.push(b'7');
+    assert_eq!(take_packet(&mut buffer), Some(Packet::Command(b"g".to_vec())));
+
+    assert_eq!(encode_packet(b"OK"), b"$OK#9a");
+    assert_eq!(parse_range(b"80001000,10"), Some((0x80001000, 0x10)));
+    assert_eq!(from_hex(b"0a0b"), Some(vec![0x0a, 0x0b]));
+    assert_eq!(from_hex(b"0a0"), None);
+}
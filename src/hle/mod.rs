@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+
+use log::{error, warn};
+use unicorn_engine::{RegisterARM, uc_error};
+
+use crate::{RuntimeError, device::UnicornContext, mmu};
+
+mod printf;
+
+/// HLE handler. Installed as a block hook at the entry point of the function it replaces.
+pub type HLECallback = fn(&mut UnicornContext, u64, u32);
+
+/// HLE handlers that can be referenced by name in a symbol map.
+const HANDLERS: &[(&str, HLECallback)] = &[
+    ("printf", printf::printf_callback),
+];
+
+/// Symbols installed when no symbol map is given.
+pub const DEFAULT_SYMBOLS: &[(u64, &str)] = &[
+    (0x800053e0, "printf"),
+];
+
+/// Longest string read from the guest. Guards against runaway reads of unterminated strings.
+const MAX_CSTR_LEN: usize = 4096;
+
+/// Most stack frames walked by `walk_frames()`.
+const MAX_BACKTRACE_DEPTH: usize = 8;
+
+/// Symbols of a symbol map, used to name code addresses in diagnostics.
+#[derive(Default)]
+pub struct SymbolTable {
+    /// Sorted by address.
+    symbols: Vec<(u64, String)>,
+}
+
+impl SymbolTable {
+    pub fn new<S: AsRef<str>>(symbols: &[(u64, S)]) -> Self {
+        let mut symbols: Vec<_> = symbols.iter().map(|(address, name)| (*address, name.as_ref().to_owned())).collect();
+        symbols.sort_by_key(|(address, _)| *address);
+        Self { symbols }
+    }
+
+    /// Name an address as `symbol+offset` after the closest symbol at or below it.
+    pub fn resolve(&self, addr: u64) -> Option<String> {
+        // Thumb addresses have bit 0 set.
+        let addr = addr & !1;
+        let index = self.symbols.partition_point(|(address, _)| *address <= addr).checked_sub(1)?;
+        let (address, name) = &self.symbols[index];
+        Some(format!("{name}+0x{:x}", addr - address))
+    }
+
+    /// Format an address with its symbol, if any.
+    pub fn describe(&self, addr: u64) -> String {
+        match self.resolve(addr) {
+            Some(symbol) => format!("0x{addr:08x} <{symbol}>"),
+            None => format!("0x{addr:08x}"),
+        }
+    }
+}
+
+/// Walk APCS stack frames starting at frame pointer `fp` and return the saved return addresses.
+///
+/// Each frame is pushed as `{fp, ip, lr, pc}` with `fp` pointing at the saved `pc`, so the saved `lr` is at `fp - 4`
+/// and the caller's `fp` at `fp - 12`. Stops at a null or non-increasing frame pointer, or when a read fails.
+pub fn walk_frames(mut fp: u32, mut read_word: impl FnMut(u32) -> Option<u32>) -> Vec<u32> {
+    let mut frames = vec![];
+    while fp >= 12 && frames.len() < MAX_BACKTRACE_DEPTH {
+        let (Some(lr), Some(caller_fp)) = (read_word(fp - 4), read_word(fp - 12)) else {
+            break;
+        };
+        frames.push(lr);
+        // Stacks grow down, so callers' frames are at higher addresses.
+        if caller_fp <= fp {
+            break;
+        }
+        fp = caller_fp;
+    }
+    frames
+}
+
+/// Log a backtrace of the guest, read from the current frame pointer.
+pub fn log_backtrace(uc: &UnicornContext, symbols: &SymbolTable) {
+    let reg = |reg| uc.reg_read(reg).unwrap_or(0);
+    error!("  pc {}", symbols.describe(reg(RegisterARM::PC)));
+    error!("  lr {}", symbols.describe(reg(RegisterARM::LR)));
+    let fp = u32::try_from(reg(RegisterARM::R11) & 0xffffffff).unwrap();
+    let frames = walk_frames(fp, |addr| {
+        let mut bytes = [0u8; 4];
+        mmu::read_virtual(uc, addr.into(), &mut bytes).ok().map(|_| uc.get_data().endian.u32_from_bytes(bytes))
+    });
+    for (i, lr) in frames.into_iter().enumerate() {
+        error!("  #{i} {}", symbols.describe(lr.into()));
+    }
+}
+
+/// Look up an HLE handler by name.
+pub fn lookup(name: &str) -> Option<HLECallback> {
+    HANDLERS.iter().find(|(handler_name, _)| *handler_name == name).map(|(_, callback)| *callback)
+}
+
+/// Parse a symbol map. Each line is an address followed by a handler name, e.g. `0x1234 printf`. Empty lines and
+/// lines starting with `#` are ignored.
+pub fn parse_symbol_map(text: &str) -> Result<Vec<(u64, String)>, String> {
+    let mut symbols = vec![];
+    for (lineno, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut parts = line.split_whitespace();
+        let (Some(address), Some(name), None) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(format!("Line {}: expecting `<address> <name>`", lineno + 1));
+        };
+        let address = address.strip_prefix("0x").or_else(|| address.strip_prefix("0X")).unwrap_or(address);
+        let address = u64::from_str_radix(address, 16)
+            .map_err(|err| format!("Line {}: invalid address {address}: {err}", lineno + 1))?;
+        symbols.push((address, name.to_owned()));
+    }
+    Ok(symbols)
+}
+
+/// Install HLE hooks for each symbol. Symbols without a known handler are skipped with a warning.
+pub fn install<S: AsRef<str>>(uc: &mut UnicornContext, symbols: &[(u64, S)]) -> Result<(), uc_error> {
+    let mut installed = HashMap::new();
+    for (address, name) in symbols {
+        let name = name.as_ref();
+        let Some(callback) = lookup(name) else {
+            warn!("No HLE handler named {name}, skipping 0x{address:08x}");
+            continue;
+        };
+        if let Some(previous) = installed.insert(*address, name) {
+            warn!("0x{address:08x} is already hooked by {previous}, skipping {name}");
+            continue;
+        }
+        uc.add_block_hook(*address, *address, callback)?;
+    }
+    Ok(())
+}
+
+pub fn get_arg_at(uc: &UnicornContext, pos: u64) -> Result<u32, uc_error> {
+    match pos {
+        0 => Ok(u32::try_from(uc.reg_read(RegisterARM::R0)? & 0xffffffff).unwrap()),
+        1 => Ok(u32::try_from(uc.reg_read(RegisterARM::R1)? & 0xffffffff).unwrap()),
+        2 => Ok(u32::try_from(uc.reg_read(RegisterARM::R2)? & 0xffffffff).unwrap()),
+        3 => Ok(u32::try_from(uc.reg_read(RegisterARM::R3)? & 0xffffffff).unwrap()),
+        _ => {
+            let stack_offset = 4 * (pos - 4) + uc.reg_read(RegisterARM::SP)?;
+            let mut bytes = [0u8; 4];
+            mmu::read_virtual(uc, stack_offset, &mut bytes)?;
+            Ok(uc.get_data().endian.u32_from_bytes(bytes))
+        }
+    }
+}
+
+/// Read a NUL-terminated string of at most `max_len` bytes from the guest.
+fn read_cstr(uc: &UnicornContext, address: u64, max_len: usize) -> Result<String, RuntimeError> {
+    let mut tmp = [0u8; 256];
+    let mut result: Vec<u8> = vec![];
+    let mut current_address = address;
+    let max_len = max_len.min(MAX_CSTR_LEN);
+    while result.len() < max_len {
+        let chunk = &mut tmp[..(max_len - result.len()).min(256)];
+        mmu::read_virtual(uc, current_address, chunk)?;
+        let copy_size = chunk.iter().position(|e| *e == 0).unwrap_or(chunk.len());
+        result.extend_from_slice(&chunk[..copy_size]);
+        if copy_size < chunk.len() {
+            return Ok(utf8_prefix(result)?);
+        }
+        current_address += u64::try_from(chunk.len()).unwrap();
+    }
+
+    if max_len == MAX_CSTR_LEN {
+        warn!("String at 0x{address:08x} is longer than {MAX_CSTR_LEN} bytes, truncating");
+    }
+    Ok(utf8_prefix(result)?)
+}
+
+/// Decode a possibly truncated UTF-8 string, dropping a partial character at the end.
+fn utf8_prefix(mut bytes: Vec<u8>) -> Result<String, std::string::FromUtf8Error> {
+    if let Err(err) = std::str::from_utf8(&bytes) && err.error_len().is_none() {
+        bytes.truncate(err.valid_up_to());
+    }
+    String::from_utf8(bytes)
+}
+
+#[test]
+fn test_utf8_prefix() {
+    assert_eq!(utf8_prefix(b"abc".to_vec()).unwrap(), "abc");
+    assert_eq!(utf8_prefix("a\u{4e2d}".as_bytes()[..3].to_vec()).unwrap(), "a");
+    assert!(utf8_prefix(vec![b'a', 0xff, b'b']).is_err());
+}
+
+#[test]
+fn test_parse_symbol_map() {
+    let symbols = parse_symbol_map("# comment\n0x1234 printf\n\n  800053e0  memcpy  \n").unwrap();
+    assert_eq!(symbols, vec![(0x1234, String::from("printf")), (0x800053e0, String::from("memcpy"))]);
+    assert!(parse_symbol_map("0x1234").is_err());
+    assert!(parse_symbol_map("0x1234 printf extra").is_err());
+    assert!(parse_symbol_map("zzz printf").is_err());
+    assert!(lookup("printf").is_some());
+    assert!(lookup("memcpy").is_none());
+}
+
+#[test]
+fn test_symbol_table() {
+    let symbols = SymbolTable::new(&[(0x2000, "main"), (0x1000, "start")]);
+    assert_eq!(symbols.resolve(0xfff), None);
+    assert_eq!(symbols.resolve(0x1000).as_deref(), Some("start+0x0"));
+    assert_eq!(symbols.resolve(0x2011).as_deref(), Some("main+0x10"));
+    assert_eq!(symbols.describe(0x1234), "0x00001234 <start+0x234>");
+
+    // Two frames above 0x7f00, the outermost one ending the chain with a null frame pointer.
+    let stack = [(0x7efc, 0x1010), (0x7ef4, 0x7f20), (0x7f1c, 0x2020), (0x7f14, 0)];
+    let read_word = |addr| stack.iter().find(|(a, _)| *a == addr).map(|(_, value)| *value);
+    assert_eq!(walk_frames(0x7f00, read_word), [0x1010, 0x2020]);
+    assert_eq!(walk_frames(0x7000, read_word), []);
+}
@@ -0,0 +1,608 @@
+use std::fmt::Write;
+
+use bitflags::bitflags;
+use log::{error, info, warn};
+use regex::Regex;
+use unicorn_engine::RegisterARM;
+
+use crate::{RuntimeError, device::{QuitDetail, UnicornContext, request_quit}, memmap::Endian, mmu};
+
+use super::{MAX_CSTR_LEN, get_arg_at, read_cstr};
+
+const NAME_PRINTF: &str = "lle::hle::printf";
+
+const FORMAT_REGEX: &str = concat!(
+    r"%(?:(?<escape>%)|",
+        r"(?<flags>[-+ #0]+)?",
+        r"(?<width>[0-9]+)?",
+        r"(?:\.(?<precision>[0-9]+))?",
+        r"(?<length>hh|h|l|ll|j|z|t|L)?",
+        r"(?<specifier>[csdioxXufFeEaAgGnp])",
+    r")"
+);
+
+bitflags! {
+    #[derive(Debug)]
+    pub struct FormatFlags: u8 {
+        const LeftJustified = 1 << 0;
+        const AlwaysSign = 1 << 1;
+        const PadZero = 1 << 2;
+        const PadSpace = 1 << 3;
+        const AltMode = 1 << 4;
+        const DynamicWidth = 1 << 5;
+        const DynamicPrecision = 1 << 6;
+        const Capital = 1 << 7;
+    }
+}
+
+#[derive(Default, Debug, PartialEq)]
+pub enum LengthModifier {
+    /// Quarter-width data (the `hh` modifier).
+    Quarter,
+    /// Half-width data (the `h` modifier).
+    Half,
+    /// Full-width data (no modifier).
+    #[default]
+    Full,
+    /// Double-width data (the `l` modifier).
+    Double,
+    /// Quadruple-width data (the `L` modifier for float types and `ll` for integer types).
+    Quadruple,
+    /// `(u)intmax_t` (the `j` modifier).
+    IntMax,
+    /// `(s)size_t` (the `z` modifier).
+    Size,
+    /// `(u)ptrdiff_t` (the `t` modifier).
+    PointerOffset,
+}
+
+#[derive(Debug)]
+pub enum IntegerType {
+    SignedDecimal,
+    UnsignedDecimal,
+    Octal,
+    Hexadecimal,
+}
+
+#[derive(Debug)]
+pub enum FloatType {
+    Normal,
+    DecimalExponent,
+    HexadecimalExponent,
+    AutoExponent,
+}
+
+#[derive(Debug)]
+pub struct NumericalFormat {
+    padding: usize,
+    precision: Option<usize>,
+    length: LengthModifier,
+    flags: FormatFlags,
+}
+
+#[derive(Debug)]
+pub enum ConversionSegment {
+    Literal{start: usize, end: usize},
+    Escape,
+    Character{flags: FormatFlags, padding: Option<usize>},
+    String{flags: FormatFlags, padding: Option<usize>, limit: Option<usize>},
+    Integer{format: NumericalFormat, type_: IntegerType},
+    Float{format: NumericalFormat, type_: FloatType},
+    Pointer{flags: FormatFlags, padding: Option<usize>},
+    Count{length: LengthModifier},
+}
+
+#[derive(Debug)]
+pub struct FormatString {
+    raw: String,
+    parsed: Vec<ConversionSegment>,
+}
+
+impl From<String> for FormatString {
+    fn from(value: String) -> Self {
+        let mut obj = Self { raw: value, parsed: vec![] };
+        let regex = Regex::new(FORMAT_REGEX).unwrap();
+        let mut literal_offset = 0usize;
+        for c in regex.captures_iter(&obj.raw) {
+            let m = c.get(0).unwrap();
+            if m.start() != literal_offset {
+                obj.parsed.push(ConversionSegment::Literal { start: literal_offset, end: m.start() });
+                literal_offset = m.start();
+            }
+
+            literal_offset += m.len();
+
+            if c.name("escape").is_some() {
+                obj.parsed.push(ConversionSegment::Escape);
+                continue;
+            }
+
+            let ff_flags = if let Some(m_flags) = c.name("flags") {
+                let flags_str = m_flags.as_str();
+                flags_str.chars().fold(FormatFlags::empty(), |acc, flag| {
+                    acc | match flag {
+                        '-' => FormatFlags::LeftJustified,
+                        '+' => FormatFlags::AlwaysSign,
+                        ' ' => FormatFlags::PadSpace,
+                        '#' => FormatFlags::AltMode,
+                        '0' => FormatFlags::PadZero,
+                        _ => FormatFlags::empty(),
+                    }
+                })
+            } else { FormatFlags::empty() };
+
+            let width = if let Some(m_padding) = c.name("width") {
+                m_padding.as_str().parse::<usize>().ok()
+            } else {
+                None
+            };
+
+            let precision = if let Some(m_padding) = c.name("precision") {
+                m_padding.as_str().parse::<usize>().ok()
+            } else {
+                None
+            };
+
+            let length = if let Some(m_length) = c.name("length") {
+                match m_length.as_str() {
+                    "hh" => LengthModifier::Quarter,
+                    "h" => LengthModifier::Half,
+                    "l" => LengthModifier::Double,
+                    "ll" | "L" => LengthModifier::Quadruple,
+                    "j" => LengthModifier::IntMax,
+                    "z" => LengthModifier::Size,
+                    "t" => LengthModifier::PointerOffset,
+                    _ => LengthModifier::Full,
+                }
+            } else {
+                LengthModifier::Full
+            };
+
+            let specifier = c.name("specifier").unwrap().as_str();
+
+            match specifier {
+                "c" => obj.parsed.push(ConversionSegment::Character { flags: ff_flags, padding: width }),
+                "s" => obj.parsed.push(ConversionSegment::String { flags: ff_flags, padding: width, limit: precision }),
+                "d" | "i" | "o" | "x" | "X" | "u" => obj.parsed.push(ConversionSegment::Integer {
+                    format: NumericalFormat {
+                        padding: width.unwrap_or(0usize),
+                        precision,
+                        length,
+                        flags: ff_flags | if specifier.to_uppercase() == specifier {
+                            FormatFlags::Capital
+                        } else {
+                            FormatFlags::empty()
+                        },
+                    },
+                    type_: match specifier {
+                        "d" | "i" => IntegerType::SignedDecimal,
+                        "o" => IntegerType::Octal,
+                        "x" | "X" => IntegerType::Hexadecimal,
+                        "u" => IntegerType::UnsignedDecimal,
+                        _ => panic!(),
+                    },
+                }),
+                "f" | "F" | "e" | "E" | "a" | "A" | "g" | "G" => obj.parsed.push(ConversionSegment::Float {
+                    format: NumericalFormat {
+                        padding: width.unwrap_or(0usize),
+                        precision,
+                        length,
+                        flags: ff_flags | if specifier.to_uppercase() == specifier {
+                            FormatFlags::Capital
+                        } else {
+                            FormatFlags::empty()
+                        },
+                    },
+                    type_: match specifier {
+                        "f" | "F" => FloatType::Normal,
+                        "e" | "E" => FloatType::DecimalExponent,
+                        "a" | "A" => FloatType::HexadecimalExponent,
+                        "g" | "G" => FloatType::AutoExponent,
+                        _ => panic!(),
+                    },
+                }),
+                "p" => obj.parsed.push(ConversionSegment::Pointer { flags: ff_flags, padding: width }),
+                "n" => obj.parsed.push(ConversionSegment::Count { length }),
+                _ => {
+                    warn!("Unhandled specifier {specifier}");
+                }
+            }
+        }
+        if literal_offset < obj.raw.len() {
+            obj.parsed.push(ConversionSegment::Literal { start: literal_offset, end: obj.raw.len() });
+        }
+        obj 
+    }
+}
+
+#[test]
+fn test() {
+    let s = String::from("Hello %01.2d%02X world!");
+    let fmt = FormatString::from(s);
+    println!("{fmt:?}");
+}
+
+#[test]
+fn test_format_integer() {
+    let format = |fmt: &str, value: i64| {
+        let fmt = FormatString::from(fmt.to_owned());
+        let Some(ConversionSegment::Integer { format, type_ }) = fmt.parsed.first() else {
+            panic!("Not an integer conversion");
+        };
+        format_integer(value.unsigned_abs(), value < 0, format, type_)
+    };
+
+    assert_eq!(format("%05d", 42), "00042");
+    assert_eq!(format("%05d", -42), "-0042");
+    assert_eq!(format("%-8x|", 255), "ff      ");
+    assert_eq!(format("%+d", 5), "+5");
+    assert_eq!(format("% d", 5), " 5");
+    assert_eq!(format("%8.3d", -7), "    -007");
+    assert_eq!(format("%08.3d", 7), "     007");
+    assert_eq!(format("%.0d", 0), "");
+    assert_eq!(format("%#06X", 0xab), "0X00AB");
+    assert_eq!(format("%-+5d|", 3), "+3   ");
+    assert_eq!(format("%o", 8), "10");
+    assert_eq!(format("%#o", 0o755), "0755");
+    assert_eq!(format("%#o", 0), "0");
+    assert_eq!(format("%#.0o", 0), "0");
+    assert_eq!(format("%#6.4o", 8), "  0010");
+    assert_eq!(format("%-#6o|", 8), "010   ");
+}
+
+#[test]
+fn test_pointer_and_count() {
+    let fmt = FormatString::from(String::from("%p %hhn%n%lln"));
+    let widths: Vec<_> = fmt.parsed.iter().filter_map(|conv| match conv {
+        ConversionSegment::Count { length } => Some(integer_bits(length) / 8),
+        _ => None,
+    }).collect();
+    assert!(matches!(fmt.parsed[0], ConversionSegment::Pointer { .. }));
+    assert_eq!(widths, vec![1, 4, 8]);
+}
+
+#[test]
+fn test_length_modifiers() {
+    let fmt = FormatString::from(String::from("%zu %jd %td %hhd %lld"));
+    let bits: Vec<_> = fmt.parsed.iter().filter_map(|conv| match conv {
+        ConversionSegment::Integer { format, .. } => Some(integer_bits(&format.length)),
+        _ => None,
+    }).collect();
+    assert_eq!(bits, vec![32, 64, 32, 8, 64]);
+
+    assert_eq!(sign_magnitude(0xffffffff, 32, false), (false, 0xffffffff));
+    assert_eq!(sign_magnitude(0xffffffff, 32, true), (true, 1));
+    assert_eq!(sign_magnitude(u64::MAX - 41, 64, true), (true, 42));
+    assert_eq!(sign_magnitude(0x180, 8, true), (true, 128));
+}
+
+#[test]
+fn test_format_float() {
+    let format = |fmt: &str, value: f64| {
+        let fmt = FormatString::from(fmt.to_owned());
+        let Some(ConversionSegment::Float { format, type_ }) = fmt.parsed.first() else {
+            panic!("Not a float conversion");
+        };
+        format_float(value, format, type_)
+    };
+
+    assert_eq!(format("%f", 1.23456), "1.234560");
+    assert_eq!(format("%.2f", 2.675), "2.67");
+    assert_eq!(format("%#.0f", 2.0), "2.");
+    assert_eq!(format("%08.2f", -3.5), "-0003.50");
+    assert_eq!(format("%e", 12345.678), "1.234568e+04");
+    assert_eq!(format("%+.0e", 0.0), "+0e+00");
+    assert_eq!(format("%E", 1.5e-10), "1.500000E-10");
+    assert_eq!(format("%g", 100000.0), "100000");
+    assert_eq!(format("%g", 123456789.0), "1.23457e+08");
+    assert_eq!(format("%g", 1e-5), "1e-05");
+    assert_eq!(format("%G", 0.0001), "0.0001");
+    assert_eq!(format("%#g", 1.0), "1.00000");
+    assert_eq!(format("%a", 1.0), "0x1p+0");
+    assert_eq!(format("%.1a", 1.0), "0x1.0p+0");
+    assert_eq!(format("%A", -0.5), "-0X1P-1");
+    assert_eq!(format("%a", 3.0), "0x1.8p+1");
+    assert_eq!(format("%F", f64::INFINITY), "INF");
+    assert_eq!(format("%05f", f64::NEG_INFINITY), " -inf");
+    assert_eq!(format("%5f", f64::NAN), "  nan");
+}
+
+/// Width in bits of an integer argument with the given length modifier.
+fn integer_bits(length: &LengthModifier) -> u32 {
+    match length {
+        LengthModifier::Quarter => 8,
+        LengthModifier::Half => 16,
+        LengthModifier::Full | LengthModifier::Double | LengthModifier::Size | LengthModifier::PointerOffset => 32,
+        LengthModifier::Quadruple | LengthModifier::IntMax => 64,
+    }
+}
+
+/// Split a `bits` wide integer into its sign and magnitude.
+fn sign_magnitude(raw: u64, bits: u32, signed: bool) -> (bool, u64) {
+    if signed {
+        let shift = 64 - bits;
+        let value = ((raw << shift) as i64) >> shift;
+        (value < 0, value.unsigned_abs())
+    } else {
+        (false, raw & (u64::MAX >> (64 - bits)))
+    }
+}
+
+/// Read a 64-bit argument. It takes an even-aligned pair of argument words, which hold its halves in guest byte order.
+fn read_u64(uc: &UnicornContext, offset: &mut u64) -> Result<u64, RuntimeError> {
+    *offset += *offset % 2;
+    let first: u64 = get_arg_at(uc, *offset)?.into();
+    let second: u64 = get_arg_at(uc, *offset + 1)?.into();
+    *offset += 2;
+    Ok(match uc.get_data().endian {
+        Endian::Little => second << 32 | first,
+        Endian::Big => first << 32 | second,
+    })
+}
+
+/// Read an integer argument of the given length. Returns the sign and the magnitude of the value. 64-bit arguments
+/// start at an even position, like doubles.
+fn read_integer(uc: &UnicornContext, offset: &mut u64, length: &LengthModifier, signed: bool) -> Result<(bool, u64), RuntimeError> {
+    let bits = integer_bits(length);
+    let raw = if bits == 64 {
+        read_u64(uc, offset)?
+    } else {
+        let a: u64 = get_arg_at(uc, *offset)?.into();
+        *offset += 1;
+        a
+    };
+
+    Ok(sign_magnitude(raw, bits, signed))
+}
+
+/// Pad `s` with spaces to at least `width` characters.
+fn justify(s: String, width: usize, left: bool) -> String {
+    if left {
+        format!("{s:<width$}")
+    } else {
+        format!("{s:>width$}")
+    }
+}
+
+/// Format an integer the same way C printf does, given its sign and magnitude.
+fn format_integer(value: u64, negative: bool, format: &NumericalFormat, type_: &IntegerType) -> String {
+    let flags = &format.flags;
+    let mut digits = match type_ {
+        IntegerType::SignedDecimal | IntegerType::UnsignedDecimal => value.to_string(),
+        IntegerType::Hexadecimal if flags.contains(FormatFlags::Capital) => format!("{value:X}"),
+        IntegerType::Hexadecimal => format!("{value:x}"),
+        IntegerType::Octal => format!("{value:o}"),
+    };
+
+    // Precision is the minimum number of digits. A zero value with zero precision prints no digits at all.
+    match format.precision {
+        Some(0) if value == 0 => digits.clear(),
+        Some(precision) if digits.len() < precision => digits.insert_str(0, &"0".repeat(precision - digits.len())),
+        _ => (),
+    }
+
+    // Alt mode for octal raises the precision just enough for the first digit to be a zero.
+    if matches!(type_, IntegerType::Octal) && flags.contains(FormatFlags::AltMode) && !digits.starts_with('0') {
+        digits.insert(0, '0');
+    }
+
+    let prefix = match type_ {
+        IntegerType::SignedDecimal if negative => "-",
+        IntegerType::SignedDecimal if flags.contains(FormatFlags::AlwaysSign) => "+",
+        IntegerType::SignedDecimal if flags.contains(FormatFlags::PadSpace) => " ",
+        IntegerType::Hexadecimal if flags.contains(FormatFlags::AltMode) && value != 0 => {
+            if flags.contains(FormatFlags::Capital) { "0X" } else { "0x" }
+        },
+        _ => "",
+    };
+
+    // Zero padding is ignored for integers when a precision is given.
+    let zero_pad = flags.contains(FormatFlags::PadZero) && format.precision.is_none();
+    pad_number(prefix, &digits, format.padding, flags, zero_pad)
+}
+
+/// Pad a formatted number to `width`. Zero padding goes between the prefix and the digits, and is ignored when
+/// left-justified.
+fn pad_number(prefix: &str, body: &str, width: usize, flags: &FormatFlags, zero_pad: bool) -> String {
+    let left = flags.contains(FormatFlags::LeftJustified);
+    let len = prefix.len() + body.len();
+    if zero_pad && !left && len < width {
+        format!("{prefix}{}{body}", "0".repeat(width - len))
+    } else {
+        justify(format!("{prefix}{body}"), width, left)
+    }
+}
+
+/// Read a floating point argument. Variadic floats are always promoted to double and passed in a pair of core
+/// registers or stack words starting at an even position, even for hard-float callers, so there is no need to look at
+/// the VFP registers.
+fn read_double(uc: &UnicornContext, offset: &mut u64) -> Result<f64, RuntimeError> {
+    Ok(f64::from_bits(read_u64(uc, offset)?))
+}
+
+/// `%f` of a non-negative finite value.
+fn format_fixed(value: f64, precision: usize, alt: bool) -> String {
+    let mut s = format!("{value:.precision$}");
+    if alt && precision == 0 {
+        s.push('.');
+    }
+    s
+}
+
+/// `%e` of a non-negative finite value.
+fn format_exponent(value: f64, precision: usize, alt: bool) -> String {
+    let s = format!("{value:.precision$e}");
+    let (mantissa, exponent) = s.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+    let point = if alt && precision == 0 { "." } else { "" };
+    format!("{mantissa}{point}e{}{:02}", if exponent < 0 { '-' } else { '+' }, exponent.unsigned_abs())
+}
+
+/// `%g` of a non-negative finite value.
+fn format_general(value: f64, precision: usize, alt: bool) -> String {
+    let precision = precision.max(1);
+    let exponent: i32 = if value == 0.0 {
+        0
+    } else {
+        let s = format!("{value:.0$e}", precision - 1);
+        s.split_once('e').unwrap().1.parse().unwrap()
+    };
+
+    let s = if exponent >= -4 && exponent < precision as i32 {
+        format_fixed(value, (precision as i32 - 1 - exponent) as usize, alt)
+    } else {
+        format_exponent(value, precision - 1, alt)
+    };
+
+    if alt {
+        return s;
+    }
+
+    // Trailing zeros of the fraction are dropped unless in alt mode.
+    let (mantissa, exponent) = match s.find('e') {
+        Some(pos) => s.split_at(pos),
+        None => (s.as_str(), ""),
+    };
+    if mantissa.contains('.') {
+        format!("{}{exponent}", mantissa.trim_end_matches('0').trim_end_matches('.'))
+    } else {
+        s
+    }
+}
+
+/// `%a` of a non-negative finite value, without the `0x` prefix.
+fn format_hex_float(value: f64, precision: Option<usize>, alt: bool) -> String {
+    const MANTISSA_DIGITS: usize = 13;
+    let bits = value.to_bits();
+    let biased_exponent = ((bits >> 52) & 0x7ff) as i32;
+    let mantissa = bits & ((1 << 52) - 1);
+    let (mut lead, exponent) = match (biased_exponent, mantissa) {
+        (0, 0) => (0u64, 0),
+        (0, _) => (0, -1022),
+        _ => (1, biased_exponent - 1023),
+    };
+
+    let digits = match precision {
+        Some(precision) if precision < MANTISSA_DIGITS => {
+            let shift = 4 * (MANTISSA_DIGITS - precision);
+            let rounded = (mantissa + ((1u64 << shift) >> 1)) >> shift;
+            let width = 4 * precision;
+            if rounded >> width != 0 {
+                lead += 1;
+            }
+            let fraction = rounded & ((1u64 << width) - 1);
+            if precision == 0 { String::new() } else { format!("{fraction:0precision$x}") }
+        },
+        Some(precision) => format!("{mantissa:013x}{}", "0".repeat(precision - MANTISSA_DIGITS)),
+        None => format!("{mantissa:013x}").trim_end_matches('0').to_owned(),
+    };
+
+    let point = if alt || !digits.is_empty() { "." } else { "" };
+    format!("{lead}{point}{digits}p{}{}", if exponent < 0 { '-' } else { '+' }, exponent.unsigned_abs())
+}
+
+/// Format a floating point value the same way C printf does.
+fn format_float(value: f64, format: &NumericalFormat, type_: &FloatType) -> String {
+    let flags = &format.flags;
+    let alt = flags.contains(FormatFlags::AltMode);
+    let precision = format.precision.unwrap_or(6);
+    let magnitude = value.abs();
+
+    let mut prefix = String::from(if value.is_sign_negative() {
+        "-"
+    } else if flags.contains(FormatFlags::AlwaysSign) {
+        "+"
+    } else if flags.contains(FormatFlags::PadSpace) {
+        " "
+    } else {
+        ""
+    });
+
+    let body = if value.is_nan() {
+        String::from("nan")
+    } else if value.is_infinite() {
+        String::from("inf")
+    } else {
+        match type_ {
+            FloatType::Normal => format_fixed(magnitude, precision, alt),
+            FloatType::DecimalExponent => format_exponent(magnitude, precision, alt),
+            FloatType::AutoExponent => format_general(magnitude, precision, alt),
+            FloatType::HexadecimalExponent => {
+                prefix.push_str("0x");
+                format_hex_float(magnitude, format.precision, alt)
+            },
+        }
+    };
+
+    let (prefix, body) = if flags.contains(FormatFlags::Capital) {
+        (prefix.to_uppercase(), body.to_uppercase())
+    } else {
+        (prefix, body)
+    };
+
+    // Zero padding does not apply to inf and nan.
+    let zero_pad = flags.contains(FormatFlags::PadZero) && value.is_finite();
+    pad_number(&prefix, &body, format.padding, flags, zero_pad)
+}
+
+fn printf(uc: &mut UnicornContext) -> Result<(), RuntimeError> {
+    let fmt_offset = uc.reg_read(RegisterARM::R0)?;
+
+    let mut out = String::new();
+    let fmt = read_cstr(uc, fmt_offset, MAX_CSTR_LEN)?;
+    let fmt_obj = FormatString::from(fmt);
+    let mut offset = 1u64;
+    for conv in fmt_obj.parsed.iter() {
+        match conv {
+            ConversionSegment::Literal { start, end } => {
+                write!(&mut out, "{}", &fmt_obj.raw[*start..*end])?;
+            },
+            ConversionSegment::Escape => {
+                write!(&mut out, "%")?;
+            },
+            ConversionSegment::Character { flags, padding } => {
+                let arg = get_arg_at(uc, offset)?;
+                offset += 1;
+                let c = char::from_u32(arg & 0xff).unwrap().to_string();
+                write!(&mut out, "{}", justify(c, padding.unwrap_or(0), flags.contains(FormatFlags::LeftJustified)))?;
+            },
+            ConversionSegment::String { flags, padding, limit } => {
+                let arg = get_arg_at(uc, offset)?;
+                offset += 1;
+                let s = read_cstr(uc, arg.into(), limit.unwrap_or(MAX_CSTR_LEN))?;
+                write!(&mut out, "{}", justify(s, padding.unwrap_or(0), flags.contains(FormatFlags::LeftJustified)))?;
+            },
+            ConversionSegment::Integer { format, type_ } => {
+                let signed = matches!(type_, IntegerType::SignedDecimal);
+                let (negative, value) = read_integer(uc, &mut offset, &format.length, signed)?;
+                write!(&mut out, "{}", format_integer(value, negative, format, type_))?;
+            },
+            ConversionSegment::Float { format, type_ } => {
+                let value = read_double(uc, &mut offset)?;
+                write!(&mut out, "{}", format_float(value, format, type_))?;
+            },
+            ConversionSegment::Pointer { flags, padding } => {
+                let arg = get_arg_at(uc, offset)?;
+                offset += 1;
+                write!(&mut out, "{}", justify(format!("0x{arg:08x}"), padding.unwrap_or(0), flags.contains(FormatFlags::LeftJustified)))?;
+            },
+            ConversionSegment::Count { length } => {
+                let arg = get_arg_at(uc, offset)?;
+                offset += 1;
+                let mut bytes = vec![0u8; integer_bits(length) as usize / 8];
+                uc.get_data().endian.pack(out.len() as u64, &mut bytes);
+                mmu::write_virtual(uc, arg.into(), &bytes)?;
+            },
+        }
+    }
+    info!(target: NAME_PRINTF, "{}", &out.trim());
+    Ok(())
+}
+
+pub fn printf_callback(uc: &mut UnicornContext, _addr: u64, _size: u32) {
+    printf(uc).unwrap_or_else(|err| {
+        let lr = uc.reg_read(RegisterARM::LR).unwrap();
+        error!("Failed to execute printf at 0x{lr:08x}: {err:?}");
+        request_quit(uc, QuitDetail::HLECallbackFailure);
+    })
+}
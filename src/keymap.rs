@@ -0,0 +1,86 @@
+use winit::keyboard::KeyCode;
+
+use crate::extdev::input::{parse_key_type, KeyType};
+
+/// Host keys that can be bound to device buttons.
+const BINDABLE_KEYS: &[KeyCode] = &[
+    KeyCode::ArrowUp, KeyCode::ArrowDown, KeyCode::ArrowLeft, KeyCode::ArrowRight,
+    KeyCode::Enter, KeyCode::Escape, KeyCode::Backspace, KeyCode::Space, KeyCode::Tab,
+    KeyCode::Home, KeyCode::End, KeyCode::PageUp, KeyCode::PageDown, KeyCode::Insert, KeyCode::Delete,
+    KeyCode::Minus, KeyCode::Equal,
+    KeyCode::KeyA, KeyCode::KeyB, KeyCode::KeyC, KeyCode::KeyD, KeyCode::KeyE, KeyCode::KeyF, KeyCode::KeyG,
+    KeyCode::KeyH, KeyCode::KeyI, KeyCode::KeyJ, KeyCode::KeyK, KeyCode::KeyL, KeyCode::KeyM, KeyCode::KeyN,
+    KeyCode::KeyO, KeyCode::KeyP, KeyCode::KeyQ, KeyCode::KeyR, KeyCode::KeyS, KeyCode::KeyT, KeyCode::KeyU,
+    KeyCode::KeyV, KeyCode::KeyW, KeyCode::KeyX, KeyCode::KeyY, KeyCode::KeyZ,
+    KeyCode::Digit0, KeyCode::Digit1, KeyCode::Digit2, KeyCode::Digit3, KeyCode::Digit4,
+    KeyCode::Digit5, KeyCode::Digit6, KeyCode::Digit7, KeyCode::Digit8, KeyCode::Digit9,
+    KeyCode::F1, KeyCode::F2, KeyCode::F3, KeyCode::F4, KeyCode::F5, KeyCode::F6, KeyCode::F7, KeyCode::F8,
+    KeyCode::F10, KeyCode::F11, KeyCode::F12,
+];
+
+/// Mapping from host keys to device buttons.
+pub struct Keymap {
+    bindings: Vec<(KeyCode, KeyType)>,
+}
+
+/// Built-in bindings from host keys to the board buttons, overridden by the bindings given on the command line.
+pub const DEFAULT_BINDINGS: &[(KeyCode, &str)] = &[
+    (KeyCode::Home, "Home"),
+    (KeyCode::Backspace, "Home"),
+    (KeyCode::Escape, "Power"),
+    (KeyCode::End, "Power"),
+];
+
+impl Default for Keymap {
+    fn default() -> Self {
+        let bindings = DEFAULT_BINDINGS.iter()
+            .map(|(code, button)| (*code, parse_key_type(button).unwrap()))
+            .collect();
+        Self { bindings }
+    }
+}
+
+impl Keymap {
+    /// Bind a host key to a device button, replacing the existing binding of that key.
+    pub fn bind(&mut self, code: KeyCode, key: KeyType) {
+        self.bindings.retain(|(c, _)| *c != code);
+        self.bindings.push((code, key));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(KeyCode, KeyType)> {
+        self.bindings.iter()
+    }
+}
+
+fn parse_key_code(name: &str) -> Option<KeyCode> {
+    BINDABLE_KEYS.iter().copied().find(|code| format!("{code:?}").eq_ignore_ascii_case(name))
+}
+
+/// Parse a `<host key>=<device button>` binding from the command line.
+pub fn parse_binding(binding: &str) -> Result<(KeyCode, KeyType), String> {
+    let (code, key) = binding.split_once('=').ok_or_else(|| format!("Expecting <host key>=<device button>, got {binding}"))?;
+    let code = parse_key_code(code.trim()).ok_or_else(|| format!("Unknown or unbindable host key {code}"))?;
+    let key = parse_key_type(key.trim()).ok_or_else(|| format!("Unknown device button {key}"))?;
+    Ok((code, key))
+}
+
+#[test]
+fn test_parse_binding() {
+    assert_eq!(parse_binding("ArrowUp=GPB5"), Ok((KeyCode::ArrowUp, KeyType::Gpio { port: 1, pin: 5 })));
+    assert_eq!(parse_binding("enter=home"), Ok((KeyCode::Enter, KeyType::Home)));
+    assert!(parse_binding("ArrowUp=GPF0").is_err());
+    assert!(parse_binding("ArrowUp=GPA16").is_err());
+    assert!(parse_binding("F9=Power").is_err());
+}
+
+#[test]
+fn test_default_keymap() {
+    let keymap = Keymap::default();
+    assert_eq!(keymap.iter().count(), DEFAULT_BINDINGS.len());
+    assert!(keymap.iter().any(|binding| *binding == (KeyCode::Escape, KeyType::Power)));
+
+    let mut keymap = Keymap::default();
+    keymap.bind(KeyCode::Escape, KeyType::Home);
+    assert!(keymap.iter().any(|binding| *binding == (KeyCode::Escape, KeyType::Home)));
+    assert_eq!(keymap.iter().count(), DEFAULT_BINDINGS.len());
+}
@@ -1,3 +1,6 @@
+// Peripheral, register and exception names follow the datasheet and the ARM ARM.
+#![allow(clippy::upper_case_acronyms)]
+
 /// MMIO peripheral emulation routines.
 mod peripherals;
 /// External device emulation routines.
@@ -9,35 +12,50 @@ mod extdev;
 mod device;
 /// CPU exception handling.
 mod exception;
-
+/// High-level emulation of guest functions.
 mod hle;
+/// Guest MMU helpers.
+mod mmu;
+/// Guest memory layout.
+mod memmap;
+/// GDB remote debugging support.
+mod gdb;
+/// Emulator state save and restore.
+mod snapshot;
+/// MMIO and instruction tracing.
+mod trace;
+/// Frame output targets.
+mod render;
+/// Host key to device button mapping.
+mod keymap;
+/// Memory watchpoints.
+mod watch;
 
 use std::fs::File;
 use std::io;
 use std::io::Read;
 use std::io::Seek;
 use std::io::SeekFrom;
-use std::os::raw::c_void;
 use std::string::FromUtf8Error;
 use std::fmt::Error as FormatError;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
 
-use log::LevelFilter;
-use log::debug;
 use log::error;
 use log::info;
+use log::trace;
 use pixels::Pixels;
 use pixels::SurfaceTexture;
 use unicorn_engine::ArmCpuModel;
 use unicorn_engine::HookType;
-use unicorn_engine::Permission;
+use unicorn_engine::RegisterARM;
 use unicorn_engine::TlbType;
 use unicorn_engine::Unicorn;
 use unicorn_engine::Arch;
-use unicorn_engine::Mode;
 use unicorn_engine::uc_error;
 
 use clap::Parser;
-use env_logger;
 
 use device::Device;
 use peripherals::{sic, sys, gpio};
@@ -46,21 +64,38 @@ use winit::event::WindowEvent;
 use winit::keyboard::KeyCode;
 
 use crate::device::ExtraState;
+use crate::device::QuitDetail;
+use crate::device::StopReason;
+use crate::device::request_stop;
 use crate::device::UnicornContext;
-use crate::exception::dump_data;
-use crate::extdev::input::KeyType;
-use crate::extdev::sd::{CID_ESD, CID_XSD};
+use crate::exception::{ExceptionAction, ExceptionType, dump_data};
+use crate::gdb::{GdbAction, GdbStub};
+use crate::extdev::camera::Camera;
+use crate::extdev::eeprom::EEPROM;
+use crate::extdev::input::{self, KeyType};
+use crate::keymap::Keymap;
+use crate::memmap::{Endian, MemoryMap};
+use crate::mmu::CP15Register;
+use crate::render::{FrameBuffer, FrameSink};
+use crate::extdev::sd::SD;
+use crate::extdev::spi_flash::SPIFlash;
 use crate::peripherals::adc;
 use crate::peripherals::aic;
 use crate::peripherals::blt;
-use crate::peripherals::common::mmio_set_store_only;
+use crate::peripherals::des;
+use crate::peripherals::edma;
+use crate::peripherals::common::{MmioRead, MmioWrite};
+use crate::peripherals::i2c;
 use crate::peripherals::i2s;
+use crate::peripherals::jpg;
 use crate::peripherals::pwm;
 use crate::peripherals::rtc;
+use crate::peripherals::spi;
 use crate::peripherals::sdram;
 use crate::peripherals::spu;
 use crate::peripherals::tmr;
 use crate::peripherals::uart;
+use crate::peripherals::videoin;
 use crate::peripherals::vpost;
 
 use winit::{dpi::LogicalSize, event_loop::EventLoop, window::WindowBuilder};
@@ -75,8 +110,21 @@ pub enum RuntimeError {
     LoaderInvalidMagic,
     SDAlreadyMounted,
     SDNotMounted,
+    SDImageEmpty,
+    /// SD image size is not a multiple of 512KiB.
+    SDImageUnaligned,
+    /// SD image is larger than the 2TiB an SDHC CSD can describe.
+    SDImageTooLarge,
+    NANDAlreadyMounted,
+    NANDInvalidImage,
+    SPIFlashAlreadyMounted,
+    SPIFlashInvalidImage,
+    EEPROMInvalidImage,
+    /// I2C address is out of range or taken by another slave.
+    I2CAddressUnavailable(u8),
     FromUtf8Error(FromUtf8Error),
     FormatError(FormatError),
+    SnapshotInvalid,
 }
 
 impl From<io::Error> for RuntimeError {
@@ -113,15 +161,228 @@ struct Args {
     #[arg(long)]
     esd: String,
 
+    /// Mount the embedded SD card image as write-protected.
+    #[arg(long)]
+    esd_readonly: bool,
+
     /// External SD card image.
     #[arg(long, required = false)]
     xsd: Option<String>,
+
+    /// Mount the external SD card image as write-protected.
+    #[arg(long)]
+    xsd_readonly: bool,
+
+    /// Number of SD controller ticks a card stays busy after a write or erase. 0 makes cards ready immediately.
+    #[arg(long, default_value_t = 8)]
+    sd_busy_ticks: u32,
+
+    /// Amount of written SD card data kept in memory before it's written back to the image, e.g. `4M`. 0 writes
+    /// through.
+    #[arg(long, value_parser = memmap::parse_size, default_value = "4M")]
+    sd_write_cache: usize,
+
+    /// Raw NAND flash image attached to the FMI NAND interface. Each 2KiB page is followed by its 64 byte spare area.
+    #[arg(long)]
+    nand: Option<String>,
+
+    /// SPI NOR flash image attached to an SPI master. The image size must be a power of 2 between 64KiB and 16MiB.
+    #[arg(long)]
+    spi_flash: Option<String>,
+
+    /// SPI master port and chip select of the SPI flash, as `<port>:<cs>`.
+    #[arg(long, value_parser = spi::parse_spi_slot, default_value = "0:0")]
+    spi_flash_cs: (usize, usize),
+
+    /// 24Cxx EEPROM image attached to the I2C bus. The part is picked from the image size, from 128 bytes (24C01) to
+    /// 64KiB (24C512).
+    #[arg(long)]
+    i2c_eeprom: Option<String>,
+
+    /// I2C address of the EEPROM. Parts up to 24C16 also take the following addresses, one per 256 byte block.
+    #[arg(long, value_parser = i2c::parse_i2c_address, default_value = "0x50")]
+    i2c_eeprom_addr: u8,
+
+    /// Raw YUYV frames fed into the video capture engine, at the size the guest captures. Played in a loop. The
+    /// camera shows color bars without it.
+    #[arg(long)]
+    camera: Option<String>,
+
+    /// Emulate CRC checksums on SD card responses and data blocks.
+    #[arg(long)]
+    sd_crc: bool,
+
+    /// Feed the content of a file into UART0 as received data.
+    #[arg(long)]
+    uart_input: Option<String>,
+
+    /// Feed the content of a file into UART1 as received data.
+    #[arg(long)]
+    uart1_input: Option<String>,
+
+    /// Speed of the guest clock relative to the host clock. 0 stops it. Defaults to 1, or 0 with `--frozen-time`.
+    #[arg(long, value_parser = rtc::parse_time_scale)]
+    time_scale: Option<f64>,
+
+    /// Start the guest clock at this local time, as `YYYY-MM-DD HH:MM:SS`, for reproducible timestamps. The clock
+    /// stands still unless `--time-scale` is also given.
+    #[arg(long, value_parser = rtc::parse_local_time)]
+    frozen_time: Option<SystemTime>,
+
+    /// Play a sine wave of this frequency in Hz into the microphone.
+    #[arg(long, default_value_t = 0)]
+    mic_tone: u32,
+
+    /// Feed a raw 16-bit little endian mono PCM file into the microphone, one sample per conversion. The tone set by
+    /// `--mic-tone` plays after the end of the file.
+    #[arg(long)]
+    mic_input: Option<String>,
+
+    /// Raw touchscreen readings at the panel edges, as `x_min,x_max,y_min,y_max`. X goes from left to right and Y
+    /// goes from bottom to top.
+    #[arg(long)]
+    touch_calibration: Option<adc::TouchCalibration>,
+
+    /// Maximum number of pending touch updates. When the guest polls slower than the pointer moves, the oldest
+    /// intermediate drag positions are dropped first, then the oldest strokes.
+    #[arg(long, default_value_t = input::DEFAULT_TOUCH_QUEUE_LEN)]
+    touch_queue_len: usize,
+
+    /// Bind a host key to a device button, e.g. `ArrowUp=GPB5`. Buttons are the board buttons `Home` and `Power`, or an
+    /// active-low GPIO pin `GP<port><pin>`. Can be repeated. Home and Backspace are bound to `Home`, and Escape and End
+    /// to `Power` by default.
+    #[arg(long = "bind", value_parser = keymap::parse_binding)]
+    bindings: Vec<(KeyCode, KeyType)>,
+
+    /// Replay timed touch and button events from this file. Each line is `<frame> <event>`, with events `move <x> <y>`,
+    /// `lift`, `press <button>` and `release <button>`. Frames are counted from emulator start.
+    #[arg(long)]
+    input_script: Option<String>,
+
+    /// Symbol map for HLE hooks, with one `<address> <handler>` pair per line, e.g. `0x800053e0 printf`. Defaults to
+    /// the known printf location. Accesses to unmapped memory are logged with symbols and a backtrace if given.
+    #[arg(long)]
+    hle_map: Option<String>,
+
+    /// Wait for a GDB connection on this TCP port before starting the guest.
+    #[arg(long)]
+    gdb: Option<u16>,
+
+    /// Restore the emulator state from a snapshot instead of booting. The SD card and NAND images must be the same
+    /// ones used when the snapshot was taken.
+    #[arg(long)]
+    restore: Option<String>,
+
+    /// Save the emulator state to this file on exit.
+    #[arg(long)]
+    snapshot_on_exit: Option<String>,
+
+    /// Record every MMIO access to this file, as one JSON object per line.
+    #[arg(long)]
+    trace: Option<String>,
+
+    /// Also record every executed instruction in the trace. Slows down emulation considerably.
+    #[arg(long, requires = "trace")]
+    trace_instructions: bool,
+
+    /// Log the peripheral registers the guest accessed on exit, with access counts and whether they are emulated.
+    #[arg(long)]
+    dump_mmio_map: bool,
+
+    /// Maximum number of CPU cycles to run before returning to the device loop, even if no peripheral needs
+    /// attention. 0 means unlimited.
+    #[arg(long, default_value_t = 1_000_000)]
+    max_slice: u64,
+
+    /// Average CPU cycles taken by one instruction. Peripheral timers and the frame rate follow the configured CPU
+    /// clock at this rate.
+    #[arg(long, default_value_t = 1.5)]
+    cpi: f64,
+
+    /// SDRAM size in bytes, with an optional `K` or `M` suffix. Devices ship with 16M, 32M or 64M.
+    #[arg(long, value_parser = memmap::parse_size, default_value = "32M")]
+    sdram_size: usize,
+
+    /// Byte order of the guest, `little` or `big`. Also applies to the SD boot header.
+    #[arg(long, value_parser = memmap::parse_endian, default_value = "little")]
+    endian: Endian,
+
+    /// Dumped N329x boot ROM image. It is mapped at the high exception vector base (0xffff0000) and booted from
+    /// natively instead of running the HLE boot ROM. Exceptions are delivered to the vectors in the ROM.
+    #[arg(long)]
+    bootrom: Option<String>,
+
+    /// Directory to write crash reports and memory dumps to.
+    #[arg(long, default_value = ".")]
+    crash_dir: PathBuf,
+
+    /// Run without a window. Frames are rendered into memory.
+    #[arg(long)]
+    headless: bool,
+
+    /// Quit after this many frames. 0 means unlimited. Only applies to headless mode.
+    #[arg(long, default_value_t = 0, requires = "headless")]
+    frame_limit: u64,
+
+    /// Save the last frame as a PNG file on exit. Only applies to headless mode.
+    #[arg(long, requires = "headless")]
+    dump_frame: Option<String>,
+
+    /// Deliver accesses to unmapped memory to the guest's abort handlers instead of stopping emulation. Only useful
+    /// for firmware that handles aborts itself. Same as `--on-exception prefetch=deliver --on-exception data=deliver`.
+    #[arg(long)]
+    recoverable_aborts: bool,
+
+    /// What to do when the guest raises a CPU exception, as `<exception>=<action>`, e.g. `undefined=deliver`.
+    /// Exceptions are `undefined`, `svc`, `prefetch` and `data`. Actions are `deliver` to the guest handler, `skip` the
+    /// faulting instruction or `quit`. Can be repeated. By default SVCs are delivered and everything else quits.
+    #[arg(long = "on-exception", value_parser = exception::parse_policy)]
+    exception_policy: Vec<(ExceptionType, ExceptionAction)>,
+
+    /// Deliver an interrupt source as FIQ, e.g. `TMR0`, on top of the sources the guest routes to FIQ through AIC_SCR.
+    /// Can be repeated.
+    #[arg(long = "fiq-source", value_parser = aic::parse_interrupt_number)]
+    fiq_sources: Vec<aic::InterruptNumber>,
 }
 
+/// SCTLR bit selecting the exception vectors at 0xffff0000.
+const SCTLR_HIGH_VECTORS: u32 = 1 << 13;
+
 #[inline]
-fn read_le_u32(input: &[u8]) -> Result<u32, RuntimeError> {
+fn read_u32(input: &[u8], endian: Endian) -> Result<u32, RuntimeError> {
     let conv = input.try_into().map_err(|_| RuntimeError::LoaderParserFailed)?;
-    Ok(u32::from_le_bytes(conv))
+    Ok(endian.u32_from_bytes(conv))
+}
+
+/// Set the inputs wired on the board, which the bootrom and firmware may probe.
+fn init_board(uc: &mut UnicornContext) {
+    // Home Key - not pressed
+    uc.get_data_mut().gpio.set_input(0, 2, true);
+
+    // VBAT comparator input
+    uc.get_data_mut().gpio.set_input(0, 3, true);
+
+    // PCB Version (3)
+    // TODO: Visually they look unconnected but actually measure these with a multimeter.
+    uc.get_data_mut().gpio.set_input(0, 0, true);
+    uc.get_data_mut().gpio.set_input(0, 7, true);
+}
+
+/// Boot from the boot ROM image mapped by `MemoryMap`, starting from the CPU and clock reset state.
+fn boot_native(uc: &mut UnicornContext) -> Result<(), RuntimeError> {
+    let config_clk = &mut uc.get_data_mut().clk;
+    config_clk.ahbclk.set_cpu(true);
+    config_clk.ahbclk.set_sram(true);
+    config_clk.power_on_reset();
+
+    // SVC mode with interrupts masked, with the high vectors selected by the VINITHI pin.
+    uc.reg_write(RegisterARM::CPSR, 0b11010011)?;
+    let sctlr = CP15Register::Control.read(uc)?;
+    CP15Register::Control.write(uc, sctlr | SCTLR_HIGH_VECTORS)?;
+    uc.set_pc(ExceptionType::Reset.to_vector_address(memmap::ROM_BASE))?;
+
+    info!("Booting from the boot ROM image.");
+    Ok(())
 }
 
 /// Run HLE bootrom.
@@ -132,14 +393,16 @@ fn run_bootrom(uc: &mut UnicornContext, sd_image: &mut File) -> Result<(), Runti
     sd_image.seek(SeekFrom::Start(0x200))?;
     sd_image.read_exact(&mut nvt_sd_boot_header)?;
 
-    let magic = read_le_u32(&nvt_sd_boot_header[0..4])?;
-    let magic_tail = read_le_u32(&nvt_sd_boot_header[12..16])?;
+    // The header is written in the byte order of the firmware it loads.
+    let endian = uc.get_data().endian;
+    let magic = read_u32(&nvt_sd_boot_header[0..4], endian)?;
+    let magic_tail = read_u32(&nvt_sd_boot_header[12..16], endian)?;
     if magic != 0x57425aa5u32 || magic_tail != 0xa55a4257u32 {
         return Err(RuntimeError::LoaderInvalidMagic);
     }
 
-    let load_addr = read_le_u32(&nvt_sd_boot_header[4..8])?;
-    let load_size: usize = usize::try_from(read_le_u32(&nvt_sd_boot_header[8..12])?).map_err(|_| RuntimeError::LoaderParserFailed)?;
+    let load_addr = read_u32(&nvt_sd_boot_header[4..8], endian)?;
+    let load_size: usize = usize::try_from(read_u32(&nvt_sd_boot_header[8..12], endian)?).map_err(|_| RuntimeError::LoaderParserFailed)?;
     info!("bootrom_hle: Loading 0x{load_size:x} bytes of code at 0x{load_addr:08x}...");
 
     let mut code = vec![0u8; load_size];
@@ -151,39 +414,28 @@ fn run_bootrom(uc: &mut UnicornContext, sd_image: &mut File) -> Result<(), Runti
     config_clk.ahbclk.set_cpu(true);
     config_clk.ahbclk.set_sram(true);
 
-    // SDRAM stub
-    mmio_set_store_only(uc, 0xb0003000, 0x00130456);
-    mmio_set_store_only(uc, 0xb0003030, 0x00001010);
-    mmio_set_store_only(uc, 0xb0003010, 0x00000005);
-    mmio_set_store_only(uc, 0xb0003004, 0x00000021);
-    mmio_set_store_only(uc, 0xb0003004, 0x00000023);
-    mmio_set_store_only(uc, 0xb0003004, 0x00000027);
-    mmio_set_store_only(uc, 0xb000301C, 0x00001002);
-    mmio_set_store_only(uc, 0xb0003018, 0x00000122);
-    mmio_set_store_only(uc, 0xb0003004, 0x00000027);
-    mmio_set_store_only(uc, 0xb0003004, 0x0000002B);
-    mmio_set_store_only(uc, 0xb0003004, 0x0000002B);
-    mmio_set_store_only(uc, 0xb0003018, 0x00000022);
-    mmio_set_store_only(uc, 0xb0003004, 0x00000020);
-    mmio_set_store_only(uc, 0xb0003034, 0x00AAAA00);
-    mmio_set_store_only(uc, 0xb0003008, 0x0000805A);
-    mmio_set_store_only(uc, 0xb0003028, 0x094E7425);
-
-    // Home Key - not pressed
-    uc.get_data_mut().gpio.ports[0].data_in.set_p2(true);
-
-    // VBAT comparator input
-    uc.get_data_mut().gpio.ports[0].data_in.set_p3(true);
-
-    // PCB Version (3)
-    // TODO: Visually they look unconnected but actually measure these with a multimeter.
-    uc.get_data_mut().gpio.ports[0].data_in.set_p0(true);
-    uc.get_data_mut().gpio.ports[0].data_in.set_p7(true);
+    // SDRAM controller init sequence
+    sdram::write(uc, 0x00, 4, 0x00130456);
+    sdram::write(uc, 0x30, 4, 0x00001010);
+    sdram::write(uc, 0x10, 4, 0x00000005);
+    sdram::write(uc, 0x04, 4, 0x00000021);
+    sdram::write(uc, 0x04, 4, 0x00000023);
+    sdram::write(uc, 0x04, 4, 0x00000027);
+    sdram::write(uc, 0x1c, 4, 0x00001002);
+    sdram::write(uc, 0x18, 4, 0x00000122);
+    sdram::write(uc, 0x04, 4, 0x00000027);
+    sdram::write(uc, 0x04, 4, 0x0000002B);
+    sdram::write(uc, 0x04, 4, 0x0000002B);
+    sdram::write(uc, 0x18, 4, 0x00000022);
+    sdram::write(uc, 0x04, 4, 0x00000020);
+    sdram::write(uc, 0x34, 4, 0x00AAAA00);
+    sdram::write(uc, 0x08, 4, 0x0000805A);
+    sdram::write(uc, 0x28, 4, 0x094E7425);
 
     // UPLL (192MHz)
     uc.get_data_mut().clk.apll.set_reg(0x0001c02e);
     uc.get_data_mut().clk.upll.set_reg(0x0000447e);
-    uc.get_data_mut().clk.update_tick_config();
+    uc.get_data_mut().clk.power_up_all();
 
     // TODO: Set other initial states
 
@@ -194,73 +446,181 @@ fn run_bootrom(uc: &mut UnicornContext, sd_image: &mut File) -> Result<(), Runti
     Ok(())
 }
 
+/// Map a peripheral's MMIO registers, recording accesses to the trace and the MMIO coverage when enabled and
+/// rescheduling timed events after writes.
+fn map_peripheral(
+    uc: &mut UnicornContext, name: &'static str, base: u64, size: usize, read: MmioRead, write: MmioWrite,
+) -> Result<(), uc_error> {
+    uc.get_data_mut().mmio_coverage.add_peripheral(name, base, size);
+    let traced_read = move |uc: &mut UnicornContext, addr, size| {
+        trace::take_unsupported();
+        let value = read(uc, addr, size);
+        let unsupported = trace::take_unsupported();
+        uc.get_data_mut().mmio_coverage.record(false, base + addr, unsupported);
+        trace::record_mmio(uc, false, base + addr, size, value);
+        value
+    };
+    let traced_write = move |uc: &mut UnicornContext, addr, size, value| {
+        trace::record_mmio(uc, true, base + addr, size, value);
+        trace::take_unsupported();
+        write(uc, addr, size, value);
+        let unsupported = trace::take_unsupported();
+        uc.get_data_mut().mmio_coverage.record(true, base + addr, unsupported);
+        // The write may have started or reconfigured a timed event.
+        device::schedule_next_event(uc);
+    };
+    uc.mmio_map(base, size, Some(traced_read), Some(traced_write))
+}
+
 /// Initialize emulator.
 /// 
-/// This does not populate registers, nor boots from the SD card. These are handled in run_bootrom().
-fn emu_init<'a>() -> Result<UnicornContext<'a>, uc_error> {
+/// This does not populate registers, nor boots from the SD card. These are handled in run_bootrom() or boot_native().
+fn emu_init<'a>(memmap: &MemoryMap) -> Result<UnicornContext<'a>, uc_error> {
     let mut uc = {
         let data = Box::new(ExtraState {
-            raw_sdram: vec![0u8; 0x2000000], ..Default::default()
+            raw_sdram: vec![0u8; memmap.sdram_size], cycles_per_insn: device::CPI_SCALE, endian: memmap.endian,
+            ..Default::default()
         });
-        Unicorn::new_with_data(Arch::ARM, Mode::LITTLE_ENDIAN, data)?
+        Unicorn::new_with_data(Arch::ARM, memmap.endian.mode(), data)?
     };
 
     uc.ctl_set_cpu_model(ArmCpuModel::UC_CPU_ARM_926.into())?;
     uc.ctl_tlb_type(TlbType::CPU)?;
 
     // Stop condition hook
-    uc.add_code_hook(0, 0xffffffff, device::check_stop_condition)?;
+    uc.add_block_hook(0, 0xffffffff, device::check_stop_condition)?;
 
     uc.add_mem_hook(HookType::MEM_INVALID, 0, 0xffffffff, exception::unmapped_access)?;
     uc.add_intr_hook(exception::intr)?;
 
     // MMIO registers
-    uc.mmio_map(sys::BASE, sys::SIZE, Some(sys::read), Some(sys::write))?;
-    uc.mmio_map(sdram::BASE, sdram::SIZE, Some(sdram::read), Some(sdram::write))?;
-    uc.mmio_map(sic::BASE, sic::SIZE, Some(sic::read), Some(sic::write))?;
-    uc.mmio_map(gpio::BASE, gpio::SIZE, Some(gpio::read), Some(gpio::write))?;
-    uc.mmio_map(rtc::BASE, rtc::SIZE, Some(rtc::read), Some(rtc::write))?;
-    uc.mmio_map(uart::BASE, uart::SIZE, Some(uart::read), Some(uart::write))?;
-    uc.mmio_map(tmr::BASE, tmr::SIZE, Some(tmr::read), Some(tmr::write))?;
-    uc.mmio_map(aic::BASE, aic::SIZE, Some(aic::read), Some(aic::write))?;
-    uc.mmio_map(adc::BASE, adc::SIZE, Some(adc::read), Some(adc::write))?;
-    uc.mmio_map(vpost::BASE, vpost::SIZE, Some(vpost::read), Some(vpost::write))?;
-    uc.mmio_map(spu::BASE, spu::SIZE, Some(spu::read), Some(spu::write))?;
-    uc.mmio_map(pwm::BASE, pwm::SIZE, Some(pwm::read), Some(pwm::write))?;
-    uc.mmio_map(i2s::BASE, i2s::SIZE, Some(i2s::read), Some(i2s::write))?;
-    uc.mmio_map(blt::BASE, blt::SIZE, Some(blt::read), Some(blt::write))?;
-
-    // Memory
-    // SDRAM (32MiB) (Mapped at 0x80000000, mirrored to 0x00000000)
-    let sdram_size = uc.get_data().raw_sdram.len();
-    unsafe {
-        let sdram_ptr = uc.get_data_mut().raw_sdram.as_mut_ptr() as *mut c_void;
-        uc.mem_map_ptr(0x00000000, sdram_size, Permission::ALL, sdram_ptr)?;
-        uc.mem_map_ptr(0x80000000, sdram_size, Permission::ALL, sdram_ptr)?;
-    }
-
-    // SRAM (8KiB)
-    uc.mem_map(0xff000000, 0x2000, Permission::ALL)?;
-
-    // HLE callbacks
-    // TODO: make these configurable and optional.
-    uc.add_block_hook(0x800053e0, 0x800053e0, hle::printf_callback)?;
-
-    if log::max_level() >= LevelFilter::Debug {
-        debug!("Memory map:");
-        let mem_regions = uc.mem_regions()?;
-        for region in mem_regions {
-            debug!("0x{:08x} - 0x{:08x} {:?}", region.begin, region.end, region.perms);
-        }
-    }
+    map_peripheral(&mut uc, "SYS", sys::BASE, sys::SIZE, sys::read, sys::write)?;
+    map_peripheral(&mut uc, "SDRAM", sdram::BASE, sdram::SIZE, sdram::read, sdram::write)?;
+    map_peripheral(&mut uc, "SIC", sic::BASE, sic::SIZE, sic::read, sic::write)?;
+    map_peripheral(&mut uc, "GPIO", gpio::BASE, gpio::SIZE, gpio::read, gpio::write)?;
+    map_peripheral(&mut uc, "RTC", rtc::BASE, rtc::SIZE, rtc::read, rtc::write)?;
+    map_peripheral(&mut uc, "UART", uart::BASE, uart::SIZE, uart::read, uart::write)?;
+    map_peripheral(&mut uc, "TMR", tmr::BASE, tmr::SIZE, tmr::read, tmr::write)?;
+    map_peripheral(&mut uc, "AIC", aic::BASE, aic::SIZE, aic::read, aic::write)?;
+    map_peripheral(&mut uc, "ADC", adc::BASE, adc::SIZE, adc::read, adc::write)?;
+    map_peripheral(&mut uc, "VPOST", vpost::BASE, vpost::SIZE, vpost::read, vpost::write)?;
+    map_peripheral(&mut uc, "SPU", spu::BASE, spu::SIZE, spu::read, spu::write)?;
+    map_peripheral(&mut uc, "PWM", pwm::BASE, pwm::SIZE, pwm::read, pwm::write)?;
+    map_peripheral(&mut uc, "I2S", i2s::BASE, i2s::SIZE, i2s::read, i2s::write)?;
+    map_peripheral(&mut uc, "BLT", blt::BASE, blt::SIZE, blt::read, blt::write)?;
+    map_peripheral(&mut uc, "EDMA", edma::BASE, edma::SIZE, edma::read, edma::write)?;
+    map_peripheral(&mut uc, "JPG", jpg::BASE, jpg::SIZE, jpg::read, jpg::write)?;
+    map_peripheral(&mut uc, "DES", des::BASE, des::SIZE, des::read, des::write)?;
+    map_peripheral(&mut uc, "SPI", spi::BASE, spi::SIZE, spi::read, spi::write)?;
+    map_peripheral(&mut uc, "I2C", i2c::BASE, i2c::SIZE, i2c::read, i2c::write)?;
+    map_peripheral(&mut uc, "VIDEOIN", videoin::BASE, videoin::SIZE, videoin::read, videoin::write)?;
+
+    memmap.map(&mut uc)?;
 
     Ok(uc)
 }
 
-fn main() {
-    env_logger::init();
-    let args = Args::parse();
+/// Outcome of one pass of the main loop.
+#[derive(PartialEq)]
+enum LoopAction {
+    Continue,
+    /// The debugger halted the guest.
+    Halted,
+    Quit,
+}
+
+/// Report a Unicorn error that stopped the guest, and write a crash report.
+fn quit_on_error(uc: &mut UnicornContext, args: &Args, err: uc_error) -> LoopAction {
+    let pc = uc.pc_read().unwrap_or_default();
+    let detail = QuitDetail::EmulatorError(err);
+    let reason = format!("{detail} PC=0x{pc:08x}");
+    error!("{reason}");
+    dump_data(uc, &args.crash_dir, &reason).unwrap_or_else(|err| {
+        error!("Failed to dump memory: {err:?}");
+    });
+    uc.get_data_mut().quit_detail = Some(detail);
+    LoopAction::Quit
+}
 
+/// Run the guest for one slice and process the device events it raised.
+fn run_slice<S: FrameSink>(
+    uc: &mut UnicornContext, device: &mut Device, gdb: &mut Option<GdbStub>, args: &Args, render: &mut S,
+) -> LoopAction {
+    let count = match gdb.as_mut().map(|gdb| gdb.poll(uc)) {
+        None | Some(Ok(GdbAction::Continue)) => 0,
+        Some(Ok(GdbAction::Step)) => 1,
+        Some(Ok(GdbAction::Halt)) => {
+            // Guest time stands still while the debugger holds the guest.
+            uc.get_data_mut().rtc.timekeeper.clock_mut().pause();
+            return LoopAction::Halted;
+        }
+        Some(Err(err)) => {
+            info!("GDB session ended: {err}");
+            if let Some(gdb) = gdb.take() {
+                gdb.close(uc);
+            }
+            0
+        }
+    };
+    uc.get_data_mut().rtc.timekeeper.clock_mut().resume();
+    device::schedule_next_event(uc);
+    let slice_end = match args.max_slice {
+        0 => u64::MAX,
+        max_slice => uc.get_data().steps.saturating_add(max_slice),
+    };
+    uc.get_data_mut().slice_end = slice_end;
+    let pc = uc.pc_read().unwrap();
+    // Hooks stop emulation with `emu_stop()`, which is not an error. Errors are faults, and emulation only goes on if
+    // the fault is an abort the guest can take.
+    if let Err(err) = uc.emu_start(pc, 0xffffffffffffffff, 0, count) {
+        match exception::deliver_abort(uc) {
+            Ok(true) => {}
+            Ok(false) => return quit_on_error(uc, args, err),
+            Err(deliver_err) => {
+                error!("Failed to deliver abort: {deliver_err:?}");
+                return quit_on_error(uc, args, err);
+            }
+        }
+    }
+    if let Some(gdb) = gdb && let Err(err) = gdb.check_stop(uc) {
+        error!("Failed to report stop to GDB: {err:?}");
+    }
+    if !device.tick(uc, render) {
+        if let Some(detail) = &uc.get_data().quit_detail && detail.is_crash() {
+            let reason = detail.to_string();
+            dump_data(uc, &args.crash_dir, &reason).unwrap_or_else(|err| {
+                error!("Failed to dump memory: {err:?}");
+            });
+        }
+        return LoopAction::Quit;
+    }
+    // No audio backend yet. Discard played samples so only their rate gets reported.
+    let samples = device.take_audio_samples(usize::MAX);
+    if !samples.is_empty() {
+        trace!("I2S: {} samples played, {} total", samples.len(), device.audio_frames);
+    }
+    LoopAction::Continue
+}
+
+/// Run the guest without a window until it quits or the frame limit is reached.
+fn run_headless(uc: &mut UnicornContext, device: &mut Device, gdb: &mut Option<GdbStub>, args: &Args) {
+    let mut frame = FrameBuffer::new(320, 240);
+    while args.frame_limit == 0 || device.frames < args.frame_limit {
+        match run_slice(uc, device, gdb, args, &mut frame) {
+            LoopAction::Continue => {}
+            LoopAction::Halted => std::thread::sleep(Duration::from_millis(10)),
+            LoopAction::Quit => break,
+        }
+    }
+    if let Some(path) = &args.dump_frame {
+        render::save_png(&frame, path).unwrap_or_else(|err| {
+            error!("Failed to save frame to {path}: {err:?}");
+        });
+    }
+}
+
+/// Run the guest in a window, rendering a frame whenever the window is redrawn.
+fn run_windowed(uc: &mut UnicornContext, device: &mut Device, gdb: &mut Option<GdbStub>, args: &Args, keymap: &Keymap) {
     let event_loop = EventLoop::new().unwrap();
     let mut input = WinitInputHelper::new();
     let window = {
@@ -271,40 +631,24 @@ fn main() {
             .with_min_inner_size(size)
             .build(&event_loop).unwrap()
     };
-
-    let mut emulator = emu_init().unwrap();
-    let uc = &mut emulator;
-
-    let mut device = Box::new(Device::default());
     let mut pixels = {
         let window_size = window.inner_size();
         let surface_texture = SurfaceTexture::new(window_size.width, window_size.height, &window);
         Pixels::new(320, 240, surface_texture).unwrap()
     };
 
-    let mut esd_img = File::open(&args.esd).unwrap();
-    run_bootrom(uc, &mut esd_img).unwrap();
-    device.internal_sd.mount(&args.esd).unwrap();
-    device.internal_sd.set_cid(&CID_ESD);
-    if let Some(xsd_path) = &args.xsd {
-        device.external_sd.mount(xsd_path).unwrap();
-        device.external_sd.set_cid(&CID_XSD);
-    }
-
-    // TODO move this out of main
     event_loop.run(|event, elwt| {
         if let Event::WindowEvent { event: WindowEvent::RedrawRequested, .. } = event {
-            let pc = uc.pc_read().unwrap();
-            uc.emu_start(pc, 0xffffffffffffffff, 0, 0).or_else(|err| {
-                error!("Unhandled Unicorn error {err:?} at PC=0x{:08x}", uc.pc_read().unwrap());
-                dump_data(uc).unwrap_or_else(|err| {
-                    error!("Failed to dump memory: {err:?}");
-                });
-                Err(err)
-            }).unwrap();
-            if !device.tick(uc, &mut pixels) {
-                elwt.exit();
-                return;
+            match run_slice(uc, device, gdb, args, &mut pixels) {
+                LoopAction::Continue => {}
+                LoopAction::Halted => {
+                    window.request_redraw();
+                    return;
+                }
+                LoopAction::Quit => {
+                    elwt.exit();
+                    return;
+                }
             }
             window.request_redraw();
             // TODO
@@ -314,44 +658,250 @@ fn main() {
         }
 
         if input.update(&event) {
-            if input.mouse_pressed(0) || input.mouse_held(0) {
-                if let Some(window_pos) = input.cursor() {
-                    if let Ok(converted_pos) = pixels.window_pos_to_pixel(window_pos) {
-                        device.input.touch_move(converted_pos);
-                    }
-                }
+            if
+                (input.mouse_pressed(0) || input.mouse_held(0)) &&
+                let Some(window_pos) = input.cursor() &&
+                let Ok(converted_pos) = pixels.window_pos_to_pixel(window_pos)
+            {
+                device.input.touch_move(converted_pos);
             }
 
             if input.mouse_released(0) {
                 device.input.touch_release();
             }
 
-            if input.key_pressed(KeyCode::Home) {
-                device.input.key_press(KeyType::Home);
-            }
+            for (code, key) in keymap.iter() {
+                if input.key_pressed(*code) {
+                    device.input.key_press(*key);
+                }
 
-            if input.key_released(KeyCode::Home) {
-                device.input.key_release(KeyType::Home);
+                if input.key_released(*code) {
+                    device.input.key_release(*key);
+                }
             }
 
-            if input.key_pressed(KeyCode::Escape) {
-                device.input.key_press(KeyType::Power);
+            if input.key_pressed(KeyCode::F9) && device.external_sd.is_mounted() {
+                if device.external_sd.is_inserted() {
+                    device.external_sd.eject();
+                } else {
+                    device.external_sd.insert();
+                }
+                request_stop(uc, StopReason::Tick);
             }
 
-            if input.key_released(KeyCode::Escape) {
-                device.input.key_release(KeyType::Power);
+            if input.key_pressed(KeyCode::PrintScreen) {
+                match render::save_screenshot(&pixels) {
+                    Ok(path) => info!("Screenshot saved to {path}"),
+                    Err(err) => error!("Failed to save screenshot: {err:?}"),
+                }
             }
 
-            if let Some(size) = input.window_resized() {
-                if let Err(err) = pixels.resize_surface(size.width, size.height) {
-                    error!("pixels.resize_surface: {:?}", err);
-                    elwt.exit();
-                    return;
-                }
+            if
+                let Some(size) = input.window_resized() &&
+                let Err(err) = pixels.resize_surface(size.width, size.height)
+            {
+                error!("pixels.resize_surface: {:?}", err);
+                elwt.exit();
             }
         }
     }).unwrap();
+}
+
+fn main() {
+    env_logger::init();
+    let args = Args::parse();
+
+    let mut keymap = Keymap::default();
+    for (code, key) in &args.bindings {
+        keymap.bind(*code, *key);
+    }
+
+    let memmap = MemoryMap {
+        sdram_size: args.sdram_size,
+        rom: args.bootrom.as_ref().map(|path| {
+            std::fs::read(path).unwrap_or_else(|err| {
+                error!("Failed to read {path}: {err:?}");
+                std::process::exit(1);
+            })
+        }),
+        endian: args.endian,
+    };
+    if let Err(err) = memmap.validate() {
+        error!("Invalid memory map: {err}");
+        std::process::exit(1);
+    }
+    let mut emulator = emu_init(&memmap).unwrap();
+    let uc = &mut emulator;
+    uc.get_data_mut().adc.mic_tone_hz = args.mic_tone;
+    {
+        let timekeeper = &mut uc.get_data_mut().rtc.timekeeper;
+        let clock = timekeeper.clock_mut();
+        clock.set_scale(args.time_scale.unwrap_or(if args.frozen_time.is_some() { 0.0 } else { 1.0 }));
+        if let Some(start) = args.frozen_time {
+            clock.set_start(start);
+        }
+        timekeeper.sync_clock();
+    }
+    uc.get_data_mut().cycles_per_insn = ((args.cpi * device::CPI_SCALE as f64).round() as u64).max(1);
+    if args.recoverable_aborts {
+        let policy = &mut uc.get_data_mut().exception_policy;
+        policy.set(ExceptionType::PrefetchAbort, ExceptionAction::Deliver);
+        policy.set(ExceptionType::DataAbort, ExceptionAction::Deliver);
+    }
+    for (exc_type, action) in &args.exception_policy {
+        uc.get_data_mut().exception_policy.set(*exc_type, *action);
+    }
+    for intno in &args.fiq_sources {
+        uc.get_data_mut().aic.fiq_sources |= intno.as_mask();
+    }
+    if let Some(calibration) = args.touch_calibration {
+        uc.get_data_mut().adc.touch_calibration = calibration;
+    }
+    if let Some(trace_path) = &args.trace {
+        let tracer = trace::Tracer::create(trace_path).unwrap_or_else(|err| {
+            error!("Failed to create trace {trace_path}: {err:?}");
+            std::process::exit(1);
+        });
+        uc.get_data_mut().tracer = Some(tracer);
+        if args.trace_instructions {
+            uc.add_code_hook(0, 0xffffffff, trace::record_instruction).unwrap();
+        }
+    }
+    uc.get_data_mut().mmio_coverage.enabled = args.dump_mmio_map;
+    if let Some(hle_map) = &args.hle_map {
+        let text = std::fs::read_to_string(hle_map).unwrap_or_else(|err| {
+            error!("Failed to read {hle_map}: {err:?}");
+            std::process::exit(1);
+        });
+        let symbols = hle::parse_symbol_map(&text).unwrap_or_else(|err| {
+            error!("Failed to parse {hle_map}: {err}");
+            std::process::exit(1);
+        });
+        if let Err(err) = hle::install(uc, &symbols) {
+            error!("Failed to install HLE callbacks from {hle_map}: {err:?}");
+            std::process::exit(1);
+        }
+        uc.get_data_mut().symbols = Some(hle::SymbolTable::new(&symbols));
+    } else {
+        hle::install(uc, hle::DEFAULT_SYMBOLS).unwrap();
+    }
+
+    let mut device = Box::new(Device::default());
+
+    for (port, uart_input) in [&args.uart_input, &args.uart1_input].into_iter().enumerate() {
+        if let Some(uart_input) = uart_input {
+            match std::fs::read(uart_input) {
+                Ok(data) => device.push_uart_input(port, &data),
+                Err(err) => error!("Failed to read UART{port} input {uart_input}: {err:?}"),
+            }
+        }
+    }
+    if let Some(mic_input) = &args.mic_input {
+        match std::fs::read(mic_input) {
+            Ok(data) => device.push_mic_input(&data),
+            Err(err) => error!("Failed to read microphone input {mic_input}: {err:?}"),
+        }
+    }
+
+    device.input.set_touch_queue_len(args.touch_queue_len);
+    if let Some(script_path) = &args.input_script {
+        let text = std::fs::read_to_string(script_path)
+            .unwrap_or_else(|err| panic!("Failed to read {script_path}: {err}"));
+        let script = input::parse_input_script(&text)
+            .unwrap_or_else(|err| panic!("Failed to parse {script_path}: {err}"));
+        device.input.load_script(script);
+    }
+
+    let mount = |sd: &mut SD, path: &str, read_only: bool| {
+        let result = if read_only { sd.mount_ro(path) } else { sd.mount(path) };
+        if let Err(err) = result {
+            error!("Failed to mount {path}: {err:?}");
+            std::process::exit(1);
+        }
+    };
+    mount(&mut device.internal_sd, &args.esd, args.esd_readonly);
+    device.internal_sd.set_crc_enabled(args.sd_crc);
+    device.internal_sd.set_busy_ticks(args.sd_busy_ticks);
+    device.internal_sd.set_write_cache_size(args.sd_write_cache);
+    if let Some(xsd_path) = &args.xsd {
+        mount(&mut device.external_sd, xsd_path, args.xsd_readonly);
+        device.external_sd.set_crc_enabled(args.sd_crc);
+        device.external_sd.set_busy_ticks(args.sd_busy_ticks);
+        device.external_sd.set_write_cache_size(args.sd_write_cache);
+    }
+    if let Some(nand_path) = &args.nand {
+        let nand = &mut uc.get_data_mut().sic.nand;
+        if let Err(err) = nand.mount(nand_path) {
+            error!("Failed to mount {nand_path}: {err:?}");
+            std::process::exit(1);
+        }
+    }
+    if let Some(spi_flash_path) = &args.spi_flash {
+        let (port, cs) = args.spi_flash_cs;
+        let flash = &mut uc.get_data_mut().spi.ports[port].flash[cs];
+        if let Err(err) = flash.mount(spi_flash_path) {
+            error!("Failed to mount {spi_flash_path}: {err:?}");
+            std::process::exit(1);
+        }
+    }
+    if let Some(eeprom_path) = &args.i2c_eeprom {
+        let eeprom = EEPROM::open(eeprom_path, args.i2c_eeprom_addr).unwrap_or_else(|err| {
+            error!("Failed to open {eeprom_path}: {err:?}");
+            std::process::exit(1);
+        });
+        if let Err(err) = uc.get_data_mut().i2c.bus.attach(&eeprom.addresses(), Box::new(eeprom)) {
+            error!("Failed to attach {eeprom_path}: {err:?}");
+            std::process::exit(1);
+        }
+    }
+    if let Some(camera_path) = &args.camera {
+        uc.get_data_mut().videoin.camera = Camera::open(camera_path).unwrap_or_else(|err| {
+            error!("Failed to open {camera_path}: {err:?}");
+            std::process::exit(1);
+        });
+    }
+
+    if let Some(snapshot_path) = &args.restore {
+        if let Err(err) = snapshot::load_snapshot(uc, &mut device, snapshot_path) {
+            error!("Invalid snapshot {snapshot_path}: {err:?}");
+            std::process::exit(1);
+        }
+    } else if memmap.rom.is_some() {
+        init_board(uc);
+        boot_native(uc).unwrap();
+    } else {
+        init_board(uc);
+        let mut esd_img = File::open(&args.esd).unwrap();
+        run_bootrom(uc, &mut esd_img).unwrap();
+    }
+
+    let mut gdb = args.gdb.map(|port| GdbStub::listen(port).unwrap_or_else(|err| {
+        error!("Failed to start the GDB stub on port {port}: {err:?}");
+        std::process::exit(1);
+    }));
+
+    if args.headless {
+        run_headless(uc, &mut device, &mut gdb, &args);
+    } else {
+        run_windowed(uc, &mut device, &mut gdb, &args, &keymap);
+    }
+
+    trace::flush(uc);
+    uc.get_data().mmio_coverage.log_report();
+
+    if let Some(snapshot_path) = &args.snapshot_on_exit {
+        // The snapshot expects the images to hold everything written so far.
+        device.internal_sd.flush();
+        device.external_sd.flush();
+        snapshot::save_snapshot(uc, &device, snapshot_path).unwrap_or_else(|err| {
+            error!("Failed to save snapshot: {err:?}");
+        });
+    }
 
     device.internal_sd.unmount();
     device.external_sd.unmount();
+    uc.get_data_mut().sic.nand.unmount();
+    for port in &mut uc.get_data_mut().spi.ports {
+        port.flash.iter_mut().for_each(SPIFlash::unmount);
+    }
 }
@@ -0,0 +1,243 @@
+use std::os::raw::c_void;
+
+use log::{LevelFilter, debug};
+use unicorn_engine::{Mode, Permission, uc_error};
+
+use crate::device::UnicornContext;
+
+/// Granularity of Unicorn memory mappings.
+const PAGE_SIZE: u64 = 0x1000;
+
+pub const SDRAM_BASE: u64 = 0x80000000;
+/// SDRAM is mirrored at the bottom of the address space.
+pub const SDRAM_MIRROR_BASE: u64 = 0x00000000;
+pub const DEFAULT_SDRAM_SIZE: usize = 0x2000000;
+
+pub const SRAM_BASE: u64 = 0xff000000;
+pub const SRAM_SIZE: usize = 0x2000;
+
+/// Boot ROM location, which is also the high exception vector base.
+pub const ROM_BASE: u64 = 0xffff0000;
+
+/// Address range taken by the peripheral registers.
+const MMIO_BASE: u64 = 0xb0000000;
+const MMIO_END: u64 = 0xc0000000;
+
+/// A range of the guest physical address space.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub name: &'static str,
+    pub base: u64,
+    pub size: u64,
+}
+
+impl Region {
+    fn end(&self) -> u64 {
+        self.base + self.size
+    }
+
+    fn overlaps(&self, other: &Region) -> bool {
+        self.base < other.end() && other.base < self.end()
+    }
+}
+
+/// Byte order of the guest CPU and everything it reads from or writes to memory.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Endian {
+    #[default]
+    Little,
+    Big,
+}
+
+impl Endian {
+    pub fn mode(self) -> Mode {
+        match self {
+            Self::Little => Mode::LITTLE_ENDIAN,
+            Self::Big => Mode::BIG_ENDIAN,
+        }
+    }
+
+    pub fn u32_from_bytes(self, bytes: [u8; 4]) -> u32 {
+        match self {
+            Self::Little => u32::from_le_bytes(bytes),
+            Self::Big => u32::from_be_bytes(bytes),
+        }
+    }
+
+    pub fn u32_to_bytes(self, value: u32) -> [u8; 4] {
+        match self {
+            Self::Little => value.to_le_bytes(),
+            Self::Big => value.to_be_bytes(),
+        }
+    }
+
+    /// Combine the bytes of a multi-byte access into the value seen by the CPU.
+    pub fn unpack(self, bytes: &[u8]) -> u64 {
+        let fold = |acc, byte: &u8| acc << 8 | u64::from(*byte);
+        match self {
+            Self::Little => bytes.iter().rev().fold(0, fold),
+            Self::Big => bytes.iter().fold(0, fold),
+        }
+    }
+
+    /// Split the low `bytes.len()` bytes of a value written by the CPU into memory order.
+    pub fn pack(self, value: u64, bytes: &mut [u8]) {
+        let len = bytes.len();
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            let shift = match self {
+                Self::Little => 8 * i,
+                Self::Big => 8 * (len - 1 - i),
+            };
+            *byte = (value >> shift) as u8;
+        }
+    }
+}
+
+/// Guest memory layout of the board variant being emulated.
+pub struct MemoryMap {
+    pub sdram_size: usize,
+    /// Boot ROM image mapped read-only at `ROM_BASE`, if any.
+    pub rom: Option<Vec<u8>>,
+    pub endian: Endian,
+}
+
+impl Default for MemoryMap {
+    fn default() -> Self {
+        Self { sdram_size: DEFAULT_SDRAM_SIZE, rom: None, endian: Endian::Little }
+    }
+}
+
+impl MemoryMap {
+    /// ROM images are padded to the next page boundary.
+    fn rom_size(&self) -> Option<u64> {
+        self.rom.as_ref().map(|rom| (rom.len() as u64).next_multiple_of(PAGE_SIZE))
+    }
+
+    /// All mapped regions, including the peripheral registers.
+    pub fn regions(&self) -> Vec<Region> {
+        let sdram_size = self.sdram_size as u64;
+        let mut regions = vec![
+            Region { name: "SDRAM mirror", base: SDRAM_MIRROR_BASE, size: sdram_size },
+            Region { name: "SDRAM", base: SDRAM_BASE, size: sdram_size },
+            Region { name: "MMIO", base: MMIO_BASE, size: MMIO_END - MMIO_BASE },
+            Region { name: "SRAM", base: SRAM_BASE, size: SRAM_SIZE as u64 },
+        ];
+        if let Some(size) = self.rom_size() {
+            regions.push(Region { name: "ROM", base: ROM_BASE, size });
+        }
+        regions
+    }
+
+    /// Check that all regions are page-aligned, fit in the 32-bit address space and don't overlap.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.sdram_size == 0 || !(self.sdram_size as u64).is_multiple_of(PAGE_SIZE) {
+            return Err(format!("SDRAM size 0x{:x} is not a non-zero multiple of 0x{PAGE_SIZE:x}", self.sdram_size));
+        }
+        if self.rom.as_ref().is_some_and(Vec::is_empty) {
+            return Err("ROM image is empty".into());
+        }
+
+        let regions = self.regions();
+        for (i, region) in regions.iter().enumerate() {
+            if region.end() > 1 << 32 {
+                return Err(format!("{} at 0x{:08x} extends past the 32-bit address space", region.name, region.base));
+            }
+            if let Some(other) = regions[i + 1..].iter().find(|other| region.overlaps(other)) {
+                return Err(format!(
+                    "{} (0x{:08x} - 0x{:08x}) overlaps {} (0x{:08x} - 0x{:08x})",
+                    region.name, region.base, region.end(), other.name, other.base, other.end(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Map SDRAM (backed by `ExtraState::raw_sdram`), SRAM and the boot ROM, if any. Peripheral registers are mapped
+    /// separately.
+    pub fn map(&self, uc: &mut UnicornContext) -> Result<(), uc_error> {
+        let sdram_size = uc.get_data().raw_sdram.len();
+        unsafe {
+            let sdram_ptr = uc.get_data_mut().raw_sdram.as_mut_ptr() as *mut c_void;
+            uc.mem_map_ptr(SDRAM_MIRROR_BASE, sdram_size, Permission::ALL, sdram_ptr)?;
+            uc.mem_map_ptr(SDRAM_BASE, sdram_size, Permission::ALL, sdram_ptr)?;
+        }
+
+        uc.mem_map(SRAM_BASE, SRAM_SIZE, Permission::ALL)?;
+
+        if let (Some(rom), Some(size)) = (&self.rom, self.rom_size()) {
+            uc.mem_map(ROM_BASE, size as usize, Permission::READ | Permission::EXEC)?;
+            uc.mem_write(ROM_BASE, rom)?;
+            uc.get_data_mut().vector_base = Some(ROM_BASE);
+        }
+
+        if log::max_level() >= LevelFilter::Debug {
+            debug!("Memory map:");
+            for region in uc.mem_regions()? {
+                debug!("0x{:08x} - 0x{:08x} {:?}", region.begin, region.end, region.perms);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parse a memory size in bytes, e.g. `0x2000000`, `32768K` or `32M`.
+pub fn parse_size(value: &str) -> Result<usize, String> {
+    let lower = value.trim().to_ascii_lowercase();
+    let (digits, scale) = if let Some(digits) = lower.strip_suffix("mib").or_else(|| lower.strip_suffix('m')) {
+        (digits, 1 << 20)
+    } else if let Some(digits) = lower.strip_suffix("kib").or_else(|| lower.strip_suffix('k')) {
+        (digits, 1 << 10)
+    } else {
+        (lower.as_str(), 1)
+    };
+    let parsed = match digits.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => digits.parse(),
+    };
+    parsed.ok().and_then(|size| size.checked_mul(scale)).ok_or_else(|| format!("Invalid size `{value}`"))
+}
+
+/// Parse a byte order, `little` (`le`) or `big` (`be`).
+pub fn parse_endian(value: &str) -> Result<Endian, String> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "little" | "le" => Ok(Endian::Little),
+        "big" | "be" => Ok(Endian::Big),
+        _ => Err(format!("Invalid byte order `{value}`, expecting `little` or `big`")),
+    }
+}
+
+#[test]
+fn test_parse_size() {
+    assert_eq!(parse_size("0x2000000"), Ok(0x2000000));
+    assert_eq!(parse_size("64M"), Ok(0x4000000));
+    assert_eq!(parse_size("16MiB"), Ok(0x1000000));
+    assert_eq!(parse_size("4096k"), Ok(0x400000));
+    assert_eq!(parse_size("65536"), Ok(0x10000));
+    assert!(parse_size("lots").is_err());
+}
+
+#[test]
+fn test_validate() {
+    assert!(MemoryMap::default().validate().is_ok());
+    assert!(MemoryMap { sdram_size: 0x4000000, rom: Some(vec![0; 0x8000]), ..Default::default() }.validate().is_ok());
+    // Not page-aligned.
+    assert!(MemoryMap { sdram_size: 0x2000800, ..Default::default() }.validate().is_err());
+    // Runs into the peripheral registers.
+    assert!(MemoryMap { sdram_size: 0x40000000, ..Default::default() }.validate().is_err());
+    // Past the end of the address space.
+    assert!(MemoryMap { rom: Some(vec![0; 0x20000]), ..Default::default() }.validate().is_err());
+}
+
+#[test]
+fn test_endian() {
+    let mut bytes = [0u8; 4];
+    Endian::Big.pack(0x11223344, &mut bytes);
+    assert_eq!(bytes, [0x11, 0x22, 0x33, 0x44]);
+    assert_eq!(Endian::Little.unpack(&bytes), 0x44332211);
+    assert_eq!(Endian::Big.unpack(&bytes[2..]), 0x3344);
+    Endian::Little.pack(0xaabb, &mut bytes[..2]);
+    assert_eq!(bytes, [0xbb, 0xaa, 0x33, 0x44]);
+    assert_eq!(Endian::Big.u32_from_bytes(bytes), 0xbbaa3344);
+    assert_eq!(parse_endian("BE"), Ok(Endian::Big));
+    assert!(parse_endian("middle").is_err());
+}
@@ -0,0 +1,193 @@
+use std::os::raw::c_void;
+
+use unicorn_engine::{RegisterARM, ffi, uc_error};
+
+use crate::device::UnicornContext;
+
+/// Smallest page size supported by the ARMv5 MMU (tiny pages). Translations stay valid within one granule.
+const PAGE_GRANULE: u64 = 0x400;
+
+const SCTLR_MMU_ENABLE: u32 = 1 << 0;
+
+/// Mirrors `uc_arm_cp_reg`, which `RegisterARM::CP_REG` reads and writes take a pointer to.
+#[repr(C)]
+#[derive(Default)]
+struct ArmCpReg {
+    cp: u32,
+    is64: u32,
+    sec: u32,
+    crn: u32,
+    crm: u32,
+    opc1: u32,
+    opc2: u32,
+    val: u64,
+}
+
+/// CP15 registers accessed from the host side.
+///
+/// Unicorn emulates the ARM926 system control coprocessor itself, so guest `MRC`/`MCR` accesses to these work as on
+/// hardware, including turning the MMU on and off. The emulator reads and writes them to translate guest addresses,
+/// deliver aborts, save snapshots and report crashes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CP15Register {
+    /// Cache type register. Read only.
+    CacheType,
+    /// System control register (SCTLR).
+    Control,
+    TranslationTableBase,
+    DomainAccessControl,
+    DataFaultStatus,
+    InstructionFaultStatus,
+    FaultAddress,
+}
+
+impl CP15Register {
+    /// CRn, CRm, opc1 and opc2 of the register.
+    pub const fn encoding(self) -> (u32, u32, u32, u32) {
+        match self {
+            Self::CacheType => (0, 0, 0, 1),
+            Self::Control => (1, 0, 0, 0),
+            Self::TranslationTableBase => (2, 0, 0, 0),
+            Self::DomainAccessControl => (3, 0, 0, 0),
+            Self::DataFaultStatus => (5, 0, 0, 0),
+            Self::InstructionFaultStatus => (5, 0, 0, 1),
+            Self::FaultAddress => (6, 0, 0, 0),
+        }
+    }
+
+    pub fn read(self, uc: &UnicornContext) -> Result<u32, uc_error> {
+        let (crn, crm, opc1, opc2) = self.encoding();
+        read_cp15(uc, crn, crm, opc1, opc2)
+    }
+
+    pub fn write(self, uc: &mut UnicornContext, value: u32) -> Result<(), uc_error> {
+        let (crn, crm, opc1, opc2) = self.encoding();
+        write_cp15(uc, crn, crm, opc1, opc2, value)
+    }
+}
+
+/// Read a CP15 register.
+pub fn read_cp15(uc: &UnicornContext, crn: u32, crm: u32, opc1: u32, opc2: u32) -> Result<u32, uc_error> {
+    let mut reg = ArmCpReg { cp: 15, crn, crm, opc1, opc2, ..Default::default() };
+    // SAFETY: reg outlives the call and has the layout unicorn expects for CP_REG.
+    let err = unsafe {
+        ffi::uc_reg_read(uc.get_handle(), RegisterARM::CP_REG as i32, &mut reg as *mut ArmCpReg as *mut c_void)
+    };
+    err.and(Ok(reg.val as u32))
+}
+
+/// Write a CP15 register.
+pub fn write_cp15(uc: &mut UnicornContext, crn: u32, crm: u32, opc1: u32, opc2: u32, value: u32) -> Result<(), uc_error> {
+    let reg = ArmCpReg { cp: 15, crn, crm, opc1, opc2, val: value.into(), ..Default::default() };
+    // SAFETY: reg outlives the call and has the layout unicorn expects for CP_REG.
+    let err = unsafe {
+        ffi::uc_reg_write(uc.get_handle(), RegisterARM::CP_REG as i32, &reg as *const ArmCpReg as *const c_void)
+    };
+    err.and(Ok(()))
+}
+
+/// Walk the ARMv5 page tables at `ttb` to translate `va`, faulting like the MMU on domains that `dacr` denies access
+/// to. `read_word` reads a word from physical memory.
+fn walk<F>(ttb: u32, dacr: u32, va: u32, read_word: F) -> Result<u32, uc_error>
+where
+    F: Fn(u32) -> Result<u32, uc_error>,
+{
+    let l1 = read_word((ttb & 0xffffc000) | ((va >> 20) << 2))?;
+    let domain = (l1 >> 5) & 0xf;
+    // No access and reserved. Clients are checked against the access permissions, which are not emulated here.
+    if l1 & 0b11 != 0 && matches!((dacr >> (2 * domain)) & 0b11, 0b00 | 0b10) {
+        return Err(uc_error::READ_UNMAPPED);
+    }
+    let l2_addr = match l1 & 0b11 {
+        // Section
+        0b10 => return Ok((l1 & 0xfff00000) | (va & 0x000fffff)),
+        // Coarse page table
+        0b01 => (l1 & 0xfffffc00) | (((va >> 12) & 0xff) << 2),
+        // Fine page table
+        0b11 => (l1 & 0xfffff000) | (((va >> 10) & 0x3ff) << 2),
+        _ => return Err(uc_error::READ_UNMAPPED),
+    };
+
+    let l2 = read_word(l2_addr)?;
+    match l2 & 0b11 {
+        // Large page
+        0b01 => Ok((l2 & 0xffff0000) | (va & 0x0000ffff)),
+        // Small page
+        0b10 => Ok((l2 & 0xfffff000) | (va & 0x00000fff)),
+        // Tiny page
+        0b11 => Ok((l2 & 0xfffffc00) | (va & 0x000003ff)),
+        _ => Err(uc_error::READ_UNMAPPED),
+    }
+}
+
+/// Translate a guest virtual address into a physical address usable with `mem_read`/`mem_write`, using the current
+/// MMU state.
+pub fn translate(uc: &UnicornContext, va: u64) -> Result<u64, uc_error> {
+    let sctlr = CP15Register::Control.read(uc)?;
+    if sctlr & SCTLR_MMU_ENABLE == 0 {
+        return Ok(va);
+    }
+
+    let ttb = CP15Register::TranslationTableBase.read(uc)?;
+    let dacr = CP15Register::DomainAccessControl.read(uc)?;
+    let va = u32::try_from(va).map_err(|_| uc_error::READ_UNMAPPED)?;
+    let pa = walk(ttb, dacr, va, |addr| {
+        let mut bytes = [0u8; 4];
+        uc.mem_read(addr.into(), &mut bytes)?;
+        Ok(uc.get_data().endian.u32_from_bytes(bytes))
+    })?;
+    Ok(pa.into())
+}
+
+/// Read guest virtual memory. Reads spanning multiple pages are translated page by page.
+pub fn read_virtual(uc: &UnicornContext, va: u64, buf: &mut [u8]) -> Result<(), uc_error> {
+    let mut done = 0usize;
+    while done < buf.len() {
+        let current = va + done as u64;
+        let len = (buf.len() - done).min((PAGE_GRANULE - current % PAGE_GRANULE) as usize);
+        uc.mem_read(translate(uc, current)?, &mut buf[done..done + len])?;
+        done += len;
+    }
+    Ok(())
+}
+
+/// Write guest virtual memory. Writes spanning multiple pages are translated page by page.
+pub fn write_virtual(uc: &mut UnicornContext, va: u64, data: &[u8]) -> Result<(), uc_error> {
+    let mut done = 0usize;
+    while done < data.len() {
+        let current = va + done as u64;
+        let len = (data.len() - done).min((PAGE_GRANULE - current % PAGE_GRANULE) as usize);
+        let pa = translate(uc, current)?;
+        uc.mem_write(pa, &data[done..done + len])?;
+        done += len;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_walk() {
+    use std::collections::HashMap;
+
+    let mut memory = HashMap::new();
+    // 0x00000000: coarse table at 0x1000, page 0 and 1 are small pages in SRAM.
+    memory.insert(0x4000u32, 0x1000 | 0b01);
+    memory.insert(0x1000, 0xff000000 | 0b10);
+    memory.insert(0x1004, 0xff001000 | 0b10);
+    // 0x80000000: section mapped to 0x00000000.
+    memory.insert(0x4000 | (0x800 << 2), 0b10);
+    // 0x90000000: section in domain 1.
+    memory.insert(0x4000 | (0x900 << 2), 0x00100000 | 1 << 5 | 0b10);
+    let read_word = |addr| memory.get(&addr).copied().ok_or(uc_error::READ_UNMAPPED);
+    // Domain 0 is a client, domain 1 has no access.
+    let dacr = 0b0001;
+
+    assert_eq!(walk(0x4000, dacr, 0x00000123, read_word), Ok(0xff000123));
+    // A string straddling the page boundary continues in the next page.
+    assert_eq!(walk(0x4000, dacr, 0x00001ffc, read_word), Ok(0xff001ffc));
+    assert_eq!(walk(0x4000, dacr, 0x80012345, read_word), Ok(0x00012345));
+    assert_eq!(walk(0x4000, dacr, 0x00002000, read_word), Err(uc_error::READ_UNMAPPED));
+    assert_eq!(walk(0x4000, dacr, 0x40000000, read_word), Err(uc_error::READ_UNMAPPED));
+    assert_eq!(walk(0x4000, dacr, 0x90000010, read_word), Err(uc_error::READ_UNMAPPED));
+    // Managers skip the permission checks.
+    assert_eq!(walk(0x4000, 0b1100, 0x90000010, read_word), Ok(0x00100010));
+}
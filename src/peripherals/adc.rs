@@ -1,6 +1,9 @@
+use std::{collections::VecDeque, f64::consts::TAU, str::FromStr};
+
 use bit_field::{B1, B6, B7, B8, bitfield};
 use log::{trace, warn};
 use crate::{device::{Device, UnicornContext}, log_unsupported_read, log_unsupported_write, peripherals::aic::{InterruptNumber, post_interrupt}};
+use crate::{impl_reset, impl_snapshot, impl_snapshot_bitfield};
 
 pub const BASE: u64 = 0xb800e000;
 pub const SIZE: usize = 0x1000;
@@ -15,6 +18,53 @@ const X_MAX: f64 = 238559.0 / 260.0;
 const Y_MIN: f64 = 95.0;
 const Y_MAX: f64 = 172211.0 / 180.0;
 
+/// Raw touchscreen readings at the edges of the panel.
+///
+/// X readings go from `x_min` at the left edge to `x_max` at the right edge, and Y readings go from `y_min` at the
+/// bottom edge to `y_max` at the top edge. Swap the values to flip an axis.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TouchCalibration {
+    pub x_min: f64,
+    pub x_max: f64,
+    pub y_min: f64,
+    pub y_max: f64,
+}
+
+impl Default for TouchCalibration {
+    fn default() -> Self {
+        Self { x_min: X_MIN, x_max: X_MAX, y_min: Y_MIN, y_max: Y_MAX }
+    }
+}
+
+impl FromStr for TouchCalibration {
+    type Err = String;
+
+    /// Parse `x_min,x_max,y_min,y_max`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s.split(',')
+            .map(|v| v.trim().parse::<f64>().map_err(|err| format!("Invalid calibration value {v}: {err}")))
+            .collect::<Result<Vec<_>, _>>()?;
+        let [x_min, x_max, y_min, y_max] = values[..] else {
+            return Err(format!("Expecting x_min,x_max,y_min,y_max, got {s}"));
+        };
+        Ok(Self { x_min, x_max, y_min, y_max })
+    }
+}
+
+impl TouchCalibration {
+    /// Convert a position on a `width` x `height` panel into raw readings.
+    pub fn map(&self, pos: (usize, usize), width: u32, height: u32) -> (u16, u16) {
+        let x_ratio = pos.0 as f64 / f64::from(width.max(2) - 1);
+        let y_ratio = (f64::from(height.max(2) - 1) - pos.1 as f64) / f64::from(height.max(2) - 1);
+        let x = self.x_min + x_ratio * (self.x_max - self.x_min);
+        let y = self.y_min + y_ratio * (self.y_max - self.y_min);
+        (x.round().clamp(0.0, 1023.0) as u16, y.round().clamp(0.0, 1023.0) as u16)
+    }
+}
+
+/// Sample rate assumed when generating the microphone test tone.
+const MIC_SAMPLE_RATE: f64 = 8000.0;
+
 #[bitfield]
 #[derive(Debug, PartialEq)]
 pub enum ADCMux {
@@ -37,6 +87,7 @@ pub enum TouchMode {
     WaitForTrigger,
 }
 
+/// Wiring of the touchscreen. Only 4-wire panels are emulated.
 #[bitfield]
 #[derive(Debug, PartialEq)]
 pub enum TouchscreenType {
@@ -101,10 +152,68 @@ pub struct ADCConfig {
 
     pub touch_x: u16,
     pub touch_y: u16,
+    pub touch_calibration: TouchCalibration,
+
+    /// Samples from the host microphone source.
+    pub mic_samples: VecDeque<i16>,
+    /// Frequency of the test tone played into the microphone when there is no host sample, in Hz. Silence if 0.
+    pub mic_tone_hz: u32,
+    mic_phase: f64,
+    mic_filtered: f64,
+    /// A streaming conversion is due on the next frame step.
+    streaming_pending: bool,
 
     pub irq_on_frame_step: bool,
 }
 
+impl ADCConfig {
+    /// Run a conversion on the selected input. In streaming mode, the next conversion is scheduled.
+    fn convert(&mut self) {
+        self.control.set_irq_status(true);
+        match self.control.get_mux() {
+            // Auto touch mode takes over the mux.
+            _ if self.control.get_touch_mode() == TouchMode::Auto => {
+                self.xdata = self.touch_x;
+                self.ydata = self.touch_y;
+            }
+            mux @ (ADCMux::MicPos | ADCMux::MicNeg) => {
+                self.xdata = self.sample_mic(mux == ADCMux::MicNeg);
+                self.ydata = 0;
+            }
+            ADCMux::AIn2 => {
+                self.xdata = 1023;
+                self.ydata = 0;
+            }
+            _ => {
+                self.xdata = 0;
+                self.ydata = 0;
+            }
+        }
+        self.streaming_pending = self.control.get_streaming();
+    }
+
+    /// Take the next microphone sample as a 10-bit conversion result.
+    fn sample_mic(&mut self, negative: bool) -> u16 {
+        let sample = match self.mic_samples.pop_front() {
+            Some(sample) => f64::from(sample) / 32768.0,
+            None if self.mic_tone_hz != 0 => {
+                self.mic_phase = (self.mic_phase + f64::from(self.mic_tone_hz) / MIC_SAMPLE_RATE).fract();
+                (self.mic_phase * TAU).sin() * 0.5
+            }
+            None => 0.0,
+        };
+        let sample = if negative { -sample } else { sample };
+        let sample = if self.control.get_audio_raw() {
+            sample
+        } else {
+            // Single pole low pass filter.
+            self.mic_filtered += (sample - self.mic_filtered) / 4.0;
+            self.mic_filtered
+        };
+        ((sample + 1.0) * 511.5).round().clamp(0.0, 1023.0) as u16
+    }
+}
+
 pub fn read(uc: &mut UnicornContext, addr: u64, size: usize) -> u64 {
     if size != 4 {
         log_unsupported_read!(addr, size);
@@ -145,26 +254,20 @@ pub fn write(uc: &mut UnicornContext, addr: u64, size: usize, value: u64) {
             if adc.control.get_start_sample() {
                 trace!("ADC sample with {:?}", adc.control);
                 adc.control.set_start_sample(false);
-                adc.control.set_irq_status(true);
-                match adc.control.get_mux() {
-                    ADCMux::MicPos => {
-                        if adc.control.get_touch_mode() == TouchMode::Auto {
-                            adc.xdata = adc.touch_x;
-                            adc.ydata = adc.touch_y;
-                        }
-                    },
-                    ADCMux::AIn2 => {
-                        adc.xdata = 1023;
-                        adc.ydata = 0;
-                    }
-                    _ => {
-                        adc.xdata = 0;
-                        adc.ydata = 0;
-                    }
+                adc.convert();
+                if adc.control.get_irq_enable() {
+                    post_interrupt(uc, InterruptNumber::ADC);
                 }
             }
         }
-        ADC_TSC => adc.touch_control.set(1, 15, value >> 1),
+        ADC_TSC => {
+            let prev_type = adc.touch_control.get_touchscreen_type();
+            adc.touch_control.set(1, 15, value >> 1);
+            let touchscreen_type = adc.touch_control.get_touchscreen_type();
+            if touchscreen_type != TouchscreenType::FourWire && touchscreen_type != prev_type {
+                warn!("ADC: {touchscreen_type:?} touchscreens are not supported. Converting as 4-wire.");
+            }
+        }
         _ => {
             log_unsupported_write!(addr, size, value);
         }
@@ -176,6 +279,15 @@ pub fn frame_step(uc: &mut UnicornContext) {
         return;
     }
 
+    let adc = &mut uc.get_data_mut().adc;
+    // Streaming stops once the bit is cleared.
+    if adc.streaming_pending && adc.control.get_streaming() {
+        adc.convert();
+        if adc.control.get_irq_enable() {
+            post_interrupt(uc, InterruptNumber::ADC);
+        }
+    }
+
     let adc = &uc.get_data().adc;
     if adc.control.get_touch_mode() == TouchMode::WaitForTrigger &&
         adc.control.get_wait_for_trigger_enable() && 
@@ -183,7 +295,7 @@ pub fn frame_step(uc: &mut UnicornContext) {
         adc.irq_on_frame_step
     {
         uc.get_data_mut().adc.irq_on_frame_step = false;
-        post_interrupt(uc, InterruptNumber::ADC, true, false);
+        post_interrupt(uc, InterruptNumber::ADC);
     }
 }
 
@@ -194,14 +306,19 @@ pub fn tick(uc: &mut UnicornContext, device: &mut Device) {
 
     //trace!("frame step {:?}", uc.get_data().adc.control);
 
+    if !device.mic_input.is_empty() {
+        let adc = &mut uc.get_data_mut().adc;
+        adc.mic_samples.extend(device.mic_input.drain(..));
+    }
+
     if uc.get_data().adc.control.get_touch_mode() == TouchMode::WaitForTrigger &&
         let Some(update) = device.input.check_touch()
     {
         trace!("Touch triggered");
+        let (width, height) = (uc.get_data().vpost.width(), uc.get_data().vpost.height());
         let adc = &mut uc.get_data_mut().adc;
         if let Some(pos) = update {
-            adc.touch_x = (X_MIN + ((pos.0 as f64 / 319.0) * (X_MAX - X_MIN))).round() as u16;
-            adc.touch_y = (Y_MIN + (((239 - pos.1) as f64 / 239.0) * (Y_MAX - Y_MIN))).round() as u16;
+            (adc.touch_x, adc.touch_y) = adc.touch_calibration.map(pos, width, height);
             adc.control.set_wait_for_trigger_status(true);
             adc.touch_control.set_pressing(true);
             trace!("New x={} y={}", adc.touch_x, adc.touch_y);
@@ -213,3 +330,55 @@ pub fn tick(uc: &mut UnicornContext, device: &mut Device) {
         adc.irq_on_frame_step = true;
     }
 }
+
+impl_snapshot_bitfield!(ADCControl, ADCTouchControl);
+impl_snapshot!(TouchCalibration { x_min, x_max, y_min, y_max });
+impl_snapshot!(ADCConfig {
+    control, touch_control, xdata, ydata, touch_x, touch_y, touch_calibration, mic_samples, mic_tone_hz, mic_phase,
+    mic_filtered, streaming_pending, irq_on_frame_step,
+});
+impl_reset!(ADCConfig { touch_calibration, mic_samples, mic_tone_hz });
+
+#[test]
+fn test_sample_mic() {
+    let mut adc = ADCConfig::default();
+    adc.control.set_audio_raw(true);
+    adc.mic_samples.extend([0, i16::MAX, i16::MIN]);
+    assert_eq!(adc.sample_mic(false), 512);
+    assert_eq!(adc.sample_mic(false), 1023);
+    assert_eq!(adc.sample_mic(true), 1023);
+
+    // Test tone goes up first, and filtered samples lag behind raw samples.
+    adc.mic_tone_hz = 1000;
+    let raw = adc.sample_mic(false);
+    adc.control.set_audio_raw(false);
+    adc.mic_phase = 0.0;
+    let filtered = adc.sample_mic(false);
+    assert!(raw > 512);
+    assert!(filtered > 512 && filtered < raw);
+}
+
+#[test]
+fn test_streaming_schedule() {
+    let mut adc = ADCConfig::default();
+    adc.control.set_mux(ADCMux::AIn2);
+    adc.convert();
+    assert!(!adc.streaming_pending);
+
+    adc.control.set_streaming(true);
+    adc.convert();
+    assert!(adc.streaming_pending);
+    assert_eq!(adc.xdata, 1023);
+}
+
+#[test]
+fn test_touch_calibration() {
+    let calibration = TouchCalibration::default();
+    assert_eq!(calibration.map((0, 239), 320, 240), (82, 95));
+    assert_eq!(calibration.map((319, 0), 320, 240), (918, 957));
+
+    let flipped: TouchCalibration = "900, 100, 50, 950".parse().unwrap();
+    assert_eq!(flipped.map((0, 0), 480, 272), (900, 950));
+    assert!("1,2,3".parse::<TouchCalibration>().is_err());
+    assert!("1,2,3,x".parse::<TouchCalibration>().is_err());
+}
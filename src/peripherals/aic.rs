@@ -1,6 +1,7 @@
 use log::{error, trace, warn};
 use unicorn_engine::RegisterARM;
 use crate::{device::{StopReason, UnicornContext, request_stop}, exception, log_unsupported_read, log_unsupported_write};
+use crate::{impl_reset, impl_snapshot};
 
 pub const BASE: u64 = 0xb8000000;
 pub const SIZE: usize = 0x1000;
@@ -20,24 +21,13 @@ const REG_AIC_SSCR: u64 = 0x128;
 const REG_AIC_SCCR: u64 = 0x12c;
 const REG_AIC_EOSCR: u64 = 0x130;
 
-const BCS8: [u8; 256] = [
-    0, 0, 1, 0, 2, 0, 1, 0, 3, 0, 1, 0, 2, 0, 1, 0,
-    4, 0, 1, 0, 2, 0, 1, 0, 3, 0, 1, 0, 2, 0, 1, 0,
-    5, 0, 1, 0, 2, 0, 1, 0, 3, 0, 1, 0, 2, 0, 1, 0,
-    4, 0, 1, 0, 2, 0, 1, 0, 3, 0, 1, 0, 2, 0, 1, 0,
-    6, 0, 1, 0, 2, 0, 1, 0, 3, 0, 1, 0, 2, 0, 1, 0,
-    4, 0, 1, 0, 2, 0, 1, 0, 3, 0, 1, 0, 2, 0, 1, 0,
-    5, 0, 1, 0, 2, 0, 1, 0, 3, 0, 1, 0, 2, 0, 1, 0,
-    4, 0, 1, 0, 2, 0, 1, 0, 3, 0, 1, 0, 2, 0, 1, 0,
-    7, 0, 1, 0, 2, 0, 1, 0, 3, 0, 1, 0, 2, 0, 1, 0,
-    4, 0, 1, 0, 2, 0, 1, 0, 3, 0, 1, 0, 2, 0, 1, 0,
-    5, 0, 1, 0, 2, 0, 1, 0, 3, 0, 1, 0, 2, 0, 1, 0,
-    4, 0, 1, 0, 2, 0, 1, 0, 3, 0, 1, 0, 2, 0, 1, 0,
-    6, 0, 1, 0, 2, 0, 1, 0, 3, 0, 1, 0, 2, 0, 1, 0,
-    4, 0, 1, 0, 2, 0, 1, 0, 3, 0, 1, 0, 2, 0, 1, 0,
-    5, 0, 1, 0, 2, 0, 1, 0, 3, 0, 1, 0, 2, 0, 1, 0,
-    4, 0, 1, 0, 2, 0, 1, 0, 3, 0, 1, 0, 2, 0, 1, 0,
-];
+/// Interrupt number reported by IPER and ISNR when no interrupt is being serviced. Channel 0 has no source connected.
+pub const SPURIOUS_INTERRUPT: u8 = 0;
+
+/// Source control bit routing a source to FIQ instead of IRQ. The other fields are the trigger type in bits 7:6 and
+/// the priority in bits 2:0. Assumed to be the lowest of the bits left over in between, which is unconfirmed, so
+/// `fiq_sources` can route sources to FIQ regardless.
+pub const SCR_FIQ: u32 = 1 << 3;
 
 /// Flag storage and manipulation for AIC. Actual interrupt dispatch logic is in the `tick()` Device callback.
 pub struct AICConfig {
@@ -51,7 +41,13 @@ pub struct AICConfig {
     pub status: [u32; 8],
     /// Interrupt mask bitmap (0 - masked, 1 - unmasked).
     pub enabled: u32,
-    pub current_interrupt: (u8, u8),
+    /// Last level seen on each source line, for edge detection.
+    pub source_levels: u32,
+    /// Priority and number of the interrupts being serviced, innermost last. An interrupt only preempts the one being
+    /// serviced if its priority is strictly higher.
+    pub in_service: Vec<(u8, u8)>,
+    /// Sources delivered as FIQ on top of those the guest routes with `SCR_FIQ`.
+    pub fiq_sources: u32,
 }
 
 impl Default for AICConfig {
@@ -62,14 +58,16 @@ impl Default for AICConfig {
             step: Default::default(),
             status: Default::default(),
             enabled: Default::default(),
-            current_interrupt: Default::default(),
+            source_levels: Default::default(),
+            in_service: Default::default(),
+            fiq_sources: Default::default(),
         }
     }
 }
 
 #[allow(dead_code, reason = "For documentation purpose.")]
 #[repr(u8)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum InterruptNumber {
     WDT = 1, EXTINT0, EXTINT1, EXTINT2, EXTINT3, SPU, I2S,  // 1..=7
     VPOST, VIDEOIN, GPU, BLT, FSC, HUART, TMR0, TMR1,  // 8..=15
@@ -77,15 +75,31 @@ pub enum InterruptNumber {
     UART, PWM, JPG, PWM2, KPI, DES, I2C, PWR,  // 24..=31
 }
 
-impl Into<u8> for InterruptNumber {
-    fn into(self) -> u8 {
-        self as u8
+impl InterruptNumber {
+    pub const ALL: [Self; 31] = [
+        Self::WDT, Self::EXTINT0, Self::EXTINT1, Self::EXTINT2, Self::EXTINT3, Self::SPU, Self::I2S,
+        Self::VPOST, Self::VIDEOIN, Self::GPU, Self::BLT, Self::FSC, Self::HUART, Self::TMR0, Self::TMR1,
+        Self::UDC, Self::SIC, Self::UHC, Self::EDMA, Self::SPIMS0, Self::SPIMS1, Self::ADC, Self::RTC,
+        Self::UART, Self::PWM, Self::JPG, Self::PWM2, Self::KPI, Self::DES, Self::I2C, Self::PWR,
+    ];
+}
+
+/// Parse an interrupt source from its name, like `TMR0`, or its number.
+pub fn parse_interrupt_number(name: &str) -> Result<InterruptNumber, String> {
+    InterruptNumber::ALL.into_iter()
+        .find(|intno| format!("{intno:?}").eq_ignore_ascii_case(name) || name.parse() == Ok(*intno as u8))
+        .ok_or_else(|| format!("Unknown interrupt source {name}"))
+}
+
+impl From<InterruptNumber> for u8 {
+    fn from(intno: InterruptNumber) -> Self {
+        intno as u8
     }
 }
 
-impl Into<usize> for InterruptNumber {
-    fn into(self) -> usize {
-        usize::from(self as u8)
+impl From<InterruptNumber> for usize {
+    fn from(intno: InterruptNumber) -> Self {
+        usize::from(intno as u8)
     }
 }
 
@@ -107,10 +121,20 @@ impl AICConfig {
         u8::try_from((self.levels[offset] >> shift) & 0xff).unwrap()
     }
 
-    /// Check whether there's a need to fire ann interrupt, and if so, record it and return `true`.
-    pub fn check_interrupt(&mut self, intno: InterruptNumber, incoming: bool, latched: bool) -> bool {
-        trace!("IRQ check {intno:?} in={incoming} latch={latched}");
+    /// Update the level of a source line, and check whether there's a need to fire an interrupt. If so, record it and
+    /// return `true`.
+    ///
+    /// The level is tracked even while the source is masked, so edge-triggered sources fire exactly once per edge.
+    pub fn check_interrupt(&mut self, intno: InterruptNumber, incoming: bool) -> bool {
         let mask: u32 = intno.as_mask();
+        let latched = self.source_levels & mask != 0;
+        trace!("IRQ check {intno:?} in={incoming} latch={latched}");
+        if incoming {
+            self.source_levels |= mask;
+        } else {
+            self.source_levels &= !mask;
+        }
+
         if self.enabled & mask == 0 {
             trace!(" => IRQ is masked.");
             return false;
@@ -162,8 +186,8 @@ impl AICConfig {
         for i in 0u8..32u8 {
             let mask = 1 << i;
             if status & mask != 0 {
-                let offset = usize::from(i / 8);
-                let shift = (i % 8) * 8;
+                let offset = usize::from(i / 4);
+                let shift = (i % 4) * 8;
                 let level = u8::try_from((self.levels[offset] >> shift) & 0xff).unwrap();
 
                 let prio = level & 0x7;
@@ -181,52 +205,85 @@ impl AICConfig {
         self.set_joint_status(js | mask);
     }
 
+    /// Trigger interrupts from software, like hardware sources would. Masked sources are left alone.
+    pub fn software_trigger(&mut self, mask: u32) {
+        self.apply_status_set_mask(mask & self.enabled);
+    }
+
     /// Apply a clear mask to the joint status bitfield.
     pub fn apply_status_clear_mask(&mut self, mask: u32) {
         let js = self.get_joint_status();
         self.set_joint_status(js & !mask);
     }
 
-    pub fn next_interrupt(&self, skip_fiq: bool) -> (u8, u8) {
-        if self.status_map == 0 {
-            warn!("Interrupt status table is empty. This is probably a redundant check.");
-            return (0, 0);
-        }
-        let fiq_mask = !u8::from(skip_fiq);
-        if self.status_map & fiq_mask == 0 {
-            return (0, 0);
+    /// Priorities allowed to preempt the interrupt being serviced, as a bitmap like `status_map`.
+    fn preempt_mask(&self) -> u8 {
+        match self.in_service.last() {
+            Some(&(prio, _)) => (1u8 << prio) - 1,
+            None => 0xff,
         }
-        let next_pending_prio = BCS8[usize::from(self.status_map & fiq_mask)];
-        let next_pending = self.status[usize::from(next_pending_prio)];
+    }
 
-        if next_pending == 0 {
-            error!("Interrupt status table has bad index at prio {next_pending_prio}. This is a bug.");
-            return (next_pending_prio, 0)
-        }
+    /// Sources delivered as FIQ, as a bitmap. These are the sources the guest routes to FIQ in their source control
+    /// register, plus `fiq_sources`.
+    fn fiq_mask(&self) -> u32 {
+        (0u8..32).filter(|&i| {
+            let level = (self.levels[usize::from(i / 4)] >> ((i % 4) * 8)) & 0xff;
+            level & SCR_FIQ != 0
+        }).fold(self.fiq_sources, |mask, i| mask | (1 << i))
+    }
 
-        let mut num = 0;
-        for i in 1..32 {
-            if (1 << i) & next_pending != 0 {
-                num = i;
-                break;
-            }
-        };
+    /// Whether an interrupt is delivered as FIQ rather than IRQ.
+    pub fn exception_type(&self, num: u8) -> exception::ExceptionType {
+        if self.fiq_mask() & (1 << num) != 0 {
+            exception::ExceptionType::FIQ
+        } else {
+            exception::ExceptionType::IRQ
+        }
+    }
 
-        (next_pending_prio, num)
+    /// Priority and number of the next interrupt to service, or `None` if nothing is pending or nothing pending can
+    /// preempt the interrupt being serviced. Sources with `SCR_FIQ` set or in `fiq_sources` are delivered as FIQ and
+    /// the others as IRQ, which the CPU may mask separately.
+    pub fn next_interrupt(&self, skip_fiq: bool, skip_irq: bool) -> Option<(u8, u8)> {
+        let pending_map = self.status_map & self.preempt_mask();
+        let fiq = self.fiq_mask();
+        (0..8u8).filter(|&prio| pending_map & (1 << prio) != 0).find_map(|prio| {
+            let cpu_mask = match (skip_fiq, skip_irq) {
+                (false, false) => u32::MAX,
+                (true, false) => !fiq,
+                (false, true) => fiq,
+                (true, true) => 0,
+            };
+            // Sources masked after becoming pending stay pending, but are not dispatched until unmasked.
+            let pending = self.status[usize::from(prio)] & self.enabled & cpu_mask;
+            (pending != 0).then(|| (prio, u8::try_from(pending.trailing_zeros()).unwrap()))
+        })
     }
 
-    pub fn pop_next_interrupt(&mut self, skip_fiq: bool) -> (u8, u8) {
-        let (prio, num) = self.next_interrupt(skip_fiq);
-        if prio == 0 && num == 0 {
-            return (0, 0);
-        }
-        self.current_interrupt = (prio, num);
+    /// Start servicing the next pending interrupt, nesting it in the one being serviced if any. Returns its priority
+    /// and number, or `None` if nothing can be dispatched, in which case the interrupts being serviced are left
+    /// untouched.
+    pub fn pop_next_interrupt(&mut self, skip_fiq: bool, skip_irq: bool) -> Option<(u8, u8)> {
+        let (prio, num) = self.next_interrupt(skip_fiq, skip_irq)?;
+        self.in_service.push((prio, num));
         let new_status = self.status[usize::from(prio)] & !(1 << num);
         self.status[usize::from(prio)] = new_status;
         if new_status == 0 {
             self.status_map &= !(1 << prio);
         }
-        (prio, num)
+        Some((prio, num))
+    }
+
+    /// Number of the innermost interrupt being serviced, as reported by IPER and ISNR.
+    pub fn current_number(&self) -> u8 {
+        self.in_service.last().map_or(SPURIOUS_INTERRUPT, |&(_, num)| num)
+    }
+
+    /// Finish servicing the innermost interrupt, resuming the one it preempted if any. Returns the priority and number
+    /// of the interrupt that was finished, or `None` if nothing was being serviced.
+    pub fn end_of_service(&mut self) -> Option<(u8, u8)> {
+        self.in_service.pop()
     }
 }
 
@@ -238,15 +295,15 @@ pub fn read(uc: &mut UnicornContext, addr: u64, size: usize) -> u64 {
 
     match addr {
         REG_AIC_SCR_START..REG_AIC_SCR_END => {
-            if addr % 4 != 0 {
+            if !addr.is_multiple_of(4) {
                 log_unsupported_read!(addr, size);
                 return 0;
             }
 
             uc.get_data().aic.levels[usize::try_from(addr / 4).unwrap()].into()
         }
-        REG_AIC_IPER => u64::from(uc.get_data().aic.current_interrupt.1) << 2,
-        REG_AIC_ISNR => uc.get_data().aic.current_interrupt.1.into(),
+        REG_AIC_IPER => u64::from(uc.get_data().aic.current_number()) << 2,
+        REG_AIC_ISNR => uc.get_data().aic.current_number().into(),
         REG_AIC_IMR => uc.get_data().aic.enabled.into(),
         REG_AIC_ISR => {
             uc.get_data_mut().aic.step = false;
@@ -268,7 +325,7 @@ pub fn write(uc: &mut UnicornContext, addr: u64, size: usize, value: u64) {
     let v32 = u32::try_from(value & 0xffffffff).unwrap();
     match addr {
         REG_AIC_SCR_START..REG_AIC_SCR_END => {
-            if addr % 4 != 0 {
+            if !addr.is_multiple_of(4) {
                 log_unsupported_write!(addr, size, value);
             }
 
@@ -276,8 +333,11 @@ pub fn write(uc: &mut UnicornContext, addr: u64, size: usize, value: u64) {
         }
         REG_AIC_IMR => uc.get_data_mut().aic.enabled = v32,
         REG_AIC_ISR => {
-            uc.get_data_mut().aic.set_joint_status(v32);
-            if v32 != 0 {
+            let aic = &mut uc.get_data_mut().aic;
+            // Bits of masked sources can only be cleared.
+            let status = v32 & (aic.enabled | aic.get_joint_status());
+            aic.set_joint_status(status);
+            if status != 0 {
                 uc.get_data_mut().aic.step = true;
                 request_stop(uc, StopReason::Tick);
             }
@@ -285,8 +345,9 @@ pub fn write(uc: &mut UnicornContext, addr: u64, size: usize, value: u64) {
         REG_AIC_MECR => uc.get_data_mut().aic.apply_enable_mask(v32),
         REG_AIC_MDCR => uc.get_data_mut().aic.apply_disable_mask(v32),
         REG_AIC_SSCR => {
-            uc.get_data_mut().aic.apply_status_set_mask(v32);
-            if v32 != 0 {
+            let aic = &mut uc.get_data_mut().aic;
+            aic.software_trigger(v32);
+            if v32 & aic.enabled != 0 {
                 uc.get_data_mut().aic.step = true;
                 request_stop(uc, StopReason::Tick);
             }
@@ -296,6 +357,10 @@ pub fn write(uc: &mut UnicornContext, addr: u64, size: usize, value: u64) {
             // Clear is guaranteed to not trigger an interrupt, so no request_stop() here.
         }
         REG_AIC_EOSCR => {
+            match uc.get_data_mut().aic.end_of_service() {
+                Some((prio, num)) => trace!("End of service of interrupt {num} at priority {prio}"),
+                None => warn!("End of service with no interrupt being serviced"),
+            }
             // Request stop so aic::tick() can dispatch the next interrupt.
             if uc.get_data().aic.get_joint_status() != 0 {
                 uc.get_data_mut().aic.step = true;
@@ -308,32 +373,218 @@ pub fn write(uc: &mut UnicornContext, addr: u64, size: usize, value: u64) {
 }
 
 pub fn tick(uc: &mut UnicornContext) {
-    if uc.get_data().aic.step && uc.get_data().aic.status_map != 0 {
-        if
-            let Ok(cpsr) = uc.reg_read(RegisterARM::CPSR) &&
-            cpsr & 0b11000000 != 0b11000000
-        {
-            let (prio, num) = uc.get_data_mut().aic.pop_next_interrupt(cpsr & 0b1000000 != 0);
-            if prio == 0 && num == 0 {
-                return;
-            }
-            exception::call_exception_handler(uc, match prio {
-                0 => exception::ExceptionType::FIQ,
-                _ => exception::ExceptionType::IRQ,
-            }).unwrap_or_else(|err| {
-                error!("Failed to invoke exception handler: {err:?}.");
-            });
-        }
+    if
+        uc.get_data().aic.step &&
+        uc.get_data().aic.status_map != 0 &&
+        let Ok(cpsr) = uc.reg_read(RegisterARM::CPSR) &&
+        cpsr & 0b11000000 != 0b11000000
+    {
+        let (skip_fiq, skip_irq) = (cpsr & 0b1000000 != 0, cpsr & 0b10000000 != 0);
+        let Some((_prio, num)) = uc.get_data_mut().aic.pop_next_interrupt(skip_fiq, skip_irq) else {
+            return;
+        };
+        let exc_type = uc.get_data().aic.exception_type(num);
+        exception::call_exception_handler(uc, exc_type).unwrap_or_else(|err| {
+            error!("Failed to invoke exception handler: {err:?}.");
+        });
     }
 }
 
-/// Utility function for the host part of the emulator to inject an interrupt.
+/// Utility function for the host part of the emulator to drive the line of an interrupt source.
 ///
 /// This will automatically initiate an emulator stop when necessary.
 #[inline]
-pub fn post_interrupt(uc: &mut UnicornContext, intno: InterruptNumber, incoming: bool, latched: bool) {
-    if uc.get_data_mut().aic.check_interrupt(intno, incoming, latched) {
+pub fn set_interrupt_level(uc: &mut UnicornContext, intno: InterruptNumber, level: bool) {
+    if uc.get_data_mut().aic.check_interrupt(intno, level) {
         uc.get_data_mut().aic.step = true;
         request_stop(uc, StopReason::Tick);
     }
 }
+
+/// Utility function for the host part of the emulator to inject an interrupt, as a pulse on the source line.
+///
+/// This will automatically initiate an emulator stop when necessary.
+#[inline]
+pub fn post_interrupt(uc: &mut UnicornContext, intno: InterruptNumber) {
+    set_interrupt_level(uc, intno, true);
+    set_interrupt_level(uc, intno, false);
+}
+
+impl_snapshot!(AICConfig { levels, status_map, step, status, enabled, source_levels, in_service });
+impl_reset!(AICConfig { fiq_sources });
+
+#[test]
+fn test_spurious_interrupt() {
+    let mut aic = AICConfig::default();
+    aic.apply_enable_mask(InterruptNumber::TMR0.as_mask());
+    assert!(aic.check_interrupt(InterruptNumber::TMR0, true));
+    assert_eq!(aic.pop_next_interrupt(false, false), Some((7, InterruptNumber::TMR0 as u8)));
+    assert_eq!(aic.current_number(), InterruptNumber::TMR0 as u8);
+
+    // Nothing else pending. The interrupt being serviced stays in place.
+    assert_eq!(aic.pop_next_interrupt(false, false), None);
+    assert_eq!(aic.current_number(), InterruptNumber::TMR0 as u8);
+
+    aic.end_of_service();
+    assert_eq!(aic.current_number(), SPURIOUS_INTERRUPT);
+}
+
+#[test]
+fn test_preemption() {
+    let mut aic = AICConfig::default();
+    // WDT at priority 1, timers at the default priority 7.
+    aic.levels[0] = 0x47474147;
+    aic.apply_enable_mask(InterruptNumber::WDT.as_mask() | InterruptNumber::TMR0.as_mask() | InterruptNumber::TMR1.as_mask());
+
+    aic.check_interrupt(InterruptNumber::TMR0, true);
+    assert_eq!(aic.pop_next_interrupt(false, false), Some((7, InterruptNumber::TMR0 as u8)));
+
+    // Same priority waits for the end of service.
+    aic.check_interrupt(InterruptNumber::TMR1, true);
+    assert_eq!(aic.pop_next_interrupt(false, false), None);
+
+    // Higher priority preempts, unless the CPU masks IRQ.
+    aic.check_interrupt(InterruptNumber::WDT, true);
+    assert_eq!(aic.pop_next_interrupt(false, true), None);
+    assert_eq!(aic.pop_next_interrupt(false, false), Some((1, InterruptNumber::WDT as u8)));
+    assert_eq!(aic.current_number(), InterruptNumber::WDT as u8);
+
+    aic.end_of_service();
+    assert_eq!(aic.current_number(), InterruptNumber::TMR0 as u8);
+    assert_eq!(aic.pop_next_interrupt(false, false), None);
+
+    aic.end_of_service();
+    assert_eq!(aic.pop_next_interrupt(false, false), Some((7, InterruptNumber::TMR1 as u8)));
+}
+
+#[test]
+fn test_nested_end_of_service() {
+    let mut aic = AICConfig::default();
+    // WDT at priority 1, TMR1 at priority 4, TMR0 at the default priority 7.
+    aic.levels[0] = 0x47474147;
+    aic.levels[3] = 0x44474747;
+    aic.apply_enable_mask(InterruptNumber::WDT.as_mask() | InterruptNumber::TMR0.as_mask() | InterruptNumber::TMR1.as_mask());
+
+    aic.check_interrupt(InterruptNumber::TMR0, true);
+    assert_eq!(aic.pop_next_interrupt(false, false), Some((7, InterruptNumber::TMR0 as u8)));
+    aic.check_interrupt(InterruptNumber::TMR1, true);
+    assert_eq!(aic.pop_next_interrupt(false, false), Some((4, InterruptNumber::TMR1 as u8)));
+    aic.check_interrupt(InterruptNumber::WDT, true);
+    assert_eq!(aic.pop_next_interrupt(false, false), Some((1, InterruptNumber::WDT as u8)));
+
+    // TMR0 again can't preempt any of them until they all end.
+    aic.check_interrupt(InterruptNumber::TMR0, true);
+    assert_eq!(aic.end_of_service(), Some((1, InterruptNumber::WDT as u8)));
+    assert_eq!(aic.pop_next_interrupt(false, false), None);
+    assert_eq!(aic.end_of_service(), Some((4, InterruptNumber::TMR1 as u8)));
+    assert_eq!(aic.current_number(), InterruptNumber::TMR0 as u8);
+    assert_eq!(aic.pop_next_interrupt(false, false), None);
+    assert_eq!(aic.end_of_service(), Some((7, InterruptNumber::TMR0 as u8)));
+    assert_eq!(aic.pop_next_interrupt(false, false), Some((7, InterruptNumber::TMR0 as u8)));
+    assert_eq!(aic.end_of_service(), Some((7, InterruptNumber::TMR0 as u8)));
+    assert_eq!(aic.end_of_service(), None);
+}
+
+#[test]
+fn test_edge_trigger() {
+    let mut aic = AICConfig::default();
+    aic.apply_enable_mask(InterruptNumber::EXTINT0.as_mask() | InterruptNumber::EXTINT1.as_mask());
+    // EXTINT0 on rising edges, EXTINT1 on falling edges.
+    aic.levels[0] = 0x87c74747;
+
+    assert!(!aic.check_interrupt(InterruptNumber::EXTINT0, false));
+    assert!(aic.check_interrupt(InterruptNumber::EXTINT0, true));
+    assert!(!aic.check_interrupt(InterruptNumber::EXTINT0, true));
+    assert!(!aic.check_interrupt(InterruptNumber::EXTINT0, false));
+    assert!(aic.check_interrupt(InterruptNumber::EXTINT0, true));
+
+    assert!(!aic.check_interrupt(InterruptNumber::EXTINT1, true));
+    assert!(aic.check_interrupt(InterruptNumber::EXTINT1, false));
+    assert!(!aic.check_interrupt(InterruptNumber::EXTINT1, false));
+
+    // Edges seen while masked are not replayed on unmask.
+    aic.apply_disable_mask(InterruptNumber::EXTINT1.as_mask());
+    assert!(!aic.check_interrupt(InterruptNumber::EXTINT1, true));
+    aic.apply_enable_mask(InterruptNumber::EXTINT1.as_mask());
+    assert!(!aic.check_interrupt(InterruptNumber::EXTINT1, true));
+    assert!(aic.check_interrupt(InterruptNumber::EXTINT1, false));
+}
+
+#[test]
+fn test_software_trigger() {
+    let mut aic = AICConfig::default();
+    aic.apply_enable_mask(InterruptNumber::TMR0.as_mask());
+
+    // Masked sources can't be forced pending.
+    aic.software_trigger(InterruptNumber::TMR1.as_mask());
+    assert_eq!(aic.get_joint_status(), 0);
+
+    aic.software_trigger(InterruptNumber::TMR0.as_mask());
+    assert_eq!(aic.get_joint_status(), InterruptNumber::TMR0.as_mask());
+
+    // Masking a pending source holds it back until it is unmasked again.
+    aic.apply_disable_mask(InterruptNumber::TMR0.as_mask());
+    assert_eq!(aic.pop_next_interrupt(false, false), None);
+    aic.apply_enable_mask(InterruptNumber::TMR0.as_mask());
+    assert_eq!(aic.pop_next_interrupt(false, false), Some((7, InterruptNumber::TMR0 as u8)));
+}
+
+#[test]
+fn test_fiq_routing() {
+    let mut aic = AICConfig::default();
+    // WDT at priority 0 as IRQ, timers at the default priority 7 with TMR1 routed to FIQ.
+    aic.levels[0] = 0x47474047;
+    aic.levels[3] = 0x4f474747;
+    aic.apply_enable_mask(InterruptNumber::WDT.as_mask() | InterruptNumber::TMR0.as_mask() | InterruptNumber::TMR1.as_mask());
+    assert_eq!(aic.exception_type(InterruptNumber::WDT as u8), exception::ExceptionType::IRQ);
+    assert_eq!(aic.exception_type(InterruptNumber::TMR0 as u8), exception::ExceptionType::IRQ);
+    assert_eq!(aic.exception_type(InterruptNumber::TMR1 as u8), exception::ExceptionType::FIQ);
+
+    aic.check_interrupt(InterruptNumber::TMR0, true);
+    aic.check_interrupt(InterruptNumber::TMR1, true);
+    // TMR0 comes first at the same priority, unless the CPU masks IRQ.
+    assert_eq!(aic.next_interrupt(false, false), Some((7, InterruptNumber::TMR0 as u8)));
+    assert_eq!(aic.next_interrupt(true, false), Some((7, InterruptNumber::TMR0 as u8)));
+    assert_eq!(aic.next_interrupt(false, true), Some((7, InterruptNumber::TMR1 as u8)));
+    assert_eq!(aic.next_interrupt(true, true), None);
+
+    // Priority alone does not make an interrupt FIQ.
+    aic.check_interrupt(InterruptNumber::WDT, true);
+    assert_eq!(aic.next_interrupt(true, false), Some((0, InterruptNumber::WDT as u8)));
+    assert_eq!(aic.next_interrupt(false, true), Some((7, InterruptNumber::TMR1 as u8)));
+
+    // Sources can be routed to FIQ from the host as well.
+    aic.fiq_sources = InterruptNumber::WDT.as_mask();
+    assert_eq!(aic.exception_type(InterruptNumber::WDT as u8), exception::ExceptionType::FIQ);
+    assert_eq!(aic.next_interrupt(false, true), Some((0, InterruptNumber::WDT as u8)));
+
+    assert_eq!(parse_interrupt_number("tmr1"), Ok(InterruptNumber::TMR1));
+    assert_eq!(parse_interrupt_number("15"), Ok(InterruptNumber::TMR1));
+    assert!(parse_interrupt_number("TMR2").is_err());
+}
+
+#[test]
+fn test_fiq_delivery() {
+    use unicorn_engine::{Arch, ArmCpuModel, Mode, Unicorn};
+    use crate::device::ExtraState;
+
+    const RESUME_PC: u64 = 0x100;
+
+    let mut uc = Unicorn::new_with_data(Arch::ARM, Mode::LITTLE_ENDIAN, Box::new(ExtraState::default())).unwrap();
+    uc.ctl_set_cpu_model(ArmCpuModel::UC_CPU_ARM_926.into()).unwrap();
+    // System mode with IRQ masked, so only FIQ can be taken.
+    uc.reg_write(RegisterARM::CPSR, 0b10011111).unwrap();
+    uc.set_pc(RESUME_PC).unwrap();
+
+    // TMR1 routed to FIQ at the default priority.
+    let aic = &mut uc.get_data_mut().aic;
+    aic.levels[3] = 0x47474747 | (SCR_FIQ << 24);
+    aic.apply_enable_mask(InterruptNumber::TMR1.as_mask());
+    set_interrupt_level(&mut uc, InterruptNumber::TMR1, true);
+    tick(&mut uc);
+
+    assert_eq!(uc.get_data().aic.current_number(), InterruptNumber::TMR1 as u8);
+    assert_eq!(uc.reg_read(RegisterARM::CPSR).unwrap() & 0b11111, 0b10001);
+    assert_eq!(uc.reg_read(RegisterARM::LR).unwrap(), RESUME_PC + 4);
+    assert_eq!(uc.pc_read().unwrap(), exception::ExceptionType::FIQ.to_vector_address(exception::HLE_VECTOR_BASE));
+}
@@ -1,6 +1,8 @@
 use bit_field::{B4, B5, bitfield};
-use log::{trace, warn};
+use log::{error, trace, warn};
+use unicorn_engine::uc_error;
 use crate::{device::{StopReason, UnicornContext, request_stop}, log_unsupported_read, log_unsupported_write, peripherals::aic::{InterruptNumber, post_interrupt}};
+use crate::{RuntimeError, impl_reset, impl_snapshot, impl_snapshot_bitfield, snapshot::{Snapshot, load_new}};
 
 pub const BASE: u64 = 0xb100d000;
 pub const SIZE: usize = 0x1000;
@@ -9,6 +11,7 @@ const REG_SET: u64 = 0x0;
 const REG_SFMT: u64 = 0x4;
 const REG_DFMT: u64 = 0x8;
 const REG_BLTINTCR: u64 = 0xc;
+const REG_MLTA: u64 = 0x10;
 const REG_SWIDTH: u64 = 0x20;
 const REG_SHEIGHT: u64 = 0x24;
 const REG_DWIDTH: u64 = 0x28;
@@ -23,6 +26,7 @@ const REG_SSTRIDE: u64 = 0x48;
 const REG_DSTRIDE: u64 = 0x4c;
 const REG_OFFSETX: u64 = 0x50;
 const REG_OFFSETY: u64 = 0x54;
+const REG_FILLARGB: u64 = 0x60;
 
 #[bitfield]
 #[derive(Default)]
@@ -98,15 +102,96 @@ impl From<u64> for DestinationFormat {
     }
 }
 
-impl Into<u64> for SourceFormat {
-    fn into(self) -> u64 {
-        1 << ((self as u8) - 1)
+impl SourceFormat {
+    /// Number of bytes per pixel of the directly convertible formats, or `None` for palette formats.
+    pub fn bpp(&self) -> Option<usize> {
+        match self {
+            Self::ARGB8888 => Some(4),
+            Self::RGB565 => Some(2),
+            _ => None,
+        }
+    }
+
+    /// Unpack a little endian pixel in this format into an ARGB8888 color.
+    pub fn unpack(&self, pixel: &[u8]) -> u32 {
+        match self {
+            Self::ARGB8888 => u32::from_le_bytes(pixel.try_into().unwrap()),
+            Self::RGB565 => DestinationFormat::RGB565.unpack(pixel),
+            _ => 0,
+        }
+    }
+}
+
+impl DestinationFormat {
+    /// Pack an ARGB8888 color into this format, in little endian.
+    pub fn pack(&self, argb: u32) -> Vec<u8> {
+        let [b, g, r, _a] = argb.to_le_bytes();
+        let (r, g, b) = (u16::from(r), u16::from(g), u16::from(b));
+        match self {
+            Self::ARGB8888 => argb.to_le_bytes().to_vec(),
+            Self::RGB565 => ((r >> 3) << 11 | (g >> 2) << 5 | (b >> 3)).to_le_bytes().to_vec(),
+            Self::RGB555 => ((r >> 3) << 10 | (g >> 3) << 5 | (b >> 3)).to_le_bytes().to_vec(),
+            Self::Unspecified => vec![],
+        }
+    }
+
+    /// Unpack a little endian pixel in this format into an opaque ARGB8888 color.
+    pub fn unpack(&self, pixel: &[u8]) -> u32 {
+        let (r, g, b) = match self {
+            Self::ARGB8888 => return u32::from_le_bytes(pixel.try_into().unwrap()) | 0xff000000,
+            Self::RGB565 => {
+                let px = u16::from_le_bytes(pixel.try_into().unwrap());
+                (expand_bits(px >> 11, 5), expand_bits(px >> 5, 6), expand_bits(px, 5))
+            }
+            Self::RGB555 => {
+                let px = u16::from_le_bytes(pixel.try_into().unwrap());
+                (expand_bits(px >> 10, 5), expand_bits(px >> 5, 5), expand_bits(px, 5))
+            }
+            Self::Unspecified => return 0,
+        };
+        u32::from_le_bytes([b, g, r, 0xff])
+    }
+
+    /// Number of bytes per pixel.
+    pub fn bpp(&self) -> usize {
+        match self {
+            Self::ARGB8888 => 4,
+            Self::RGB565 | Self::RGB555 => 2,
+            Self::Unspecified => 0,
+        }
+    }
+}
+
+/// Expand the lowest `bits` bits of a color channel to 8 bits.
+#[inline]
+fn expand_bits(value: u16, bits: u32) -> u8 {
+    let value = value & ((1 << bits) - 1);
+    u8::try_from((value << (8 - bits)) | (value >> (2 * bits - 8))).unwrap()
+}
+
+/// Composite an ARGB8888 color over an opaque ARGB8888 color, with an additional global alpha in 8.8 fixed point.
+fn blend(src: u32, dest: u32, global_alpha: u16) -> u32 {
+    let [sb, sg, sr, sa] = src.to_le_bytes();
+    let alpha = ((u32::from(sa) * u32::from(global_alpha)) >> 8).min(0xff);
+    if alpha == 0xff {
+        return src | 0xff000000;
+    } else if alpha == 0 {
+        return dest;
     }
+    let [db, dg, dr, _] = dest.to_le_bytes();
+    let mix = |s: u8, d: u8| u8::try_from((u32::from(s) * alpha + u32::from(d) * (0xff - alpha) + 0x7f) / 0xff).unwrap();
+    u32::from_le_bytes([mix(sb, db), mix(sg, dg), mix(sr, dr), 0xff])
 }
 
-impl Into<u64> for DestinationFormat {
-    fn into(self) -> u64 {
-        1 << ((self as u8) - 1)
+impl From<SourceFormat> for u64 {
+    fn from(format: SourceFormat) -> Self {
+        1 << ((format as u8) - 1)
+    }
+}
+
+impl From<DestinationFormat> for u64 {
+    fn from(format: DestinationFormat) -> Self {
+        1 << ((format as u8) - 1)
     }
 }
 
@@ -131,12 +216,46 @@ pub struct BLTConfig {
     pub element_d: i32,
     pub translate_x: i32,
     pub translate_y: i32,
+    /// Global alpha multiplier in 8.8 fixed point, applied when `apply_alpha_transform` is set.
+    pub alpha_multiplier: u16,
+    /// Fill color in ARGB8888.
+    pub fill_color: u32,
+}
+
+/// Inverse mapping from destination to source coordinates, in 16.16 fixed point.
+///
+/// `src_x = a * x + c * y + translate_x`, `src_y = b * x + d * y + translate_y`
+#[derive(Debug, Clone, Copy)]
+pub struct Transform {
+    pub a: i32,
+    pub b: i32,
+    pub c: i32,
+    pub d: i32,
+    pub translate_x: i32,
+    pub translate_y: i32,
 }
 
-// #[inline]
-// fn fixed1616_to_f32(fixed: i32) -> f32 {
-//     (f64::from(fixed) / 65536.0) as f32
-// }
+impl Transform {
+    pub fn is_singular(&self) -> bool {
+        i64::from(self.a) * i64::from(self.d) - i64::from(self.b) * i64::from(self.c) == 0
+    }
+
+    /// Map a destination pixel to a source pixel. Coordinates may be out of bounds.
+    pub fn map(&self, x: usize, y: usize) -> (i64, i64) {
+        let (x, y) = (x as i64, y as i64);
+        let src_x = i64::from(self.a) * x + i64::from(self.c) * y + i64::from(self.translate_x);
+        let src_y = i64::from(self.b) * x + i64::from(self.d) * y + i64::from(self.translate_y);
+        (src_x >> 16, src_y >> 16)
+    }
+}
+
+/// Geometry of a pixel buffer.
+#[derive(Debug, Clone, Copy)]
+struct Surface {
+    width: usize,
+    height: usize,
+    pitch: usize,
+}
 
 pub fn read(uc: &mut UnicornContext, addr: u64, size: usize) -> u64 {
     if size != 4 {
@@ -151,6 +270,7 @@ pub fn read(uc: &mut UnicornContext, addr: u64, size: usize) -> u64 {
         REG_SFMT => blt.src_format.into(),
         REG_DFMT => blt.dest_format.into(),
         REG_BLTINTCR => blt.status.get(0, 8),
+        REG_MLTA => blt.alpha_multiplier.into(),
         REG_SWIDTH => blt.src_width.into(),
         REG_SHEIGHT => blt.src_height.into(),
         REG_DWIDTH => blt.dest_width.into(),
@@ -165,6 +285,7 @@ pub fn read(uc: &mut UnicornContext, addr: u64, size: usize) -> u64 {
         REG_DSTRIDE => blt.dest_pitch.into(),
         REG_OFFSETX => blt.translate_x.cast_unsigned().into(),
         REG_OFFSETY => blt.translate_y.cast_unsigned().into(),
+        REG_FILLARGB => blt.fill_color.into(),
         _ => {
             log_unsupported_read!(addr, size);
             0
@@ -196,6 +317,7 @@ pub fn write(uc: &mut UnicornContext, addr: u64, size: usize, value: u64) {
             }
             blt.status.set_enabled(value & 0b10 != 0);
         },
+        REG_MLTA => blt.alpha_multiplier = u16::try_from(value & 0xffff).unwrap(),
         REG_SWIDTH => blt.src_width = u16::try_from(value & 0xffff).unwrap(),
         REG_SHEIGHT => blt.src_height = u16::try_from(value & 0xffff).unwrap(),
         REG_DWIDTH => blt.dest_width = u16::try_from(value & 0xffff).unwrap(),
@@ -210,76 +332,189 @@ pub fn write(uc: &mut UnicornContext, addr: u64, size: usize, value: u64) {
         REG_DSTRIDE => blt.dest_pitch = u16::try_from(value & 0xffff).unwrap(),
         REG_OFFSETX => blt.translate_x = u32::try_from(value & 0xffffffff).unwrap().cast_signed(),
         REG_OFFSETY => blt.translate_y = u32::try_from(value & 0xffffffff).unwrap().cast_signed(),
+        REG_FILLARGB => blt.fill_color = u32::try_from(value & 0xffffffff).unwrap(),
         _ => {
             log_unsupported_write!(addr, size, value);
         }
     };
 }
 
+/// Sample every destination pixel from the source through `transform`. Destination pixels that map outside of the
+/// source are left untouched.
+fn transform_copy(src: &[u8], src_surface: Surface, dest: &mut [u8], dest_surface: Surface, bpp: usize, transform: &Transform) {
+    let src_width = src_surface.width.min(src_surface.pitch / bpp);
+    let dest_width = dest_surface.width.min(dest_surface.pitch / bpp);
+    for y in 0..dest_surface.height {
+        for x in 0..dest_width {
+            let (src_x, src_y) = transform.map(x, y);
+            let (Ok(src_x), Ok(src_y)) = (usize::try_from(src_x), usize::try_from(src_y)) else {
+                continue;
+            };
+            if src_x >= src_width || src_y >= src_surface.height {
+                continue;
+            }
+            let src_offset = src_y * src_surface.pitch + src_x * bpp;
+            let dest_offset = y * dest_surface.pitch + x * bpp;
+            if src_offset + bpp > src.len() || dest_offset + bpp > dest.len() {
+                continue;
+            }
+            dest[dest_offset..dest_offset + bpp].copy_from_slice(&src[src_offset..src_offset + bpp]);
+        }
+    }
+}
+
+/// Convert pixels from the source format into the destination format, compositing with the source alpha unless
+/// `global_alpha` is `None`.
+fn convert_copy(
+    src: &[u8], src_surface: Surface, src_format: SourceFormat,
+    dest: &mut [u8], dest_surface: Surface, dest_format: DestinationFormat,
+    global_alpha: Option<u16>,
+) {
+    let (Some(src_bpp), dest_bpp) = (src_format.bpp(), dest_format.bpp()) else {
+        return;
+    };
+    if dest_bpp == 0 {
+        return;
+    }
+    let width = src_surface.width.min(dest_surface.width).min(src_surface.pitch / src_bpp).min(dest_surface.pitch / dest_bpp);
+    let height = src_surface.height.min(dest_surface.height);
+    for y in 0..height {
+        for x in 0..width {
+            let src_offset = y * src_surface.pitch + x * src_bpp;
+            let dest_offset = y * dest_surface.pitch + x * dest_bpp;
+            let src_pixel = src_format.unpack(&src[src_offset..src_offset + src_bpp]);
+            let dest_pixel = &mut dest[dest_offset..dest_offset + dest_bpp];
+            let color = match global_alpha {
+                Some(global_alpha) if src_pixel >> 24 != 0xff || global_alpha != 0x100 => {
+                    blend(src_pixel, dest_format.unpack(dest_pixel), global_alpha)
+                }
+                _ => src_pixel,
+            };
+            dest_pixel.copy_from_slice(&dest_format.pack(color));
+        }
+    }
+}
+
+/// Fill a `width` x `height` rectangle with a packed color. Lines are clipped to the pitch so the fill never wraps
+/// around into the next line.
+fn fill_rect(buf: &mut [u8], pitch: usize, width: usize, height: usize, color: &[u8]) {
+    let width = width.min(pitch / color.len());
+    for line in buf.chunks_exact_mut(pitch).take(height) {
+        for pixel in line[..width * color.len()].chunks_exact_mut(color.len()) {
+            pixel.copy_from_slice(color);
+        }
+    }
+}
+
+/// Complete the operation and raise the interrupt. A failed memory access is reported with the error flag.
+fn finish(uc: &mut UnicornContext, result: Result<(), uc_error>) {
+    let blt = &mut uc.get_data_mut().blt;
+    if let Err(err) = result {
+        error!("BLIT: Operation aborted: {err:?}");
+        blt.status.set_error(true);
+    }
+    blt.flags.set_trigger(false);
+    blt.status.set_status(true);
+    if blt.status.get_enabled() {
+        post_interrupt(uc, InterruptNumber::BLT);
+    }
+}
+
 pub fn tick(uc: &mut UnicornContext) {
     let blt = &uc.get_data().blt;
-    if !blt.flags.get_trigger() {
+    // A blit triggered with the clock off starts once the clock is enabled.
+    if !blt.flags.get_trigger() || !uc.get_data().clk.ahbclk.get_blt() {
         return;
     }
 
     trace!("BLIT action {blt:?}");
 
     if blt.flags.get_fill() {
-        warn!("Fill mode not implemented yet.");
-        let blt = &mut uc.get_data_mut().blt;
-        blt.flags.set_fill(false);
-        blt.flags.set_trigger(false);
-        blt.status.set_status(true);
-        if blt.status.get_enabled() {
-            post_interrupt(uc, InterruptNumber::BLT, true, false);
+        if blt.flags.get_blend_on_fill() {
+            warn!("Blending on fill not implemented yet. Filling without blending.");
         }
+        let pitch = usize::from(blt.dest_pitch);
+        let height = usize::from(blt.dest_height);
+        let color = blt.dest_format.pack(blt.fill_color);
+        let width = usize::from(blt.dest_width);
+        let dest = u64::from(blt.dest);
+        let result = if !color.is_empty() && pitch != 0 {
+            uc.mem_read_as_vec(dest, pitch * height).and_then(|mut destbuf| {
+                fill_rect(&mut destbuf, pitch, width, height, &color);
+                uc.mem_write(dest, &destbuf)
+            })
+        } else {
+            warn!("Ignoring fill with format {:?} and pitch {pitch}.", blt.dest_format);
+            Ok(())
+        };
+        uc.get_data_mut().blt.flags.set_fill(false);
+        finish(uc, result);
+        return;
+    }
+
+    let transform = Transform {
+        a: blt.element_a,
+        b: blt.element_b,
+        c: blt.element_c,
+        d: blt.element_d,
+        translate_x: blt.translate_x,
+        translate_y: blt.translate_y,
+    };
+
+    if transform.is_singular() {
+        error!("Cannot invert transform {transform:?}");
+        uc.get_data_mut().blt.status.set_error(true);
+        finish(uc, Ok(()));
         return;
     }
 
+    let result = blit(uc, &transform);
+    finish(uc, result);
+}
+
+/// Copy the source to the destination. Returns early if a memory access fails.
+fn blit(uc: &mut UnicornContext, transform: &Transform) -> Result<(), uc_error> {
+    let blt = &uc.get_data().blt;
     let is_identity = 
         blt.element_a == 0x10000 &&
         blt.element_b == 0 &&
         blt.element_c == 0 &&
         blt.element_d == 0x10000;
 
-    // let proj = Projection::from_matrix([
-    //     fixed1616_to_f32(blt.element_a), fixed1616_to_f32(blt.element_c), fixed1616_to_f32(blt.translate_x),
-    //     fixed1616_to_f32(blt.element_b), fixed1616_to_f32(blt.element_d), fixed1616_to_f32(blt.translate_y),
-    //     0.0, 0.0, 1.0,
-    // ]);
-
-    // if proj.is_none() {
-    //     error!("Cannot build projection matrix from {blt:?}");
-    //     let blt = &mut uc.get_data_mut().blt;
-    //     blt.status.set_error(true);
-    //     blt.status.set_status(true);
-    //     blt.flags.set_trigger(false);
-    //     if blt.status.get_enabled() {
-    //         post_interrupt(uc, InterruptNumber::BLT, true, false);
-    //     }
-    //     return;
-    // }
-
-    //let proj = proj.unwrap();
     if is_identity &&
         blt.src_width == blt.dest_width &&
         blt.src_height == blt.dest_height
     {
-        if matches!(blt.src_format, SourceFormat::ARGB8888) &&
+        if matches!(blt.src_format, SourceFormat::RGB565) &&
             matches!(blt.dest_format, DestinationFormat::RGB565)
         {
-            let buf = uc.mem_read_as_vec(blt.src.into(), 320 * 240 * 4).unwrap();
-            let mut buf2: Vec<u8> = vec![];
-            for pixel in buf.chunks_exact(4) {
-                buf2.push((pixel[0] >> 3) | ((pixel[1] & 0b111) << 5));
-                buf2.push((pixel[2] & 0xf8) | (pixel[1] >> 5));
-            }
-            uc.mem_write(blt.dest.into(), &buf2).unwrap();
-        } else if matches!(blt.src_format, SourceFormat::RGB565) &&
-            matches!(blt.dest_format, DestinationFormat::RGB565)
+            let size = if blt.src_pitch != 0 { usize::from(blt.src_pitch) } else { usize::from(blt.src_width) * 2 };
+            let buf = uc.mem_read_as_vec(blt.src.into(), size * usize::from(blt.src_height))?;
+            uc.mem_write(blt.dest.into(), &buf)?;
+        } else if let Some(src_bpp) = blt.src_format.bpp() &&
+            blt.dest_format.bpp() != 0
         {
-            let buf = uc.mem_read_as_vec(blt.src.into(), 320 * 240 * 2).unwrap();
-            uc.mem_write(blt.dest.into(), &buf).unwrap();
+            let src_surface = Surface {
+                width: blt.src_width.into(),
+                height: blt.src_height.into(),
+                pitch: if blt.src_pitch != 0 { blt.src_pitch.into() } else { usize::from(blt.src_width) * src_bpp },
+            };
+            let dest_surface = Surface {
+                width: blt.dest_width.into(),
+                height: blt.dest_height.into(),
+                pitch: if blt.dest_pitch != 0 { blt.dest_pitch.into() } else { usize::from(blt.dest_width) * blt.dest_format.bpp() },
+            };
+            let global_alpha = match (blt.flags.get_ignore_src_alpha(), blt.flags.get_apply_alpha_transform()) {
+                (true, _) => None,
+                (false, true) => Some(blt.alpha_multiplier),
+                (false, false) => Some(0x100),
+            };
+            let srcbuf = uc.mem_read_as_vec(blt.src.into(), src_surface.pitch * src_surface.height)?;
+            let mut destbuf = uc.mem_read_as_vec(blt.dest.into(), dest_surface.pitch * dest_surface.height)?;
+            convert_copy(&srcbuf, src_surface, blt.src_format, &mut destbuf, dest_surface, blt.dest_format, global_alpha);
+            uc.mem_write(blt.dest.into(), &destbuf)?;
+        } else {
+            warn!("Blit from {:?} to {:?} not implemented yet.", blt.src_format, blt.dest_format);
         }
     } else if is_identity {
         if matches!(blt.src_format, SourceFormat::RGB565) &&
@@ -289,8 +524,8 @@ pub fn tick(uc: &mut UnicornContext) {
             let copy_height = usize::from(blt.src_height.min(blt.dest_height));
             let copy_offset = u64::from((blt.translate_x >> 16).cast_unsigned() * 2 + (blt.translate_y >> 16).cast_unsigned() * u32::from(blt.src_pitch));
 
-            let srcbuf = uc.mem_read_as_vec(u64::from(blt.src) + copy_offset, usize::from(blt.src_pitch) * copy_height).unwrap();
-            let mut destbuf = uc.mem_read_as_vec(blt.dest.into(), usize::from(blt.dest_pitch) * copy_height).unwrap();
+            let srcbuf = uc.mem_read_as_vec(u64::from(blt.src) + copy_offset, usize::from(blt.src_pitch) * copy_height)?;
+            let mut destbuf = uc.mem_read_as_vec(blt.dest.into(), usize::from(blt.dest_pitch) * copy_height)?;
 
             for (i, pixel) in srcbuf.chunks_exact(2).enumerate() {
                 let line = (i * 2) / usize::from(blt.src_pitch);
@@ -309,16 +544,119 @@ pub fn tick(uc: &mut UnicornContext) {
                 destbuf[copy_offset + 1] = pixel[1];
             }
 
-            uc.mem_write(blt.dest.into(), &destbuf).unwrap();
+            uc.mem_write(blt.dest.into(), &destbuf)?;
         }
+    } else if matches!(blt.src_format, SourceFormat::RGB565) &&
+        matches!(blt.dest_format, DestinationFormat::RGB565)
+    {
+        let src_surface = Surface {
+            width: blt.src_width.into(),
+            height: blt.src_height.into(),
+            pitch: blt.src_pitch.into(),
+        };
+        let dest_surface = Surface {
+            width: blt.dest_width.into(),
+            height: blt.dest_height.into(),
+            pitch: blt.dest_pitch.into(),
+        };
+        let srcbuf = uc.mem_read_as_vec(blt.src.into(), src_surface.pitch * src_surface.height)?;
+        let mut destbuf = uc.mem_read_as_vec(blt.dest.into(), dest_surface.pitch * dest_surface.height)?;
+        transform_copy(&srcbuf, src_surface, &mut destbuf, dest_surface, 2, transform);
+        uc.mem_write(blt.dest.into(), &destbuf)?;
     } else {
-        todo!();
+        warn!("Transformed blit from {:?} to {:?} not implemented yet.", blt.src_format, blt.dest_format);
     }
+    Ok(())
+}
 
-    let blt = &mut uc.get_data_mut().blt;
-    blt.status.set_status(true);
-    blt.flags.set_trigger(false);
-    if blt.status.get_enabled() {
-        post_interrupt(uc, InterruptNumber::BLT, true, false);
+impl_snapshot_bitfield!(BLTFlags, BLTStatus);
+impl_snapshot!(BLTConfig {
+    flags, status, src, dest, src_format, dest_format, src_width, src_height, dest_width, dest_height, src_pitch,
+    dest_pitch, element_a, element_b, element_c, element_d, translate_x, translate_y, alpha_multiplier, fill_color,
+});
+impl_reset!(BLTConfig);
+
+impl Snapshot for SourceFormat {
+    fn save(&self, out: &mut Vec<u8>) {
+        Into::<u64>::into(*self).save(out);
     }
+
+    fn load(&mut self, input: &mut &[u8]) -> Result<(), RuntimeError> {
+        *self = load_new::<u64>(input)?.into();
+        Ok(())
+    }
+}
+
+impl Snapshot for DestinationFormat {
+    fn save(&self, out: &mut Vec<u8>) {
+        Into::<u64>::into(*self).save(out);
+    }
+
+    fn load(&mut self, input: &mut &[u8]) -> Result<(), RuntimeError> {
+        *self = load_new::<u64>(input)?.into();
+        Ok(())
+    }
+}
+
+#[test]
+fn test_fill_rect_rgb565() {
+    let pitch = 16 * 2;
+    let mut buf = vec![0u8; pitch * 12];
+    let color = DestinationFormat::RGB565.pack(0xff00ff00);
+    assert_eq!(color, vec![0xe0, 0x07]);
+
+    fill_rect(&mut buf, pitch, 10, 10, &color);
+
+    for (i, pixel) in buf.chunks_exact(2).enumerate() {
+        let (x, y) = (i % 16, i / 16);
+        if x < 10 && y < 10 {
+            assert_eq!(pixel, [0xe0, 0x07], "pixel ({x}, {y})");
+        } else {
+            assert_eq!(pixel, [0, 0], "pixel ({x}, {y})");
+        }
+    }
+}
+
+#[test]
+fn test_transform_copy_rotate() {
+    // 2x2 RGB565 source, each pixel tagged with its index.
+    let src = [0u8, 0, 1, 0, 2, 0, 3, 0];
+    let surface = Surface { width: 2, height: 2, pitch: 4 };
+    let mut dest = [0xffu8; 8];
+    // Rotate by 90 degrees: src = (y, 1 - x)
+    let transform = Transform { a: 0, b: -0x10000, c: 0x10000, d: 0, translate_x: 0, translate_y: 0x10000 };
+    assert!(!transform.is_singular());
+
+    transform_copy(&src, surface, &mut dest, surface, 2, &transform);
+
+    assert_eq!(dest, [2, 0, 0, 0, 3, 0, 1, 0]);
+}
+
+#[test]
+fn test_blend_half_alpha() {
+    let red = 0x80ff0000u32.to_le_bytes();
+    let blue = DestinationFormat::ARGB8888.pack(0xff0000ff);
+    let surface = Surface { width: 1, height: 1, pitch: 4 };
+    let mut dest = blue.clone();
+
+    convert_copy(&red, surface, SourceFormat::ARGB8888, &mut dest, surface, DestinationFormat::ARGB8888, Some(0x100));
+
+    assert_eq!(u32::from_le_bytes(dest.try_into().unwrap()), 0xff80007f);
+}
+
+#[test]
+fn test_convert_copy_rgb555() {
+    let surface = Surface { width: 2, height: 1, pitch: 8 };
+    let dest_surface = Surface { width: 2, height: 1, pitch: 4 };
+    let src = [0x00u8, 0x00, 0xff, 0xff, 0xff, 0x00, 0x00, 0xff];
+    let mut dest = [0u8; 4];
+
+    convert_copy(&src, surface, SourceFormat::ARGB8888, &mut dest, dest_surface, DestinationFormat::RGB555, None);
+    assert_eq!(dest, [0x00, 0x7c, 0x1f, 0x00]);
+
+    // Pure green in RGB565 loses its lowest bit in RGB555.
+    let src = [0xe0u8, 0x07, 0x1f, 0x00];
+    let surface = Surface { width: 2, height: 1, pitch: 4 };
+    convert_copy(&src, surface, SourceFormat::RGB565, &mut dest, dest_surface, DestinationFormat::RGB555, None);
+    assert_eq!(dest, [0xe0, 0x03, 0x1f, 0x00]);
 }
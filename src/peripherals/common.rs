@@ -1,20 +1,29 @@
 
+use std::collections::HashMap;
+
 use crate::device::UnicornContext;
 
 #[macro_export]
 macro_rules! log_unsupported_read {
-    ($addr:expr, $size:expr) => {
+    ($addr:expr, $size:expr) => {{
+        $crate::trace::mark_unsupported();
         warn!("Unsupported read{} @ 0x{:08x}", 8 * $size, $addr)
-    };
+    }};
 }
 
 #[macro_export]
 macro_rules! log_unsupported_write {
-    ($addr:expr, $size:expr, $value:expr) => {
+    ($addr:expr, $size:expr, $value:expr) => {{
+        $crate::trace::mark_unsupported();
         warn!("Unsupported write{} of value 0x{:08x} @ 0x{:08x}", 8 * $size, $value, $addr)
-    };
+    }};
 }
 
+/// Register read handler of a peripheral, taking the offset from its base address and the access size.
+pub type MmioRead = fn(&mut UnicornContext, u64, usize) -> u64;
+/// Register write handler of a peripheral, taking the offset from its base address, the access size and the value.
+pub type MmioWrite = fn(&mut UnicornContext, u64, usize, u64);
+
 #[inline]
 pub fn mmio_get_store_only(uc: &mut UnicornContext, addr: u64) -> u64 {
     match uc.get_data().store_only.get(&addr) {
@@ -27,3 +36,83 @@ pub fn mmio_get_store_only(uc: &mut UnicornContext, addr: u64) -> u64 {
 pub fn mmio_set_store_only(uc: &mut UnicornContext, addr: u64, value: u64) {
     uc.get_data_mut().store_only.insert(addr, value);
 }
+
+/// Byte lane of an access of `size` bytes at `addr` within its 32-bit register, as (register address, shift, mask).
+#[inline]
+fn byte_lane(addr: u64, size: usize) -> (u64, u32, u64) {
+    let shift = u32::try_from(addr & 3).unwrap() * 8;
+    let mask = if size >= 4 { 0xffffffff } else { (1u64 << (8 * size)) - 1 };
+    (addr & !3, shift, mask << shift)
+}
+
+fn store_masked(store: &mut HashMap<u64, u64>, addr: u64, value: u64, size: usize) {
+    let (reg, shift, mask) = byte_lane(addr, size);
+    let stored = store.get(&reg).copied().unwrap_or(0);
+    store.insert(reg, (stored & !mask) | ((value << shift) & mask));
+}
+
+fn load_masked(store: &HashMap<u64, u64>, addr: u64, size: usize) -> u64 {
+    let (reg, shift, mask) = byte_lane(addr, size);
+    (store.get(&reg).copied().unwrap_or(0) & mask) >> shift
+}
+
+/// Write `size` bytes at `addr` into the 32-bit store-only register holding them. The other bytes of the register are
+/// kept, so a 16-bit write followed by a 32-bit read returns the written half merged with the rest of the register.
+#[inline]
+pub fn mmio_store_masked(uc: &mut UnicornContext, addr: u64, value: u64, size: usize) {
+    store_masked(&mut uc.get_data_mut().store_only, addr, value, size);
+}
+
+/// Read `size` bytes at `addr` from the 32-bit store-only register holding them.
+#[inline]
+pub fn mmio_load_masked(uc: &mut UnicornContext, addr: u64, size: usize) -> u64 {
+    load_masked(&uc.get_data().store_only, addr, size)
+}
+
+/// Peripheral state that a software reset returns to its power-on values.
+///
+/// Host-side resources attached to the peripheral, like mounted images and sample sources, are kept.
+pub trait Reset {
+    fn reset(&mut self);
+}
+
+/// Implement `Reset` for structs by rebuilding them from `Default`, keeping the listed fields.
+#[macro_export]
+macro_rules! impl_reset {
+    ($type:ty { $($field:ident),* $(,)? }) => {
+        impl $crate::peripherals::common::Reset for $type {
+            fn reset(&mut self) {
+                #[allow(unused_mut)]
+                let mut fresh = Self::default();
+                $( std::mem::swap(&mut fresh.$field, &mut self.$field); )*
+                *self = fresh;
+            }
+        }
+    };
+    ($($type:ty),* $(,)?) => {
+        $( $crate::impl_reset!($type {}); )*
+    };
+}
+
+/// First step after `steps` that is a multiple of `period`.
+#[inline]
+pub fn next_multiple(steps: u64, period: u64) -> u64 {
+    let period = period.max(1);
+    (steps / period + 1) * period
+}
+
+#[test]
+fn test_store_masked() {
+    let mut store = HashMap::new();
+    store_masked(&mut store, 0x80, 0x1_1234_5678, 4);
+    assert_eq!(load_masked(&store, 0x80, 4), 0x12345678);
+    store_masked(&mut store, 0x82, 0xabcd, 2);
+    assert_eq!(load_masked(&store, 0x80, 4), 0xabcd5678);
+    store_masked(&mut store, 0x81, 0xffee, 1);
+    assert_eq!(load_masked(&store, 0x80, 4), 0xabcdee78);
+    assert_eq!(load_masked(&store, 0x82, 2), 0xabcd);
+    assert_eq!(load_masked(&store, 0x83, 1), 0xab);
+    // A 16-bit write to a register that was never written reads back zero-extended.
+    store_masked(&mut store, 0x84, 0xbeef, 2);
+    assert_eq!(load_masked(&store, 0x84, 4), 0xbeef);
+}
@@ -0,0 +1,324 @@
+use bit_field::{B3, B4, B8, bitfield};
+use log::{error, trace, warn};
+use unicorn_engine::uc_error;
+use crate::{device::{StopReason, UnicornContext, request_stop}, log_unsupported_read, log_unsupported_write, peripherals::{aic::{InterruptNumber, post_interrupt}, common::{Reset, mmio_get_store_only, mmio_set_store_only}, sys::AHBCLKRegister}};
+use crate::{impl_reset, impl_snapshot, impl_snapshot_bitfield};
+
+pub const BASE: u64 = 0xb0008000;
+pub const SIZE: usize = 0x1000;
+
+/// Number of channels. Channel 0 is the VDMA, which only does memory to memory transfers. Channels 1 to 4 are PDMA.
+pub const CHANNELS: usize = 5;
+const CHANNEL_STRIDE: u64 = 0x100;
+
+const REG_CSR: u64 = 0x0;
+const REG_SAR: u64 = 0x4;
+const REG_DAR: u64 = 0x8;
+const REG_BCR: u64 = 0xc;
+const REG_CSAR: u64 = 0x14;
+const REG_CDAR: u64 = 0x18;
+const REG_CBCR: u64 = 0x1c;
+const REG_IER: u64 = 0x20;
+const REG_ISR: u64 = 0x24;
+const REG_CTCSR: u64 = 0x28;
+const REG_SASOCR: u64 = 0x2c;
+const REG_DASOCR: u64 = 0x30;
+
+const REG_PDSSR0: u64 = 0xc00;
+const REG_PDSSR1: u64 = 0xc04;
+const REG_GCRCSR: u64 = 0xf00;
+const REG_PDSSR2: u64 = 0xf04;
+const REG_GCRISR: u64 = 0xf0c;
+
+const COUNT_MASK: u32 = 0xffffff;
+
+/// Target abort.
+const ISR_TABORT: u8 = 1 << 0;
+/// Block transfer done.
+const ISR_BLKD: u8 = 1 << 1;
+
+#[bitfield]
+#[derive(Debug, PartialEq)]
+pub enum TransferMode {
+    MemToMem,
+    PeripheralToMem,
+    MemToPeripheral,
+    Reserved3,
+}
+
+#[bitfield]
+#[derive(Debug, PartialEq)]
+pub enum AddressMode {
+    Increment,
+    Reserved1,
+    Fixed,
+    /// Increment and wrap around to the start. Same as `Increment` within a single block.
+    Wrap,
+}
+
+#[bitfield]
+#[derive(Debug, PartialEq)]
+pub enum TransferWidth {
+    Word,
+    Byte,
+    HalfWord,
+    Reserved3,
+}
+
+impl TransferWidth {
+    pub fn bytes(&self) -> usize {
+        match self {
+            Self::Byte => 1,
+            Self::HalfWord => 2,
+            _ => 4,
+        }
+    }
+}
+
+#[bitfield]
+#[derive(Default)]
+pub struct EDMAControl {
+    enable: bool,
+    reset: bool,
+    mode: TransferMode,
+    src_mode: AddressMode,
+    dest_mode: AddressMode,
+    reserved_8: B4,
+    wrap_select: B4,
+    reserved_16: B3,
+    /// Width of each peripheral access (APB_TWS).
+    width: TransferWidth,
+    reserved_21: bool,
+    reserved_22: bool,
+    trigger: bool,
+    reserved_24: B8,
+}
+
+#[derive(Default)]
+pub struct EDMAChannel {
+    pub control: EDMAControl,
+    pub src: u32,
+    pub dest: u32,
+    /// Transfer size in bytes.
+    pub count: u32,
+    pub current_src: u32,
+    pub current_dest: u32,
+    /// Bytes left in the current transfer.
+    pub current_count: u32,
+    pub irq_enable: u8,
+    pub irq_status: u8,
+}
+
+#[derive(Default)]
+pub struct EDMAConfig {
+    pub channels: [EDMAChannel; CHANNELS],
+}
+
+impl EDMAChannel {
+    /// Split the transfer into `(src, dest, len)` moves, in order. Runs where both sides increment are merged into a
+    /// single move, while a fixed side (usually a peripheral data port) is accessed once per unit.
+    pub fn moves(&self) -> Vec<(u64, u64, usize)> {
+        let count = usize::try_from(self.count & COUNT_MASK).unwrap();
+        let src_fixed = self.control.get_src_mode() == AddressMode::Fixed;
+        let dest_fixed = self.control.get_dest_mode() == AddressMode::Fixed;
+        if !src_fixed && !dest_fixed {
+            return vec![(self.src.into(), self.dest.into(), count)];
+        }
+
+        let unit = self.control.get_width().bytes();
+        if !count.is_multiple_of(unit) {
+            warn!("EDMA: Transfer of {count} bytes is not a multiple of the {unit} byte width. Ignoring the rest.");
+        }
+        let (mut src, mut dest) = (u64::from(self.src), u64::from(self.dest));
+        let step = u64::try_from(unit).unwrap();
+        (0..count / unit).map(|_| {
+            let this_move = (src, dest, unit);
+            if !src_fixed {
+                src += step;
+            }
+            if !dest_fixed {
+                dest += step;
+            }
+            this_move
+        }).collect()
+    }
+}
+
+/// Channel and register offset of an address inside the per-channel register blocks.
+#[inline]
+fn channel_reg(addr: u64) -> Option<(usize, u64)> {
+    let index = usize::try_from(addr / CHANNEL_STRIDE).unwrap();
+    (index < CHANNELS).then_some((index, addr % CHANNEL_STRIDE))
+}
+
+pub fn read(uc: &mut UnicornContext, addr: u64, size: usize) -> u64 {
+    if size != 4 {
+        log_unsupported_read!(addr, size);
+        return 0;
+    }
+
+    if let Some((index, reg)) = channel_reg(addr) {
+        let channel = &uc.get_data().edma.channels[index];
+        match reg {
+            REG_CSR => channel.control.get(0, 32),
+            REG_SAR => channel.src.into(),
+            REG_DAR => channel.dest.into(),
+            REG_BCR => channel.count.into(),
+            REG_CSAR => channel.current_src.into(),
+            REG_CDAR => channel.current_dest.into(),
+            REG_CBCR => channel.current_count.into(),
+            REG_IER => channel.irq_enable.into(),
+            REG_ISR => channel.irq_status.into(),
+            REG_CTCSR | REG_SASOCR | REG_DASOCR => mmio_get_store_only(uc, BASE + addr),
+            _ => {
+                log_unsupported_read!(addr, size);
+                0
+            }
+        }
+    } else {
+        match addr {
+            REG_PDSSR0 | REG_PDSSR1 | REG_PDSSR2 | REG_GCRCSR => mmio_get_store_only(uc, BASE + addr),
+            REG_GCRISR => uc.get_data().edma.channels.iter().enumerate()
+                .filter(|(_, channel)| channel.irq_status & channel.irq_enable != 0)
+                .fold(0u64, |acc, (index, _)| acc | 1 << index),
+            _ => {
+                log_unsupported_read!(addr, size);
+                0
+            }
+        }
+    }
+}
+
+pub fn write(uc: &mut UnicornContext, addr: u64, size: usize, value: u64) {
+    if size != 4 {
+        log_unsupported_write!(addr, size, value);
+        return;
+    }
+
+    let Some((index, reg)) = channel_reg(addr) else {
+        match addr {
+            REG_PDSSR0 | REG_PDSSR1 | REG_PDSSR2 | REG_GCRCSR => mmio_set_store_only(uc, BASE + addr, value),
+            _ => log_unsupported_write!(addr, size, value),
+        }
+        return;
+    };
+
+    let value32 = u32::try_from(value & 0xffffffff).unwrap();
+    let channel = &mut uc.get_data_mut().edma.channels[index];
+    match reg {
+        REG_CSR => {
+            channel.control.set(0, 32, value);
+            if channel.control.get_reset() {
+                trace!("EDMA{index}: Reset");
+                channel.reset();
+                return;
+            }
+            if channel.control.get_trigger() && channel.control.get_enable() {
+                if index == 0 && channel.control.get_mode() != TransferMode::MemToMem {
+                    warn!("EDMA0: VDMA only supports memory to memory transfers.");
+                }
+                channel.current_src = channel.src;
+                channel.current_dest = channel.dest;
+                channel.current_count = channel.count & COUNT_MASK;
+                request_stop(uc, StopReason::Tick);
+            }
+        }
+        REG_SAR => channel.src = value32,
+        REG_DAR => channel.dest = value32,
+        REG_BCR => channel.count = value32 & COUNT_MASK,
+        REG_IER => channel.irq_enable = u8::try_from(value & 0xf).unwrap(),
+        REG_ISR => channel.irq_status &= !u8::try_from(value & 0xff).unwrap(),
+        REG_CTCSR | REG_SASOCR | REG_DASOCR => mmio_set_store_only(uc, BASE + addr, value),
+        _ => log_unsupported_write!(addr, size, value),
+    }
+}
+
+/// Run the moves of a transfer. Accesses to peripheral registers go through their MMIO handlers.
+fn run_moves(uc: &mut UnicornContext, moves: &[(u64, u64, usize)]) -> Result<(), uc_error> {
+    for &(src, dest, len) in moves {
+        let buf = uc.mem_read_as_vec(src, len)?;
+        uc.mem_write(dest, &buf)?;
+        uc.ctl_remove_cache(dest, dest + u64::try_from(len).unwrap()).unwrap_or_else(|err| {
+            error!("Failed to remove TB: {err:?}");
+        });
+    }
+    Ok(())
+}
+
+fn clock_enabled(ahbclk: &AHBCLKRegister, index: usize) -> bool {
+    match index {
+        0 => ahbclk.get_edma0(),
+        1 => ahbclk.get_edma1(),
+        2 => ahbclk.get_edma2(),
+        3 => ahbclk.get_edma3(),
+        4 => ahbclk.get_edma4(),
+        _ => unreachable!(),
+    }
+}
+
+/// Run triggered transfers. Peripheral transfers are not paced by the peripheral and complete at once.
+pub fn tick(uc: &mut UnicornContext) {
+    for index in 0..CHANNELS {
+        let channel = &uc.get_data().edma.channels[index];
+        // A transfer triggered with the clock off starts once the clock is enabled.
+        if !channel.control.get_trigger() || !channel.control.get_enable() ||
+            !clock_enabled(&uc.get_data().clk.ahbclk, index)
+        {
+            continue;
+        }
+
+        let moves = channel.moves();
+        trace!("EDMA{index}: {:?} 0x{:08x} -> 0x{:08x} ({} bytes)", channel.control.get_mode(), channel.src,
+            channel.dest, channel.count);
+        let result = run_moves(uc, &moves);
+
+        let channel = &mut uc.get_data_mut().edma.channels[index];
+        channel.control.set_trigger(false);
+        match result {
+            Ok(()) => {
+                if let Some(&(src, dest, len)) = moves.last() {
+                    let len = u32::try_from(len).unwrap();
+                    channel.current_src = u32::try_from(src).unwrap() + len;
+                    channel.current_dest = u32::try_from(dest).unwrap() + len;
+                }
+                channel.current_count = 0;
+                channel.irq_status |= ISR_BLKD;
+            }
+            Err(err) => {
+                error!("EDMA{index}: Transfer aborted: {err:?}");
+                channel.irq_status |= ISR_TABORT;
+            }
+        }
+        if channel.irq_status & channel.irq_enable != 0 {
+            post_interrupt(uc, InterruptNumber::EDMA);
+        }
+    }
+}
+
+impl_snapshot_bitfield!(EDMAControl);
+impl_snapshot!(EDMAChannel {
+    control, src, dest, count, current_src, current_dest, current_count, irq_enable, irq_status,
+});
+impl_snapshot!(EDMAConfig { channels });
+impl_reset!(EDMAChannel, EDMAConfig);
+
+#[test]
+fn test_edma_moves() {
+    let mut channel = EDMAChannel { src: 0x1000, dest: 0x2000, count: 0x100, ..Default::default() };
+    assert_eq!(channel.moves(), vec![(0x1000, 0x2000, 0x100)]);
+
+    // Peripheral to memory, reading a 16-bit data port.
+    channel.control.set_mode(TransferMode::PeripheralToMem);
+    channel.control.set_src_mode(AddressMode::Fixed);
+    channel.control.set_width(TransferWidth::HalfWord);
+    channel.count = 7;
+    assert_eq!(channel.moves(), vec![(0x1000, 0x2000, 2), (0x1000, 0x2002, 2), (0x1000, 0x2004, 2)]);
+
+    // Memory to peripheral, writing a byte port.
+    channel.control.set_mode(TransferMode::MemToPeripheral);
+    channel.control.set_src_mode(AddressMode::Increment);
+    channel.control.set_dest_mode(AddressMode::Fixed);
+    channel.control.set_width(TransferWidth::Byte);
+    channel.count = 2;
+    assert_eq!(channel.moves(), vec![(0x1000, 0x2000, 1), (0x1001, 0x2000, 1)]);
+}
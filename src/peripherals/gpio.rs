@@ -1,7 +1,8 @@
 use log::warn;
 use bit_field::{B2, B4, bitfield};
 
-use crate::{device::UnicornContext, log_unsupported_read, log_unsupported_write, peripherals::aic::{InterruptNumber, post_interrupt}};
+use crate::{device::UnicornContext, log_unsupported_read, log_unsupported_write, peripherals::{aic::{InterruptNumber, post_interrupt}, common::Reset}};
+use crate::{impl_reset, impl_snapshot, impl_snapshot_bitfield};
 
 pub const BASE: u64 = 0xb8001000;
 pub const SIZE: usize = 0x1000;
@@ -14,6 +15,10 @@ const REG_IRQEN_BLOCK_START: u64 = 0xa0;
 const REG_IRQEN_BLOCK_END: u64 = 0xb4;
 const REG_IRQLH_BLOCK_START: u64 = 0xd0;
 const REG_IRQLH_BLOCK_END: u64 = 0xe4;
+/// Each port has a block of 4 registers in the GPIO block.
+const GPIO_BLOCK_STRIDE: u64 = 0x10;
+/// The IRQ blocks have one register per port.
+const IRQ_BLOCK_STRIDE: u64 = 0x4;
 
 const REG_DBNCECON: u64 = 0x70;
 const REG_IRQLHSEL: u64 = 0xc0;
@@ -89,8 +94,13 @@ pub struct GPIOChannel {
     pub pull_up: GPIOFlags,
     pub data_out: GPIOFlags,
     pub data_in: GPIOFlags,
+    /// Pins driven by an external device. Other input pins read their pull-up state.
+    pub driven: GPIOFlags,
     pub irq_src: GPIOIRQSource,
+    /// Falling edge interrupt enable.
     pub irq_enable: GPIOFlags,
+    /// Rising edge interrupt enable.
+    pub irq_enable_rising: GPIOFlags,
     pub irq_latch: GPIOFlags,
     pub irq_trigger_source: GPIOFlags,
 }
@@ -104,6 +114,59 @@ pub struct GPIOConfig {
     pub irq_on_frame_step: bool,
 }
 
+impl GPIOChannel {
+    /// Levels seen on the pins. Output pins read back what they drive.
+    pub fn pin_state(&self) -> u64 {
+        let output = self.output_mode.get(0, 16);
+        let driven = self.driven.get(0, 16);
+        let input = (self.data_in.get(0, 16) & driven) | (self.pull_up.get(0, 16) & !driven);
+        ((self.data_out.get(0, 16) & output) | (input & !output)) & 0xffff
+    }
+}
+
+impl GPIOConfig {
+    /// Drive an input pin from an external device.
+    ///
+    /// Edges that have their interrupt enabled are recorded in the trigger source register and dispatched to the AIC
+    /// on the next frame step.
+    pub fn set_input(&mut self, port: usize, pin: usize, level: bool) {
+        let port_obj = &mut self.ports[port];
+        let prev = if port_obj.driven.get_bit(pin) {
+            port_obj.data_in.get_bit(pin)
+        } else {
+            port_obj.pull_up.get_bit(pin)
+        };
+        port_obj.data_in.set_bit(pin, level);
+        port_obj.driven.set_bit(pin, true);
+        if prev == level {
+            return;
+        }
+
+        let edge_enabled = if level {
+            port_obj.irq_enable_rising.get_bit(pin)
+        } else {
+            port_obj.irq_enable.get_bit(pin)
+        };
+        if !edge_enabled {
+            return;
+        }
+
+        port_obj.irq_trigger_source.set_bit(pin, true);
+        let channel = port_obj.irq_src.get(pin * 2, 2);
+        if self.irq_latch_source.get_bit(usize::try_from(channel).unwrap()) {
+            let data_in = self.ports[port].data_in.get(0, 16);
+            self.ports[port].irq_latch.set(0, 16, data_in);
+        }
+        self.irq_on_frame_step = true;
+    }
+}
+
+/// Port number of a register in a block of per-port registers.
+#[inline]
+fn port_of(addr: u64, block_start: u64, stride: u64) -> usize {
+    usize::try_from((addr - block_start) / stride).unwrap()
+}
+
 pub fn read(uc: &mut UnicornContext, addr: u64, size: usize) -> u64 {
     if size != 4 {
         log_unsupported_read!(addr, size);
@@ -111,14 +174,14 @@ pub fn read(uc: &mut UnicornContext, addr: u64, size: usize) -> u64 {
     }
     match addr {
         REG_GPIO_BLOCK_START..REG_GPIO_BLOCK_END => {
-            let port = usize::from(((addr >> 4) & 0xf) as u8);
-            let index = addr & 0xf;
+            let port = port_of(addr, REG_GPIO_BLOCK_START, GPIO_BLOCK_STRIDE);
+            let index = (addr - REG_GPIO_BLOCK_START) % GPIO_BLOCK_STRIDE;
             let port_obj = &uc.get_data().gpio.ports[port];
             match index {
-                0x0 => port_obj.output_mode.get(0, 16).into(),
-                0x4 => port_obj.pull_up.get(0, 16).into(),
-                0x8 => port_obj.data_out.get(0, 16).into(),
-                0xc => port_obj.data_in.get(0, 16).into(),
+                0x0 => port_obj.output_mode.get(0, 16),
+                0x4 => port_obj.pull_up.get(0, 16),
+                0x8 => port_obj.data_out.get(0, 16),
+                0xc => port_obj.pin_state(),
                 _ => {
                     log_unsupported_read!(addr, size);
                     0
@@ -126,15 +189,16 @@ pub fn read(uc: &mut UnicornContext, addr: u64, size: usize) -> u64 {
             }
         }
         REG_IRQSRC_BLOCK_START..REG_IRQSRC_BLOCK_END => {
-            let port = usize::from(((addr - REG_IRQSRC_BLOCK_START >> 4) & 0xf) as u8);
+            let port = port_of(addr, REG_IRQSRC_BLOCK_START, IRQ_BLOCK_STRIDE);
             uc.get_data().gpio.ports[port].irq_src.get(0, 32)
         }
         REG_IRQEN_BLOCK_START..REG_IRQEN_BLOCK_END => {
-            let port = usize::from(((addr - REG_IRQEN_BLOCK_START >> 4) & 0xf) as u8);
-            uc.get_data().gpio.ports[port].irq_enable.get(0, 16)
+            let port = port_of(addr, REG_IRQEN_BLOCK_START, IRQ_BLOCK_STRIDE);
+            let port_obj = &uc.get_data().gpio.ports[port];
+            port_obj.irq_enable.get(0, 16) | (port_obj.irq_enable_rising.get(0, 16) << 16)
         }
         REG_IRQLH_BLOCK_START..REG_IRQLH_BLOCK_END => {
-            let port = usize::from(((addr - REG_IRQLH_BLOCK_START >> 4) & 0xf) as u8);
+            let port = port_of(addr, REG_IRQLH_BLOCK_START, IRQ_BLOCK_STRIDE);
             uc.get_data().gpio.ports[port].irq_latch.get(0, 16)
         }
         REG_DBNCECON => { uc.get_data().gpio.debounce.get(0, 8) }
@@ -150,7 +214,7 @@ pub fn read(uc: &mut UnicornContext, addr: u64, size: usize) -> u64 {
             ((hi << 16) | lo).into()
         }
         REG_IRQTGSRC2 => {
-            uc.get_data().gpio.ports[4].irq_trigger_source.get(0, 16).into()
+            uc.get_data().gpio.ports[4].irq_trigger_source.get(0, 16)
         }
         _ => {
             log_unsupported_read!(addr, size);
@@ -167,8 +231,8 @@ pub fn write(uc: &mut UnicornContext, addr: u64, size: usize, value: u64) {
 
     match addr {
         REG_GPIO_BLOCK_START..REG_GPIO_BLOCK_END => {
-            let port = usize::from(((addr >> 4) & 0xf) as u8);
-            let index = addr & 0xf;
+            let port = port_of(addr, REG_GPIO_BLOCK_START, GPIO_BLOCK_STRIDE);
+            let index = (addr - REG_GPIO_BLOCK_START) % GPIO_BLOCK_STRIDE;
             let port_obj = &mut uc.get_data_mut().gpio.ports[port];
             match index {
                 0x0 => port_obj.output_mode.set(0, 16, value),
@@ -181,15 +245,17 @@ pub fn write(uc: &mut UnicornContext, addr: u64, size: usize, value: u64) {
             }
         }
         REG_IRQSRC_BLOCK_START..REG_IRQSRC_BLOCK_END => {
-            let port = usize::from(((addr - REG_IRQSRC_BLOCK_START >> 4) & 0xf) as u8);
+            let port = port_of(addr, REG_IRQSRC_BLOCK_START, IRQ_BLOCK_STRIDE);
             uc.get_data_mut().gpio.ports[port].irq_src.set(0, 32, value)
         }
         REG_IRQEN_BLOCK_START..REG_IRQEN_BLOCK_END => {
-            let port = usize::from(((addr - REG_IRQEN_BLOCK_START >> 4) & 0xf) as u8);
-            uc.get_data_mut().gpio.ports[port].irq_enable.set(0, 16, value)
+            let port = port_of(addr, REG_IRQEN_BLOCK_START, IRQ_BLOCK_STRIDE);
+            let port_obj = &mut uc.get_data_mut().gpio.ports[port];
+            port_obj.irq_enable.set(0, 16, value & 0xffff);
+            port_obj.irq_enable_rising.set(0, 16, (value >> 16) & 0xffff);
         }
         REG_IRQLH_BLOCK_START..REG_IRQLH_BLOCK_END => {
-            let port = usize::from(((addr - REG_IRQLH_BLOCK_START >> 4) & 0xf) as u8);
+            let port = port_of(addr, REG_IRQLH_BLOCK_START, IRQ_BLOCK_STRIDE);
             uc.get_data_mut().gpio.ports[port].irq_latch.set(0, 16, value)
         }
         REG_DBNCECON => { uc.get_data_mut().gpio.debounce.set(0, 8, value) }
@@ -223,7 +289,7 @@ pub fn frame_step(uc: &mut UnicornContext) {
 
     // Collect channels that will raise interrupt
     for port in &uc.get_data().gpio.ports {
-        let irq_enable = port.irq_enable.get(0, 16);
+        let irq_enable = port.irq_enable.get(0, 16) | port.irq_enable_rising.get(0, 16);
         let trigger_status = port.irq_trigger_source.get(0, 16);
         if irq_enable == 0 || trigger_status == 0 {
             continue;
@@ -251,6 +317,65 @@ pub fn frame_step(uc: &mut UnicornContext) {
             _ => panic!("wtf"),
         };
 
-        post_interrupt(uc, intno, true, false);
+        post_interrupt(uc, intno);
+    }
+}
+
+impl_snapshot_bitfield!(GPIOFlags, GPIOIRQSource, GPIODebounce, GPIOIRQLatchSource);
+impl_snapshot!(GPIOChannel {
+    output_mode, pull_up, data_out, data_in, driven, irq_src, irq_enable, irq_enable_rising, irq_latch, irq_trigger_source,
+});
+impl_snapshot!(GPIOConfig { ports, debounce, irq_latch_source, irq_on_frame_step });
+impl_reset!(GPIOChannel { data_in, driven });
+
+/// Levels driven by external devices stay on the pins.
+impl Reset for GPIOConfig {
+    fn reset(&mut self) {
+        self.ports.iter_mut().for_each(Reset::reset);
+        self.debounce = Default::default();
+        self.irq_latch_source = Default::default();
+        self.irq_on_frame_step = false;
     }
 }
+
+#[test]
+fn test_set_input_edges() {
+    let mut gpio = GPIOConfig::default();
+    gpio.set_input(0, 2, true);
+    gpio.ports[0].irq_enable.set_p2(true);
+    gpio.ports[0].irq_src.set_p2(1);
+
+    // Falling edge is enabled.
+    gpio.set_input(0, 2, false);
+    assert!(gpio.ports[0].irq_trigger_source.get_p2());
+    assert!(gpio.irq_on_frame_step);
+
+    // Rising edge is not.
+    gpio.ports[0].irq_trigger_source.set_p2(false);
+    gpio.irq_on_frame_step = false;
+    gpio.set_input(0, 2, true);
+    assert!(!gpio.ports[0].irq_trigger_source.get_p2());
+    assert!(!gpio.irq_on_frame_step);
+    assert!(gpio.ports[0].data_in.get_p2());
+}
+
+#[test]
+fn test_pin_state() {
+    let mut gpio = GPIOConfig::default();
+    let port = &mut gpio.ports[1];
+    port.output_mode.set(0, 16, 0b0011);
+    port.data_out.set(0, 16, 0b0101);
+    port.pull_up.set(0, 16, 0b1100);
+    gpio.set_input(1, 2, false);
+
+    // Pin 0-1 read back data_out, pin 2 is driven low, pin 3 is pulled up, the rest float low.
+    assert_eq!(gpio.ports[1].pin_state(), 0b1001);
+}
+
+#[test]
+fn test_port_of() {
+    assert_eq!(port_of(0x1c, REG_GPIO_BLOCK_START, GPIO_BLOCK_STRIDE), 1);
+    assert_eq!(port_of(0x84, REG_IRQSRC_BLOCK_START, IRQ_BLOCK_STRIDE), 1);
+    assert_eq!(port_of(0xa8, REG_IRQEN_BLOCK_START, IRQ_BLOCK_STRIDE), 2);
+    assert_eq!(port_of(0xe0, REG_IRQLH_BLOCK_START, IRQ_BLOCK_STRIDE), 4);
+}
@@ -0,0 +1,239 @@
+use bit_field::{B2, B20, bitfield};
+use log::{trace, warn};
+use crate::{device::{StopReason, UnicornContext, request_stop}, extdev::i2c::I2CBus, log_unsupported_read, log_unsupported_write, peripherals::{aic::{InterruptNumber, post_interrupt}, common::{mmio_get_store_only, mmio_set_store_only}}};
+use crate::{impl_reset, impl_snapshot, impl_snapshot_bitfield};
+
+pub const BASE: u64 = 0xb8004000;
+pub const SIZE: usize = 0x1000;
+
+const REG_CSR: u64 = 0x0;
+const REG_DIVIDER: u64 = 0x4;
+const REG_CMDR: u64 = 0x8;
+/// Software mode control of SCL and SDA, which is not emulated.
+const REG_SWR: u64 = 0xc;
+const REG_RXR: u64 = 0x10;
+const REG_TXR: u64 = 0x14;
+
+/// Master sends NACK after the byte read.
+const CMD_NACK: u8 = 1 << 0;
+const CMD_WRITE: u8 = 1 << 1;
+const CMD_READ: u8 = 1 << 2;
+const CMD_STOP: u8 = 1 << 3;
+const CMD_START: u8 = 1 << 4;
+
+/// Write 1 to clear interrupt flag of `I2CControl`.
+const CSR_IF: u64 = 1 << 2;
+/// Bits of `I2CControl` that software can write.
+const CSR_WRITABLE: u64 = 0x33;
+
+#[bitfield]
+#[derive(Default)]
+pub struct I2CControl {
+    enable: bool,
+    irq_enable: bool,
+    irq_flag: bool,
+    reserved_3: bool,
+    /// Bytes sent by a write command, minus 1.
+    tx_num: B2,
+    reserved_6: B2,
+    /// Transfer in progress.
+    tip: bool,
+    arbitration_lost: bool,
+    /// Bus is held between start and stop.
+    busy: bool,
+    /// The slave did not acknowledge the last byte written.
+    rx_nack: bool,
+    reserved_12: B20,
+}
+
+/// I2C master controller.
+///
+/// Commands complete at once, regardless of the clock divider. Slaves are attached to `bus` on the host side.
+#[derive(Default)]
+pub struct I2CConfig {
+    pub control: I2CControl,
+    pub divider: u32,
+    /// Pending command bits.
+    pub command: u8,
+    pub rx: u8,
+    /// Bytes to send, highest byte first.
+    pub tx: u32,
+    /// The next byte written is an address byte.
+    address_phase: bool,
+    /// Address of the slave that acknowledged the current transfer.
+    target: Option<u8>,
+    pub bus: I2CBus,
+}
+
+impl I2CConfig {
+    /// Write a byte to the bus. Returns whether it was acknowledged.
+    fn write_byte(&mut self, data: u8) -> bool {
+        if self.address_phase {
+            self.address_phase = false;
+            let (addr, read) = (data >> 1, data & 1 != 0);
+            let ack = self.bus.slave(addr).is_some_and(|slave| slave.start(addr, read));
+            trace!("I2C: Address 0x{addr:02x} {}: {}", if read { "R" } else { "W" }, if ack { "ACK" } else { "NACK" });
+            self.target = ack.then_some(addr);
+            return ack;
+        }
+        match self.target.and_then(|addr| self.bus.slave(addr)) {
+            Some(slave) => slave.write(data),
+            None => false,
+        }
+    }
+
+    /// Run the pending command. Returns whether an interrupt needs to be raised.
+    fn run_command(&mut self) -> bool {
+        let command = std::mem::take(&mut self.command);
+        if command & CMD_START != 0 {
+            self.address_phase = true;
+            self.target = None;
+            self.control.set_busy(true);
+        }
+        if command & CMD_WRITE != 0 {
+            let count = u32::from(self.control.get_tx_num()) + 1;
+            let mut ack = true;
+            for i in (0..count).rev() {
+                ack = self.write_byte(self.tx.to_be_bytes()[usize::try_from(3 - i).unwrap()]);
+                if !ack {
+                    break;
+                }
+            }
+            self.control.set_rx_nack(!ack);
+        }
+        if command & CMD_READ != 0 {
+            // Nothing drives SDA without a slave, so the byte reads as all ones.
+            self.rx = match self.target.and_then(|addr| self.bus.slave(addr)) {
+                Some(slave) => slave.read(),
+                None => {
+                    warn!("I2C: Read without an addressed slave");
+                    0xff
+                }
+            };
+            trace!("I2C: Read 0x{:02x}: {}", self.rx, if command & CMD_NACK != 0 { "NACK" } else { "ACK" });
+        }
+        if command & CMD_STOP != 0 {
+            if let Some(slave) = self.target.take().and_then(|addr| self.bus.slave(addr)) {
+                slave.stop();
+            }
+            self.address_phase = false;
+            self.control.set_busy(false);
+        }
+
+        self.control.set_tip(false);
+        self.control.set_irq_flag(true);
+        self.control.get_irq_enable()
+    }
+}
+
+/// Parse a 7-bit I2C address, in decimal or hexadecimal with a `0x` prefix.
+pub fn parse_i2c_address(value: &str) -> Result<u8, String> {
+    let parsed = match value.trim().strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => value.trim().parse(),
+    };
+    parsed.ok().filter(|addr| *addr <= 0x7f).ok_or_else(|| format!("Invalid I2C address `{value}`"))
+}
+
+pub fn read(uc: &mut UnicornContext, addr: u64, size: usize) -> u64 {
+    if size != 4 {
+        log_unsupported_read!(addr, size);
+        return 0;
+    }
+
+    let i2c = &uc.get_data().i2c;
+    match addr {
+        REG_CSR => i2c.control.get(0, 32),
+        REG_DIVIDER => i2c.divider.into(),
+        REG_CMDR => i2c.command.into(),
+        REG_SWR => mmio_get_store_only(uc, BASE + addr),
+        REG_RXR => i2c.rx.into(),
+        REG_TXR => i2c.tx.into(),
+        _ => {
+            log_unsupported_read!(addr, size);
+            0
+        }
+    }
+}
+
+pub fn write(uc: &mut UnicornContext, addr: u64, size: usize, value: u64) {
+    if size != 4 {
+        log_unsupported_write!(addr, size, value);
+        return;
+    }
+
+    let value32 = u32::try_from(value & 0xffffffff).unwrap();
+    let i2c = &mut uc.get_data_mut().i2c;
+    match addr {
+        REG_CSR => {
+            let status = i2c.control.get(0, 32) & !CSR_WRITABLE & !(value & CSR_IF);
+            i2c.control.set(0, 32, status | value & CSR_WRITABLE);
+        }
+        REG_DIVIDER => i2c.divider = value32 & 0xffff,
+        REG_CMDR => {
+            i2c.command = u8::try_from(value & 0x1f).unwrap();
+            if i2c.command != 0 {
+                i2c.control.set_tip(true);
+                request_stop(uc, StopReason::Tick);
+            }
+        }
+        REG_SWR => mmio_set_store_only(uc, BASE + addr, value),
+        REG_TXR => i2c.tx = value32,
+        _ => log_unsupported_write!(addr, size, value),
+    }
+}
+
+pub fn tick(uc: &mut UnicornContext) {
+    let i2c = &uc.get_data().i2c;
+    // A command issued with the clock off or the controller disabled runs once both are enabled.
+    if i2c.command == 0 || !i2c.control.get_enable() || !uc.get_data().clk.apbclk.get_i2c() {
+        return;
+    }
+    if uc.get_data_mut().i2c.run_command() {
+        post_interrupt(uc, InterruptNumber::I2C);
+    }
+}
+
+impl_snapshot_bitfield!(I2CControl);
+impl_snapshot!(I2CConfig { control, divider, command, rx, tx, address_phase, target, bus });
+impl_reset!(I2CConfig { bus });
+
+#[test]
+fn test_i2c_eeprom_read() {
+    use crate::extdev::{eeprom::EEPROM, make_test_file};
+
+    let path = make_test_file("i2c.bin", 256);
+    let eeprom = EEPROM::open(path.to_str().unwrap(), 0x50).unwrap();
+    let mut i2c = I2CConfig::default();
+    i2c.bus.attach(&eeprom.addresses(), Box::new(eeprom)).unwrap();
+    i2c.control.set_irq_enable(true);
+
+    // Nobody at 0x51.
+    i2c.tx = 0x51 << 1;
+    i2c.command = CMD_START | CMD_WRITE;
+    assert!(i2c.run_command());
+    assert!(i2c.control.get_rx_nack());
+
+    // Set the address pointer to 0x10 with 2 bytes in one command, then read 2 bytes.
+    i2c.control.set_tx_num(1);
+    i2c.tx = (0x50 << 1) << 8 | 0x10;
+    i2c.command = CMD_START | CMD_WRITE;
+    i2c.run_command();
+    assert!(!i2c.control.get_rx_nack());
+    i2c.control.set_tx_num(0);
+    i2c.tx = 0x50 << 1 | 1;
+    i2c.command = CMD_START | CMD_WRITE;
+    i2c.run_command();
+    i2c.command = CMD_READ;
+    i2c.run_command();
+    assert_eq!(i2c.rx, 0x10);
+    i2c.command = CMD_READ | CMD_NACK | CMD_STOP;
+    i2c.run_command();
+    assert_eq!(i2c.rx, 0x11);
+    assert!(!i2c.control.get_busy());
+
+    assert_eq!(parse_i2c_address("0x50"), Ok(0x50));
+    assert!(parse_i2c_address("0x80").is_err());
+
+    std::fs::remove_file(&path).unwrap();
+}
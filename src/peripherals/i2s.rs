@@ -1,15 +1,212 @@
+use std::collections::VecDeque;
+
+use bit_field::{B3, B4, B5, B7, B8, B12, B15, B18, B30, bitfield};
 use log::warn;
-use crate::{device::UnicornContext, log_unsupported_read, log_unsupported_write};
+use crate::{device::{Device, StopReason, UnicornContext, request_stop}, log_unsupported_read, log_unsupported_write, peripherals::aic::{InterruptNumber, post_interrupt}};
+use crate::{impl_reset, impl_snapshot, impl_snapshot_bitfield};
 
 pub const BASE: u64 = 0xb1001000;
 pub const SIZE: usize = 0x1000;
 
+// Audio controller registers, named after Nuvoton's `ACTL_*` definitions. Playback and recording both run from a DMA
+// ring buffer in memory, which raises an interrupt when the transfer crosses the middle and the end of the buffer.
+const REG_ACTL_CON: u64 = 0x00;
+const REG_ACTL_RESET: u64 = 0x04;
+const REG_ACTL_RDSTB: u64 = 0x08;
+const REG_ACTL_RDST_LENGTH: u64 = 0x0c;
+const REG_ACTL_RDSTC: u64 = 0x10;
+const REG_ACTL_RSR: u64 = 0x14;
+const REG_ACTL_PDSTB: u64 = 0x18;
+const REG_ACTL_PDST_LENGTH: u64 = 0x1c;
+const REG_ACTL_PDSTC: u64 = 0x20;
+const REG_ACTL_PSR: u64 = 0x24;
+const REG_ACTL_I2SCON: u64 = 0x28;
+
+/// Write-1-to-clear DMA interrupt flags in ACTL_CON.
+const CON_DMA_IRQ_MASK: u64 = 0b11 << 12;
+/// Capacity of the host-side sample buffer. Oldest samples are dropped when the consumer falls behind.
+pub const AUDIO_BUFFER_SIZE: usize = 65536;
+
+/// ACTL_CON
+#[bitfield]
+#[derive(Default)]
+pub struct AudioControl {
+    i2s_enable: bool,
+    reserved_1: B7,
+    /// true - the shared pins go to the AC-link interface, false - to I2S.
+    ac_pin_select: bool,
+    reserved_9: B3,
+    /// Record DMA reached the middle or the end of its buffer. Write 1 to clear.
+    record_dma_irq: bool,
+    /// Play DMA reached the middle or the end of its buffer. Write 1 to clear.
+    play_dma_irq: bool,
+    reserved_14: B18,
+}
+
+/// ACTL_RESET
+#[bitfield]
+#[derive(Default)]
+pub struct AudioReset {
+    /// Holds the I2S interface in reset while set.
+    i2s_reset: bool,
+    reserved_1: B4,
+    i2s_play: bool,
+    i2s_record: bool,
+    reserved_7: B5,
+    play_left: bool,
+    play_right: bool,
+    record_left: bool,
+    record_right: bool,
+    /// Holds the whole audio controller in reset while set.
+    actl_reset: bool,
+    reserved_17: B15,
+}
+
+/// ACTL_RSR and ACTL_PSR
+#[bitfield]
+#[derive(Default)]
+pub struct DMAStatus {
+    /// Write 1 to clear.
+    dma_middle: bool,
+    /// Write 1 to clear.
+    dma_end: bool,
+    reserved_2: B30,
+}
+
+/// ACTL_I2SCON
+#[bitfield]
+#[derive(Default)]
+pub struct I2SControl {
+    reserved_0: B3,
+    /// true - MSB justified, false - I2S.
+    msb_justified: bool,
+    /// true - 384 fs, false - 256 fs.
+    fs_384: bool,
+    bclk_select: B3,
+    reserved_8: B8,
+    /// Master clock prescaler, minus 1.
+    prescaler: B4,
+    reserved_20: B12,
+}
+
+#[derive(Default)]
+pub struct I2SConfig {
+    pub control: AudioControl,
+    pub reset: AudioReset,
+    pub i2s_control: I2SControl,
+    /// Record buffer. Recording is not emulated, so it is only stored and the record DMA never moves.
+    pub record_base: u32,
+    pub record_length: u32,
+    pub record_status: DMAStatus,
+    pub play_base: u32,
+    pub play_length: u32,
+    pub play_status: DMAStatus,
+    /// Offset of the next frame in the play buffer, in bytes.
+    play_offset: u32,
+    /// Step at which the next frame has been shifted out.
+    frame_done_at: u64,
+    /// Samples shifted out since the last tick, waiting to be moved to the host-side buffer.
+    shifted: VecDeque<u32>,
+}
+
+impl I2SConfig {
+    fn is_playing(&self) -> bool {
+        self.control.get_i2s_enable() &&
+            self.reset.get_i2s_play() &&
+            !self.reset.get_i2s_reset() &&
+            !self.reset.get_actl_reset() &&
+            (self.reset.get_play_left() || self.reset.get_play_right()) &&
+            self.play_length >= self.frame_bytes()
+    }
+
+    /// Bytes read from the play buffer per frame: a 16-bit sample for each enabled channel.
+    fn frame_bytes(&self) -> u32 {
+        if self.reset.get_play_left() && self.reset.get_play_right() { 4 } else { 2 }
+    }
+
+    /// Number of CPU steps needed to shift out one frame. The master clock comes from the audio divider of the clock
+    /// controller, which isn't emulated, so the CPU clock divided by the prescaler stands in for it.
+    fn frame_steps(&self) -> u64 {
+        let fs_ratio = if self.i2s_control.get_fs_384() { 384 } else { 256 };
+        (u64::from(self.i2s_control.get_prescaler()) + 1) * fs_ratio
+    }
+
+    /// Rewind the play DMA to the start of the buffer, with the first frame done one frame from `steps`.
+    fn start_play(&mut self, steps: u64) {
+        self.play_offset = 0;
+        self.frame_done_at = steps + self.frame_steps();
+    }
+
+    /// Take the next frame if it is done by `steps`. Returns its address in the play buffer, and whether it made the
+    /// DMA cross the middle or the end of the buffer, which raises the play interrupt.
+    fn next_frame(&mut self, steps: u64) -> Option<(u32, bool)> {
+        if self.frame_done_at > steps {
+            return None;
+        }
+        let address = self.play_base.wrapping_add(self.play_offset);
+        let half = self.play_length / 2;
+        let old_offset = self.play_offset;
+        self.play_offset += self.frame_bytes();
+        self.frame_done_at += self.frame_steps();
+
+        let mut irq = false;
+        if old_offset < half && self.play_offset >= half {
+            self.play_status.set_dma_middle(true);
+            irq = true;
+        }
+        if self.play_offset + self.frame_bytes() > self.play_length {
+            self.play_status.set_dma_end(true);
+            self.play_offset = 0;
+            irq = true;
+        }
+        if irq {
+            self.control.set_play_dma_irq(true);
+        }
+        Some((address, irq))
+    }
+
+    /// Step at which the play DMA next crosses the middle or the end of the buffer.
+    fn next_boundary(&self) -> u64 {
+        let half = self.play_length / 2;
+        let frame_bytes = self.frame_bytes();
+        let boundary = if self.play_offset < half {
+            half
+        } else {
+            self.play_length - frame_bytes + 1
+        };
+        let frames = boundary.saturating_sub(self.play_offset).div_ceil(frame_bytes).max(1);
+        self.frame_done_at + u64::from(frames - 1) * self.frame_steps()
+    }
+
+    /// Move the shifted out samples into the host-side buffer. Returns the number of samples moved.
+    pub fn drain_tx(&mut self, output: &mut VecDeque<u32>) -> usize {
+        let count = self.shifted.len();
+        let overrun = (output.len() + count).saturating_sub(AUDIO_BUFFER_SIZE);
+        output.drain(..overrun.min(output.len()));
+        output.extend(self.shifted.drain(..));
+        count
+    }
+}
+
 pub fn read(uc: &mut UnicornContext, addr: u64, size: usize) -> u64 {
     if size != 4 {
         log_unsupported_read!(addr, size);
         return 0;
     }
+
+    let i2s = &uc.get_data().i2s;
     match addr {
+        REG_ACTL_CON => i2s.control.get(0, 32),
+        REG_ACTL_RESET => i2s.reset.get(0, 32),
+        REG_ACTL_RDSTB => i2s.record_base.into(),
+        REG_ACTL_RDST_LENGTH => i2s.record_length.into(),
+        REG_ACTL_RDSTC => i2s.record_base.into(),
+        REG_ACTL_RSR => i2s.record_status.get(0, 32),
+        REG_ACTL_PDSTB => i2s.play_base.into(),
+        REG_ACTL_PDST_LENGTH => i2s.play_length.into(),
+        REG_ACTL_PDSTC => i2s.play_base.wrapping_add(i2s.play_offset).into(),
+        REG_ACTL_PSR => i2s.play_status.get(0, 32),
+        REG_ACTL_I2SCON => i2s.i2s_control.get(0, 32),
         _ => {
             log_unsupported_read!(addr, size);
             0
@@ -22,9 +219,145 @@ pub fn write(uc: &mut UnicornContext, addr: u64, size: usize, value: u64) {
         log_unsupported_write!(addr, size, value);
         return;
     }
+
+    let steps = uc.get_data().steps;
+    let i2s = &mut uc.get_data_mut().i2s;
+    let was_playing = i2s.is_playing();
     match addr {
-        _ => {
-            log_unsupported_write!(addr, size, value);
+        REG_ACTL_CON => {
+            let irq = i2s.control.get(0, 32) & CON_DMA_IRQ_MASK & !value;
+            i2s.control.set(0, 32, (value & !CON_DMA_IRQ_MASK) | irq);
+        }
+        REG_ACTL_RESET => {
+            i2s.reset.set(0, 32, value);
+            if i2s.reset.get_i2s_reset() || i2s.reset.get_actl_reset() {
+                i2s.play_offset = 0;
+                i2s.play_status.set(0, 32, 0);
+                i2s.record_status.set(0, 32, 0);
+            }
+        }
+        REG_ACTL_RDSTB => i2s.record_base = value as u32,
+        REG_ACTL_RDST_LENGTH => i2s.record_length = value as u32,
+        REG_ACTL_RSR => {
+            let status = i2s.record_status.get(0, 32) & !value;
+            i2s.record_status.set(0, 32, status);
+        }
+        REG_ACTL_PDSTB => i2s.play_base = value as u32,
+        REG_ACTL_PDST_LENGTH => i2s.play_length = value as u32,
+        REG_ACTL_PSR => {
+            let status = i2s.play_status.get(0, 32) & !value;
+            i2s.play_status.set(0, 32, status);
         }
+        REG_ACTL_I2SCON => i2s.i2s_control.set(0, 32, value),
+        _ => log_unsupported_write!(addr, size, value),
+    }
+    if !was_playing && i2s.is_playing() {
+        i2s.start_play(steps);
+    }
+}
+
+/// Shift out the frames that are done by `steps`, raising the play interrupt at the middle and the end of the buffer.
+fn play(uc: &mut UnicornContext, steps: u64) {
+    let endian = uc.get_data().endian;
+    while let Some((address, irq)) = uc.get_data_mut().i2s.next_frame(steps) {
+        let i2s = &uc.get_data().i2s;
+        let (left, right) = (i2s.reset.get_play_left(), i2s.reset.get_play_right());
+        let mut bytes = [0u8; 4];
+        let bytes = &mut bytes[..usize::try_from(i2s.frame_bytes()).unwrap()];
+        if let Err(err) = uc.mem_read(address.into(), bytes) {
+            warn!("Play DMA read from 0x{address:08x} failed: {err:?}");
+            bytes.fill(0);
+        }
+        // Frames are kept as a stereo pair with the left channel in the low half, and silence in a disabled channel.
+        let sample = u32::try_from(endian.unpack(bytes)).unwrap();
+        let frame = match (left, right) {
+            (true, false) => sample & 0xffff,
+            (false, true) => sample << 16,
+            _ => sample,
+        };
+        uc.get_data_mut().i2s.shifted.push_back(frame);
+        if irq {
+            post_interrupt(uc, InterruptNumber::I2S);
+        }
+    }
+}
+
+pub fn generate_stop_condition(uc: &mut UnicornContext, steps: u64) {
+    if !uc.get_data().clk.ahbclk.get_i2s() || !uc.get_data().i2s.is_playing() {
+        return;
+    }
+
+    play(uc, steps);
+    if !uc.get_data().i2s.shifted.is_empty() {
+        request_stop(uc, StopReason::Tick);
     }
 }
+
+/// Step at which the play DMA next crosses the middle or the end of the buffer.
+pub fn next_event(uc: &UnicornContext, steps: u64) -> Option<u64> {
+    let i2s = &uc.get_data().i2s;
+    if !uc.get_data().clk.ahbclk.get_i2s() || !i2s.is_playing() {
+        return None;
+    }
+    Some(i2s.next_boundary().max(steps + 1))
+}
+
+pub fn tick(uc: &mut UnicornContext, device: &mut Device) {
+    if !uc.get_data().clk.ahbclk.get_i2s() || !uc.get_data().i2s.is_playing() {
+        return;
+    }
+
+    let steps = uc.get_data().steps;
+    play(uc, steps);
+    device.audio_frames += uc.get_data_mut().i2s.drain_tx(&mut device.audio_out) as u64;
+}
+
+impl_snapshot_bitfield!(AudioControl, AudioReset, DMAStatus, I2SControl);
+impl_snapshot!(I2SConfig {
+    control, reset, i2s_control, record_base, record_length, record_status, play_base, play_length, play_status,
+    play_offset, frame_done_at, shifted,
+});
+impl_reset!(I2SConfig);
+
+#[test]
+fn test_drain_tx() {
+    let mut i2s = I2SConfig::default();
+    let mut output = VecDeque::from(vec![0u32; AUDIO_BUFFER_SIZE - 2]);
+    i2s.shifted.extend([1, 2, 3, 4]);
+
+    assert_eq!(i2s.drain_tx(&mut output), 4);
+    assert!(i2s.shifted.is_empty());
+    assert_eq!(output.len(), AUDIO_BUFFER_SIZE);
+    assert_eq!(output.iter().rev().take(4).copied().collect::<Vec<_>>(), vec![4, 3, 2, 1]);
+}
+
+#[test]
+fn test_play_dma() {
+    let mut i2s = I2SConfig { play_base: 0x1000, play_length: 16, ..Default::default() };
+    i2s.control.set_i2s_enable(true);
+    i2s.reset.set_i2s_play(true);
+    i2s.reset.set_play_left(true);
+    i2s.reset.set_play_right(true);
+    i2s.i2s_control.set_prescaler(1);
+    assert!(i2s.is_playing());
+    assert_eq!(i2s.frame_steps(), 512);
+    i2s.start_play(1000);
+
+    // One stereo frame every 512 steps, with the middle of the buffer crossed by the second one.
+    assert_eq!(i2s.next_boundary(), 1000 + 512 * 2);
+    assert_eq!(i2s.next_frame(1511), None);
+    assert_eq!(i2s.next_frame(1512), Some((0x1000, false)));
+    assert_eq!(i2s.next_frame(2024), Some((0x1004, true)));
+    assert!(i2s.play_status.get_dma_middle() && i2s.control.get_play_dma_irq());
+
+    // The end of the buffer wraps around to the start.
+    assert_eq!(i2s.next_boundary(), 1000 + 512 * 4);
+    assert_eq!(i2s.next_frame(2536), Some((0x1008, false)));
+    assert_eq!(i2s.next_frame(3048), Some((0x100c, true)));
+    assert!(i2s.play_status.get_dma_end());
+    assert_eq!(i2s.next_frame(3560), Some((0x1000, false)));
+
+    // A single channel takes 16 bits per frame.
+    i2s.reset.set_play_right(false);
+    assert_eq!(i2s.frame_bytes(), 2);
+}
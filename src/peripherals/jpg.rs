@@ -0,0 +1,269 @@
+use bit_field::{B4, B16, bitfield};
+use log::{trace, warn};
+use crate::{device::{StopReason, UnicornContext, request_stop}, log_unsupported_read, log_unsupported_write, peripherals::{aic::{InterruptNumber, post_interrupt}, common::{mmio_get_store_only, mmio_set_store_only}}};
+use crate::{impl_reset, impl_snapshot, impl_snapshot_bitfield};
+
+pub const BASE: u64 = 0xb000a000;
+pub const SIZE: usize = 0x1000;
+
+const REG_JMCR: u64 = 0x0;
+const REG_JPRIWH: u64 = 0x18;
+const REG_JDECWH: u64 = 0x28;
+const REG_JINTCR: u64 = 0x2c;
+const REG_JYADDR0: u64 = 0x7c;
+const REG_JUADDR0: u64 = 0x80;
+const REG_JVADDR0: u64 = 0x84;
+const REG_JIOADDR0: u64 = 0xa0;
+const REG_JPRI_SIZE: u64 = 0xa8;
+
+/// Registers that only need to hold their value: header and quantization control, scaling, windowed decode, strides
+/// and the secondary buffers.
+const STORE_ONLY: &[u64] = &[
+    0x04, 0x08, 0x10, 0x14, 0x1c, 0x20, 0x24, 0x40, 0x44, 0x48, 0x4c, 0x50, 0x54, 0x58, 0x70, 0x74, 0x78, 0x88, 0x8c,
+    0x90, 0x94, 0x98, 0x9c, 0xa4, 0xac, 0xb0,
+];
+/// Quantization tables.
+const REG_QTBL_START: u64 = 0x100;
+const REG_QTBL_END: u64 = 0x200;
+
+/// Bitstream bytes searched for the frame header when decoding.
+const HEADER_SCAN_LIMIT: usize = 0x10000;
+const HEADER_SCAN_CHUNK: usize = 0x1000;
+
+#[bitfield]
+#[derive(Default)]
+pub struct JPGControl {
+    /// Start the operation. Cleared when the codec finishes.
+    enable: bool,
+    reserved_1: bool,
+    reset: bool,
+    thumbnail: bool,
+    y_only: bool,
+    primary: bool,
+    windowed_decode: bool,
+    /// true - encode, false - decode.
+    encode: bool,
+    resume_output: bool,
+    resume_input: bool,
+    reserved_10: B4,
+    reserved_14: bool,
+    reserved_15: bool,
+    reserved_16: B16,
+}
+
+#[bitfield]
+#[derive(Default)]
+pub struct JPGInterrupt {
+    header_decoded: bool,
+    decode_done: bool,
+    encode_done: bool,
+    decode_error: bool,
+    reserved_4: B4,
+    header_decoded_enable: bool,
+    decode_done_enable: bool,
+    encode_done_enable: bool,
+    decode_error_enable: bool,
+    reserved_12: B4,
+    reserved_16: B16,
+}
+
+/// Status bits of `JPGInterrupt`. The enable bits are the same, shifted by 8.
+const JINTCR_STATUS_MASK: u64 = 0xf;
+
+/// JPEG codec.
+///
+/// Operations complete immediately, without touching the image data. Decoding reports the image size from the frame
+/// header so firmware can go on with the (unmodified) output buffers.
+#[derive(Default)]
+pub struct JPGConfig {
+    pub control: JPGControl,
+    pub interrupt: JPGInterrupt,
+    /// Encoder input size, as `height << 16 | width`.
+    pub encode_size: u32,
+    /// Decoded image size, as `height << 16 | width`.
+    pub decode_size: u32,
+    pub y_addr: u32,
+    pub u_addr: u32,
+    pub v_addr: u32,
+    /// Bitstream address. Input when decoding, output when encoding.
+    pub bitstream_addr: u32,
+    /// Size of the encoded bitstream.
+    pub encoded_size: u32,
+}
+
+impl JPGConfig {
+    fn irq_pending(&self) -> bool {
+        let value = self.interrupt.get(0, 32);
+        value & (value >> 8) & JINTCR_STATUS_MASK != 0
+    }
+}
+
+/// Find the image size in the frame header (SOFn) of a JPEG bitstream. Returns `None` if the stream does not start
+/// with SOI or ends before a frame header.
+pub fn parse_frame_size(data: &[u8]) -> Option<(u16, u16)> {
+    if data.get(..2)? != [0xff, 0xd8] {
+        return None;
+    }
+    let mut offset = 2;
+    loop {
+        let [0xff, marker] = *data.get(offset..offset + 2)? else {
+            return None;
+        };
+        // Fill bytes before a marker.
+        if marker == 0xff {
+            offset += 1;
+            continue;
+        }
+        let len = usize::from(u16::from_be_bytes(data.get(offset + 2..offset + 4)?.try_into().unwrap()));
+        // SOF0 to SOF15, except DHT, JPG and DAC.
+        if (0xc0..=0xcf).contains(&marker) && ![0xc4, 0xc8, 0xcc].contains(&marker) {
+            let header = data.get(offset + 4..offset + 9)?;
+            let height = u16::from_be_bytes([header[1], header[2]]);
+            let width = u16::from_be_bytes([header[3], header[4]]);
+            return Some((width, height));
+        }
+        // Entropy coded data follows SOS, so there is no frame header in this stream.
+        if marker == 0xda {
+            return None;
+        }
+        offset += 2 + len;
+    }
+}
+
+pub fn read(uc: &mut UnicornContext, addr: u64, size: usize) -> u64 {
+    if size != 4 {
+        log_unsupported_read!(addr, size);
+        return 0;
+    }
+
+    let jpg = &uc.get_data().jpg;
+
+    match addr {
+        REG_JMCR => jpg.control.get(0, 32),
+        REG_JPRIWH => jpg.encode_size.into(),
+        REG_JDECWH => jpg.decode_size.into(),
+        REG_JINTCR => jpg.interrupt.get(0, 32),
+        REG_JYADDR0 => jpg.y_addr.into(),
+        REG_JUADDR0 => jpg.u_addr.into(),
+        REG_JVADDR0 => jpg.v_addr.into(),
+        REG_JIOADDR0 => jpg.bitstream_addr.into(),
+        REG_JPRI_SIZE => jpg.encoded_size.into(),
+        _ if STORE_ONLY.contains(&addr) || (REG_QTBL_START..REG_QTBL_END).contains(&addr) => {
+            mmio_get_store_only(uc, BASE + addr)
+        }
+        _ => {
+            log_unsupported_read!(addr, size);
+            0
+        }
+    }
+}
+
+pub fn write(uc: &mut UnicornContext, addr: u64, size: usize, value: u64) {
+    if size != 4 {
+        log_unsupported_write!(addr, size, value);
+        return;
+    }
+
+    let value32 = u32::try_from(value & 0xffffffff).unwrap();
+    let jpg = &mut uc.get_data_mut().jpg;
+
+    match addr {
+        REG_JMCR => {
+            jpg.control.set(0, 32, value);
+            if jpg.control.get_reset() {
+                trace!("JPG: Reset");
+                jpg.control.set_enable(false);
+                jpg.interrupt.set(0, 8, 0);
+            } else if jpg.control.get_enable() {
+                request_stop(uc, StopReason::Tick);
+            }
+        }
+        REG_JPRIWH => jpg.encode_size = value32,
+        REG_JINTCR => {
+            let status = jpg.interrupt.get(0, 32) & !(value & JINTCR_STATUS_MASK);
+            jpg.interrupt.set(0, 32, (value & !JINTCR_STATUS_MASK) | status & JINTCR_STATUS_MASK);
+        }
+        REG_JYADDR0 => jpg.y_addr = value32,
+        REG_JUADDR0 => jpg.u_addr = value32,
+        REG_JVADDR0 => jpg.v_addr = value32,
+        REG_JIOADDR0 => jpg.bitstream_addr = value32,
+        _ if STORE_ONLY.contains(&addr) || (REG_QTBL_START..REG_QTBL_END).contains(&addr) => {
+            mmio_set_store_only(uc, BASE + addr, value)
+        }
+        _ => log_unsupported_write!(addr, size, value),
+    }
+}
+
+/// Read the beginning of the bitstream until a frame header is found or the stream stops being readable.
+fn scan_frame_size(uc: &mut UnicornContext, addr: u64) -> Option<(u16, u16)> {
+    let mut data = vec![];
+    while data.len() < HEADER_SCAN_LIMIT {
+        let chunk_addr = addr + u64::try_from(data.len()).unwrap();
+        let Ok(chunk) = uc.mem_read_as_vec(chunk_addr, HEADER_SCAN_CHUNK) else {
+            break;
+        };
+        data.extend(chunk);
+        if let Some(size) = parse_frame_size(&data) {
+            return Some(size);
+        }
+    }
+    None
+}
+
+pub fn tick(uc: &mut UnicornContext) {
+    let jpg = &uc.get_data().jpg;
+    // An operation started with the clock off runs once the clock is enabled.
+    if !jpg.control.get_enable() || !uc.get_data().clk.ahbclk.get_jpg() {
+        return;
+    }
+
+    if jpg.control.get_encode() {
+        warn!("JPG: Encoding is not implemented. Reporting an empty bitstream.");
+        let jpg = &mut uc.get_data_mut().jpg;
+        jpg.encoded_size = 0;
+        jpg.interrupt.set_encode_done(true);
+    } else {
+        let size = scan_frame_size(uc, jpg.bitstream_addr.into());
+        trace!("JPG: Decode 0x{:08x}, frame size {size:?}", uc.get_data().jpg.bitstream_addr);
+        let jpg = &mut uc.get_data_mut().jpg;
+        match size {
+            Some((width, height)) => {
+                jpg.decode_size = u32::from(height) << 16 | u32::from(width);
+                jpg.interrupt.set_header_decoded(true);
+                jpg.interrupt.set_decode_done(true);
+            }
+            None => {
+                warn!("JPG: No frame header in bitstream at 0x{:08x}.", jpg.bitstream_addr);
+                jpg.interrupt.set_decode_error(true);
+            }
+        }
+    }
+
+    let jpg = &mut uc.get_data_mut().jpg;
+    jpg.control.set_enable(false);
+    if jpg.irq_pending() {
+        post_interrupt(uc, InterruptNumber::JPG);
+    }
+}
+
+impl_snapshot_bitfield!(JPGControl, JPGInterrupt);
+impl_snapshot!(JPGConfig {
+    control, interrupt, encode_size, decode_size, y_addr, u_addr, v_addr, bitstream_addr, encoded_size,
+});
+impl_reset!(JPGConfig);
+
+#[test]
+fn test_parse_frame_size() {
+    // SOI, APP0 with 2 bytes of payload, fill byte, SOF0 of a 320x240 3 component image.
+    let stream = [
+        0xff, 0xd8, 0xff, 0xe0, 0x00, 0x04, 0x4a, 0x46, 0xff, 0xff, 0xc0, 0x00, 0x11, 0x08, 0x00, 0xf0, 0x01, 0x40,
+        0x03,
+    ];
+    assert_eq!(parse_frame_size(&stream), Some((320, 240)));
+    // Truncated inside the frame header.
+    assert_eq!(parse_frame_size(&stream[..16]), None);
+    // Not a JPEG.
+    assert_eq!(parse_frame_size(&stream[2..]), None);
+    // Scan data without a frame header.
+    assert_eq!(parse_frame_size(&[0xff, 0xd8, 0xff, 0xda, 0x00, 0x02, 0xff, 0xc0]), None);
+}
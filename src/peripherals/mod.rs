@@ -3,14 +3,20 @@ pub mod common;
 pub mod adc;
 pub mod aic;
 pub mod blt;
+pub mod des;
+pub mod edma;
 pub mod gpio;
+pub mod i2c;
 pub mod i2s;
+pub mod jpg;
 pub mod pwm;
 pub mod rtc;
 pub mod sdram;
 pub mod sic;
+pub mod spi;
 pub mod spu;
 pub mod sys;
 pub mod tmr;
 pub mod uart;
+pub mod videoin;
 pub mod vpost;
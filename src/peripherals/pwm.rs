@@ -1,15 +1,266 @@
-use log::warn;
-use crate::{device::UnicornContext, log_unsupported_read, log_unsupported_write};
+use bit_field::{B2, B4, B8, B12, bitfield};
+use log::{trace, warn};
+use crate::{device::UnicornContext, log_unsupported_read, log_unsupported_write, peripherals::aic::{InterruptNumber, post_interrupt}};
+use crate::{impl_reset, impl_snapshot, impl_snapshot_bitfield};
 
 pub const BASE: u64 = 0xb8007000;
 pub const SIZE: usize = 0x1000;
 
+const REG_PPR: u64 = 0x0;
+const REG_CSR: u64 = 0x4;
+const REG_PCR: u64 = 0x8;
+const REG_CNR_START: u64 = 0xc;
+const REG_CNR_END: u64 = 0x3c;
+const REG_PIER: u64 = 0x3c;
+const REG_PIIR: u64 = 0x40;
+
+#[bitfield]
+#[derive(Default)]
+pub struct PWMPrescaler {
+    /// Prescaler of channel 0 and 1.
+    prescale_01: B8,
+    /// Prescaler of channel 2 and 3.
+    prescale_23: B8,
+    dead_zone_01: B8,
+    dead_zone_23: B8,
+}
+
+#[bitfield]
+#[derive(Default, Debug, PartialEq)]
+pub enum PWMClockSelect {
+    #[default]
+    Div2,
+    Div4,
+    Div8,
+    Div16,
+    Div1,
+    Reserved5,
+    Reserved6,
+    Reserved7,
+}
+
+impl PWMClockSelect {
+    pub fn divider(&self) -> u64 {
+        match self {
+            Self::Div1 => 1,
+            Self::Div2 => 2,
+            Self::Div4 => 4,
+            Self::Div8 => 8,
+            Self::Div16 => 16,
+            _ => 1,
+        }
+    }
+}
+
+#[bitfield]
+#[derive(Default)]
+pub struct PWMClockSelectRegister {
+    ch0: PWMClockSelect,
+    reserved_3: bool,
+    ch1: PWMClockSelect,
+    reserved_7: bool,
+    ch2: PWMClockSelect,
+    reserved_11: bool,
+    ch3: PWMClockSelect,
+    reserved_15: bool,
+}
+
+#[bitfield]
+#[derive(Default)]
+pub struct PWMChannelControl {
+    enable: bool,
+    reserved_1: bool,
+    inverter: bool,
+    /// true - auto reload, false - one shot.
+    auto_reload: bool,
+    reserved_4: B4,
+}
+
+/// PWM control register. Each channel has a 4-bit control block laid out as `PWMChannelControl`.
+#[bitfield]
+#[derive(Default)]
+pub struct PWMControl {
+    ch0_enable: bool,
+    reserved_1: bool,
+    ch0_inverter: bool,
+    ch0_auto_reload: bool,
+    dead_zone_01: bool,
+    dead_zone_23: bool,
+    reserved_6: B2,
+    ch1_enable: bool,
+    reserved_9: bool,
+    ch1_inverter: bool,
+    ch1_auto_reload: bool,
+    ch2_enable: bool,
+    reserved_13: bool,
+    ch2_inverter: bool,
+    ch2_auto_reload: bool,
+    ch3_enable: bool,
+    reserved_17: bool,
+    ch3_inverter: bool,
+    ch3_auto_reload: bool,
+    reserved_20: B12,
+}
+
+/// Bit offset of a channel's control block in `PWMControl`.
+#[inline]
+fn control_offset(index: usize) -> usize {
+    match index {
+        0 => 0,
+        _ => 4 + 4 * index,
+    }
+}
+
+#[derive(Default)]
+pub struct PWMChannel {
+    /// Down counter. Reloaded from `reload` on underflow when auto reload is enabled.
+    pub count: u16,
+    /// Counter reload value (CNR).
+    pub reload: u16,
+    /// Comparator value (CMR). Output is high when `count <= compare`.
+    pub compare: u16,
+    /// Current output level after the inverter.
+    pub level: bool,
+    /// Step at which `count` was last brought up to date. Counts in between are applied lazily by `sync()`.
+    counted_at: u64,
+}
+
+#[derive(Default)]
+pub struct PWMConfig {
+    pub prescaler: PWMPrescaler,
+    pub clock_select: PWMClockSelectRegister,
+    pub control: PWMControl,
+    pub channels: [PWMChannel; 4],
+    pub irq_enable: u8,
+    pub irq_status: u8,
+}
+
+impl PWMConfig {
+    fn channel_control(&self, index: usize) -> PWMChannelControl {
+        let mut control = PWMChannelControl::new();
+        control.set(0, 4, self.control.get(control_offset(index), 4));
+        control
+    }
+
+    /// Number of APB ticks per count of a channel.
+    fn channel_rate(&self, index: usize) -> u64 {
+        let prescale = if index < 2 {
+            self.prescaler.get_prescale_01()
+        } else {
+            self.prescaler.get_prescale_23()
+        };
+        let clock_select = match index {
+            0 => self.clock_select.get_ch0(),
+            1 => self.clock_select.get_ch1(),
+            2 => self.clock_select.get_ch2(),
+            _ => self.clock_select.get_ch3(),
+        };
+        (u64::from(prescale) + 1) * clock_select.divider()
+    }
+
+    /// Apply the counts due by `steps` to every running channel. Returns whether a channel with its interrupt enabled
+    /// completed a period.
+    fn sync(&mut self, steps: u64, div_apb: u64, clock_enabled: bool) -> bool {
+        let mut fired = false;
+        for i in 0..4 {
+            let control = self.channel_control(i);
+            let period = div_apb * self.channel_rate(i);
+            let channel = &mut self.channels[i];
+            // Gated and stopped channels keep their count.
+            if !clock_enabled || !control.get_enable() {
+                channel.counted_at = steps;
+                continue;
+            }
+            let counts = steps.saturating_sub(channel.counted_at) / period;
+            channel.counted_at += counts * period;
+
+            let (period_done, keep_running) = channel.advance(&control, counts);
+            if !keep_running {
+                self.control.set_bit(control_offset(i), false);
+            }
+            if period_done {
+                self.irq_status |= 1 << i;
+                fired |= self.irq_enable & (1 << i) != 0;
+            }
+        }
+        fired
+    }
+
+    /// Step at which the earliest running channel underflows.
+    fn next_underflow(&self, div_apb: u64) -> Option<u64> {
+        (0..4).filter(|&i| self.channel_control(i).get_enable()).map(|i| {
+            let channel = &self.channels[i];
+            channel.counted_at + (u64::from(channel.count) + 1) * div_apb * self.channel_rate(i)
+        }).min()
+    }
+}
+
+impl PWMChannel {
+    /// Count down `counts` times. The count after reaching 0 is the underflow, which completes a period and reloads
+    /// the counter or stops the channel. Returns whether a period has been completed, and whether the channel keeps
+    /// running.
+    fn advance(&mut self, control: &PWMChannelControl, counts: u64) -> (bool, bool) {
+        if counts == 0 {
+            return (false, true);
+        }
+        let count = u64::from(self.count);
+        let mut period_done = false;
+        let mut keep_running = true;
+        if counts <= count {
+            self.count -= u16::try_from(counts).unwrap();
+        } else {
+            period_done = true;
+            if control.get_auto_reload() {
+                let since_reload = (counts - count - 1) % (u64::from(self.reload) + 1);
+                self.count = self.reload - u16::try_from(since_reload).unwrap();
+            } else {
+                self.count = 0;
+                keep_running = false;
+            }
+        }
+        self.level = (self.count <= self.compare) != control.get_inverter();
+        (period_done, keep_running)
+    }
+}
+
+/// Apply the counts due by now, posting the PWM interrupt if a period has been completed. Needs to be called before
+/// anything that changes how the counters run.
+pub fn sync(uc: &mut UnicornContext) {
+    let steps = uc.get_data().steps;
+    let div_apb = uc.get_data().clk.tick_config.apb;
+    let clock_enabled = uc.get_data().clk.apbclk.get_pwm();
+    if uc.get_data_mut().pwm.sync(steps, div_apb, clock_enabled) {
+        post_interrupt(uc, InterruptNumber::PWM);
+    }
+}
+
 pub fn read(uc: &mut UnicornContext, addr: u64, size: usize) -> u64 {
     if size != 4 {
         log_unsupported_read!(addr, size);
         return 0;
     }
+
+    sync(uc);
+    let pwm = &uc.get_data().pwm;
+
     match addr {
+        REG_PPR => pwm.prescaler.get(0, 32),
+        REG_CSR => pwm.clock_select.get(0, 16),
+        REG_PCR => pwm.control.get(0, 32),
+        REG_CNR_START..REG_CNR_END => {
+            let channel = &pwm.channels[usize::try_from((addr - REG_CNR_START) / 0xc).unwrap()];
+            match (addr - REG_CNR_START) % 0xc {
+                0x0 => channel.reload.into(),
+                0x4 => channel.compare.into(),
+                0x8 => channel.count.into(),
+                _ => {
+                    log_unsupported_read!(addr, size);
+                    0
+                }
+            }
+        }
+        REG_PIER => pwm.irq_enable.into(),
+        REG_PIIR => pwm.irq_status.into(),
         _ => {
             log_unsupported_read!(addr, size);
             0
@@ -22,9 +273,90 @@ pub fn write(uc: &mut UnicornContext, addr: u64, size: usize, value: u64) {
         log_unsupported_write!(addr, size, value);
         return;
     }
+
+    sync(uc);
+    let steps = uc.get_data().steps;
+    let pwm = &mut uc.get_data_mut().pwm;
+
     match addr {
+        REG_PPR => pwm.prescaler.set(0, 32, value),
+        REG_CSR => pwm.clock_select.set(0, 16, value & 0xffff),
+        REG_PCR => {
+            let prev_enabled: Vec<bool> = (0..4).map(|i| pwm.channel_control(i).get_enable()).collect();
+            pwm.control.set(0, 32, value);
+            for (i, was_enabled) in prev_enabled.into_iter().enumerate() {
+                // Counter is loaded from CNR when the channel starts.
+                if !was_enabled && pwm.channel_control(i).get_enable() {
+                    let channel = &mut pwm.channels[i];
+                    channel.count = channel.reload;
+                    channel.counted_at = steps;
+                    trace!("PWM{i} start reload={} compare={}", channel.reload, channel.compare);
+                }
+            }
+        }
+        REG_CNR_START..REG_CNR_END => {
+            let channel = &mut pwm.channels[usize::try_from((addr - REG_CNR_START) / 0xc).unwrap()];
+            match (addr - REG_CNR_START) % 0xc {
+                0x0 => channel.reload = u16::try_from(value & 0xffff).unwrap(),
+                0x4 => channel.compare = u16::try_from(value & 0xffff).unwrap(),
+                _ => log_unsupported_write!(addr, size, value),
+            }
+        }
+        REG_PIER => pwm.irq_enable = u8::try_from(value & 0xf).unwrap(),
+        REG_PIIR => pwm.irq_status &= !u8::try_from(value & 0xf).unwrap(),
         _ => {
             log_unsupported_write!(addr, size, value);
         }
     }
 }
+
+pub fn generate_stop_condition(uc: &mut UnicornContext, steps: u64) {
+    let div_apb = uc.get_data().clk.tick_config.apb;
+    let clock_enabled = uc.get_data().clk.apbclk.get_pwm();
+    if uc.get_data_mut().pwm.sync(steps, div_apb, clock_enabled) {
+        post_interrupt(uc, InterruptNumber::PWM);
+    }
+}
+
+/// Step at which the next channel underflows, or `None` if all channels are stopped or gated.
+pub fn next_event(uc: &UnicornContext, steps: u64) -> Option<u64> {
+    if !uc.get_data().clk.apbclk.get_pwm() {
+        return None;
+    }
+    let next = uc.get_data().pwm.next_underflow(uc.get_data().clk.tick_config.apb)?;
+    Some(next.max(steps + 1))
+}
+
+impl_snapshot_bitfield!(PWMPrescaler, PWMClockSelectRegister, PWMControl);
+impl_snapshot!(PWMChannel { count, reload, compare, level, counted_at });
+impl_snapshot!(PWMConfig { prescaler, clock_select, control, channels, irq_enable, irq_status });
+impl_reset!(PWMConfig);
+
+#[test]
+fn test_lazy_count() {
+    let mut pwm = PWMConfig::default();
+    pwm.clock_select.set_ch0(PWMClockSelect::Div1);
+    pwm.channels[0].reload = 3;
+    pwm.channels[0].count = 3;
+    pwm.irq_enable = 1;
+    pwm.control.set_ch0_enable(true);
+    pwm.control.set_ch0_auto_reload(true);
+
+    // One count every 2 steps, so the underflow is the 4th count.
+    assert_eq!(pwm.next_underflow(2), Some(8));
+    assert!(!pwm.sync(7, 2, true));
+    assert_eq!(pwm.channels[0].count, 0);
+    assert!(pwm.sync(8, 2, true));
+    assert_eq!(pwm.channels[0].count, 3);
+
+    // Several periods at once land on the same count as counting one by one.
+    assert!(pwm.sync(8 + 2 * 10, 2, true));
+    assert_eq!(pwm.channels[0].count, 1);
+    assert_eq!(pwm.next_underflow(2), Some(28 + 2 * 2));
+
+    // One shot channels stop at the underflow.
+    pwm.control.set_ch0_auto_reload(false);
+    assert!(pwm.sync(100, 2, true));
+    assert!(!pwm.control.get_ch0_enable());
+    assert_eq!(pwm.next_underflow(2), None);
+}
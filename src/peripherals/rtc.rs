@@ -1,10 +1,11 @@
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
 
 use bit_field::{B4, B5, B8, B12, bitfield};
 use log::{debug, error, trace, warn};
-use chrono::{DateTime, Datelike, Local, Timelike};
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, TimeZone, Timelike};
 
-use crate::{device::{QuitDetail, StopReason, UnicornContext, request_quit, request_stop}, log_unsupported_read, log_unsupported_write, peripherals::{aic::{InterruptNumber, post_interrupt}, common::{mmio_get_store_only, mmio_set_store_only}}};
+use crate::{device::{QuitDetail, StopReason, UnicornContext, request_quit, request_stop}, log_unsupported_read, log_unsupported_write, peripherals::{aic::{InterruptNumber, post_interrupt}, common::{mmio_load_masked, mmio_store_masked}}};
+use crate::{RuntimeError, impl_reset, impl_snapshot_bitfield, snapshot::{Snapshot, load_new}};
 
 pub const BASE: u64 = 0xb8003000;
 pub const SIZE: usize = 0x1000;
@@ -16,8 +17,11 @@ const REG_TLR: u64 = 0xc;
 const REG_CLR: u64 = 0x10;
 const REG_TSSR: u64 = 0x14;
 const REG_DWR: u64 = 0x18;
+const REG_TAR: u64 = 0x1c;
+const REG_CAR: u64 = 0x20;
 const REG_RIER: u64 = 0x28;
 const REG_RIIR: u64 = 0x2c;
+const REG_TTR: u64 = 0x30;
 const REG_PWRON: u64 = 0x34;
 
 const MAGIC_INIT: u32 = 0xa5eb1357;
@@ -31,10 +35,76 @@ pub struct RTCConfig {
     pub timekeeper: TimeKeeper,
     pub irq_enable: RTCIRQFlag,
     pub irq_status: RTCIRQFlag,
+    /// Alarm time in the same format as REG_TLR.
+    pub alarm_time: u32,
+    /// Alarm date in the same format as REG_CLR.
+    pub alarm_date: u32,
+    /// Tick rate selection. Only 1 tick per second is emulated.
+    pub tick_rate: u8,
+    /// When a delayed power off takes effect.
+    pub power_off_deadline: Option<Instant>,
+    /// Seconds left before power off, as last logged.
+    power_off_remaining: u64,
 
     pub irq_on_frame_step: bool,
 }
 
+impl RTCConfig {
+    /// Refresh the time and update the IRQ status accordingly. Returns whether an interrupt needs to be raised.
+    pub fn refresh(&mut self) -> bool {
+        let Some(prev) = self.timekeeper.refresh() else {
+            return false;
+        };
+        let now = self.timekeeper.now();
+
+        let mut fire = false;
+        self.irq_status.set_tick(true);
+        fire |= self.irq_enable.get_tick();
+
+        // Alarm fires once when the time passes it, even if the refresh skipped over the exact second.
+        if let Some(alarm) = self.alarm() && prev < alarm && alarm <= now {
+            debug!("RTC alarm at {alarm}");
+            self.irq_status.set_alarm(true);
+            fire |= self.irq_enable.get_alarm();
+        }
+        fire
+    }
+
+    fn alarm(&self) -> Option<NaiveDateTime> {
+        let date = parse_date_reg(self.alarm_date)?;
+        let time = parse_time_reg(self.alarm_time, self.timekeeper.is_24hr)?;
+        Some(date.and_time(time))
+    }
+}
+
+#[inline]
+fn from_bcd(value: u8) -> Option<u32> {
+    let (hi, lo) = (value >> 4, value & 0xf);
+    (hi < 10 && lo < 10).then_some(u32::from(hi * 10 + lo))
+}
+
+/// Parse a time in REG_TLR format.
+fn parse_time_reg(reg: u32, is_24hr: bool) -> Option<NaiveTime> {
+    let [_, hour_bcd, minute_bcd, second_bcd] = reg.to_be_bytes();
+    let hour = if is_24hr {
+        from_bcd(hour_bcd)?
+    } else {
+        let hour = from_bcd(hour_bcd & 0x1f)?;
+        if !(1..=12).contains(&hour) {
+            return None;
+        }
+        hour % 12 + if hour_bcd & 0x20 != 0 { 12 } else { 0 }
+    };
+    NaiveTime::from_hms_opt(hour, from_bcd(minute_bcd)?, from_bcd(second_bcd)?)
+}
+
+/// Parse a date in REG_CLR format.
+fn parse_date_reg(reg: u32) -> Option<NaiveDate> {
+    let [_, year_bcd, month_bcd, day_bcd] = reg.to_be_bytes();
+    let year = i32::try_from(from_bcd(year_bcd)?).unwrap() + 2000;
+    NaiveDate::from_ymd_opt(year, from_bcd(month_bcd)?, from_bcd(day_bcd)?)
+}
+
 #[bitfield]
 #[derive(Default)]
 pub struct RTCIRQFlag {
@@ -65,10 +135,91 @@ impl Default for PowerControl {
     }
 }
 
+/// Wall clock owned by the emulator. It follows the host clock at `scale` times its speed and stops while paused, so
+/// guest time does not jump after the emulator was halted.
+pub struct GuestClock {
+    /// Clock time at which `elapsed` is zero.
+    origin: SystemTime,
+    /// Clock time elapsed up to `anchor`.
+    elapsed: Duration,
+    /// Host time `elapsed` was last brought up to date at, or `None` while paused.
+    anchor: Option<Instant>,
+    scale: f64,
+}
+
+impl Default for GuestClock {
+    fn default() -> Self {
+        Self { origin: SystemTime::now(), elapsed: Duration::ZERO, anchor: Some(Instant::now()), scale: 1.0 }
+    }
+}
+
+impl GuestClock {
+    pub fn now(&self) -> SystemTime {
+        self.origin + self.elapsed()
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.elapsed + self.anchor.map_or(Duration::ZERO, |anchor| anchor.elapsed().mul_f64(self.scale))
+    }
+
+    /// Bring `elapsed` up to date, so the clock can be reconfigured from this point on.
+    fn reanchor(&mut self) {
+        self.elapsed = self.elapsed();
+        if self.anchor.is_some() {
+            self.anchor = Some(Instant::now());
+        }
+    }
+
+    pub fn pause(&mut self) {
+        self.reanchor();
+        self.anchor = None;
+    }
+
+    pub fn resume(&mut self) {
+        if self.anchor.is_none() {
+            self.anchor = Some(Instant::now());
+        }
+    }
+
+    /// Set how fast the clock runs relative to the host clock. 0 stops it.
+    pub fn set_scale(&mut self, scale: f64) {
+        self.reanchor();
+        self.scale = scale;
+    }
+
+    /// Restart the clock from `start`.
+    pub fn set_start(&mut self, start: SystemTime) {
+        self.reanchor();
+        self.origin = start;
+        self.elapsed = Duration::ZERO;
+    }
+}
+
+/// Parse a local time like `2020-01-01 00:00:00` from the command line.
+pub fn parse_local_time(s: &str) -> Result<SystemTime, String> {
+    let naive = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").map_err(|err| format!("{err}"))?;
+    let local = Local.from_local_datetime(&naive).earliest().ok_or_else(|| format!("{s} does not exist locally"))?;
+    Ok(local.into())
+}
+
+/// Parse a clock speed from the command line. The clock can't run backwards or infinitely fast.
+pub fn parse_time_scale(s: &str) -> Result<f64, String> {
+    let scale: f64 = s.parse().map_err(|err| format!("{err}"))?;
+    if scale.is_finite() && scale >= 0.0 {
+        Ok(scale)
+    } else {
+        Err(format!("{s} is not a finite, non-negative speed"))
+    }
+}
+
 pub struct TimeKeeper {
     pub is_24hr: bool,
+    /// Source of the guest time. The guest can only shift it by `offset`.
+    clock: GuestClock,
     prev_sec: i64,
     cached_dt: DateTime<Local>,
+    /// Difference between the guest time and the host time.
+    offset: TimeDelta,
 }
 
 impl Default for TimeKeeper {
@@ -79,8 +230,23 @@ impl Default for TimeKeeper {
 
 impl TimeKeeper {
     pub fn new() -> Self {
-        let (now, prev_sec) = Self::check_time();
-        Self { is_24hr: Default::default(), prev_sec, cached_dt: DateTime::<Local>::from(now) }
+        let clock = GuestClock::default();
+        let (now, prev_sec) = Self::check_time(&clock);
+        Self {
+            is_24hr: Default::default(), clock, prev_sec, cached_dt: DateTime::<Local>::from(now),
+            offset: TimeDelta::zero(),
+        }
+    }
+
+    pub fn clock_mut(&mut self) -> &mut GuestClock {
+        &mut self.clock
+    }
+
+    /// Reload the cached time after the clock has been reconfigured.
+    pub fn sync_clock(&mut self) {
+        let (now, prev_sec) = Self::check_time(&self.clock);
+        self.prev_sec = prev_sec;
+        self.cached_dt = DateTime::<Local>::from(now) + self.offset;
     }
 
     pub fn get_time_reg(&self) -> u32 {
@@ -115,12 +281,11 @@ impl TimeKeeper {
     }
 
     pub fn get_day_of_week_reg(&self) -> u32 {
-        let dow = self.cached_dt.weekday().num_days_from_sunday();
-        u32::from(dow)
+        self.cached_dt.weekday().num_days_from_sunday()
     }
 
-    fn check_time() -> (SystemTime, i64) {
-        let now = SystemTime::now();
+    fn check_time(clock: &GuestClock) -> (SystemTime, i64) {
+        let now = clock.now();
         let current_sec = match now.duration_since(SystemTime::UNIX_EPOCH) {
             Ok(d) => (d.as_secs() & 0x7fffffffffffffff) as i64,
             Err(_err) => match SystemTime::UNIX_EPOCH.duration_since(now) {
@@ -134,34 +299,70 @@ impl TimeKeeper {
         (now, current_sec)
     }
 
-    pub fn refresh(&mut self) {
-        let (now, current_sec) = Self::check_time();
+    /// Set the guest time of day, keeping the date.
+    pub fn set_time(&mut self, time: NaiveTime) {
+        self.set_datetime(self.now().date().and_time(time));
+    }
+
+    /// Set the guest date, keeping the time of day.
+    pub fn set_date(&mut self, date: NaiveDate) {
+        self.set_datetime(date.and_time(self.now().time()));
+    }
+
+    fn set_datetime(&mut self, dt: NaiveDateTime) {
+        let delta = dt - self.now();
+        self.offset += delta;
+        self.cached_dt += delta;
+        debug!("Guest time set to {dt}, offset from host is {}", self.offset);
+    }
+
+    /// Current time as seen by the guest.
+    pub fn now(&self) -> NaiveDateTime {
+        self.cached_dt.naive_local()
+    }
+
+    /// Refresh the cached time. Returns the previous time if it changed.
+    pub fn refresh(&mut self) -> Option<NaiveDateTime> {
+        let (now, current_sec) = Self::check_time(&self.clock);
         if self.prev_sec != current_sec {
             trace!("Timestamp differs for 1 or more second. Refresh triggered.");
+            let prev = self.now();
             self.prev_sec = current_sec;
-            self.cached_dt = DateTime::<Local>::from(now);
+            self.cached_dt = DateTime::<Local>::from(now) + self.offset;
+            Some(prev)
+        } else {
+            None
         }
     }
 }
 
 pub fn read(uc: &mut UnicornContext, addr: u64, size: usize) -> u64 {
     if size != 4 {
+        // The frequency compensation register only stores what the guest writes, so partial accesses are merged.
+        if addr & !3 == REG_FCR {
+            return mmio_load_masked(uc, BASE + addr, size);
+        }
         log_unsupported_read!(addr, size);
         return 0;
     }
 
-    uc.get_data_mut().rtc.timekeeper.refresh();
+    if uc.get_data_mut().rtc.refresh() {
+        post_interrupt(uc, InterruptNumber::RTC);
+    }
 
     match addr {
         REG_INIR => uc.get_data().rtc.enabled.into(),
         REG_AER => if uc.get_data().rtc.write_enabled { 0x10000 } else { 0x0 }
-        REG_FCR => mmio_get_store_only(uc, BASE + addr),
+        REG_FCR => mmio_load_masked(uc, BASE + addr, size),
         REG_TLR => uc.get_data().rtc.timekeeper.get_time_reg().into(),
         REG_CLR => uc.get_data().rtc.timekeeper.get_date_reg().into(),
         REG_TSSR => uc.get_data().rtc.timekeeper.get_time_scale_reg().into(),
         REG_DWR => uc.get_data().rtc.timekeeper.get_day_of_week_reg().into(),
+        REG_TAR => uc.get_data().rtc.alarm_time.into(),
+        REG_CAR => uc.get_data().rtc.alarm_date.into(),
         REG_RIER => uc.get_data().rtc.irq_enable.get(0, 8),
         REG_RIIR => uc.get_data().rtc.irq_status.get(0, 8),
+        REG_TTR => uc.get_data().rtc.tick_rate.into(),
         REG_PWRON => uc.get_data().rtc.power_control.get(0, 32),
         _ => {
             log_unsupported_read!(addr, size);
@@ -171,7 +372,7 @@ pub fn read(uc: &mut UnicornContext, addr: u64, size: usize) -> u64 {
 }
 
 pub fn write(uc: &mut UnicornContext, addr: u64, size: usize, value: u64) {
-    if size != 4 {
+    if size != 4 && addr & !3 != REG_FCR {
         log_unsupported_write!(addr, size, value);
         return;
     }
@@ -183,6 +384,10 @@ pub fn write(uc: &mut UnicornContext, addr: u64, size: usize, value: u64) {
         warn!("Register 0x{addr:x} is write protected.");
         return;
     }
+    if size != 4 {
+        mmio_store_masked(uc, BASE + addr, value, size);
+        return;
+    }
 
     match addr {
         REG_INIR => {
@@ -194,8 +399,23 @@ pub fn write(uc: &mut UnicornContext, addr: u64, size: usize, value: u64) {
         }
         REG_FCR => {
             debug!("Freq compensation: 0x{value:08x}");
-            mmio_set_store_only(uc, BASE + addr, value);
+            mmio_store_masked(uc, BASE + addr, value, size);
         }
+        REG_TLR => {
+            let timekeeper = &mut uc.get_data_mut().rtc.timekeeper;
+            match parse_time_reg(value as u32, timekeeper.is_24hr) {
+                Some(time) => timekeeper.set_time(time),
+                None => warn!("Ignoring invalid time 0x{value:08x}."),
+            }
+        }
+        REG_CLR => {
+            match parse_date_reg(value as u32) {
+                Some(date) => uc.get_data_mut().rtc.timekeeper.set_date(date),
+                None => warn!("Ignoring invalid date 0x{value:08x}."),
+            }
+        }
+        REG_TAR => uc.get_data_mut().rtc.alarm_time = (value & 0x3f7f7f) as u32,
+        REG_CAR => uc.get_data_mut().rtc.alarm_date = (value & 0xff1f3f) as u32,
         REG_RIER => {
             uc.get_data_mut().rtc.irq_enable.set(0, 8, value);
             trace!("Set REG_RIER {:?}", uc.get_data().rtc.irq_enable);
@@ -204,15 +424,33 @@ pub fn write(uc: &mut UnicornContext, addr: u64, size: usize, value: u64) {
             let old_value = uc.get_data().rtc.irq_status.get(0, 8);
             uc.get_data_mut().rtc.irq_status.set(0, 8, old_value & !value);
         }
+        REG_TTR => {
+            let tick_rate = (value & 0x7) as u8;
+            if tick_rate != 0 {
+                warn!("Tick rate of {} per second is not supported. Ticking once per second instead.", 1 << tick_rate);
+            }
+            uc.get_data_mut().rtc.tick_rate = tick_rate;
+        }
         REG_PWRON => {
             let power_control = &mut uc.get_data_mut().rtc.power_control;
             power_control.set(0, 32, value);
             if !power_control.get_power_on() || power_control.get_power_off() {
-                debug!("RTC power off requested.");
+                if power_control.get_power_off_delay_enable() {
+                    let delay = u64::from(power_control.get_power_off_delay_sec());
+                    debug!("RTC power off requested in {delay} seconds.");
+                    let rtc = &mut uc.get_data_mut().rtc;
+                    if rtc.power_off_deadline.is_none() {
+                        rtc.power_off_deadline = Some(Instant::now() + Duration::from_secs(delay));
+                        rtc.power_off_remaining = delay;
+                    }
+                } else {
+                    debug!("RTC power off requested.");
+                }
                 request_stop(uc, StopReason::Tick);
+            } else {
+                uc.get_data_mut().rtc.power_off_deadline = None;
             }
         }
-        // TODO Setting a time offset
         _ => {
             log_unsupported_write!(addr, size, value);
         }
@@ -222,20 +460,151 @@ pub fn write(uc: &mut UnicornContext, addr: u64, size: usize, value: u64) {
 }
 
 pub fn tick(uc: &mut UnicornContext) {
-    let power_control = &uc.get_data().rtc.power_control;
-    if power_control.get_power_off() || !power_control.get_power_on() {
-        request_quit(uc, QuitDetail::CPUHalt);
+    let rtc = &mut uc.get_data_mut().rtc;
+    let power_control = &rtc.power_control;
+    if !power_control.get_power_off() && power_control.get_power_on() {
+        return;
+    }
+
+    if let Some(deadline) = rtc.power_off_deadline {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if !remaining.is_zero() {
+            let remaining_sec = remaining.as_secs() + 1;
+            if remaining_sec != rtc.power_off_remaining {
+                debug!("Powering off in {remaining_sec} seconds.");
+                rtc.power_off_remaining = remaining_sec;
+            }
+            return;
+        }
     }
+
+    request_quit(uc, QuitDetail::CPUHalt);
 }
 
+/// The RTC runs from its own always-on power domain, so unlike other peripherals it keeps going with its APB clock
+/// disabled. The clock only gates its register interface.
 pub fn frame_step(uc: &mut UnicornContext) {
+    if uc.get_data().rtc.power_off_deadline.is_some() {
+        tick(uc);
+    }
+
+    if uc.get_data_mut().rtc.refresh() {
+        post_interrupt(uc, InterruptNumber::RTC);
+    }
+
     if uc.get_data().rtc.irq_on_frame_step {
         let rtc = &mut uc.get_data_mut().rtc;
         rtc.irq_on_frame_step = false;
         if rtc.irq_enable.get_power_key() {
             rtc.irq_status.set_power_key(true);
-            post_interrupt(uc, InterruptNumber::RTC, true, false);
+            post_interrupt(uc, InterruptNumber::RTC);
         }
-        return;
     }
 }
+
+impl_snapshot_bitfield!(RTCIRQFlag, PowerControl);
+impl_reset!(RTCConfig { timekeeper });
+/// The power off deadline is saved as the time left until it, so it stays meaningful in another process.
+impl Snapshot for RTCConfig {
+    fn save(&self, out: &mut Vec<u8>) {
+        self.enabled.save(out);
+        self.write_enabled.save(out);
+        self.power_control.save(out);
+        self.timekeeper.save(out);
+        self.irq_enable.save(out);
+        self.irq_status.save(out);
+        self.alarm_time.save(out);
+        self.alarm_date.save(out);
+        self.tick_rate.save(out);
+        self.power_off_deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()).as_millis() as u64)
+            .save(out);
+        self.power_off_remaining.save(out);
+        self.irq_on_frame_step.save(out);
+    }
+
+    fn load(&mut self, input: &mut &[u8]) -> Result<(), RuntimeError> {
+        self.enabled.load(input)?;
+        self.write_enabled.load(input)?;
+        self.power_control.load(input)?;
+        self.timekeeper.load(input)?;
+        self.irq_enable.load(input)?;
+        self.irq_status.load(input)?;
+        self.alarm_time.load(input)?;
+        self.alarm_date.load(input)?;
+        self.tick_rate.load(input)?;
+        self.power_off_deadline = load_new::<Option<u64>>(input)?
+            .map(|remaining| Instant::now() + Duration::from_millis(remaining));
+        self.power_off_remaining.load(input)?;
+        self.irq_on_frame_step.load(input)
+    }
+}
+
+/// Only the offset from the clock is saved. The clock keeps its configuration, and the cached time is refreshed from it
+/// on load.
+impl Snapshot for TimeKeeper {
+    fn save(&self, out: &mut Vec<u8>) {
+        self.is_24hr.save(out);
+        self.offset.num_milliseconds().save(out);
+    }
+
+    fn load(&mut self, input: &mut &[u8]) -> Result<(), RuntimeError> {
+        self.is_24hr.load(input)?;
+        self.offset = TimeDelta::milliseconds(load_new(input)?);
+        self.sync_clock();
+        Ok(())
+    }
+}
+
+#[test]
+fn test_parse_time_reg() {
+    assert_eq!(parse_time_reg(0x235959, true), NaiveTime::from_hms_opt(23, 59, 59));
+    assert_eq!(parse_time_reg(0x121530, false), NaiveTime::from_hms_opt(0, 15, 30));
+    assert_eq!(parse_time_reg(0x321530, false), NaiveTime::from_hms_opt(12, 15, 30));
+    assert_eq!(parse_time_reg(0x241530, true), None);
+    assert_eq!(parse_time_reg(0x0a1530, true), None);
+    assert_eq!(parse_date_reg(0x241231), NaiveDate::from_ymd_opt(2024, 12, 31));
+    assert_eq!(parse_date_reg(0x241301), None);
+}
+
+#[test]
+fn test_set_time() {
+    let mut timekeeper = TimeKeeper::new();
+    timekeeper.is_24hr = true;
+    timekeeper.set_date(NaiveDate::from_ymd_opt(2009, 2, 13).unwrap());
+    timekeeper.set_time(NaiveTime::from_hms_opt(23, 31, 30).unwrap());
+
+    assert_eq!(timekeeper.get_date_reg(), 0x090213);
+    assert_eq!(timekeeper.get_time_reg(), 0x233130);
+    assert_eq!(timekeeper.get_day_of_week_reg(), 5);
+}
+
+#[test]
+fn test_guest_clock() {
+    let start = parse_local_time("2020-01-01 00:00:00").unwrap();
+    let mut timekeeper = TimeKeeper::new();
+    timekeeper.is_24hr = true;
+    let clock = timekeeper.clock_mut();
+    clock.set_scale(0.0);
+    clock.set_start(start);
+    assert_eq!(clock.now(), start);
+    timekeeper.sync_clock();
+    assert_eq!(timekeeper.get_date_reg(), 0x200101);
+    assert_eq!(timekeeper.get_time_reg(), 0x000000);
+    assert_eq!(timekeeper.refresh(), None);
+
+    // A paused clock stands still whatever its scale.
+    let clock = timekeeper.clock_mut();
+    clock.set_scale(1000.0);
+    clock.pause();
+    let paused_at = clock.now();
+    assert_eq!(clock.now(), paused_at);
+    clock.resume();
+    assert!(clock.now() >= paused_at);
+
+    assert!(parse_local_time("2020-13-01 00:00:00").is_err());
+    assert_eq!(parse_time_scale("0.5"), Ok(0.5));
+    assert!(parse_time_scale("-1").is_err());
+    assert!(parse_time_scale("NaN").is_err());
+    assert!(parse_time_scale("inf").is_err());
+}
@@ -1,22 +1,129 @@
-use log::{trace, warn};
-use crate::{device::UnicornContext, log_unsupported_read, log_unsupported_write, peripherals::common::{mmio_get_store_only, mmio_set_store_only}};
+use log::{debug, trace, warn};
+use crate::{device::UnicornContext, log_unsupported_read, log_unsupported_write};
+use crate::impl_snapshot;
 
 pub const BASE: u64 = 0xb0003000;
 pub const SIZE: usize = 0x1000;
 
+const REG_SDCMD: u64 = 0x04;
+/// Registers past this offset are not implemented.
+const REG_END: u64 = 0x40;
+
+/// Controller is running the DDR init sequence. Cleared by software once the sequence is done.
+const SDCMD_INIT_STATE: u32 = 1 << 0;
+/// Issue a PRECHARGE ALL command.
+const SDCMD_PALL: u32 = 1 << 1;
+/// Issue a MODE REGISTER SET command, with the last written SDMR/SDEMR value.
+const SDCMD_MRS: u32 = 1 << 2;
+/// Issue an AUTO REFRESH command.
+const SDCMD_REF: u32 = 1 << 3;
+/// Command bits complete immediately and read back as 0.
+const SDCMD_COMMANDS: u32 = SDCMD_PALL | SDCMD_MRS | SDCMD_REF;
+
+/// Number of MODE REGISTER SET commands the DDR init sequence requires, one for the extended mode register and one
+/// for the mode register.
+const INIT_MODE_SETS: u8 = 2;
+/// Number of AUTO REFRESH commands the DDR init sequence requires.
+const INIT_REFRESHES: u8 = 2;
+
+#[derive(Default)]
+pub struct SDRAMConfig {
+    /// Raw register values, indexed by offset / 4.
+    regs: [u32; (REG_END / 4) as usize],
+    /// Whether a PRECHARGE ALL was issued during the current init sequence.
+    precharged: bool,
+    /// MODE REGISTER SET commands issued during the current init sequence.
+    mode_sets: u8,
+    /// AUTO REFRESH commands issued after the precharge during the current init sequence.
+    refreshes: u8,
+    /// Whether the last init sequence went through all required steps.
+    pub initialized: bool,
+}
+
+impl SDRAMConfig {
+    /// Whether the DDR init sequence went far enough for the memory to be usable.
+    fn init_sequence_done(&self) -> bool {
+        self.precharged && self.mode_sets >= INIT_MODE_SETS && self.refreshes >= INIT_REFRESHES
+    }
+
+    fn write_command(&mut self, value: u32) {
+        let prev = self.regs[(REG_SDCMD / 4) as usize];
+        let in_init = value & SDCMD_INIT_STATE != 0;
+
+        if in_init && prev & SDCMD_INIT_STATE == 0 {
+            trace!("SDRAM init sequence started");
+            self.precharged = false;
+            self.mode_sets = 0;
+            self.refreshes = 0;
+            self.initialized = false;
+        }
+
+        if in_init {
+            if value & SDCMD_PALL != 0 {
+                self.precharged = true;
+            }
+            if value & SDCMD_MRS != 0 {
+                self.mode_sets = self.mode_sets.saturating_add(1);
+            }
+            if value & SDCMD_REF != 0 && self.precharged {
+                self.refreshes = self.refreshes.saturating_add(1);
+            }
+        } else if prev & SDCMD_INIT_STATE != 0 {
+            self.initialized = self.init_sequence_done();
+            if self.initialized {
+                debug!("SDRAM initialized");
+            } else {
+                warn!(
+                    "SDRAM init sequence left early (precharged={}, mode_sets={}, refreshes={})",
+                    self.precharged, self.mode_sets, self.refreshes,
+                );
+            }
+        }
+
+        self.regs[(REG_SDCMD / 4) as usize] = value & !SDCMD_COMMANDS;
+    }
+}
+
 pub fn read(uc: &mut UnicornContext, addr: u64, size: usize) -> u64 {
-    if size != 4 {
+    if size != 4 || addr >= REG_END {
         log_unsupported_read!(addr, size);
         return 0;
     }
-    mmio_get_store_only(uc, addr)
+    uc.get_data().sdram.regs[(addr / 4) as usize].into()
 }
 
 pub fn write(uc: &mut UnicornContext, addr: u64, size: usize, value: u64) {
-    if size != 4 {
+    if size != 4 || addr >= REG_END {
         log_unsupported_write!(addr, size, value);
         return;
     }
     trace!("0x{:08x} <= 0x{:08x}", BASE + addr, value);
-    mmio_set_store_only(uc, addr, value);
+    let sdram = &mut uc.get_data_mut().sdram;
+    let value = value as u32;
+    match addr {
+        REG_SDCMD => sdram.write_command(value),
+        _ => sdram.regs[(addr / 4) as usize] = value,
+    }
+}
+
+impl_snapshot!(SDRAMConfig { regs, precharged, mode_sets, refreshes, initialized });
+
+#[test]
+fn test_init_sequence() {
+    // Sequence used by the bootrom.
+    let mut sdram = SDRAMConfig::default();
+    for value in [0x21, 0x23, 0x27, 0x27, 0x2b, 0x2b] {
+        sdram.write_command(value);
+    }
+    assert_eq!(sdram.regs[(REG_SDCMD / 4) as usize], 0x21);
+    sdram.write_command(0x20);
+    assert!(sdram.initialized);
+    assert_eq!(sdram.regs[(REG_SDCMD / 4) as usize], 0x20);
+
+    // Leaving init without refreshing.
+    let mut sdram = SDRAMConfig::default();
+    for value in [0x21, 0x23, 0x27, 0x27, 0x20] {
+        sdram.write_command(value);
+    }
+    assert!(!sdram.initialized);
 }
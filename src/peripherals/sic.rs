@@ -1,14 +1,18 @@
-use bit_field::{B1, B2, B3, B4, B5, B6, B7, B8, B9, bitfield};
+use bit_field::{B1, B2, B3, B4, B5, B6, B7, B8, B9, B11, B12, B20, bitfield};
 use log::{debug, error, trace, warn};
+use unicorn_engine::uc_error;
 
-use crate::device::{Device, StopReason, UnicornContext, request_stop};
-use crate::extdev::sd::Response;
+use crate::device::{Device, StopReason, UnicornContext, request_stop, schedule_next_event};
+use crate::extdev::nand::NAND;
+use crate::extdev::sd::{Response, SD, crc7, crc16};
 use crate::peripherals::aic::{InterruptNumber, post_interrupt};
 use crate::{log_unsupported_read, log_unsupported_write};
+use crate::{impl_reset, impl_snapshot, impl_snapshot_bitfield};
 
 pub const NAME_DMAC: &str = "DMAC";
 pub const NAME_FMI: &str = "FMI";
 pub const NAME_SD: &str = "SD";
+pub const NAME_NAND: &str = "NAND";
 pub const BASE: u64 = 0xb1006000;
 pub const SIZE: usize = 0x1000;
 
@@ -17,6 +21,8 @@ pub const BASE_FMI: u64 = 0x800;
 
 const REG_FB_0: u64 = BASE_DMAC;
 const REG_FB_0_END: u64 = BASE_DMAC + 0x400;
+/// Size of the shared FIFO buffer, in bytes.
+const FIFO_SIZE: usize = 0x400;
 
 const REG_DMACCSR: u64 = BASE_DMAC + 0x400;
 const REG_DMACSAR: u64 = BASE_DMAC + 0x408;
@@ -37,8 +43,27 @@ const REG_SDRSP0: u64 = BASE_FMI + 0x030;
 const REG_SDRSP1: u64 = BASE_FMI + 0x034;
 const REG_SDBLEN: u64 = BASE_FMI + 0x038;
 const REG_SDTMOUT: u64 = BASE_FMI + 0x03c;
+const REG_SMCSR: u64 = BASE_FMI + 0x0a0;
+const REG_SMTCR: u64 = BASE_FMI + 0x0a4;
+const REG_SMIER: u64 = BASE_FMI + 0x0a8;
+const REG_SMISR: u64 = BASE_FMI + 0x0ac;
+const REG_SMCMD: u64 = BASE_FMI + 0x0b0;
+const REG_SMADDR: u64 = BASE_FMI + 0x0b4;
+const REG_SMDATA: u64 = BASE_FMI + 0x0b8;
 
-// NAND is not emulated
+/// End of table marker in the byte count word of a scatter-gather descriptor.
+const SG_EOT: u32 = 0x80000000;
+/// Maximum number of scatter-gather descriptors to walk before giving up on a malformed table.
+const SG_MAX_DESCRIPTORS: usize = 4096;
+
+/// NAND page sizes selected by `SMCSR.PSIZE`.
+const NAND_PAGE_SIZES: [usize; 4] = [512, 2048, 4096, 8192];
+/// Write 1 to clear bits of SMISR (DMA, ECC field, RB0 and RB1 flags).
+const SMISR_W1C_MASK: u64 = 0xc05;
+/// SDTMOUT holds a 24-bit count of SD clocks.
+const SDTMOUT_MASK: u32 = 0xffffff;
+
+// The redundant area registers and ECC engine of the NAND interface are not emulated.
 
 pub struct SICConfig {
     dma_control: DMAControl,
@@ -53,9 +78,27 @@ pub struct SICConfig {
     sd_irq_enable: SDIRQEnable,
     sd_irq: SDIRQStatus,
     sd_io_size: u64,
-    fifo: [u8; 0x400],
+    /// Number of SD clocks to wait for a response before reporting a timeout. 0 reports it immediately.
+    sd_timeout: u32,
+    /// Step at which the command waiting for a response times out.
+    sd_timeout_at: Option<u64>,
+    fifo: [u8; FIFO_SIZE],
+    /// CRC16 sent by the card after the last data block, if it sends one. It is checked against the block instead of
+    /// being stored in the FIFO.
+    sd_data_crc: Option<u16>,
     fmi_irq_enable: bool,
     fmi_irq_status: bool,
+    /// Last observed card presence of each SD port. `None` if the port has not been sensed yet.
+    sd_card_present: [Option<bool>; 4],
+    nand_control: NANDControl,
+    nand_timing: u32,
+    nand_irq_enable: NANDIRQEnable,
+    nand_irq: NANDIRQStatus,
+    /// NAND flash chip on CS0.
+    ///
+    /// Unlike the SD cards, the chip lives here instead of in `Device`, since guests read the data port right after
+    /// writing the command and address cycles, without waiting for a tick.
+    pub nand: NAND,
 }
 
 impl Default for SICConfig {
@@ -73,9 +116,23 @@ impl Default for SICConfig {
             sd_irq_enable: Default::default(),
             sd_irq: Default::default(),
             sd_io_size: 1u64,
-            fifo: [0u8; 1024],
+            sd_timeout: Default::default(),
+            sd_timeout_at: Default::default(),
+            fifo: [0u8; FIFO_SIZE],
+            sd_data_crc: Default::default(),
             fmi_irq_enable: Default::default(),
             fmi_irq_status: Default::default(),
+            sd_card_present: Default::default(),
+            nand_control: Default::default(),
+            nand_timing: Default::default(),
+            nand_irq_enable: Default::default(),
+            // The chip is never busy outside of register accesses, so R/B# always reads as ready.
+            nand_irq: {
+                let mut nand_irq = NANDIRQStatus::default();
+                nand_irq.set_rb0(true);
+                nand_irq
+            },
+            nand: Default::default(),
         }
     }
 }
@@ -138,7 +195,7 @@ struct SDIRQEnable {
     reserved_15: B9,
     r1b: bool,
     reserved_25: B5,
-    card_detect_mode: bool,  // 0 - Internal (uses DAT3 status), 1 - External (uses GPIO)
+    card_detect_mode: bool,  // 0 - External (uses GPIO), 1 - Internal (uses DAT3 status)
     reserved_31: B1,
 }
 
@@ -166,6 +223,51 @@ struct SDIRQStatus {
     reserved_25: B7,
 }
 
+#[bitfield]
+#[derive(Default)]
+struct NANDControl {
+    swrst: bool,
+    drd_en: bool,
+    dwr_en: bool,
+    redun_ren: bool,
+    redun_auto_wen: bool,
+    reserved_5: B11,
+    psize: B2,
+    reserved_18: B5,
+    ecc_en: bool,
+    reserved_24: B1,
+    cs0: bool,
+    cs1: bool,
+    reserved_27: B5,
+}
+
+#[bitfield]
+#[derive(Default)]
+struct NANDIRQEnable {
+    dma: bool,
+    reserved_1: B1,
+    ecc_field: bool,
+    reserved_3: B7,
+    rb0: bool,
+    rb1: bool,
+    reserved_12: B20,
+}
+
+#[bitfield]
+#[derive(Default)]
+struct NANDIRQStatus {
+    dma: bool,
+    reserved_1: B1,
+    ecc_field: bool,
+    reserved_3: B7,
+    rb0_changed: bool,
+    rb1_changed: bool,
+    reserved_12: B6,
+    rb0: bool,
+    rb1: bool,
+    reserved_20: B12,
+}
+
 #[bitfield]
 #[derive(Default)]
 struct DMAIRQFlags {
@@ -174,36 +276,42 @@ struct DMAIRQFlags {
     reserved_2: B6,
 }
 
+/// Bytes of the FIFO buffer covered by an access. Returns `None` with a warning for unsupported sizes, unaligned
+/// accesses and accesses running past the end of the buffer.
+fn fifo_range(addr: u64, size: usize, kind: &str) -> Option<std::ops::Range<usize>> {
+    if !matches!(size, 1 | 2 | 4) {
+        warn!("{NAME_DMAC}: Unsupported FIFO {kind} of {size} bytes at address 0x{addr:x}.");
+        return None;
+    }
+    if !addr.is_multiple_of(u64::try_from(size).unwrap()) {
+        warn!("{NAME_DMAC}: Unaligned {kind}{} at address 0x{addr:x}.", size * 8);
+        return None;
+    }
+    let start = usize::try_from(addr - REG_FB_0).unwrap();
+    let end = start + size;
+    if end > FIFO_SIZE {
+        warn!("{NAME_DMAC}: FIFO {kind} of {size} bytes at address 0x{addr:x} runs past the end of the buffer.");
+        return None;
+    }
+    Some(start..end)
+}
+
 pub fn read(uc: &mut UnicornContext, addr: u64, size: usize) -> u64 {
-    if addr >= REG_FB_0 && addr < REG_FB_0_END {
-        let fifo = &uc.get_data().sic.fifo;
-        let fifo_addr: usize = (((addr - REG_FB_0) & 0x3ff) as u16).into();
-        return match size {
-            1 => fifo[fifo_addr].into(),
-            2 => {
-                if addr & 1 != 0 {
-                    warn!("{NAME_DMAC}: Unaligned read16 at address {addr}.");
-                    return 0;
-                }
-                u16::from_le_bytes(<[u8; 2]>::try_from(&fifo[fifo_addr..fifo_addr+2]).unwrap()).into()
-            }
-            4 => {
-                if addr & 3 != 0 {
-                    warn!("{NAME_DMAC}: Unaligned read32 at address {addr}.");
-                    return 0;
-                }
-                u32::from_le_bytes(<[u8; 4]>::try_from(&fifo[fifo_addr..fifo_addr+4]).unwrap()).into()
-            }
-            _ => {
-                log_unsupported_read!(addr, size);
-                0
-            }
+    if (REG_FB_0..REG_FB_0_END).contains(&addr) {
+        let Some(range) = fifo_range(addr, size, "read") else {
+            return 0;
         };
+        return uc.get_data().endian.unpack(&uc.get_data().sic.fifo[range]);
     }
     if size != 4 {
         log_unsupported_read!(addr, size);
         return 0;
     }
+    if addr == REG_SMDATA {
+        let mut data = [0u8];
+        uc.get_data_mut().sic.nand.read_data(&mut data);
+        return data[0].into();
+    }
     let sic = &uc.get_data().sic;
     match addr {
         REG_DMACCSR => sic.dma_control.get(0, 16),
@@ -219,11 +327,20 @@ pub fn read(uc: &mut UnicornContext, addr: u64, size: usize) -> u64 {
         REG_SDISR => {
             let reg = sic.sd_irq.get(0, 32);
             trace!("Read REG_SDISR => 0x{reg:08x}");
+            if !sic.sd_irq.get_available() {
+                // Guests poll DAT0 while the card is busy, so keep ticking the card until it's released.
+                request_stop(uc, StopReason::Tick);
+            }
             reg
         }
         REG_SDRSP0 => sic.sd_response.0.into(),
         REG_SDRSP1 => sic.sd_response.1.into(),
         REG_SDBLEN => (sic.sd_io_size - 1) & 0xffffffff,
+        REG_SDTMOUT => sic.sd_timeout.into(),
+        REG_SMCSR => sic.nand_control.get(0, 32),
+        REG_SMTCR => sic.nand_timing.into(),
+        REG_SMIER => sic.nand_irq_enable.get(0, 32),
+        REG_SMISR => sic.nand_irq.get(0, 32),
         _ => {
             log_unsupported_read!(addr, size);
             0
@@ -235,31 +352,11 @@ pub fn read(uc: &mut UnicornContext, addr: u64, size: usize) -> u64 {
 pub fn write(uc: &mut UnicornContext, addr: u64, size: usize, value: u64) {
     let data = uc.get_data_mut();
 
-    if addr >= REG_FB_0 && addr < REG_FB_0_END {
-        let fifo = &mut data.sic.fifo;
-        let fifo_addr: usize = (((addr - REG_FB_0) & 0x3ff) as u16).into();
-        match size {
-            1 => fifo[fifo_addr] = value as u8,
-            2 => {
-                if addr & 1 != 0 {
-                    warn!("{NAME_DMAC}: Unaligned write16 at address {addr}.");
-                    return;
-                }
-                fifo[fifo_addr] = (value & 0xff) as u8;
-                fifo[fifo_addr + 1] = (value >> 8) as u8;
-            }
-            4 => {
-                if addr & 3 != 0 {
-                    warn!("{NAME_DMAC}: Unaligned write32 at address {addr}.");
-                    return;
-                }
-                fifo[fifo_addr] = (value & 0xff) as u8;
-                fifo[fifo_addr + 1] = (value >> 8) as u8;
-                fifo[fifo_addr + 2] = (value >> 16) as u8;
-                fifo[fifo_addr + 3] = (value >> 24) as u8;
-            }
-            _ => log_unsupported_write!(addr, size, value),
+    if (REG_FB_0..REG_FB_0_END).contains(&addr) {
+        let Some(range) = fifo_range(addr, size, "write") else {
+            return;
         };
+        data.endian.pack(value, &mut data.sic.fifo[range]);
         request_stop(uc, StopReason::Tick);
         return;
     }
@@ -292,6 +389,26 @@ pub fn write(uc: &mut UnicornContext, addr: u64, size: usize, value: u64) {
             sic.sd_irq.set(0, 32, new_val);
         }
         REG_SDBLEN => sic.sd_io_size = (value + 1) & 0xffffffff,
+        REG_SDTMOUT => sic.sd_timeout = u32::try_from(value & 0xffffffff).unwrap() & SDTMOUT_MASK,
+        REG_SMCSR => sic.nand_control.set(0, 32, value),
+        REG_SMTCR => sic.nand_timing = value as u32,
+        REG_SMIER => sic.nand_irq_enable.set(0, 32, value),
+        REG_SMISR => {
+            let new_val = sic.nand_irq.get(0, 32) & !(value & SMISR_W1C_MASK);
+            sic.nand_irq.set(0, 32, new_val);
+        }
+        REG_SMCMD => {
+            sic.nand.command(value as u8);
+            if sic.nand.take_ready() {
+                // Operations complete instantly, so the busy period only shows up as a rising edge of R/B#.
+                sic.nand_irq.set_rb0_changed(true);
+                if sic.nand_irq_enable.get_rb0() {
+                    post_interrupt(uc, InterruptNumber::SIC);
+                }
+            }
+        }
+        REG_SMADDR => sic.nand.address(value as u8),
+        REG_SMDATA => sic.nand.write_data(&[value as u8]),
         _ => log_unsupported_write!(addr, size, value),
     }
     request_stop(uc, StopReason::Tick);
@@ -303,10 +420,17 @@ pub fn tick(uc: &mut UnicornContext, device: &mut Device) {
         return;
     }
 
-    if check_reset(uc) || check_delay_condition(uc) {
+    check_card_detect(uc, device);
+    check_busy(uc, device);
+
+    if check_reset(uc, device) || check_delay_condition(uc) {
         return;
     }
 
+    if uc.get_data().sic.fmi_control.get_nand_mode() {
+        tick_nand(uc);
+    }
+
     let sd_control = &uc.get_data().sic.sd_control;
     let command_enable = sd_control.get_co_en();
     let sd_port = sd_control.get_sdport();
@@ -319,7 +443,8 @@ pub fn tick(uc: &mut UnicornContext, device: &mut Device) {
     let cmd = sd_control.get_cmd_code();
     let arg = uc.get_data().sic.sd_arg;
 
-    if command_enable {
+    // The command is still waiting for a response until its timeout expires.
+    if command_enable && uc.get_data().sic.sd_timeout_at.is_none() {
         let sd_device_op = match sd_port {
             0 => Some(&mut device.internal_sd),
             2 => Some(&mut device.external_sd),
@@ -328,21 +453,35 @@ pub fn tick(uc: &mut UnicornContext, device: &mut Device) {
         match sd_device_op {
             Some(sd_device) => {
                 let sic_mut = &mut uc.get_data_mut().sic;
-                sic_mut.sd_irq.set_card_detect(!sd_device.is_mounted());
+                let crc_enabled = sd_device.is_crc_enabled();
                 match sd_device.make_request(cmd, arg) {
                     // TODO: Maybe make this a trait
                     Response::R1(resp) => {
-                        sic_mut.sd_response = (
-                            ((u32::from(resp.cmd) & 0b111111) << 24) |
-                                (u32::try_from(resp.status.get(8, 24)).unwrap()),
-                            resp.status.get(0, 8) as u32,
-                        );
+                        let rsp0 = ((u32::from(resp.cmd) & 0b111111) << 24) |
+                            (u32::try_from(resp.status.get(8, 24)).unwrap());
+                        let rsp1 = resp.status.get(0, 8) as u32;
+                        sic_mut.sd_response = if crc_enabled {
+                            // Response[16:0] with CRC7 and end bit appended.
+                            let mut body = [0u8; 5];
+                            body[..4].copy_from_slice(&rsp0.to_be_bytes());
+                            body[4] = rsp1 as u8;
+                            (rsp0, (rsp1 << 8) | (u32::from(crc7(&body)) << 1) | 1)
+                        } else {
+                            (rsp0, rsp1)
+                        };
                         sic_mut.sd_control.set_ri_en(false);
                         sic_mut.sd_irq.set_crc_ok_cmd(true);
+                        if resp.busy {
+                            sic_mut.sd_irq.set_available(false);
+                        }
                     },
                     Response::R2(resp) => {
                         sic_mut.fifo[0] = 0b00111111;  // Needs to include header as well
                         sic_mut.fifo[1..resp.cid_csd.len()+1].copy_from_slice(&resp.cid_csd);
+                        if crc_enabled {
+                            let crc_offset = resp.cid_csd.len();
+                            sic_mut.fifo[crc_offset] = (crc7(&resp.cid_csd[..crc_offset-1]) << 1) | 1;
+                        }
                         sic_mut.sd_control.set_r2_en(false);
                         sic_mut.sd_irq.set_crc_ok_cmd(true);
                     },
@@ -379,18 +518,18 @@ pub fn tick(uc: &mut UnicornContext, device: &mut Device) {
                         sic_mut.sd_irq.set_crc_ok_cmd(true);
                     },
                     Response::RNone => {
-                        sic_mut.sd_irq.set_timeout_cmd(true);
                         // Skip any data transfer if pending
                         skip_data = true;
-                        let has_data = has_data_in || has_data_out;
-                        if has_data {
-                            sic_mut.sd_control.set_di_en(false);
-                            sic_mut.sd_control.set_do_en(false);
-                            sic_mut.sd_irq.set_timeout_dat(true);
+                        let steps = uc.get_data().steps;
+                        let clock_steps = sd_clock_steps(uc);
+                        let sic_mut = &mut uc.get_data_mut().sic;
+                        if sic_mut.start_timeout(steps, clock_steps) {
+                            trace!("{NAME_SD}: CMD{cmd} timeout in {} clocks", sic_mut.sd_timeout);
+                            schedule_next_event(uc);
+                            return;
                         }
-
-                        if sic_mut.sd_irq_enable.get_timeout_cmd() || (has_data && sic_mut.sd_irq_enable.get_timeout_dat()) {
-                            post_interrupt(uc, InterruptNumber::SIC, true, false);
+                        if sic_mut.complete_timeout() {
+                            post_interrupt(uc, InterruptNumber::SIC);
                         }
                     },
                 }
@@ -414,8 +553,8 @@ pub fn tick(uc: &mut UnicornContext, device: &mut Device) {
         if let Some(sd_device) = sd_device_op {
             let size = usize::try_from(uc.get_data().sic.sd_io_size).unwrap();
             let mult = usize::from(uc.get_data().sic.sd_control.get_blkcnt());
-            // Multiply by blkcnt if that value is set, and then the recv_data routine will need to try to fill the slice as much as
-            // possible until the limit on the SD card side has been reached.
+            // Multiply by blkcnt if that value is set. Data in will be received block by block until the limit on the SD
+            // card side has been reached.
             let size_final = if mult == 0 {
                 size
             } else {
@@ -425,28 +564,26 @@ pub fn tick(uc: &mut UnicornContext, device: &mut Device) {
             if has_data_in {
                 trace!("Process data in");
                 let mut buf = vec![0u8; size_final];
-                sd_device.recv_data(&mut buf);
-                trace!("Recv done");
-                match uc.mem_write(dest, &buf) {
+                let transferred = recv_blocks(sd_device, &mut buf, size);
+                trace!("Recv done ({transferred} of {size_final} bytes)");
+                buf.truncate(transferred);
+                let data_crc = sd_device.take_data_crc();
+                let last_block = &buf[transferred.saturating_sub(size)..];
+                let crc_ok = data_crc.is_none_or(|crc| crc == crc16(last_block));
+                uc.get_data_mut().sic.sd_data_crc = data_crc;
+                match dma_segments(uc, size_final).and_then(|segments| dma_write(uc, &segments, &buf)) {
                     Err(err) => {
                         error!("{NAME_DMAC}: Cannot write to 0x{dest:08x}: {err:?}");
                         uc.get_data_mut().sic.dma_irq_status.set_target_abort(true);
                         uc.get_data_mut().sic.sd_irq.set_crc_ok_dat(false);
                         if uc.get_data().sic.dma_irq_enable.get_target_abort() {
-                            post_interrupt(uc, InterruptNumber::SIC, true, false);
+                            post_interrupt(uc, InterruptNumber::SIC);
                         }
                     },
                     Ok(_) => {
-                        let end = dest + u64::try_from(size_final).unwrap();
-                        uc.ctl_remove_cache(dest, end).unwrap_or_else(|err| {
-                            error!("Failed to remove TB: {err:?}");
-                        });
-                        uc.get_data_mut().sic.dma_count += size_final;
-                        uc.get_data_mut().sic.sd_irq.set_crc_ok_dat(true);
-                        uc.get_data_mut().sic.sd_irq.set_block_xfer_done(true);
-                        uc.get_data_mut().sic.dma_dest_addr += u64::try_from(size_final).unwrap();
-                        if uc.get_data().sic.sd_irq_enable.get_block_xfer_done() {
-                            post_interrupt(uc, InterruptNumber::SIC, true, false);
+                        uc.get_data_mut().sic.advance_dma(transferred);
+                        if uc.get_data_mut().sic.complete_data_in(transferred < size_final, crc_ok) {
+                            post_interrupt(uc, InterruptNumber::SIC);
                         }
                     }
                 }
@@ -455,24 +592,36 @@ pub fn tick(uc: &mut UnicornContext, device: &mut Device) {
 
             if has_data_out {
                 trace!("Process data out");
-                match uc.mem_read_as_vec(dest, size_final) {
+                match dma_segments(uc, size_final).and_then(|segments| dma_read(uc, &segments)) {
                     Err(err) => {
                         error!("{NAME_DMAC}: Cannot read from 0x{dest:08x}: {err:?}");
                         uc.get_data_mut().sic.dma_irq_status.set_target_abort(true);
                         uc.get_data_mut().sic.sd_irq.set_crc_ok_dat(false);
                         if uc.get_data().sic.dma_irq_enable.get_target_abort() {
-                            post_interrupt(uc, InterruptNumber::SIC, true, false);
+                            post_interrupt(uc, InterruptNumber::SIC);
                         }
                     }
-                    Ok(buf) => {
-                        sd_device.send_data(&buf);
+                    Ok(buf) if sd_device.send_data(&buf) => {
+                        if sd_device.is_busy() {
+                            uc.get_data_mut().sic.sd_irq.set_available(false);
+                        }
 
-                        uc.get_data_mut().sic.dma_count += size_final;
+                        uc.get_data_mut().sic.advance_dma(buf.len());
                         uc.get_data_mut().sic.sd_irq.set_crc_ok_dat(true);
                         uc.get_data_mut().sic.sd_irq.set_block_xfer_done(true);
-                        uc.get_data_mut().sic.dma_dest_addr += u64::try_from(size_final).unwrap();
                         if uc.get_data().sic.sd_irq_enable.get_block_xfer_done() {
-                            post_interrupt(uc, InterruptNumber::SIC, true, false);
+                            post_interrupt(uc, InterruptNumber::SIC);
+                        }
+                    }
+                    Ok(_) => {
+                        // The card dropped the data and sends no CRC status back, which the host reports as a CRC
+                        // error.
+                        let sic = &mut uc.get_data_mut().sic;
+                        sic.sd_irq.set_crc_ok_dat(false);
+                        sic.sd_irq.set_crc_error(true);
+                        sic.sd_irq.set_block_xfer_done(true);
+                        if sic.sd_irq_enable.get_crc_error() || sic.sd_irq_enable.get_block_xfer_done() {
+                            post_interrupt(uc, InterruptNumber::SIC);
                         }
                     }
                 }
@@ -482,105 +631,355 @@ pub fn tick(uc: &mut UnicornContext, device: &mut Device) {
             uc.get_data_mut().sic.sd_control.set_blkcnt(0);
         } else {
             warn!("Cannot transfer data through unmapped SD port {sd_port}");
+            let sd_control = &mut uc.get_data_mut().sic.sd_control;
+            sd_control.set_di_en(false);
+            sd_control.set_do_en(false);
+        }
+    }
+}
+
+/// Move one page between the NAND chip and guest memory when a DMA transfer is enabled in SMCSR.
+fn tick_nand(uc: &mut UnicornContext) {
+    let sic = &uc.get_data().sic;
+    let has_data_in = sic.nand_control.get_drd_en();
+    let has_data_out = sic.nand_control.get_dwr_en();
+    if !has_data_in && !has_data_out {
+        return;
+    }
+
+    let dest = sic.dma_dest_addr;
+    let size = NAND_PAGE_SIZES[usize::from(sic.nand_control.get_psize())];
+    let result = if has_data_in {
+        trace!("{NAME_NAND}: DMA read of {size} bytes");
+        let mut buf = vec![0u8; size];
+        uc.get_data_mut().sic.nand.read_data(&mut buf);
+        dma_segments(uc, size).and_then(|segments| dma_write(uc, &segments, &buf))
+    } else {
+        trace!("{NAME_NAND}: DMA write of {size} bytes");
+        dma_segments(uc, size).and_then(|segments| dma_read(uc, &segments)).map(|buf| {
+            uc.get_data_mut().sic.nand.write_data(&buf);
+        })
+    };
+
+    let sic = &mut uc.get_data_mut().sic;
+    sic.nand_control.set_drd_en(false);
+    sic.nand_control.set_dwr_en(false);
+    match result {
+        Err(err) => {
+            error!("{NAME_DMAC}: Cannot transfer NAND page at 0x{dest:08x}: {err:?}");
+            sic.dma_irq_status.set_target_abort(true);
+            if sic.dma_irq_enable.get_target_abort() {
+                post_interrupt(uc, InterruptNumber::SIC);
+            }
+        }
+        Ok(()) => {
+            sic.advance_dma(size);
+            sic.nand_irq.set_dma(true);
+            if sic.nand_irq_enable.get_dma() {
+                post_interrupt(uc, InterruptNumber::SIC);
+            }
+        }
+    }
+}
+
+impl SICConfig {
+    /// Start counting down SDTMOUT at `steps` for a command that got no response. Returns whether the timeout is
+    /// deferred, in which case CO_EN stays set until it expires.
+    fn start_timeout(&mut self, steps: u64, clock_steps: u64) -> bool {
+        if self.sd_timeout == 0 {
+            return false;
+        }
+        self.sd_timeout_at = Some(steps + u64::from(self.sd_timeout) * clock_steps);
+        true
+    }
+
+    /// Whether the command waiting for a response has timed out at `steps`.
+    fn timeout_expired(&self, steps: u64) -> bool {
+        self.sd_timeout_at.is_some_and(|timeout_at| steps >= timeout_at)
+    }
+
+    /// Report the timeout of the current command, and of its data transfer if it has one. Ends the command and returns
+    /// whether an interrupt needs to be raised.
+    fn complete_timeout(&mut self) -> bool {
+        self.sd_timeout_at = None;
+        self.sd_irq.set_timeout_cmd(true);
+        let has_data = self.sd_control.get_di_en() || self.sd_control.get_do_en();
+        if has_data {
+            self.sd_control.set_di_en(false);
+            self.sd_control.set_do_en(false);
+            self.sd_irq.set_timeout_dat(true);
+        }
+        self.sd_control.set_co_en(false);
+        self.sd_irq_enable.get_timeout_cmd() || (has_data && self.sd_irq_enable.get_timeout_dat())
+    }
+
+    /// Stop the DMA engine and clear its error flags.
+    fn reset_dmac(&mut self) {
+        self.dma_control.set_reset(false);
+        self.dma_control.set_busy(false);
+        self.dma_irq_status = Default::default();
+    }
+
+    /// Drop the NAND DMA transfer in progress.
+    fn reset_nand(&mut self) {
+        self.nand_control.set_drd_en(false);
+        self.nand_control.set_dwr_en(false);
+        self.nand_control.set_swrst(false);
+    }
+
+    /// Drop the command and data transfers in progress. Returns whether a command waiting for its timeout was
+    /// dropped, in which case the next event needs to be rescheduled.
+    fn reset_sd(&mut self) -> bool {
+        self.sd_control.set_swrst(false);
+        self.sd_control.set_co_en(false);
+        self.sd_control.set_ri_en(false);
+        self.sd_control.set_r2_en(false);
+        self.sd_control.set_di_en(false);
+        self.sd_control.set_do_en(false);
+        self.sd_control.set_blkcnt(0);
+        self.sd_irq.set_available(true);
+        self.sd_timeout_at.take().is_some()
+    }
+
+    /// Account for `size` bytes moved by the DMA engine.
+    ///
+    /// The destination address only advances in linear mode. In scatter-gather mode it points to the descriptor table
+    /// instead.
+    fn advance_dma(&mut self, size: usize) {
+        self.dma_count += size;
+        if !self.dma_control.get_scatter_gather_mode() {
+            self.dma_dest_addr += u64::try_from(size).unwrap();
         }
     }
 
-    // if !skip_data && has_data_in {
-    //     let dest = uc.get_data().sic.dma_dest_addr;
-    //     let sd_device_op = match sd_port {
-    //         0 => Some(&mut device.internal_sd),
-    //         2 => Some(&mut device.external_sd),
-    //         _ => None
-    //     };
-
-    //     match sd_device_op {
-    //         Some(sd_device) => {
-    //             let size = usize::try_from(uc.get_data().sic.sd_io_size).unwrap();
-    //             let mult = usize::from(uc.get_data().sic.sd_control.get_blkcnt());
-    //             // TODO multiply by blkcnt if that value is set, and then the recv_data routine will need to try to fill the slice as much as
-    //             // possible until the limit on the SD card side has been reached.
-    //             let size_final = if mult == 0 {
-    //                 size
-    //             } else {
-    //                 size * mult
-    //             };
-    //             let mut buf = vec![0u8; size_final];
-    //             sd_device.recv_data(&mut buf);
-    //             match uc.mem_write(dest, &buf) {
-    //                 Err(err) => {
-    //                     error!("{NAME_DMAC}: Cannot write to 0x{dest:08x}: {err:?}");
-    //                     uc.get_data_mut().sic.dma_irq_status.set_target_abort(true);
-    //                     uc.get_data_mut().sic.sd_irq.set_crc_ok_dat(false);
-    //                     if uc.get_data().sic.dma_irq_enable.get_target_abort() {
-    //                         post_interrupt(uc, InterruptNumber::SIC, true, false);
-    //                     }
-    //                 },
-    //                 Ok(_) => {
-    //                     let end = dest + u64::try_from(size_final).unwrap();
-    //                     uc.ctl_remove_cache(dest, end).unwrap_or_else(|err| {
-    //                         error!("Failed to remove TB: {err:?}");
-    //                     });
-    //                     uc.get_data_mut().sic.dma_count += size_final;
-    //                     uc.get_data_mut().sic.sd_irq.set_crc_ok_dat(true);
-    //                     uc.get_data_mut().sic.sd_irq.set_block_xfer_done(true);
-    //                     uc.get_data_mut().sic.dma_dest_addr += u64::try_from(size_final).unwrap();
-    //                     if uc.get_data().sic.sd_irq_enable.get_block_xfer_done() {
-    //                         post_interrupt(uc, InterruptNumber::SIC, true, false);
-    //                     }
-    //                 }
-    //             }
-    //             uc.get_data_mut().sic.sd_control.set_blkcnt(0);
-    //         }
-    //         None => {
-    //             warn!("Cannot receive data through unmapped SD port {sd_port}");
-    //         }
-    //     }
-    //     uc.get_data_mut().sic.sd_control.set_di_en(false);
-    // }
-
-    // if !skip_data && has_data_out {
-    //     let dest = uc.get_data().sic.dma_dest_addr;
-    //     let sd_device_op = match sd_port {
-    //         0 => Some(&mut device.internal_sd),
-    //         2 => Some(&mut device.external_sd),
-    //         _ => None
-    //     };
-
-    //     if let Some(sd_device) = sd_device_op {
-            
-    //     } else {
-    //         warn!("Cannot send data through unmapped SD port {sd_port}");
-    //     }
-    // }
+    /// Update status flags after a data in transfer and return whether an interrupt needs to be raised.
+    ///
+    /// A short transfer is reported as a DAT timeout, since the card stopped sending data before the host received all
+    /// the blocks it asked for. Block transfer done is still set so the guest can stop waiting for the transfer.
+    /// `crc_ok` tells whether the last block matched its CRC16.
+    fn complete_data_in(&mut self, short: bool, crc_ok: bool) -> bool {
+        self.sd_irq.set_crc_ok_dat(!short && crc_ok);
+        self.sd_irq.set_block_xfer_done(true);
+        if short {
+            self.sd_irq.set_timeout_dat(true);
+        }
+        self.sd_irq_enable.get_block_xfer_done() || (short && self.sd_irq_enable.get_timeout_dat())
+    }
+}
+
+/// Build the list of guest memory segments (address, length) targeted by a DMA transfer of `size` bytes.
+///
+/// In scatter-gather mode, `dma_dest_addr` points to a table of descriptors, each consisting of a 32-bit physical
+/// address followed by a 32-bit byte count, with bit 31 of the byte count marking the end of the table. The segments
+/// are clipped to `size`, and `wrong_eot` is raised if the table does not describe exactly `size` bytes.
+///
+/// In linear mode the transfer targets a single buffer at `dma_dest_addr`.
+fn dma_segments(uc: &mut UnicornContext, size: usize) -> Result<Vec<(u64, usize)>, uc_error> {
+    let sic = &uc.get_data().sic;
+    let mut table_addr = sic.dma_dest_addr;
+    if !sic.dma_control.get_scatter_gather_mode() {
+        return Ok(vec![(table_addr, size)]);
+    }
+
+    let mut segments = vec![];
+    let mut total = 0usize;
+    let mut has_eot = false;
+    for _ in 0..SG_MAX_DESCRIPTORS {
+        let mut desc = [0u8; 8];
+        uc.mem_read(table_addr, &mut desc)?;
+        let endian = uc.get_data().endian;
+        let addr = endian.u32_from_bytes(<[u8; 4]>::try_from(&desc[0..4]).unwrap());
+        let count = endian.u32_from_bytes(<[u8; 4]>::try_from(&desc[4..8]).unwrap());
+        let len = usize::try_from(count & !SG_EOT).unwrap();
+        trace!("{NAME_DMAC}: SG descriptor @ 0x{table_addr:08x}: 0x{addr:08x} ({len} bytes)");
+
+        if total < size {
+            segments.push((u64::from(addr), len.min(size - total)));
+        }
+        total += len;
+        if count & SG_EOT != 0 {
+            has_eot = true;
+            break;
+        }
+        table_addr += 8;
+    }
+
+    if !has_eot || total != size {
+        warn!("{NAME_DMAC}: SG table describes {total} bytes but the transfer is {size} bytes (EOT found: {has_eot}).");
+        uc.get_data_mut().sic.dma_irq_status.set_wrong_eot(true);
+        if uc.get_data().sic.dma_irq_enable.get_wrong_eot() {
+            post_interrupt(uc, InterruptNumber::SIC);
+        }
+    }
+
+    Ok(segments)
+}
+
+/// Scatter `buf` into guest memory segments.
+fn dma_write(uc: &mut UnicornContext, segments: &[(u64, usize)], buf: &[u8]) -> Result<(), uc_error> {
+    let mut offset = 0usize;
+    for &(addr, len) in segments {
+        let len = len.min(buf.len() - offset);
+        uc.mem_write(addr, &buf[offset..offset + len])?;
+        uc.ctl_remove_cache(addr, addr + u64::try_from(len).unwrap()).unwrap_or_else(|err| {
+            error!("Failed to remove TB: {err:?}");
+        });
+        offset += len;
+    }
+    Ok(())
+}
+
+/// Gather data from guest memory segments.
+fn dma_read(uc: &mut UnicornContext, segments: &[(u64, usize)]) -> Result<Vec<u8>, uc_error> {
+    let mut buf = vec![];
+    for &(addr, len) in segments {
+        buf.extend(uc.mem_read_as_vec(addr, len)?);
+    }
+    Ok(buf)
+}
+
+/// Receive data from the SD card one block at a time, stopping early when the card runs out of data.
+///
+/// Returns the number of bytes actually received.
+fn recv_blocks(sd_device: &mut SD, buf: &mut [u8], block_size: usize) -> usize {
+    let total = buf.len();
+    let mut transferred = 0usize;
+    for block in buf.chunks_mut(block_size) {
+        let received = sd_device.recv_data(block);
+        transferred += received;
+        if received < block.len() {
+            warn!("{NAME_SD}: Short read of {transferred} out of {total} bytes.");
+            break;
+        }
+    }
+    transferred
+}
+
+/// Level of the card detect input. DAT3 is pulled high by the card when it's inserted, while the external card detect
+/// switch pulls GPIO low.
+fn card_detect_level(dat3: bool, present: bool) -> bool {
+    if dat3 { present } else { !present }
+}
+
+/// Update card detect status of the selected SD port and raise an interrupt when the card presence changes.
+fn check_card_detect(uc: &mut UnicornContext, device: &Device) {
+    let sd_port = uc.get_data().sic.sd_control.get_sdport();
+    let present = match sd_port {
+        0 => device.internal_sd.is_inserted(),
+        2 => device.external_sd.is_inserted(),
+        _ => false,
+    };
+
+    let sic = &mut uc.get_data_mut().sic;
+    let level = card_detect_level(sic.sd_irq_enable.get_card_detect_mode(), present);
+    sic.sd_irq.set_card_detect(level);
+
+    let prev_present = sic.sd_card_present[usize::from(sd_port)].replace(present);
+    if prev_present.is_some_and(|prev| prev != present) {
+        debug!("{NAME_SD}: Card on port {sd_port} {}", if present { "inserted" } else { "removed" });
+        sic.sd_irq.set_card_detect_changed(true);
+        if sic.sd_irq_enable.get_card_detect() {
+            post_interrupt(uc, InterruptNumber::SIC);
+        }
+    }
+}
+
+/// Advance the busy period of the selected card, which drives DAT0, and raise the R1b interrupt when it ends.
+fn check_busy(uc: &mut UnicornContext, device: &mut Device) {
+    let sd_device = match uc.get_data().sic.sd_control.get_sdport() {
+        0 => &mut device.internal_sd,
+        2 => &mut device.external_sd,
+        _ => return,
+    };
+    let released = sd_device.tick();
+
+    let sic = &mut uc.get_data_mut().sic;
+    sic.sd_irq.set_available(!sd_device.is_busy());
+    if released {
+        trace!("{NAME_SD}: Card released DAT0");
+        sic.sd_irq.set_r1b(true);
+        if sic.sd_irq_enable.get_r1b() {
+            post_interrupt(uc, InterruptNumber::SIC);
+        }
+    }
 }
 
 /// Handle reset condition.
-pub fn check_reset(uc: &mut UnicornContext) -> bool {
+///
+/// The engine resets keep the control registers, like the hardware does. Resetting the SD engine also drops the data
+/// transfer the selected card is in the middle of.
+pub fn check_reset(uc: &mut UnicornContext, device: &mut Device) -> bool {
     let mut has_reset = false;
 
     if uc.get_data().sic.dma_control.get_reset() {
         debug!("{NAME_DMAC}: Reset");
-        // TODO: Reset callbacks go here.
-        uc.get_data_mut().sic.dma_control.set_reset(false);
+        uc.get_data_mut().sic.reset_dmac();
         has_reset = true;
     }
 
-    if uc.get_data().sic.fmi_control.get_reset() {
+    let fmi_reset = uc.get_data().sic.fmi_control.get_reset();
+    if fmi_reset {
         debug!("{NAME_FMI}: Reset");
-        // TODO: Reset callbacks go here.
-        uc.get_data_mut().sic.fmi_control.set_reset(false);
+        let sic = &mut uc.get_data_mut().sic;
+        sic.fmi_control.set_reset(false);
+        sic.fmi_irq_status = false;
+        has_reset = true;
+    }
+
+    if fmi_reset || uc.get_data().sic.nand_control.get_swrst() {
+        debug!("{NAME_NAND}: Reset");
+        uc.get_data_mut().sic.reset_nand();
         has_reset = true;
     }
 
-    if uc.get_data().sic.sd_control.get_swrst() {
+    if fmi_reset || uc.get_data().sic.sd_control.get_swrst() {
         debug!("{NAME_SD}: Reset");
-        // TODO: Reset callbacks go here.
-        uc.get_data_mut().sic.sd_irq.set_available(true);
-        uc.get_data_mut().sic.sd_control.set_swrst(false);
+        let sd_device_op = match uc.get_data().sic.sd_control.get_sdport() {
+            0 => Some(&mut device.internal_sd),
+            2 => Some(&mut device.external_sd),
+            _ => None
+        };
+        if let Some(sd_device) = sd_device_op {
+            sd_device.abort_transfer();
+        }
+        if uc.get_data_mut().sic.reset_sd() {
+            schedule_next_event(uc);
+        }
         has_reset = true;
     }
 
-    return has_reset;
+    has_reset
+}
+
+/// Number of CPU steps per SD clock.
+fn sd_clock_steps(uc: &UnicornContext) -> u64 {
+    let clk = &uc.get_data().clk;
+    match clk.sd_clock() {
+        0 => 1,
+        sd_clock => (clk.tick_config.f_cpu / sd_clock).max(1),
+    }
+}
+
+/// Report the timeout of a command once SDTMOUT has counted down.
+pub fn generate_stop_condition(uc: &mut UnicornContext, steps: u64) {
+    if !uc.get_data().sic.timeout_expired(steps) {
+        return;
+    }
+    if !uc.get_data().clk.ahbclk.get_sic() {
+        // The count is held while the clock is stopped.
+        let clock_steps = sd_clock_steps(uc);
+        uc.get_data_mut().sic.sd_timeout_at = Some(steps + clock_steps);
+        return;
+    }
+    trace!("{NAME_SD}: Command timed out");
+    if uc.get_data_mut().sic.complete_timeout() {
+        post_interrupt(uc, InterruptNumber::SIC);
+    }
+}
+
+/// Step at which a command times out, or `None` if no command is waiting for a response.
+pub fn next_event(uc: &UnicornContext, steps: u64) -> Option<u64> {
+    uc.get_data().sic.sd_timeout_at.map(|timeout_at| timeout_at.max(steps + 1))
 }
 
 /// Handle SD card delay conditions
@@ -595,11 +994,97 @@ fn check_delay_condition(uc: &mut UnicornContext) -> bool {
     } else if sd_control.get_clk8_oe() {
         trace!("SD delay 8 clock");
         sd_control.set_clk8_oe(false);
-        // HACK: Ensure DAT0 is high (card is available and not busy)
-        // This needs to be changed once we have proper busy signaling (like from dedicated IO thread)
-        uc.get_data_mut().sic.sd_irq.set_available(true);
         true
     } else {
         false
     }
 }
+
+impl_snapshot_bitfield!(
+    DMAControl, FMIControl, SDCR, SDIRQEnable, SDIRQStatus, DMAIRQFlags, NANDControl, NANDIRQEnable, NANDIRQStatus,
+);
+impl_snapshot!(SICConfig {
+    dma_control, dma_dest_addr, dma_irq_enable, dma_irq_status, dma_count, fmi_control, sd_arg, sd_response, sd_control,
+    sd_irq_enable, sd_irq, sd_io_size, sd_timeout, sd_timeout_at, fifo, sd_data_crc, fmi_irq_enable, fmi_irq_status,
+    sd_card_present, nand_control, nand_timing, nand_irq_enable, nand_irq, nand,
+});
+impl_reset!(SICConfig { nand });
+
+#[test]
+fn test_recv_blocks_short_read() {
+    use crate::extdev::{make_test_file, sd::select_test_card};
+
+    let path = make_test_file("blkcnt.img", 512 * 1024);
+    let mut sd = SD::default();
+    sd.mount(path.to_str().unwrap()).unwrap();
+    select_test_card(&mut sd);
+
+    // blkcnt = 4 with only 2 sectors left on the image.
+    let _ = sd.make_request(18, 1022 * 512);
+    let mut buf = vec![0u8; 512 * 4];
+    let transferred = recv_blocks(&mut sd, &mut buf, 512);
+    assert_eq!(transferred, 512 * 2);
+
+    let mut sic = SICConfig::default();
+    sic.sd_irq_enable.set_block_xfer_done(true);
+    assert!(sic.complete_data_in(transferred < buf.len(), true));
+    assert!(sic.sd_irq.get_block_xfer_done());
+    assert!(sic.sd_irq.get_timeout_dat());
+    assert!(!sic.sd_irq.get_crc_ok_dat());
+
+    // A complete transfer is only good if the CRC16 matched.
+    sic.complete_data_in(false, false);
+    assert!(!sic.sd_irq.get_crc_ok_dat());
+    sic.complete_data_in(false, true);
+    assert!(sic.sd_irq.get_crc_ok_dat());
+
+    sd.unmount();
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_command_timeout_deferred() {
+    let mut sic = SICConfig::default();
+    sic.sd_irq_enable.set_timeout_cmd(true);
+    sic.sd_control.set_co_en(true);
+    sic.sd_control.set_di_en(true);
+
+    // 0x100 SD clocks of 4 steps each.
+    sic.sd_timeout = 0x100;
+    assert!(sic.start_timeout(1000, 4));
+    assert_eq!(sic.sd_timeout_at, Some(1000 + 0x400));
+    assert!(sic.sd_control.get_co_en());
+    assert!(!sic.sd_irq.get_timeout_cmd());
+    assert!(!sic.timeout_expired(1000 + 0x3ff));
+    assert!(sic.timeout_expired(1000 + 0x400));
+
+    assert!(sic.complete_timeout());
+    assert_eq!(sic.sd_timeout_at, None);
+    assert!(!sic.sd_control.get_co_en());
+    assert!(!sic.sd_control.get_di_en());
+    assert!(sic.sd_irq.get_timeout_cmd());
+    assert!(sic.sd_irq.get_timeout_dat());
+
+    // No timeout programmed.
+    sic.sd_timeout = 0;
+    assert!(!sic.start_timeout(1000, 4));
+    assert_eq!(sic.sd_timeout_at, None);
+}
+
+#[test]
+fn test_fifo_range() {
+    assert_eq!(fifo_range(0x3fc, 4, "read"), Some(0x3fc..0x400));
+    assert_eq!(fifo_range(0x3ff, 1, "read"), Some(0x3ff..0x400));
+    assert_eq!(fifo_range(0x3fe, 4, "read"), None);
+    assert_eq!(fifo_range(0x3ff, 2, "write"), None);
+    assert_eq!(fifo_range(0x3f8, 8, "write"), None);
+}
+
+#[test]
+fn test_card_detect_level() {
+    // DAT3 reads high with a card, the GPIO switch reads low.
+    assert!(card_detect_level(true, true));
+    assert!(!card_detect_level(true, false));
+    assert!(!card_detect_level(false, true));
+    assert!(card_detect_level(false, false));
+}
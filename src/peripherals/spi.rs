@@ -0,0 +1,299 @@
+use bit_field::{B2, B4, B5, B14, B28, bitfield};
+use log::{trace, warn};
+use crate::{device::{StopReason, UnicornContext, request_stop}, extdev::spi_flash::SPIFlash, log_unsupported_read, log_unsupported_write, peripherals::{aic::{InterruptNumber, post_interrupt}, common::Reset}};
+use crate::{impl_reset, impl_snapshot, impl_snapshot_bitfield};
+
+pub const BASE: u64 = 0xb800c000;
+pub const SIZE: usize = 0x1000;
+
+/// SPIMS1 registers start at 0x400.
+const PORT_STRIDE: u64 = 0x400;
+pub const PORTS: usize = 2;
+/// Chip selects of each port.
+pub const CHIP_SELECTS: usize = 2;
+/// Depth of the TX and RX registers, in words.
+const FIFO_WORDS: usize = 4;
+
+const REG_CNTRL: u64 = 0x0;
+const REG_DIVIDER: u64 = 0x4;
+const REG_SSR: u64 = 0x8;
+const REG_RX_START: u64 = 0x10;
+const REG_RX_END: u64 = 0x20;
+const REG_TX_START: u64 = 0x20;
+const REG_TX_END: u64 = 0x30;
+
+/// Write 1 to clear interrupt flag of `SPIControl`.
+const CNTRL_IF: u64 = 1 << 16;
+
+#[bitfield]
+#[derive(Default)]
+pub struct SPIControl {
+    /// Start a transfer. Cleared when the transfer is done.
+    go_busy: bool,
+    rx_neg: bool,
+    tx_neg: bool,
+    /// Bits per word. 0 means 32.
+    tx_bit_len: B5,
+    /// Words per transfer, minus 1.
+    tx_num: B2,
+    /// Shift words out LSB first.
+    lsb: bool,
+    /// Idle level of the clock.
+    clkp: bool,
+    sleep: B4,
+    irq_flag: bool,
+    irq_enable: bool,
+    reserved_18: B14,
+}
+
+#[bitfield]
+#[derive(Default)]
+pub struct SPISlaveSelect {
+    /// Chip selects to assert.
+    ssr: B2,
+    /// Active level of the chip selects. Only changes the pin level, which slaves don't see.
+    ss_lvl: bool,
+    /// Assert the chip selects only while a transfer is running, instead of following `ssr`.
+    ass: bool,
+    reserved_4: B28,
+}
+
+#[derive(Default)]
+pub struct SPIPort {
+    pub control: SPIControl,
+    pub divider: u32,
+    pub slave_select: SPISlaveSelect,
+    pub rx: [u32; FIFO_WORDS],
+    pub tx: [u32; FIFO_WORDS],
+    /// SPI NOR flash chips on each chip select. Unmounted chips leave MISO pulled up.
+    pub flash: [SPIFlash; CHIP_SELECTS],
+}
+
+/// SPI master controllers (SPIMS0 and SPIMS1).
+///
+/// Transfers complete at once, regardless of the clock divider. Slaves only see whole bytes, so word lengths that are
+/// not a multiple of 8 bits are padded at the end of the word.
+#[derive(Default)]
+pub struct SPIConfig {
+    pub ports: [SPIPort; PORTS],
+}
+
+impl SPIPort {
+    /// Whether chip select `cs` is asserted, given whether a transfer is running.
+    fn cs_asserted(&self, cs: usize, transferring: bool) -> bool {
+        let selected = self.slave_select.get_ssr() & (1 << cs) != 0;
+        selected && (transferring || !self.slave_select.get_ass())
+    }
+
+    /// Drive the chip selects of the slaves.
+    fn update_chip_selects(&mut self, transferring: bool) {
+        for cs in 0..CHIP_SELECTS {
+            let asserted = self.cs_asserted(cs, transferring);
+            self.flash[cs].select(asserted);
+        }
+    }
+
+    /// Shift a byte out to the selected slaves and return what they shifted back. Slaves drive MISO low.
+    fn transfer_byte(&mut self, mosi: u8) -> u8 {
+        self.flash.iter_mut().fold(0xff, |miso, flash| miso & flash.transfer(mosi))
+    }
+
+    /// Shift one word of `bits` bits out to the selected slaves and return the word shifted in.
+    fn transfer_word(&mut self, word: u32, bits: u32) -> u32 {
+        let lsb = self.control.get_lsb();
+        let mask = if bits == 32 { u32::MAX } else { (1 << bits) - 1 };
+        let out = if lsb { word.reverse_bits() >> (32 - bits) } else { word & mask };
+
+        let bytes = bits.div_ceil(8);
+        let pad = bytes * 8 - bits;
+        let out = u64::from(out) << pad;
+        let received = (0..bytes).rev().fold(0u64, |acc, i| {
+            let mosi = u8::try_from(out >> (8 * i) & 0xff).unwrap();
+            acc << 8 | u64::from(self.transfer_byte(mosi))
+        });
+        let received = u32::try_from(received >> pad).unwrap() & mask;
+        if lsb { received.reverse_bits() >> (32 - bits) } else { received }
+    }
+
+    /// Run the transfer programmed in the control register. Returns whether an interrupt needs to be raised.
+    fn transfer(&mut self) -> bool {
+        let bits = match self.control.get_tx_bit_len() {
+            0 => 32,
+            bits => u32::from(bits),
+        };
+        let words = usize::from(self.control.get_tx_num()) + 1;
+
+        self.update_chip_selects(true);
+        for i in 0..words {
+            self.rx[i] = self.transfer_word(self.tx[i], bits);
+        }
+        self.update_chip_selects(false);
+
+        self.control.set_go_busy(false);
+        self.control.set_irq_flag(true);
+        self.control.get_irq_enable()
+    }
+}
+
+/// Parse a `<port>:<cs>` chip select of an SPI master.
+pub fn parse_spi_slot(value: &str) -> Result<(usize, usize), String> {
+    let (port, cs) = value.split_once(':').ok_or_else(|| format!("Expected `<port>:<cs>`, got `{value}`"))?;
+    match (port.trim().parse(), cs.trim().parse()) {
+        (Ok(port), Ok(cs)) if port < PORTS && cs < CHIP_SELECTS => Ok((port, cs)),
+        _ => Err(format!("Invalid SPI chip select `{value}`")),
+    }
+}
+
+/// Split an MMIO offset into a port number and a register offset.
+#[inline]
+fn decode(addr: u64) -> Option<(usize, u64)> {
+    let port = usize::try_from(addr / PORT_STRIDE).unwrap();
+    (port < PORTS).then_some((port, addr % PORT_STRIDE))
+}
+
+/// Interrupt number of a port.
+#[inline]
+fn intno(port: usize) -> InterruptNumber {
+    match port {
+        0 => InterruptNumber::SPIMS0,
+        _ => InterruptNumber::SPIMS1,
+    }
+}
+
+/// Whether the APB clock of a port is enabled.
+#[inline]
+fn clock_enabled(uc: &UnicornContext, port: usize) -> bool {
+    let apbclk = &uc.get_data().clk.apbclk;
+    match port {
+        0 => apbclk.get_spims0(),
+        _ => apbclk.get_spims1(),
+    }
+}
+
+pub fn read(uc: &mut UnicornContext, addr: u64, size: usize) -> u64 {
+    let Some((port, reg)) = decode(addr).filter(|_| size == 4) else {
+        log_unsupported_read!(addr, size);
+        return 0;
+    };
+
+    let port_obj = &uc.get_data().spi.ports[port];
+    match reg {
+        REG_CNTRL => port_obj.control.get(0, 32),
+        REG_DIVIDER => port_obj.divider.into(),
+        REG_SSR => port_obj.slave_select.get(0, 32),
+        REG_RX_START..REG_RX_END => port_obj.rx[usize::try_from((reg - REG_RX_START) / 4).unwrap()].into(),
+        REG_TX_START..REG_TX_END => port_obj.tx[usize::try_from((reg - REG_TX_START) / 4).unwrap()].into(),
+        _ => {
+            log_unsupported_read!(addr, size);
+            0
+        }
+    }
+}
+
+pub fn write(uc: &mut UnicornContext, addr: u64, size: usize, value: u64) {
+    let Some((port, reg)) = decode(addr).filter(|_| size == 4) else {
+        log_unsupported_write!(addr, size, value);
+        return;
+    };
+
+    let value32 = u32::try_from(value & 0xffffffff).unwrap();
+    let port_obj = &mut uc.get_data_mut().spi.ports[port];
+    match reg {
+        REG_CNTRL => {
+            let irq_flag = port_obj.control.get_irq_flag() && value & CNTRL_IF == 0;
+            port_obj.control.set(0, 32, value);
+            port_obj.control.set_irq_flag(irq_flag);
+            if port_obj.control.get_go_busy() {
+                request_stop(uc, StopReason::Tick);
+            }
+        }
+        REG_DIVIDER => port_obj.divider = value32 & 0xffff,
+        REG_SSR => {
+            port_obj.slave_select.set(0, 32, value & 0xf);
+            port_obj.update_chip_selects(false);
+        }
+        REG_TX_START..REG_TX_END => port_obj.tx[usize::try_from((reg - REG_TX_START) / 4).unwrap()] = value32,
+        REG_RX_START..REG_RX_END => warn!("SPIMS{port}: Ignoring write to read only RX{}.", (reg - REG_RX_START) / 4),
+        _ => log_unsupported_write!(addr, size, value),
+    }
+}
+
+pub fn tick(uc: &mut UnicornContext) {
+    for port in 0..PORTS {
+        // A transfer started with the clock off runs once the clock is enabled.
+        if !uc.get_data().spi.ports[port].control.get_go_busy() || !clock_enabled(uc, port) {
+            continue;
+        }
+        let port_obj = &mut uc.get_data_mut().spi.ports[port];
+        if port_obj.transfer() {
+            post_interrupt(uc, intno(port));
+        }
+        let port_obj = &uc.get_data().spi.ports[port];
+        trace!("SPIMS{port}: TX {:08x?} RX {:08x?}", port_obj.tx, port_obj.rx);
+    }
+}
+
+impl_snapshot_bitfield!(SPIControl, SPISlaveSelect);
+impl_snapshot!(SPIPort { control, divider, slave_select, rx, tx, flash });
+impl_snapshot!(SPIConfig { ports });
+impl_reset!(SPIPort { flash });
+
+/// The flash chips stay mounted.
+impl Reset for SPIConfig {
+    fn reset(&mut self) {
+        self.ports.iter_mut().for_each(Reset::reset);
+    }
+}
+
+#[test]
+fn test_spi_transfer() {
+    use crate::extdev::spi_flash::make_test_spi_flash;
+
+    let path = make_test_spi_flash("transfer");
+    let mut port = SPIPort::default();
+    port.flash[1].mount(path.to_str().unwrap()).unwrap();
+
+    // Manual chip select on CS1, read ID in a 32-bit word.
+    port.slave_select.set_ssr(0b10);
+    port.update_chip_selects(false);
+    port.tx[0] = 0x9f000000;
+    assert!(!port.transfer());
+    assert!(port.control.get_irq_flag());
+    assert_eq!(port.rx[0], 0xffef4011);
+
+    // Automatic chip select, read from 0x000100 with 4 bytes of command and 2 data words of 16 bits.
+    port.slave_select.set_ass(true);
+    port.update_chip_selects(false);
+    port.control.set_tx_bit_len(16);
+    port.control.set_tx_num(2);
+    port.tx[..3].copy_from_slice(&[0x0300, 0x0100, 0x0000]);
+    port.transfer();
+    assert_eq!(port.rx[..3], [0xffff, 0xffff, 0x0506]);
+
+    // The same read LSB first.
+    port.control.set_lsb(true);
+    port.tx[..3].copy_from_slice(&[0x0300u16.reverse_bits().into(), 0x0100u16.reverse_bits().into(), 0]);
+    port.transfer();
+    assert_eq!(port.rx[2], 0x0506u16.reverse_bits().into());
+
+    // Nothing on CS0.
+    port.slave_select.set_ssr(0b01);
+    port.control.set_tx_bit_len(8);
+    port.control.set_tx_num(0);
+    port.transfer();
+    assert_eq!(port.rx[0], 0xff);
+
+    // A reset keeps the flash mounted.
+    let mut spi = SPIConfig { ports: [SPIPort::default(), port] };
+    spi.reset();
+    let port = &mut spi.ports[1];
+    assert_eq!((port.control.get(0, 32), port.slave_select.get(0, 32), port.rx[0]), (0, 0, 0));
+    assert!(port.flash[1].is_mounted());
+
+    port.flash[1].unmount();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(parse_spi_slot("1:0"), Ok((1, 0)));
+    assert!(parse_spi_slot("2:0").is_err());
+    assert!(parse_spi_slot("0").is_err());
+}
@@ -4,27 +4,12 @@ use crate::{device::UnicornContext, log_unsupported_read, log_unsupported_write}
 pub const BASE: u64 = 0xb1000000;
 pub const SIZE: usize = 0x1000;
 
-pub fn read(uc: &mut UnicornContext, addr: u64, size: usize) -> u64 {
-    if size != 4 {
-        log_unsupported_read!(addr, size);
-        return 0;
-    }
-    match addr {
-        _ => {
-            log_unsupported_read!(addr, size);
-            0
-        }
-    }
+// No SPU register is emulated yet, so every access is reported as unsupported.
+pub fn read(_uc: &mut UnicornContext, addr: u64, size: usize) -> u64 {
+    log_unsupported_read!(addr, size);
+    0
 }
 
-pub fn write(uc: &mut UnicornContext, addr: u64, size: usize, value: u64) {
-    if size != 4 {
-        log_unsupported_write!(addr, size, value);
-        return;
-    }
-    match addr {
-        _ => {
-            log_unsupported_write!(addr, size, value);
-        }
-    }
+pub fn write(_uc: &mut UnicornContext, addr: u64, size: usize, value: u64) {
+    log_unsupported_write!(addr, size, value);
 }
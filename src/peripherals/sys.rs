@@ -1,11 +1,12 @@
 use std::fmt::Display;
 
-use bit_field::{B1, B2, B3, B4, B5, B6, B7, B8, bitfield};
+use bit_field::{B1, B2, B3, B4, B5, B6, B7, B8, B26, bitfield};
 use log::{warn, debug};
 
 use crate::{log_unsupported_read, log_unsupported_write};
 use crate::device::{QuitDetail, StopReason, UnicornContext, request_quit, request_stop};
-use crate::peripherals::common::{mmio_get_store_only, mmio_set_store_only};
+use crate::peripherals::{common::{mmio_load_masked, mmio_store_masked}, pwm};
+use crate::{impl_snapshot, impl_snapshot_bitfield};
 
 pub const BASE: u64 = 0xb0000000;
 pub const SIZE: usize = 0x1000;
@@ -21,7 +22,7 @@ const REG_GPDFUN: u64 = 0x8c;
 const REG_GPEFUN: u64 = 0x90;
 
 const CLK_BASE: u64 = 0x200;
-//const REG_PWRCON: u64 = CLK_BASE;
+const REG_PWRCON: u64 = CLK_BASE;
 const REG_AHBCLK: u64 = CLK_BASE + 0x4;
 const REG_APBCLK: u64 = CLK_BASE + 0x8;
 const REG_CLKDIV0: u64 = CLK_BASE + 0xc;
@@ -37,6 +38,9 @@ const GPIO_NAMES: [&str; 5] = ["GPIOA", "GPIOB", "GPIOC", "GPIOD", "GPIOE"];
 pub const F_BASE: u64 = 12_000_000;
 pub const F_BASE_RTC: u64 = 32_000;
 
+/// CPU cycles a PLL takes to lock after being powered up.
+const PLL_LOCK_CYCLES: u64 = 0x1000;
+
 #[bitfield]
 #[derive(Default)]
 pub struct AHBCLKRegister {
@@ -77,6 +81,20 @@ pub struct AHBCLKRegister {
     reserved_31: B1,
 }
 
+#[bitfield]
+#[derive(Default)]
+pub struct PowerControlRegister {
+    xin_enable: bool,
+    x32k_enable: bool,
+    apll_enable: bool,
+    upll_enable: bool,
+    /// Read only.
+    apll_locked: bool,
+    /// Read only.
+    upll_locked: bool,
+    reserved_6: B26,
+}
+
 #[bitfield]
 #[derive(Default)]
 pub struct APBCLKRegister {
@@ -213,8 +231,17 @@ const X32K: PLLConfig = PLLConfig {
     reg: 0x0,
 };
 
+/// Clock source that is powered down or not stable yet.
+const STOPPED: PLLConfig = PLLConfig {
+    fout: 0,
+    reg: 0x0,
+};
+
 #[derive(Default)]
 pub struct ClockConfig {
+    pub pwrcon: PowerControlRegister,
+    /// Step at which the APLL and UPLL lock, if they are powered up but not locked yet.
+    pll_lock_at: [Option<u64>; 2],
     pub ahbclk: AHBCLKRegister,
     pub apbclk: APBCLKRegister,
     pub apll: PLLConfig,
@@ -230,13 +257,89 @@ pub struct ClockConfig {
 impl ClockConfig {
     fn get_pll(&self, source: ClockSource) -> &PLLConfig {
         match source {
-            ClockSource::XIN => &XIN,
-            ClockSource::X32K => &X32K,
-            ClockSource::APLL => &self.apll,
-            ClockSource::UPLL => &self.upll,
+            ClockSource::XIN if self.pwrcon.get_xin_enable() => &XIN,
+            ClockSource::X32K if self.pwrcon.get_x32k_enable() => &X32K,
+            ClockSource::APLL if self.pwrcon.get_apll_locked() => &self.apll,
+            ClockSource::UPLL if self.pwrcon.get_upll_locked() => &self.upll,
+            _ => &STOPPED,
         }
     }
 
+    /// Power the oscillators up and leave the PLLs off, as out of reset.
+    pub fn power_on_reset(&mut self) {
+        self.pwrcon.set(0, 6, 0b000011);
+        self.pll_lock_at = [None, None];
+        self.update_tick_config();
+    }
+
+    /// Power all oscillators and PLLs up, with the PLLs already locked, as left by the bootrom.
+    pub fn power_up_all(&mut self) {
+        self.pwrcon.set(0, 6, 0b111111);
+        self.pll_lock_at = [None, None];
+        self.update_tick_config();
+    }
+
+    /// Handle a write to PWRCON at `steps`. Powering a PLL up starts its lock timer.
+    fn write_pwrcon(&mut self, value: u64, steps: u64) {
+        let prev_enable = [self.pwrcon.get_apll_enable(), self.pwrcon.get_upll_enable()];
+        self.pwrcon.set(0, 4, value & 0b1111);
+        let enable = [self.pwrcon.get_apll_enable(), self.pwrcon.get_upll_enable()];
+        for i in 0..2 {
+            if enable[i] && !prev_enable[i] {
+                self.pll_lock_at[i] = Some(steps + PLL_LOCK_CYCLES);
+            } else if !enable[i] {
+                self.pll_lock_at[i] = None;
+                self.pwrcon.set_bit(4 + i, false);
+            }
+        }
+        self.refresh_pll_lock(steps);
+        self.update_tick_config();
+    }
+
+    /// Lock the PLLs whose lock time has passed. Returns whether any PLL got locked.
+    fn refresh_pll_lock(&mut self, steps: u64) -> bool {
+        let mut locked = false;
+        for i in 0..2 {
+            if self.pll_lock_at[i].is_some_and(|lock_at| steps >= lock_at) {
+                self.pll_lock_at[i] = None;
+                self.pwrcon.set_bit(4 + i, true);
+                locked = true;
+            }
+        }
+        locked
+    }
+
+    /// Whether the APB clock of timer channel `index` is enabled.
+    pub fn timer_clock_enabled(&self, index: usize) -> bool {
+        match index {
+            0 => self.apbclk.get_tmr0(),
+            _ => self.apbclk.get_tmr1(),
+        }
+    }
+
+    /// Whether the APB clock of UART `port` is enabled.
+    pub fn uart_clock_enabled(&self, port: usize) -> bool {
+        match port {
+            0 => self.apbclk.get_uart0(),
+            _ => self.apbclk.get_uart1(),
+        }
+    }
+
+    /// UART engine clock of a port in Hz.
+    pub fn uart_clock(&self, port: usize) -> u64 {
+        let (source, prediv, div) = match port {
+            0 => (self.clkdiv3.get_uart0_source(), self.clkdiv3.get_uart0_prediv(), self.clkdiv3.get_uart0_div()),
+            _ => (self.clkdiv3.get_uart1_source(), self.clkdiv3.get_uart1_prediv(), self.clkdiv3.get_uart1_div()),
+        };
+        self.get_pll(source).get_fout() / (u64::from(prediv + 1) * u64::from(div + 1))
+    }
+
+    /// SD engine clock in Hz.
+    pub fn sd_clock(&self) -> u64 {
+        let div = (u64::from(self.clkdiv2.get_sd_prediv()) + 1) * (u64::from(self.clkdiv2.get_sd_div()) + 1);
+        self.get_pll(self.clkdiv2.get_sd_source()).get_fout() / div
+    }
+
     pub fn update_tick_config(&mut self) {
         let sys_div = u64::from(self.clkdiv0.get_sys_prediv() + 1) * u64::from(self.clkdiv0.get_sys_div() + 1);
         let f_sys = self.get_pll(self.clkdiv0.get_sys_source()).get_fout() / sys_div;
@@ -248,7 +351,8 @@ impl ClockConfig {
             1
         };
         self.tick_config.apb = self.tick_config.hclk1 * (u64::from(self.clkdiv4.get_apb_div()) + 1);
-        self.tick_config.vsync = self.tick_config.f_cpu / 60;
+        // Keep the frame rate divider valid even with the CPU clock source stopped.
+        self.tick_config.vsync = (self.tick_config.f_cpu / 60).max(1);
         debug!("{:?}", self.tick_config);
     }
 }
@@ -279,7 +383,7 @@ impl PLLConfig {
 
     pub fn set_reg(&mut self, value: u64) {
         self.reg = value;
-        self.fout = calculate_pll_fout(value.into());
+        self.fout = calculate_pll_fout(value);
     }
 }
 
@@ -291,8 +395,17 @@ fn calculate_pll_fout(pll: u64) -> u64 {
     ((F_BASE / 1000) * (2 * (fb + 2)) / (2 * (in_dv + 2)) / (OUT_DV_VALS[out_dv])) * 1000
 }
 
+/// Whether the register at `addr` only stores what the guest writes, so partial accesses can be merged into it.
+#[inline]
+fn is_store_only(addr: u64) -> bool {
+    matches!(addr & !3, REG_GPAFUN | REG_GPBFUN | REG_GPCFUN | REG_GPDFUN | REG_GPEFUN)
+}
+
 pub fn read(uc: &mut UnicornContext, addr: u64, size: usize) -> u64 {
     if size != 4 {
+        if is_store_only(addr) {
+            return mmio_load_masked(uc, BASE + addr, size);
+        }
         log_unsupported_read!(addr, size);
         return 0;
     }
@@ -302,6 +415,14 @@ pub fn read(uc: &mut UnicornContext, addr: u64, size: usize) -> u64 {
         REG_CHIPCFG => { 0x0003077b }
         // Self-test should always return OK and not running.
         REG_SDRBIST | REG_CRBIST => { 0x00000000 }
+        REG_PWRCON => {
+            let steps = uc.get_data().steps;
+            let clk = &mut uc.get_data_mut().clk;
+            if clk.refresh_pll_lock(steps) {
+                clk.update_tick_config();
+            }
+            clk.pwrcon.get(0, 32)
+        }
         REG_AHBCLK => uc.get_data().clk.ahbclk.get(0, 32),
         REG_APBCLK => uc.get_data().clk.apbclk.get(0, 32),
         REG_CLKDIV0 => uc.get_data().clk.clkdiv0.get(0, 32),
@@ -310,24 +431,38 @@ pub fn read(uc: &mut UnicornContext, addr: u64, size: usize) -> u64 {
         REG_CLKDIV3 => uc.get_data().clk.clkdiv3.get(0, 32),
         REG_CLKDIV4 => uc.get_data().clk.clkdiv4.get(0, 32),
         REG_GPAFUN | REG_GPBFUN | REG_GPCFUN | REG_GPDFUN | REG_GPEFUN => {
-            mmio_get_store_only(uc, BASE + addr)
+            mmio_load_masked(uc, BASE + addr, size)
         }
         REG_APLLCON => uc.get_data().clk.apll.get_reg(),
         REG_UPLLCON => uc.get_data().clk.upll.get_reg(),
         _ => {
             log_unsupported_read!(addr, size);
-            mmio_get_store_only(uc, BASE + addr)
+            mmio_load_masked(uc, BASE + addr, size)
         }
     }
 }
 
 pub fn write(uc: &mut UnicornContext, addr: u64, size: usize, value: u64) {
     if size != 4 {
-        log_unsupported_write!(addr, size, value);
+        if is_store_only(addr) {
+            mmio_store_masked(uc, BASE + addr, value, size);
+        } else {
+            log_unsupported_write!(addr, size, value);
+        }
         return;
     }
 
+    // PWM counts lazily, so it's brought up to date before its clock changes.
+    if (REG_PWRCON..=REG_UPLLCON).contains(&addr) {
+        pwm::sync(uc);
+    }
+
     match addr {
+        REG_PWRCON => {
+            let steps = uc.get_data().steps;
+            uc.get_data_mut().clk.write_pwrcon(value, steps);
+            debug!("PWRCON 0x{value:08x}");
+        }
         REG_AHBCLK => {
             uc.get_data_mut().clk.ahbclk.set(0, 32, value);
             // AHBCLK may halt the CPU. Request a tick.
@@ -357,7 +492,7 @@ pub fn write(uc: &mut UnicornContext, addr: u64, size: usize, value: u64) {
         REG_GPAFUN | REG_GPBFUN | REG_GPCFUN | REG_GPDFUN | REG_GPEFUN => {
             let index = usize::try_from(((addr - REG_GPAFUN) / 4) & 0x7).unwrap();
             debug!("{} config 0x{value:08x}", GPIO_NAMES[index]);
-            mmio_set_store_only(uc, BASE + addr, value);
+            mmio_store_masked(uc, BASE + addr, value, size);
         }
         REG_APLLCON => {
             uc.get_data_mut().clk.apll.set_reg(value);
@@ -371,18 +506,49 @@ pub fn write(uc: &mut UnicornContext, addr: u64, size: usize, value: u64) {
         }
         _ => {
             log_unsupported_write!(addr, size, value);
-            mmio_set_store_only(uc, BASE + addr, value);
+            mmio_store_masked(uc, BASE + addr, value, size);
         }
     }
 }
 
 pub fn tick(uc: &mut UnicornContext) {
+    let steps = uc.get_data().steps;
+    let clk = &mut uc.get_data_mut().clk;
+    if clk.refresh_pll_lock(steps) {
+        clk.update_tick_config();
+    }
+
     if !uc.get_data().clk.ahbclk.get_cpu() {
         request_quit(uc, QuitDetail::CPUHalt);
     }
 }
 
 
+impl_snapshot_bitfield!(PowerControlRegister, AHBCLKRegister, APBCLKRegister, ClockDivider0, ClockDivider1, ClockDivider2, ClockDivider3, ClockDivider4);
+impl_snapshot!(PLLConfig { reg, fout });
+impl_snapshot!(TickConfig { f_cpu, hclk1, apb, vsync });
+impl_snapshot!(ClockConfig { pwrcon, pll_lock_at, ahbclk, apbclk, apll, upll, clkdiv0, clkdiv1, clkdiv2, clkdiv3, clkdiv4, tick_config });
+
+#[test]
+fn test_pll_lock() {
+    let mut clk = ClockConfig::default();
+    clk.power_up_all();
+    clk.apll.set_reg(0x0000001e);
+    clk.clkdiv0.set_sys_source(ClockSource::APLL);
+    clk.update_tick_config();
+    assert_eq!(clk.tick_config.f_cpu, 192_000_000);
+
+    // Power cycle the APLL. The clock stops until the PLL locks again.
+    clk.write_pwrcon(0b1011, 100);
+    assert!(!clk.pwrcon.get_apll_locked());
+    clk.write_pwrcon(0b1111, 200);
+    assert_eq!(clk.tick_config.f_cpu, 0);
+    assert!(!clk.refresh_pll_lock(200 + PLL_LOCK_CYCLES - 1));
+    assert!(clk.refresh_pll_lock(200 + PLL_LOCK_CYCLES));
+    assert!(clk.pwrcon.get_apll_locked());
+    assert!(clk.pwrcon.get_upll_locked());
+}
+
 #[test]
 fn test_calculate_pll_fout() {
     assert_eq!(calculate_pll_fout(0x0000001e), 192_000_000);
@@ -1,6 +1,7 @@
 use bit_field::{B2, B8, bitfield};
 use log::{trace, warn};
-use crate::{device::UnicornContext, log_unsupported_read, log_unsupported_write, peripherals::aic::{InterruptNumber, post_interrupt}};
+use crate::{device::{QuitDetail, UnicornContext, request_quit}, log_unsupported_read, log_unsupported_write, peripherals::{aic::{InterruptNumber, post_interrupt}, common::next_multiple}};
+use crate::{impl_reset, impl_snapshot, impl_snapshot_bitfield};
 
 pub const BASE: u64 = 0xb8002000;
 pub const SIZE: usize = 0x1000;
@@ -17,15 +18,22 @@ const REG_WTCR: u64 = 0x1c;
 #[bitfield]
 #[derive(Default)]
 pub struct WatchdogControl {
+    /// Write 1 to restart the watchdog counter.
     alive: bool,
     auto_reset_enabled: bool,
     reset_flag: bool,
-    irq_enabled: bool,
+    irq_flag: bool,
     interval: B2,
-    irq_handler_installed: bool,
+    irq_enabled: bool,
     enabled: bool,
 }
 
+/// Timer counters are 24-bit wide.
+const COUNTER_MASK: u32 = 0xffffff;
+
+/// Number of watchdog ticks between the timeout interrupt and the system reset.
+const WATCHDOG_RESET_DELAY: u64 = 1024;
+
 #[bitfield]
 #[derive(Default, Debug, PartialEq)]
 pub enum TimerMode {
@@ -66,32 +74,62 @@ pub struct TimerConfig {
     pub status: u8,
     pub channels: [TimerChannel; 2],
     pub watchdog: WatchdogControl,
+    /// Watchdog ticks since the last kick.
+    pub watchdog_count: u64,
+}
+
+impl TimerConfig {
+    /// Advance the watchdog by one tick. Returns whether the timeout interrupt needs to be raised, and whether the
+    /// system needs to be reset.
+    fn step_watchdog(&mut self) -> (bool, bool) {
+        if !self.watchdog.get_enabled() {
+            return (false, false);
+        }
+
+        self.watchdog_count += 1;
+        let timeout = 1u64 << (14 + 2 * self.watchdog.get_interval());
+        if self.watchdog_count == timeout {
+            self.watchdog.set_irq_flag(true);
+            (self.watchdog.get_irq_enabled(), false)
+        } else if self.watchdog_count == timeout + WATCHDOG_RESET_DELAY && self.watchdog.get_auto_reset_enabled() {
+            self.watchdog.set_reset_flag(true);
+            (false, true)
+        } else {
+            (false, false)
+        }
+    }
 }
 
 impl TimerChannel {
-    pub fn check_irq_condition(&mut self) -> bool {
+    /// Advance the counter by `ticks`. Returns whether the counter reached the compare value, in which case an
+    /// interrupt needs to be raised.
+    pub fn advance(&mut self, ticks: u32) -> bool {
         if !self.control.get_enable() {
             return false;
         }
 
-        if self.count == self.compare {
-            let mut rv = true;
-            match self.control.get_mode() {
-                TimerMode::OneShot => self.control.set_enable(false),
-                TimerMode::Periodic => self.count = 0,
-                TimerMode::Toggle => {
-                    self.count = 0;
-                    if self.level {
-                        rv = false;
-                        self.level = !self.level;
-                    }
-                },
-                TimerMode::Uninterrupted => {},
+        let prev = self.count & COUNTER_MASK;
+        // Ticks needed to reach the compare value, going through a wrap if the counter is already past it.
+        let distance = (self.compare.wrapping_sub(prev).wrapping_sub(1) & COUNTER_MASK) + 1;
+        if ticks < distance {
+            self.count = prev.wrapping_add(ticks) & COUNTER_MASK;
+            return false;
+        }
+
+        let overshoot = ticks - distance;
+        match self.control.get_mode() {
+            TimerMode::OneShot => {
+                self.count = self.compare;
+                self.control.set_enable(false);
             }
-            rv
-        } else {
-            false
+            TimerMode::Periodic => self.count = overshoot,
+            TimerMode::Toggle => {
+                self.count = overshoot;
+                self.level = !self.level;
+            },
+            TimerMode::Uninterrupted => self.count = prev.wrapping_add(ticks) & COUNTER_MASK,
         }
+        true
     }
 
     #[inline]
@@ -170,7 +208,18 @@ pub fn write(uc: &mut UnicornContext, addr: u64, size: usize, value: u64) {
             trace!("REG_TICR1 {:?}", uc.get_data().tmr.channels[1].compare);
         }
         REG_TISR => uc.get_data_mut().tmr.status &= !u8::try_from(value & 0xff).unwrap(),
-        REG_WTCR => uc.get_data_mut().tmr.watchdog.set(0, 8, value),
+        REG_WTCR => {
+            let tmr = &mut uc.get_data_mut().tmr;
+            // Flags are write 1 to clear.
+            let flags = tmr.watchdog.get(2, 2) & !((value >> 2) & 0b11);
+            tmr.watchdog.set(0, 8, value);
+            tmr.watchdog.set(2, 2, flags);
+            if tmr.watchdog.get_alive() {
+                tmr.watchdog.set_alive(false);
+                tmr.watchdog_count = 0;
+            }
+            trace!("REG_WTCR {:?}", tmr.watchdog);
+        }
         _ => log_unsupported_write!(addr, size, value),
     }
     
@@ -178,28 +227,105 @@ pub fn write(uc: &mut UnicornContext, addr: u64, size: usize, value: u64) {
 
 pub fn generate_stop_condition(uc: &mut UnicornContext, steps: u64) {
     let div_apb = uc.get_data().clk.tick_config.apb;
-    if steps % div_apb != 0 {
+    if !steps.is_multiple_of(div_apb) {
         return;
     }
 
-    for timer in &mut uc.get_data_mut().tmr.channels {
-        if !timer.control.get_enable() {
+    // Gated channels keep their count and resume from it once their clock is enabled again.
+    for (i, intno) in [InterruptNumber::TMR0, InterruptNumber::TMR1].into_iter().enumerate() {
+        let clock_enabled = uc.get_data().clk.timer_clock_enabled(i);
+        let timer = &mut uc.get_data_mut().tmr.channels[i];
+        let rate = div_apb * (u64::from(timer.control.get_prescale()) + 1);
+        if !clock_enabled || !timer.control.get_enable() || !steps.is_multiple_of(rate) {
             continue;
         }
-        let rate = div_apb * (u64::from(timer.control.get_prescale()) + 1);
-        if steps % rate == 0 {
-            timer.count += 1;
+        if timer.advance(1) {
+            uc.get_data_mut().tmr.status |= 1 << i;
+            post_interrupt(uc, intno);
         }
     }
 
-    if uc.get_data_mut().tmr.channels[0].check_irq_condition() {
-        uc.get_data_mut().tmr.status |= 0x1;
-        post_interrupt(uc, InterruptNumber::TMR0, true, false);
+    if !uc.get_data().clk.apbclk.get_wdclk() {
+        return;
+    }
+    let (watchdog_irq, watchdog_reset) = uc.get_data_mut().tmr.step_watchdog();
+    if watchdog_irq {
+        post_interrupt(uc, InterruptNumber::WDT);
+    }
+    if watchdog_reset {
+        warn!("Watchdog timer expired.");
+        request_quit(uc, QuitDetail::WatchdogReset);
+    }
+}
+
+/// Step at which `generate_stop_condition` next needs to run, or `None` if all timers are stopped or gated.
+pub fn next_event(uc: &UnicornContext, steps: u64) -> Option<u64> {
+    let clk = &uc.get_data().clk;
+    let div_apb = clk.tick_config.apb;
+    let tmr = &uc.get_data().tmr;
+    let timers = tmr.channels.iter()
+        .enumerate()
+        .filter(|(i, timer)| clk.timer_clock_enabled(*i) && timer.control.get_enable())
+        .map(|(_, timer)| next_multiple(steps, div_apb * (u64::from(timer.control.get_prescale()) + 1)));
+    let watchdog = (clk.apbclk.get_wdclk() && tmr.watchdog.get_enabled()).then(|| next_multiple(steps, div_apb));
+    timers.chain(watchdog).min()
+}
+
+impl_snapshot_bitfield!(WatchdogControl, TimerControl);
+impl_snapshot!(TimerChannel { count, compare, control, level });
+impl_snapshot!(TimerConfig { status, channels, watchdog, watchdog_count });
+impl_reset!(TimerConfig);
+
+#[test]
+fn test_watchdog() {
+    let mut tmr = TimerConfig::default();
+    tmr.watchdog.set_enabled(true);
+    tmr.watchdog.set_irq_enabled(true);
+    tmr.watchdog.set_auto_reset_enabled(true);
+
+    for _ in 1..(1 << 14) {
+        assert_eq!(tmr.step_watchdog(), (false, false));
+    }
+    assert_eq!(tmr.step_watchdog(), (true, false));
+    assert!(tmr.watchdog.get_irq_flag());
+    for _ in 1..WATCHDOG_RESET_DELAY {
+        assert_eq!(tmr.step_watchdog(), (false, false));
     }
+    assert_eq!(tmr.step_watchdog(), (false, true));
+    assert!(tmr.watchdog.get_reset_flag());
+}
 
-    if uc.get_data_mut().tmr.channels[1].check_irq_condition() {
-        uc.get_data_mut().tmr.status |= 0x2;
-        post_interrupt(uc, InterruptNumber::TMR1, true, false);
+#[test]
+fn test_toggle_output() {
+    let mut timer = TimerChannel { compare: 3, ..Default::default() };
+    timer.control.set_mode(TimerMode::Toggle);
+    timer.control.set_enable(true);
+
+    let mut levels = vec![];
+    for _ in 0..12 {
+        if timer.advance(1) {
+            levels.push(timer.level);
+        }
     }
-    // TODO
+    assert_eq!(levels, vec![true, false, true, false]);
+}
+
+#[test]
+fn test_advance_unaligned() {
+    let mut timer = TimerChannel { compare: 10, ..Default::default() };
+    timer.control.set_mode(TimerMode::Periodic);
+    timer.control.set_enable(true);
+
+    let fired: Vec<bool> = (0..6).map(|_| timer.advance(4)).collect();
+    // Reached at 12 and 20 (2 and 0 after reload), overshoot carried over.
+    assert_eq!(fired, vec![false, false, true, false, true, false]);
+    assert_eq!(timer.count, 4);
+
+    // Counter already past a lowered compare value wraps around instead of running away.
+    timer.control.set_mode(TimerMode::Uninterrupted);
+    timer.count = COUNTER_MASK - 1;
+    timer.compare = 2;
+    assert!(!timer.advance(2));
+    assert_eq!(timer.count, 0);
+    assert!(timer.advance(2));
 }
@@ -1,23 +1,29 @@
-use std::mem;
+use std::{collections::VecDeque, mem};
 
-use bit_field::{B2, B3, B6, bitfield};
-use log::{info, warn};
+use bit_field::{B2, B3, B4, B6, B8, B16, bitfield};
+use log::{info, trace, warn};
 
-use crate::{device::UnicornContext, log_unsupported_read, log_unsupported_write};
+use crate::{device::{Device, UnicornContext}, log_unsupported_read, log_unsupported_write, peripherals::aic::{InterruptNumber, post_interrupt}};
+use crate::{impl_reset, impl_snapshot, impl_snapshot_bitfield};
 
 pub const BASE: u64 = 0xb8008000;
 pub const SIZE: usize = 0x1000;
 
 pub const REG_UART_DATA: u64 = 0x0;
-// pub const REG_UART_IER: u64 = 0x4;
+pub const REG_UART_IER: u64 = 0x4;
 // pub const REG_UART_FCR: u64 = 0x8;
-// pub const REG_UART_LCR: u64 = 0xc;
+pub const REG_UART_LCR: u64 = 0xc;
 // pub const REG_UART_MCR: u64 = 0x10;
 // pub const REG_UART_MSR: u64 = 0x14;
 pub const REG_UART_FSR: u64 = 0x18;
-// pub const REG_UART_ISR: u64 = 0x1c;
+pub const REG_UART_ISR: u64 = 0x1c;
 // pub const REG_UART_TOR: u64 = 0x20;
-// pub const REG_UART_BAUD: u64 = 0x24;
+pub const REG_UART_BAUD: u64 = 0x24;
+
+/// Depth of the RX FIFO.
+const RX_FIFO_DEPTH: usize = 16;
+/// Depth of the TX FIFO.
+const TX_FIFO_DEPTH: usize = 16;
 
 #[derive(Default)]
 pub struct UARTConfig {
@@ -26,6 +32,16 @@ pub struct UARTConfig {
 
 pub struct UARTPort {
     fifo_status: UARTFIFOStatus,
+    irq_enable: UARTInterruptEnable,
+    rx_fifo: VecDeque<u8>,
+    /// TX FIFO drained since the last THR write or ISR read.
+    thre_pending: bool,
+    line_control: UARTLineControl,
+    baud_rate: UARTBaudRate,
+    /// Bytes in the TX FIFO, including the one being shifted out.
+    tx_pending: usize,
+    /// Step at which the byte being shifted out finishes.
+    tx_done_at: u64,
     line_buffer: [u8; 80],
     line_offset: usize,
 }
@@ -35,10 +51,118 @@ impl Default for UARTPort {
         let mut fifo_status = UARTFIFOStatus::new();
         fifo_status.set_rx_empty(true);
         fifo_status.set_tx_empty(true);
-        Self { fifo_status, line_buffer: [0u8; 80], line_offset: 0 }
+        Self {
+            fifo_status,
+            irq_enable: Default::default(),
+            rx_fifo: VecDeque::with_capacity(RX_FIFO_DEPTH),
+            thre_pending: false,
+            line_control: Default::default(),
+            baud_rate: Default::default(),
+            tx_pending: 0,
+            tx_done_at: 0,
+            line_buffer: [0u8; 80],
+            line_offset: 0,
+        }
+    }
+}
+
+impl UARTPort {
+    fn update_rx_status(&mut self) {
+        let len = self.rx_fifo.len();
+        self.fifo_status.set_rx_pointer(u8::try_from(len.min(0x3f)).unwrap());
+        self.fifo_status.set_rx_empty(len == 0);
+        self.fifo_status.set_rx_full(len >= RX_FIFO_DEPTH);
+    }
+
+    /// Move as many bytes as the RX FIFO can take from `input`. Returns whether any byte was received.
+    pub fn receive(&mut self, input: &mut VecDeque<u8>) -> bool {
+        let count = input.len().min(RX_FIFO_DEPTH - self.rx_fifo.len());
+        self.rx_fifo.extend(input.drain(..count));
+        self.update_rx_status();
+        count != 0
+    }
+
+    fn update_tx_status(&mut self) {
+        self.fifo_status.set_tx_pointer(u8::try_from(self.tx_pending.min(0x3f)).unwrap());
+        self.fifo_status.set_tx_empty(self.tx_pending == 0);
+        self.fifo_status.set_tx_full(self.tx_pending >= TX_FIFO_DEPTH);
+        self.fifo_status.set_te_flag(self.tx_pending == 0);
+    }
+
+    /// Number of bits in a frame, including start, parity and stop bits.
+    fn frame_bits(&self) -> u64 {
+        let word_length = 5 + u64::from(self.line_control.get_word_length());
+        let parity = u64::from(self.line_control.get_parity_enable());
+        let stop_bits = if self.line_control.get_extra_stop_bit() {
+            // 1.5 stop bits for 5-bit words, rounded up.
+            2
+        } else {
+            1
+        };
+        1 + word_length + parity + stop_bits
+    }
+
+    /// Baud rate given the UART engine clock.
+    fn baud(&self, clock: u64) -> u64 {
+        let divisor = u64::from(self.baud_rate.get_divisor()) + 2;
+        let prescale = match (self.baud_rate.get_div_x_en(), self.baud_rate.get_div_x_one()) {
+            (false, _) => 16,
+            (true, false) => u64::from(self.baud_rate.get_div_x()) + 1,
+            (true, true) => 1,
+        };
+        clock / (prescale * divisor)
+    }
+
+    fn irq_status(&self) -> UARTInterruptEnable {
+        let mut status = UARTInterruptEnable::new();
+        status.set_rda(!self.rx_fifo.is_empty());
+        status.set_thre(self.thre_pending);
+        status
     }
 }
 
+#[bitfield]
+#[derive(Default)]
+pub struct UARTLineControl {
+    /// Word length minus 5.
+    word_length: B2,
+    extra_stop_bit: bool,
+    parity_enable: bool,
+    even_parity: bool,
+    stick_parity: bool,
+    break_control: bool,
+    reserved_7: bool,
+}
+
+#[bitfield]
+#[derive(Default)]
+pub struct UARTBaudRate {
+    divisor: B16,
+    reserved_16: B8,
+    div_x: B4,
+    div_x_one: bool,
+    div_x_en: bool,
+    reserved_30: B2,
+}
+
+#[bitfield]
+#[derive(Default)]
+pub struct UARTInterruptEnable {
+    /// Receive data available.
+    rda: bool,
+    /// Transmit holding register empty.
+    thre: bool,
+    /// Receive line status.
+    rls: bool,
+    /// Modem status.
+    modem: bool,
+    rx_timeout: bool,
+    buffer_error: bool,
+    reserved_6: B2,
+    reserved_8: B8,
+    reserved_16: B16,
+}
+
 #[bitfield]
 #[derive(Default)]
 pub struct UARTFIFOStatus {
@@ -61,17 +185,43 @@ pub struct UARTFIFOStatus {
     tx_err: bool,
 }
 
+/// Split an MMIO offset into a port number and a register offset. UART1 registers start at 0x100.
+#[inline]
+fn decode(addr: u64) -> Option<(usize, u64)> {
+    match addr >> 8 {
+        0 => Some((0, addr & 0xff)),
+        1 => Some((1, addr & 0xff)),
+        _ => None,
+    }
+}
+
 pub fn read(uc: &mut UnicornContext, addr: u64, size: usize) -> u64 {
-    let port = usize::from(((addr >> 8) & 0x1) as u8);
-    let paddr = addr & 0xff;
+    let Some((port, paddr)) = decode(addr) else {
+        log_unsupported_read!(addr, size);
+        return 0;
+    };
 
     match size {
-        // 1 => {
-        //     TODO: support inject data into UART
-        // }
+        1 | 4 if paddr == REG_UART_DATA => {
+            let port_obj = &mut uc.get_data_mut().uart.ports[port];
+            let value = port_obj.rx_fifo.pop_front().unwrap_or(0);
+            port_obj.update_rx_status();
+            value.into()
+        }
         4 => {
             match paddr {
+                REG_UART_IER => uc.get_data().uart.ports[port].irq_enable.get(0, 32),
+                REG_UART_LCR => uc.get_data().uart.ports[port].line_control.get(0, 8),
+                REG_UART_BAUD => uc.get_data().uart.ports[port].baud_rate.get(0, 32),
                 REG_UART_FSR => uc.get_data().uart.ports[port].fifo_status.get(0, 32),
+                REG_UART_ISR => {
+                    // Raw status in the low byte, status masked by enable in the next byte.
+                    let port_obj = &mut uc.get_data_mut().uart.ports[port];
+                    let status = port_obj.irq_status().get(0, 8);
+                    // Reading the status acknowledges TX empty.
+                    port_obj.thre_pending = false;
+                    status | ((status & port_obj.irq_enable.get(0, 8)) << 8)
+                }
                 _ => {
                     log_unsupported_read!(addr, size);
                     0
@@ -88,13 +238,18 @@ pub fn read(uc: &mut UnicornContext, addr: u64, size: usize) -> u64 {
 }
 
 pub fn write(uc: &mut UnicornContext, addr: u64, size: usize, value: u64) {
-    //log_unsupported_write!(addr, size, value);
-    let port = usize::from(((addr >> 8) & 0x1) as u8);
-    let paddr = addr & 0xff;
+    let Some((port, paddr)) = decode(addr) else {
+        log_unsupported_write!(addr, size, value);
+        return;
+    };
 
     match size {
         1 => if paddr == REG_UART_DATA {
             let port_obj = &mut uc.get_data_mut().uart.ports[port];
+            if port_obj.tx_pending >= TX_FIFO_DEPTH {
+                port_obj.fifo_status.set_tx_overflow(true);
+                return;
+            }
             port_obj.line_buffer[port_obj.line_offset] = value as u8;
             port_obj.line_offset += 1;
             if port_obj.line_offset == port_obj.line_buffer.len() || value == 0x0a {
@@ -103,12 +258,161 @@ pub fn write(uc: &mut UnicornContext, addr: u64, size: usize, value: u64) {
                 info!("UART{port}: {}", printable.trim());
                 port_obj.line_offset = 0;
             }
+            port_obj.thre_pending = false;
+            port_obj.tx_pending += 1;
+            port_obj.update_tx_status();
+            if port_obj.tx_pending == 1 {
+                let frame_steps = frame_steps(uc, port);
+                let steps = uc.get_data().steps;
+                uc.get_data_mut().uart.ports[port].tx_done_at = steps + frame_steps;
+            }
         } else {
             log_unsupported_write!(addr, size, value);
         },
         4 => match paddr {
+            REG_UART_IER => {
+                let port_obj = &mut uc.get_data_mut().uart.ports[port];
+                let prev_thre = port_obj.irq_enable.get_thre();
+                port_obj.irq_enable.set(0, 32, value);
+                // Enabling TX empty interrupt while the TX FIFO is empty raises it right away.
+                if !prev_thre && port_obj.irq_enable.get_thre() {
+                    port_obj.thre_pending = true;
+                }
+                let status = port_obj.irq_status().get(0, 8) & port_obj.irq_enable.get(0, 8);
+                if status != 0 {
+                    post_interrupt(uc, intno(port));
+                }
+            }
+            REG_UART_LCR => {
+                let port_obj = &mut uc.get_data_mut().uart.ports[port];
+                port_obj.line_control.set(0, 8, value & 0xff);
+                trace!("UART{port}: {} bits per frame", port_obj.frame_bits());
+            }
+            REG_UART_BAUD => {
+                uc.get_data_mut().uart.ports[port].baud_rate.set(0, 32, value);
+                let clock = uc.get_data().clk.uart_clock(port);
+                trace!("UART{port}: {} baud", uc.get_data().uart.ports[port].baud(clock));
+            }
             _ => log_unsupported_write!(addr, size, value),
         },
         _ => log_unsupported_write!(addr, size, value),
     }
 }
+
+/// Interrupt number of a port. UART0 is the high speed UART.
+#[inline]
+fn intno(port: usize) -> InterruptNumber {
+    match port {
+        0 => InterruptNumber::HUART,
+        _ => InterruptNumber::UART,
+    }
+}
+
+/// Number of CPU steps needed to shift out one frame.
+fn frame_steps(uc: &UnicornContext, port: usize) -> u64 {
+    let clk = &uc.get_data().clk;
+    let port_obj = &uc.get_data().uart.ports[port];
+    let baud = port_obj.baud(clk.uart_clock(port));
+    if baud == 0 {
+        return 1;
+    }
+    (clk.tick_config.f_cpu * port_obj.frame_bits() / baud).max(1)
+}
+
+pub fn generate_stop_condition(uc: &mut UnicornContext, steps: u64) {
+    for port in 0..2 {
+        let port_obj = &uc.get_data().uart.ports[port];
+        if port_obj.tx_pending == 0 || steps < port_obj.tx_done_at {
+            continue;
+        }
+
+        let frame_steps = frame_steps(uc, port);
+        if !uc.get_data().clk.uart_clock_enabled(port) {
+            // Hold the frame being shifted out until the clock is enabled again.
+            uc.get_data_mut().uart.ports[port].tx_done_at = steps + frame_steps;
+            continue;
+        }
+        let port_obj = &mut uc.get_data_mut().uart.ports[port];
+        port_obj.tx_pending -= 1;
+        port_obj.tx_done_at = steps + frame_steps;
+        port_obj.update_tx_status();
+        if port_obj.tx_pending == 0 {
+            port_obj.thre_pending = true;
+            if port_obj.irq_enable.get_thre() {
+                post_interrupt(uc, intno(port));
+            }
+        }
+    }
+}
+
+/// Step at which `generate_stop_condition` next needs to run, or `None` if nothing is being transmitted.
+pub fn next_event(uc: &UnicornContext, steps: u64) -> Option<u64> {
+    uc.get_data().uart.ports.iter()
+        .filter(|port_obj| port_obj.tx_pending != 0)
+        .map(|port_obj| port_obj.tx_done_at.max(steps + 1))
+        .min()
+}
+
+pub fn tick(uc: &mut UnicornContext, device: &mut Device) {
+    for (port, input) in device.uart_input.iter_mut().enumerate() {
+        if input.is_empty() || !uc.get_data().clk.uart_clock_enabled(port) {
+            continue;
+        }
+        let port_obj = &mut uc.get_data_mut().uart.ports[port];
+        if port_obj.receive(input) {
+            trace!("UART{port}: {} bytes in RX FIFO", port_obj.rx_fifo.len());
+            if port_obj.irq_enable.get_rda() {
+                post_interrupt(uc, intno(port));
+            }
+        }
+    }
+}
+
+impl_snapshot_bitfield!(UARTLineControl, UARTBaudRate, UARTInterruptEnable, UARTFIFOStatus);
+impl_snapshot!(UARTPort {
+    fifo_status, irq_enable, rx_fifo, thre_pending, line_control, baud_rate, tx_pending, tx_done_at, line_buffer, line_offset,
+});
+impl_snapshot!(UARTConfig { ports });
+impl_reset!(UARTConfig);
+
+#[test]
+fn test_receive() {
+    let mut port = UARTPort::default();
+    let mut input: VecDeque<u8> = (0..20).collect();
+
+    assert!(port.receive(&mut input));
+    assert_eq!(input.len(), 4);
+    assert!(port.fifo_status.get_rx_full());
+    assert_eq!(port.fifo_status.get_rx_pointer(), 16);
+
+    port.rx_fifo.clear();
+    port.update_rx_status();
+    assert!(port.receive(&mut input));
+    assert!(input.is_empty());
+    assert!(!port.fifo_status.get_rx_full());
+    assert!(!port.fifo_status.get_rx_empty());
+    assert!(!port.receive(&mut input));
+}
+
+#[test]
+fn test_baud() {
+    let mut port = UARTPort::default();
+    // 115200 8N1 from a 12MHz clock.
+    port.line_control.set_word_length(3);
+    port.baud_rate.set_div_x_en(true);
+    port.baud_rate.set_div_x_one(true);
+    port.baud_rate.set_divisor(102);
+    assert_eq!(port.baud(12_000_000), 115384);
+    assert_eq!(port.frame_bits(), 10);
+
+    port.baud_rate.set_div_x_en(false);
+    port.baud_rate.set_divisor(4);
+    assert_eq!(port.baud(12_000_000), 125000);
+}
+
+#[test]
+fn test_decode() {
+    assert_eq!(decode(0x18), Some((0, REG_UART_FSR)));
+    assert_eq!(decode(0x118), Some((1, REG_UART_FSR)));
+    assert_eq!(decode(0x218), None);
+}
@@ -0,0 +1,189 @@
+use bit_field::{B2, B3, B5, B11, B12, B15, B25, bitfield};
+use log::{trace, warn};
+use crate::{device::UnicornContext, extdev::camera::Camera, log_unsupported_read, log_unsupported_write, peripherals::{aic::{InterruptNumber, post_interrupt}, common::{mmio_get_store_only, mmio_set_store_only}}};
+use crate::{impl_reset, impl_snapshot, impl_snapshot_bitfield};
+
+pub const BASE: u64 = 0xb1003000;
+pub const SIZE: usize = 0x1000;
+
+const REG_VPECTL: u64 = 0x0;
+const REG_VPEINT: u64 = 0x8;
+const REG_VPECWS: u64 = 0x24;
+const REG_VSTRIDE: u64 = 0x34;
+const REG_PACBA0: u64 = 0x60;
+
+/// Registers that only need to hold their value: sensor timing and polarity, motion detection, cropping start,
+/// scaling, frame rate control, FIFO thresholds, the secondary packet buffer and the planar buffers.
+const STORE_ONLY: &[u64] = &[
+    0x04, 0x0c, 0x10, 0x14, 0x18, 0x1c, 0x20, 0x28, 0x2c, 0x30, 0x3c, 0x40, 0x64, 0x80, 0x84, 0x88,
+];
+
+/// Bytes per pixel of the packet output, which is YUV 4:2:2.
+const PACKET_PIXEL_SIZE: usize = 2;
+
+#[bitfield]
+#[derive(Default)]
+pub struct VideoInControl {
+    /// Capture frames. Cleared after one frame in one shot mode.
+    enable: bool,
+    reserved_1: bool,
+    packet_enable: bool,
+    planar_enable: bool,
+    reserved_4: B2,
+    /// Stop after capturing one frame.
+    capture_one: bool,
+    reserved_7: B25,
+}
+
+#[bitfield]
+#[derive(Default)]
+pub struct VideoInInterrupt {
+    /// A frame was captured. Write 1 to clear.
+    frame_end: bool,
+    /// Motion detection, address match and memory error flags, which are never raised.
+    reserved_1: B3,
+    reserved_4: B12,
+    frame_end_enable: bool,
+    reserved_17: B15,
+}
+
+#[bitfield]
+#[derive(Default)]
+pub struct VideoInCropSize {
+    width: B11,
+    reserved_11: B5,
+    height: B11,
+    reserved_27: B5,
+}
+
+/// Video capture engine (VIDEOIN).
+///
+/// A frame is captured from `camera` on every emulator frame while capture is enabled, and written to the packet
+/// buffer in YUYV order regardless of the configured packet format. Scaling, frame rate control and planar output are
+/// not emulated.
+#[derive(Default)]
+pub struct VideoInConfig {
+    pub control: VideoInControl,
+    pub interrupt: VideoInInterrupt,
+    pub crop_size: VideoInCropSize,
+    /// Line stride of the packet buffer, in pixels. 0 means the lines are packed.
+    pub stride: u32,
+    pub packet_addr: u32,
+    pub camera: Camera,
+}
+
+impl VideoInConfig {
+    /// Lay out a captured frame in the packet buffer. Returns the offset and bytes of each line.
+    fn packet_lines<'a>(&self, frame: &'a [u8], width: usize) -> impl Iterator<Item = (u64, &'a [u8])> {
+        let line_size = width * PACKET_PIXEL_SIZE;
+        let stride = match usize::try_from(self.stride).unwrap() {
+            0 => width,
+            stride => stride,
+        };
+        let pitch = u64::try_from(stride * PACKET_PIXEL_SIZE).unwrap();
+        // The camera pads odd widths, which the engine does not write.
+        let padded_line_size = width.next_multiple_of(2) * PACKET_PIXEL_SIZE;
+        frame.chunks_exact(padded_line_size).zip((0..).map(move |y| y * pitch)).map(move |(line, offset)| {
+            (offset, &line[..line_size])
+        })
+    }
+}
+
+pub fn read(uc: &mut UnicornContext, addr: u64, size: usize) -> u64 {
+    if size != 4 {
+        log_unsupported_read!(addr, size);
+        return 0;
+    }
+
+    let videoin = &uc.get_data().videoin;
+
+    match addr {
+        REG_VPECTL => videoin.control.get(0, 32),
+        REG_VPEINT => videoin.interrupt.get(0, 32),
+        REG_VPECWS => videoin.crop_size.get(0, 32),
+        REG_VSTRIDE => videoin.stride.into(),
+        REG_PACBA0 => videoin.packet_addr.into(),
+        _ if STORE_ONLY.contains(&addr) => mmio_get_store_only(uc, BASE + addr),
+        _ => {
+            log_unsupported_read!(addr, size);
+            0
+        }
+    }
+}
+
+pub fn write(uc: &mut UnicornContext, addr: u64, size: usize, value: u64) {
+    if size != 4 {
+        log_unsupported_write!(addr, size, value);
+        return;
+    }
+
+    let value32 = u32::try_from(value & 0xffffffff).unwrap();
+    let videoin = &mut uc.get_data_mut().videoin;
+
+    match addr {
+        REG_VPECTL => {
+            videoin.control.set(0, 32, value);
+            trace!("VIDEOIN: Capture {}", if videoin.control.get_enable() { "enabled" } else { "disabled" });
+        }
+        REG_VPEINT => {
+            let frame_end = videoin.interrupt.get_frame_end() && value & 1 == 0;
+            videoin.interrupt.set(0, 32, value);
+            videoin.interrupt.set_frame_end(frame_end);
+        }
+        REG_VPECWS => videoin.crop_size.set(0, 32, value),
+        REG_VSTRIDE => videoin.stride = value32 & 0x1fff,
+        REG_PACBA0 => videoin.packet_addr = value32,
+        _ if STORE_ONLY.contains(&addr) => mmio_set_store_only(uc, BASE + addr, value),
+        _ => log_unsupported_write!(addr, size, value),
+    }
+}
+
+pub fn frame_step(uc: &mut UnicornContext) {
+    let ahbclk = &uc.get_data().clk.ahbclk;
+    if !(ahbclk.get_cap() && ahbclk.get_sen() && uc.get_data().videoin.control.get_enable()) {
+        return;
+    }
+
+    let videoin = &mut uc.get_data_mut().videoin;
+    let width = usize::from(videoin.crop_size.get_width());
+    let height = usize::from(videoin.crop_size.get_height());
+    if videoin.control.get_packet_enable() && width > 0 && height > 0 {
+        let frame = videoin.camera.capture(width, height);
+        let base = u64::from(videoin.packet_addr);
+        let lines: Vec<_> = videoin.packet_lines(&frame, width).collect();
+        for (offset, line) in lines {
+            if let Err(err) = uc.mem_write(base + offset, line) {
+                warn!("VIDEOIN: Writing packet buffer at 0x{:08x} failed: {err:?}", base + offset);
+                break;
+            }
+        }
+        trace!("VIDEOIN: Captured {width}x{height} frame to 0x{base:08x}");
+    }
+
+    let videoin = &mut uc.get_data_mut().videoin;
+    if videoin.control.get_capture_one() {
+        videoin.control.set_enable(false);
+    }
+    videoin.interrupt.set_frame_end(true);
+    if videoin.interrupt.get_frame_end_enable() {
+        post_interrupt(uc, InterruptNumber::VIDEOIN);
+    }
+}
+
+impl_snapshot_bitfield!(VideoInControl, VideoInInterrupt, VideoInCropSize);
+impl_snapshot!(VideoInConfig { control, interrupt, crop_size, stride, packet_addr });
+impl_reset!(VideoInConfig { camera });
+
+#[test]
+fn test_packet_lines() {
+    let mut videoin = VideoInConfig::default();
+    let frame = videoin.camera.capture(3, 2);
+
+    // Odd widths drop the padding pixel of each line.
+    let lines: Vec<_> = videoin.packet_lines(&frame, 3).collect();
+    assert_eq!(lines, [(0, &frame[..6]), (6, &frame[8..14])]);
+
+    videoin.stride = 8;
+    let offsets: Vec<_> = videoin.packet_lines(&frame, 3).map(|(offset, _)| offset).collect();
+    assert_eq!(offsets, [0, 16]);
+}
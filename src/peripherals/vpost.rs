@@ -1,6 +1,7 @@
-use bit_field::{B2, B3, B7, B8, B12, bitfield};
+use bit_field::{B2, B3, B7, B8, B12, B16, bitfield};
 use log::{trace, warn};
-use crate::{device::{StopReason, UnicornContext, request_stop}, log_unsupported_read, log_unsupported_write, peripherals::common::{mmio_get_store_only, mmio_set_store_only}};
+use crate::{device::{StopReason, UnicornContext, request_stop}, log_unsupported_read, log_unsupported_write, peripherals::{aic::{InterruptNumber, post_interrupt}, common::{mmio_load_masked, mmio_store_masked, next_multiple}}};
+use crate::{impl_reset, impl_snapshot, impl_snapshot_bitfield};
 
 pub const BASE: u64 = 0xb1002000;
 pub const SIZE: usize = 0x1000;
@@ -15,6 +16,15 @@ const TCON4: u64 = 0x1c;
 
 const FSADDR: u64 = 0x50;
 
+/// Panel resolution used until the firmware programs TCON3.
+const DEFAULT_WIDTH: u32 = 320;
+const DEFAULT_HEIGHT: u32 = 240;
+
+/// Largest panel the emulator renders. TCON3 values beyond this are clamped, so a bogus resolution can't make the
+/// frame copy read or allocate gigabytes.
+const MAX_WIDTH: u32 = 1024;
+const MAX_HEIGHT: u32 = 768;
+
 #[bitfield]
 #[derive(Debug, PartialEq)]
 pub enum FrameBufferFormat {
@@ -28,6 +38,16 @@ pub enum FrameBufferFormat {
     Y0Cr0Y1Cb0,
 }
 
+impl FrameBufferFormat {
+    /// Number of bytes per pixel.
+    pub fn bpp(&self) -> usize {
+        match self {
+            Self::XRGB | Self::RGBX => 4,
+            _ => 2,
+        }
+    }
+}
+
 #[bitfield]
 #[derive(Debug, PartialEq)]
 pub enum ParallelRGBBusType {
@@ -72,22 +92,131 @@ pub struct LCDIRQStatus {
     reserved_24: B8,
 }
 
+/// Panel size, as set in TCON3.
+#[bitfield]
+#[derive(Default)]
+pub struct LCDResolution {
+    /// Lines per panel minus 1.
+    lpp: B16,
+    /// Pixels per line minus 1.
+    ppl: B16,
+}
+
 #[derive(Default)]
 pub struct LCDConfig {
     pub control: LCDControl,
     pub irq: LCDIRQStatus,
+    pub resolution: LCDResolution,
     pub fb: u32,
 }
 
+impl LCDConfig {
+    /// Width of the panel in pixels.
+    pub fn width(&self) -> u32 {
+        if self.resolution.get(0, 32) == 0 {
+            DEFAULT_WIDTH
+        } else {
+            (u32::from(self.resolution.get_ppl()) + 1).min(MAX_WIDTH)
+        }
+    }
+
+    /// Height of the panel in pixels.
+    pub fn height(&self) -> u32 {
+        if self.resolution.get(0, 32) == 0 {
+            DEFAULT_HEIGHT
+        } else {
+            (u32::from(self.resolution.get_lpp()) + 1).min(MAX_HEIGHT)
+        }
+    }
+
+    /// Size of the frame buffer in bytes.
+    pub fn fb_size(&self) -> usize {
+        usize::try_from(self.width() * self.height()).unwrap() * self.control.get_fb_format().bpp()
+    }
+}
+
+/// Convert a BT.601 YUV sample to RGB.
+fn yuv_to_rgb(y: u8, cb: u8, cr: u8) -> [u8; 3] {
+    let y = i32::from(y);
+    let cb = i32::from(cb) - 128;
+    let cr = i32::from(cr) - 128;
+    let clamp = |v: i32| u8::try_from(v.clamp(0, 255)).unwrap();
+    [
+        clamp(y + ((91881 * cr) >> 16)),
+        clamp(y - ((22554 * cb + 46802 * cr) >> 16)),
+        clamp(y + ((116130 * cb) >> 16)),
+    ]
+}
+
+/// Convert a frame buffer into RGBA pixels.
+///
+/// For YUV422 formats, the component order in the format name starts at the most significant byte of each 32-bit
+/// word. The word is stored in big endian unless `yuv_le` is set.
+pub fn convert_frame(control: &LCDControl, src: &[u8], dest: &mut [u8]) {
+    let format = control.get_fb_format();
+    match format {
+        FrameBufferFormat::RGB565 | FrameBufferFormat::RGB555 => {
+            for (spx, dpx) in src.chunks_exact(2).zip(dest.chunks_exact_mut(4)) {
+                let px = u16::from_le_bytes([spx[0], spx[1]]);
+                let (r, g, b) = if format == FrameBufferFormat::RGB565 {
+                    ((px >> 11) << 3, ((px >> 5) & 0x3f) << 2, (px & 0x1f) << 3)
+                } else {
+                    (((px >> 10) & 0x1f) << 3, ((px >> 5) & 0x1f) << 3, (px & 0x1f) << 3)
+                };
+                dpx.copy_from_slice(&[r as u8, g as u8, b as u8, 0xff]);
+            }
+        }
+        FrameBufferFormat::XRGB => {
+            for (spx, dpx) in src.chunks_exact(4).zip(dest.chunks_exact_mut(4)) {
+                dpx.copy_from_slice(&[spx[2], spx[1], spx[0], 0xff]);
+            }
+        }
+        FrameBufferFormat::RGBX => {
+            for (spx, dpx) in src.chunks_exact(4).zip(dest.chunks_exact_mut(4)) {
+                dpx.copy_from_slice(&[spx[3], spx[2], spx[1], 0xff]);
+            }
+        }
+        _ => {
+            // Indices of Y0, Cb, Y1, Cr in the big endian word.
+            let [y0, cb, y1, cr] = match format {
+                FrameBufferFormat::Cb0Y0Cr0Y1 => [1, 0, 3, 2],
+                FrameBufferFormat::Y0Cb0Y1Cr0 => [0, 1, 2, 3],
+                FrameBufferFormat::Cr0Y0Cb0Y1 => [1, 2, 3, 0],
+                _ => [0, 3, 2, 1],
+            };
+            for (word, dpx) in src.chunks_exact(4).zip(dest.chunks_exact_mut(8)) {
+                let mut word: [u8; 4] = word.try_into().unwrap();
+                if control.get_yuv_le() {
+                    word.reverse();
+                }
+                let [r, g, b] = yuv_to_rgb(word[y0], word[cb], word[cr]);
+                dpx[..4].copy_from_slice(&[r, g, b, 0xff]);
+                let [r, g, b] = yuv_to_rgb(word[y1], word[cb], word[cr]);
+                dpx[4..].copy_from_slice(&[r, g, b, 0xff]);
+            }
+        }
+    }
+}
+
+/// Whether the register at `addr` only stores what the guest writes, so partial accesses can be merged into it.
+#[inline]
+fn is_store_only(addr: u64) -> bool {
+    matches!(addr & !3, LCDC_PRM | TCON1 | TCON2 | TCON4)
+}
+
 pub fn read(uc: &mut UnicornContext, addr: u64, size: usize) -> u64 {
     if size != 4 {
+        if is_store_only(addr) {
+            return mmio_load_masked(uc, BASE + addr, size);
+        }
         log_unsupported_read!(addr, size);
         return 0;
     }
     match addr {
         LCDC_CTL => uc.get_data().vpost.control.get(0, 32),
         LCDC_INT => uc.get_data().vpost.irq.get(0, 32),
-        LCDC_PRM | TCON1 | TCON2 | TCON3 | TCON4 => mmio_get_store_only(uc, BASE + addr),
+        LCDC_PRM | TCON1 | TCON2 | TCON4 => mmio_load_masked(uc, BASE + addr, size),
+        TCON3 => uc.get_data().vpost.resolution.get(0, 32),
         FSADDR => uc.get_data().vpost.fb.into(),
         _ => {
             log_unsupported_read!(addr, size);
@@ -98,7 +227,11 @@ pub fn read(uc: &mut UnicornContext, addr: u64, size: usize) -> u64 {
 
 pub fn write(uc: &mut UnicornContext, addr: u64, size: usize, value: u64) {
     if size != 4 {
-        log_unsupported_write!(addr, size, value);
+        if is_store_only(addr) {
+            mmio_store_masked(uc, BASE + addr, value, size);
+        } else {
+            log_unsupported_write!(addr, size, value);
+        }
         return;
     }
     match addr {
@@ -108,9 +241,18 @@ pub fn write(uc: &mut UnicornContext, addr: u64, size: usize, value: u64) {
         },
         LCDC_INT => {
             trace!("LCDCInt = 0x{:08x}", value);
-            uc.get_data_mut().vpost.irq.set(0, 32, value);
+            // Status bits are write 1 to clear. Enable bits are stored as-is.
+            let irq = &mut uc.get_data_mut().vpost.irq;
+            let status = irq.get(0, 16) & !(value & 0xffff);
+            irq.set(0, 16, status);
+            irq.set(16, 16, value >> 16);
         },
-        LCDC_PRM | TCON1 | TCON2 | TCON3 | TCON4 => mmio_set_store_only(uc, BASE + addr, value),
+        LCDC_PRM | TCON1 | TCON2 | TCON4 => mmio_store_masked(uc, BASE + addr, value, size),
+        TCON3 => {
+            let vpost = &mut uc.get_data_mut().vpost;
+            vpost.resolution.set(0, 32, value);
+            trace!("Panel resolution {}x{}", vpost.width(), vpost.height());
+        }
         FSADDR => {
             uc.get_data_mut().vpost.fb = value as u32;
         }
@@ -122,7 +264,81 @@ pub fn write(uc: &mut UnicornContext, addr: u64, size: usize, value: u64) {
 
 pub fn generate_stop_condition(uc: &mut UnicornContext, steps: u64) {
     let div_vsync = uc.get_data().clk.tick_config.vsync;
-    if steps % div_vsync == 0 {
+    if steps.is_multiple_of(div_vsync) {
         request_stop(uc, StopReason::FrameStep);
     }
+
+    if !uc.get_data().clk.ahbclk.get_vpost() {
+        return;
+    }
+    let vpost = &mut uc.get_data_mut().vpost;
+    if !vpost.control.get_run() {
+        return;
+    }
+
+    let mut fired = false;
+    if steps.is_multiple_of(div_vsync) {
+        vpost.irq.set_vsync(true);
+        fired |= vpost.irq.get_vsync_enable();
+    }
+    if vpost.irq.get_hsync_enable() {
+        let div_hsync = (div_vsync / u64::from(vpost.height())).max(1);
+        if steps.is_multiple_of(div_hsync) {
+            vpost.irq.set_hsync(true);
+            fired = true;
+        }
+    }
+
+    if fired {
+        post_interrupt(uc, InterruptNumber::VPOST);
+    }
+}
+
+/// Step at which `generate_stop_condition` next needs to run. Frame steps are always scheduled, even with the
+/// panel off.
+pub fn next_event(uc: &UnicornContext, steps: u64) -> Option<u64> {
+    let div_vsync = uc.get_data().clk.tick_config.vsync;
+    let vsync = next_multiple(steps, div_vsync);
+
+    let vpost = &uc.get_data().vpost;
+    if !uc.get_data().clk.ahbclk.get_vpost() || !vpost.control.get_run() || !vpost.irq.get_hsync_enable() {
+        return Some(vsync);
+    }
+    let div_hsync = (div_vsync / u64::from(vpost.height())).max(1);
+    Some(vsync.min(next_multiple(steps, div_hsync)))
+}
+
+impl_snapshot_bitfield!(LCDControl, LCDIRQStatus, LCDResolution);
+impl_snapshot!(LCDConfig { control, irq, resolution, fb });
+impl_reset!(LCDConfig);
+
+#[test]
+fn test_convert_frame_yuv() {
+    let mut control = LCDControl::new();
+    control.set_fb_format(FrameBufferFormat::Cb0Y0Cr0Y1);
+    // Neutral chroma with Y0 = 0x10 and Y1 = 0xeb.
+    let src = [0x80, 0x10, 0x80, 0xeb];
+    let mut dest = [0u8; 8];
+
+    convert_frame(&control, &src, &mut dest);
+    assert_eq!(dest, [0x10, 0x10, 0x10, 0xff, 0xeb, 0xeb, 0xeb, 0xff]);
+
+    control.set_yuv_le(true);
+    let src = [0xeb, 0x80, 0x10, 0x80];
+    convert_frame(&control, &src, &mut dest);
+    assert_eq!(dest, [0x10, 0x10, 0x10, 0xff, 0xeb, 0xeb, 0xeb, 0xff]);
+
+    // Pure red.
+    control.set_yuv_le(false);
+    let src = [85, 76, 255, 76];
+    convert_frame(&control, &src, &mut dest);
+    assert_eq!(dest[..4], [0xfe, 0x01, 0x00, 0xff]);
+}
+
+#[test]
+fn test_resolution_clamp() {
+    let mut vpost = LCDConfig::default();
+    assert_eq!((vpost.width(), vpost.height()), (DEFAULT_WIDTH, DEFAULT_HEIGHT));
+    vpost.resolution.set(0, 32, 0xffff_ffff);
+    assert_eq!((vpost.width(), vpost.height()), (MAX_WIDTH, MAX_HEIGHT));
 }
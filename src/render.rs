@@ -0,0 +1,178 @@
+use std::{fmt, fs::File, io::{self, BufWriter, Write}, path::Path};
+
+use chrono::Local;
+use pixels::Pixels;
+
+/// Destination of the frames copied out of the LCD controller.
+pub trait FrameSink {
+    type Error: fmt::Debug;
+
+    /// Current frame size in pixels.
+    fn size(&self) -> (u32, u32);
+    /// Change the frame size. The content of the frame is undefined afterwards.
+    fn resize(&mut self, width: u32, height: u32) -> Result<(), Self::Error>;
+    /// RGBA pixels of the frame, row by row.
+    fn frame(&self) -> &[u8];
+    fn frame_mut(&mut self) -> &mut [u8];
+    /// Show the frame.
+    fn present(&mut self) -> Result<(), Self::Error>;
+}
+
+impl FrameSink for Pixels<'_> {
+    type Error = pixels::Error;
+
+    fn size(&self) -> (u32, u32) {
+        let texture = self.texture();
+        (texture.width(), texture.height())
+    }
+
+    fn resize(&mut self, width: u32, height: u32) -> Result<(), Self::Error> {
+        self.resize_buffer(width, height).map_err(pixels::Error::from)
+    }
+
+    fn frame(&self) -> &[u8] {
+        Pixels::frame(self)
+    }
+
+    fn frame_mut(&mut self) -> &mut [u8] {
+        Pixels::frame_mut(self)
+    }
+
+    fn present(&mut self) -> Result<(), Self::Error> {
+        self.render()
+    }
+}
+
+/// In-memory frame, for running without a window.
+pub struct FrameBuffer {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+impl FrameBuffer {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self { width, height, pixels: vec![0; width as usize * height as usize * 4] }
+    }
+}
+
+impl FrameSink for FrameBuffer {
+    type Error = std::convert::Infallible;
+
+    fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    fn resize(&mut self, width: u32, height: u32) -> Result<(), Self::Error> {
+        *self = Self::new(width, height);
+        Ok(())
+    }
+
+    fn frame(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    fn frame_mut(&mut self) -> &mut [u8] {
+        &mut self.pixels
+    }
+
+    fn present(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// Save the current frame of `sink` as a PNG file.
+pub fn save_png<S: FrameSink>(sink: &S, path: impl AsRef<Path>) -> io::Result<()> {
+    let (width, height) = sink.size();
+    let mut out = BufWriter::new(File::create(path)?);
+    write_png(&mut out, width, height, sink.frame())?;
+    out.flush()
+}
+
+/// Save the current frame of `sink` to a timestamped PNG file in the working directory. Returns the file name.
+pub fn save_screenshot<S: FrameSink>(sink: &S) -> io::Result<String> {
+    let path = Local::now().format("screenshot-%Y%m%d-%H%M%S%.3f.png").to_string();
+    save_png(sink, &path)?;
+    Ok(path)
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ u32::from(byte), |crc, _| (crc >> 1) ^ (0xedb88320 & (crc & 1).wrapping_neg()))
+    })
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), &byte| {
+        let a = (a + u32::from(byte)) % 65521;
+        (a, (b + a) % 65521)
+    });
+    (b << 16) | a
+}
+
+fn write_chunk(out: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    let mut crc_input = kind.to_vec();
+    crc_input.extend_from_slice(data);
+    out.write_all(&crc32(&crc_input).to_be_bytes())
+}
+
+/// Encode RGBA pixels as a PNG. The image data is stored without compression, which keeps the encoder trivial.
+pub fn write_png(out: &mut impl Write, width: u32, height: u32, rgba: &[u8]) -> io::Result<()> {
+    let stride = width as usize * 4;
+    if rgba.len() != stride * height as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame size does not match its dimensions"));
+    }
+
+    out.write_all(b"\x89PNG\r\n\x1a\n")?;
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // 8 bits per channel, RGBA, deflate, adaptive filtering, no interlace.
+    header.extend_from_slice(&[8, 6, 0, 0, 0]);
+    write_chunk(out, b"IHDR", &header)?;
+
+    // Every row starts with filter type 0 (none).
+    let mut raw = Vec::with_capacity((stride + 1) * height as usize);
+    for row in rgba.chunks_exact(stride.max(1)).take(height as usize) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    // zlib stream made of stored deflate blocks.
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = raw.chunks(0xffff).peekable();
+    if blocks.peek().is_none() {
+        zlib.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let len = block.len() as u16;
+        zlib.push(u8::from(blocks.peek().is_none()));
+        zlib.extend_from_slice(&len.to_le_bytes());
+        zlib.extend_from_slice(&(!len).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+    write_chunk(out, b"IDAT", &zlib)?;
+
+    write_chunk(out, b"IEND", &[])
+}
+
+#[test]
+fn test_write_png() {
+    assert_eq!(crc32(b"IEND"), 0xae426082);
+    assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+
+    let mut out = Vec::new();
+    write_png(&mut out, 2, 1, &[0xff, 0, 0, 0xff, 0, 0xff, 0, 0xff]).unwrap();
+    assert!(out.starts_with(b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR"));
+    assert!(out.ends_with(b"\x00\x00\x00\x00IEND\xae\x42\x60\x82"));
+    // IDAT holds a single final stored block with the filtered row.
+    let idat = out.windows(4).position(|window| window == b"IDAT").unwrap() + 4;
+    assert_eq!(&out[idat..idat + 8], &[0x78, 0x01, 1, 9, 0, 0xf6, 0xff, 0]);
+
+    assert!(write_png(&mut Vec::new(), 2, 2, &[0; 8]).is_err());
+}
@@ -0,0 +1,369 @@
+use std::{collections::{HashMap, VecDeque}, fs};
+
+use log::info;
+use unicorn_engine::RegisterARM;
+
+use crate::{RuntimeError, device::{Device, UnicornContext}, memmap::{SRAM_BASE, SRAM_SIZE}, mmu::CP15Register};
+
+const MAGIC: &[u8; 8] = b"LLESNAP\0";
+const VERSION: u32 = 21;
+
+/// Processor modes with banked registers. System mode shares its registers with user mode.
+const MODES: [u64; 6] = [0x1f, 0x11, 0x12, 0x13, 0x17, 0x1b];
+const MODE_FIQ: u64 = 0x11;
+const MODE_SYS: u64 = 0x1f;
+
+const UNBANKED_REGS: [RegisterARM; 9] = [
+    RegisterARM::R0,
+    RegisterARM::R1,
+    RegisterARM::R2,
+    RegisterARM::R3,
+    RegisterARM::R4,
+    RegisterARM::R5,
+    RegisterARM::R6,
+    RegisterARM::R7,
+    RegisterARM::PC,
+];
+/// Registers banked in FIQ mode only.
+const FIQ_BANKED_REGS: [RegisterARM; 5] = [
+    RegisterARM::R8,
+    RegisterARM::R9,
+    RegisterARM::R10,
+    RegisterARM::R11,
+    RegisterARM::R12,
+];
+
+/// Writable CP15 registers, in snapshot order.
+const CP15_REGS: [CP15Register; 6] = [
+    CP15Register::Control,
+    CP15Register::TranslationTableBase,
+    CP15Register::DomainAccessControl,
+    CP15Register::DataFaultStatus,
+    CP15Register::InstructionFaultStatus,
+    CP15Register::FaultAddress,
+];
+
+/// State that can be written to and restored from a snapshot.
+///
+/// Fields are stored in order without any tagging, so `load` must read back exactly what `save` wrote.
+pub trait Snapshot {
+    fn save(&self, out: &mut Vec<u8>);
+    fn load(&mut self, input: &mut &[u8]) -> Result<(), RuntimeError>;
+}
+
+/// Take `len` bytes from the front of `input`.
+pub fn take<'a>(input: &mut &'a [u8], len: usize) -> Result<&'a [u8], RuntimeError> {
+    if input.len() < len {
+        return Err(RuntimeError::SnapshotInvalid);
+    }
+    let (head, tail) = input.split_at(len);
+    *input = tail;
+    Ok(head)
+}
+
+/// Read a value that was saved with `Snapshot::save`.
+pub fn load_new<T: Snapshot + Default>(input: &mut &[u8]) -> Result<T, RuntimeError> {
+    let mut value = T::default();
+    value.load(input)?;
+    Ok(value)
+}
+
+macro_rules! impl_snapshot_primitive {
+    ($($type:ty),* $(,)?) => {
+        $(
+            impl Snapshot for $type {
+                fn save(&self, out: &mut Vec<u8>) {
+                    out.extend_from_slice(&self.to_le_bytes());
+                }
+
+                fn load(&mut self, input: &mut &[u8]) -> Result<(), RuntimeError> {
+                    *self = Self::from_le_bytes(take(input, size_of::<Self>())?.try_into().unwrap());
+                    Ok(())
+                }
+            }
+        )*
+    };
+}
+
+impl_snapshot_primitive!(u8, u16, u32, u64, i16, i32, i64, f64);
+
+impl Snapshot for usize {
+    fn save(&self, out: &mut Vec<u8>) {
+        (*self as u64).save(out);
+    }
+
+    fn load(&mut self, input: &mut &[u8]) -> Result<(), RuntimeError> {
+        *self = usize::try_from(load_new::<u64>(input)?).map_err(|_| RuntimeError::SnapshotInvalid)?;
+        Ok(())
+    }
+}
+
+impl Snapshot for bool {
+    fn save(&self, out: &mut Vec<u8>) {
+        out.push(u8::from(*self));
+    }
+
+    fn load(&mut self, input: &mut &[u8]) -> Result<(), RuntimeError> {
+        *self = load_new::<u8>(input)? != 0;
+        Ok(())
+    }
+}
+
+impl<T: Snapshot + Default> Snapshot for Option<T> {
+    fn save(&self, out: &mut Vec<u8>) {
+        self.is_some().save(out);
+        if let Some(value) = self {
+            value.save(out);
+        }
+    }
+
+    fn load(&mut self, input: &mut &[u8]) -> Result<(), RuntimeError> {
+        *self = if load_new::<bool>(input)? { Some(load_new(input)?) } else { None };
+        Ok(())
+    }
+}
+
+impl<T: Snapshot, const N: usize> Snapshot for [T; N] {
+    fn save(&self, out: &mut Vec<u8>) {
+        self.iter().for_each(|value| value.save(out));
+    }
+
+    fn load(&mut self, input: &mut &[u8]) -> Result<(), RuntimeError> {
+        self.iter_mut().try_for_each(|value| value.load(input))
+    }
+}
+
+impl<A: Snapshot, B: Snapshot> Snapshot for (A, B) {
+    fn save(&self, out: &mut Vec<u8>) {
+        self.0.save(out);
+        self.1.save(out);
+    }
+
+    fn load(&mut self, input: &mut &[u8]) -> Result<(), RuntimeError> {
+        self.0.load(input)?;
+        self.1.load(input)
+    }
+}
+
+impl<T: Snapshot + Default> Snapshot for VecDeque<T> {
+    fn save(&self, out: &mut Vec<u8>) {
+        self.len().save(out);
+        self.iter().for_each(|value| value.save(out));
+    }
+
+    fn load(&mut self, input: &mut &[u8]) -> Result<(), RuntimeError> {
+        let len: usize = load_new(input)?;
+        self.clear();
+        for _ in 0..len {
+            self.push_back(load_new(input)?);
+        }
+        Ok(())
+    }
+}
+
+impl<T: Snapshot + Default> Snapshot for Vec<T> {
+    fn save(&self, out: &mut Vec<u8>) {
+        self.len().save(out);
+        self.iter().for_each(|value| value.save(out));
+    }
+
+    fn load(&mut self, input: &mut &[u8]) -> Result<(), RuntimeError> {
+        let len: usize = load_new(input)?;
+        self.clear();
+        for _ in 0..len {
+            self.push(load_new(input)?);
+        }
+        Ok(())
+    }
+}
+
+impl Snapshot for HashMap<u64, u64> {
+    fn save(&self, out: &mut Vec<u8>) {
+        let mut entries: Vec<_> = self.iter().collect();
+        entries.sort();
+        entries.len().save(out);
+        entries.into_iter().for_each(|(key, value)| {
+            key.save(out);
+            value.save(out);
+        });
+    }
+
+    fn load(&mut self, input: &mut &[u8]) -> Result<(), RuntimeError> {
+        let len: usize = load_new(input)?;
+        self.clear();
+        for _ in 0..len {
+            let key = load_new(input)?;
+            self.insert(key, load_new(input)?);
+        }
+        Ok(())
+    }
+}
+
+/// Implement `Snapshot` for a struct by saving the listed fields in order.
+#[macro_export]
+macro_rules! impl_snapshot {
+    ($type:ty { $($field:ident),* $(,)? }) => {
+        impl $crate::snapshot::Snapshot for $type {
+            fn save(&self, out: &mut Vec<u8>) {
+                $( $crate::snapshot::Snapshot::save(&self.$field, out); )*
+            }
+
+            fn load(&mut self, input: &mut &[u8]) -> Result<(), $crate::RuntimeError> {
+                $( $crate::snapshot::Snapshot::load(&mut self.$field, input)?; )*
+                Ok(())
+            }
+        }
+    };
+}
+
+/// Implement `Snapshot` for `#[bitfield]` structs by saving their raw bytes.
+#[macro_export]
+macro_rules! impl_snapshot_bitfield {
+    ($($type:ty),* $(,)?) => {
+        $(
+            impl $crate::snapshot::Snapshot for $type {
+                fn save(&self, out: &mut Vec<u8>) {
+                    for i in 0..size_of::<Self>() {
+                        out.push(self.get(i * 8, 8) as u8);
+                    }
+                }
+
+                fn load(&mut self, input: &mut &[u8]) -> Result<(), $crate::RuntimeError> {
+                    let bytes = $crate::snapshot::take(input, size_of::<Self>())?;
+                    for (i, byte) in bytes.iter().enumerate() {
+                        self.set(i * 8, 8, (*byte).into());
+                    }
+                    Ok(())
+                }
+            }
+        )*
+    };
+}
+
+fn save_cpu(uc: &mut UnicornContext, out: &mut Vec<u8>) -> Result<(), RuntimeError> {
+    let cpsr = uc.reg_read(RegisterARM::CPSR)?;
+    cpsr.save(out);
+    for reg in UNBANKED_REGS {
+        uc.reg_read(reg)?.save(out);
+    }
+
+    // Visit each mode to read its banked registers.
+    for mode in MODES {
+        uc.reg_write(RegisterARM::CPSR, (cpsr & !0x1f) | mode)?;
+        if mode == MODE_FIQ || mode == MODE_SYS {
+            for reg in FIQ_BANKED_REGS {
+                uc.reg_read(reg)?.save(out);
+            }
+        }
+        uc.reg_read(RegisterARM::SP)?.save(out);
+        uc.reg_read(RegisterARM::LR)?.save(out);
+        if mode != MODE_SYS {
+            uc.reg_read(RegisterARM::SPSR)?.save(out);
+        }
+    }
+    uc.reg_write(RegisterARM::CPSR, cpsr)?;
+
+    for reg in CP15_REGS {
+        reg.read(uc)?.save(out);
+    }
+    Ok(())
+}
+
+fn load_cpu(uc: &mut UnicornContext, input: &mut &[u8]) -> Result<(), RuntimeError> {
+    let cpsr: u64 = load_new(input)?;
+    let mut unbanked = [0u64; UNBANKED_REGS.len()];
+    unbanked.load(input)?;
+
+    for mode in MODES {
+        uc.reg_write(RegisterARM::CPSR, (cpsr & !0x1f) | mode)?;
+        if mode == MODE_FIQ || mode == MODE_SYS {
+            for reg in FIQ_BANKED_REGS {
+                uc.reg_write(reg, load_new(input)?)?;
+            }
+        }
+        uc.reg_write(RegisterARM::SP, load_new(input)?)?;
+        uc.reg_write(RegisterARM::LR, load_new(input)?)?;
+        if mode != MODE_SYS {
+            uc.reg_write(RegisterARM::SPSR, load_new(input)?)?;
+        }
+    }
+    uc.reg_write(RegisterARM::CPSR, cpsr)?;
+    for (reg, value) in UNBANKED_REGS.into_iter().zip(unbanked) {
+        uc.reg_write(reg, value)?;
+    }
+
+    for reg in CP15_REGS {
+        reg.write(uc, load_new(input)?)?;
+    }
+    uc.ctl_flush_tlb()?;
+    Ok(())
+}
+
+/// Save the CPU, memory, peripheral and device states to `path`.
+pub fn save_snapshot(uc: &mut UnicornContext, device: &Device, path: &str) -> Result<(), RuntimeError> {
+    let mut out = Vec::with_capacity(uc.get_data().raw_sdram.len() + SRAM_SIZE + 0x10000);
+    out.extend_from_slice(MAGIC);
+    VERSION.save(&mut out);
+
+    save_cpu(uc, &mut out)?;
+    out.extend_from_slice(&uc.mem_read_as_vec(SRAM_BASE, SRAM_SIZE)?);
+    let sdram = &uc.get_data().raw_sdram;
+    sdram.len().save(&mut out);
+    out.extend_from_slice(sdram);
+    uc.get_data().save(&mut out);
+    device.save(&mut out);
+
+    fs::write(path, out)?;
+    info!("Snapshot saved to {path}");
+    Ok(())
+}
+
+/// Restore a snapshot saved by `save_snapshot`.
+///
+/// `uc` must be freshly initialized, with all MMIO maps and hooks registered, and `device` must have its SD card
+/// images mounted. Only their states are restored.
+pub fn load_snapshot(uc: &mut UnicornContext, device: &mut Device, path: &str) -> Result<(), RuntimeError> {
+    let data = fs::read(path)?;
+    let input = &mut data.as_slice();
+    if take(input, MAGIC.len())? != MAGIC || load_new::<u32>(input)? != VERSION {
+        return Err(RuntimeError::SnapshotInvalid);
+    }
+
+    load_cpu(uc, input)?;
+    let sram = take(input, SRAM_SIZE)?;
+    uc.mem_write(SRAM_BASE, sram)?;
+    // SDRAM is mapped directly from this buffer, so it must be copied in place.
+    let sdram_size: usize = load_new(input)?;
+    if sdram_size != uc.get_data().raw_sdram.len() {
+        return Err(RuntimeError::SnapshotInvalid);
+    }
+    uc.get_data_mut().raw_sdram.copy_from_slice(take(input, sdram_size)?);
+    uc.get_data_mut().load(input)?;
+    device.load(input)?;
+
+    if !input.is_empty() {
+        return Err(RuntimeError::SnapshotInvalid);
+    }
+    info!("Snapshot restored from {path}");
+    Ok(())
+}
+
+#[test]
+fn test_roundtrip() {
+    let mut out = vec![];
+    let values = (Some(0x1234u16), [true, false]);
+    let queue = VecDeque::from([1u32, 2, 3]);
+    let map = HashMap::from([(1u64, 2u64), (3, 4)]);
+    values.save(&mut out);
+    queue.save(&mut out);
+    map.save(&mut out);
+    (-1.5f64).save(&mut out);
+
+    let input = &mut out.as_slice();
+    assert_eq!(load_new::<(Option<u16>, [bool; 2])>(input).unwrap(), values);
+    assert_eq!(load_new::<VecDeque<u32>>(input).unwrap(), queue);
+    assert_eq!(load_new::<HashMap<u64, u64>>(input).unwrap(), map);
+    assert_eq!(load_new::<f64>(input).unwrap(), -1.5);
+    assert!(input.is_empty());
+    assert!(load_new::<u32>(input).is_err());
+}
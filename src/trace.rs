@@ -0,0 +1,185 @@
+use std::{cell::Cell, collections::BTreeMap, fs::File, io::{self, BufWriter, Write}};
+
+use log::{error, info};
+
+use crate::device::UnicornContext;
+
+/// Structured trace of peripheral accesses, written as one JSON object per line.
+///
+/// Each line carries the instruction count at the time of the event so the trace can be lined up with other output.
+pub struct Tracer {
+    out: BufWriter<File>,
+}
+
+impl Tracer {
+    pub fn create(path: &str) -> io::Result<Self> {
+        Ok(Self { out: BufWriter::with_capacity(1 << 20, File::create(path)?) })
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Write a line to the trace if tracing is enabled. Tracing is turned off on the first write error.
+fn record<F>(uc: &mut UnicornContext, write_line: F)
+where
+    F: FnOnce(&mut BufWriter<File>, u64, u64) -> io::Result<()>,
+{
+    if uc.get_data().tracer.is_none() {
+        return;
+    }
+    let pc = uc.pc_read().unwrap_or_default();
+    let data = uc.get_data_mut();
+    let steps = data.steps;
+    if let Some(tracer) = &mut data.tracer && let Err(err) = write_line(&mut tracer.out, steps, pc) {
+        error!("Failed to write trace, tracing disabled: {err:?}");
+        data.tracer = None;
+    }
+}
+
+pub fn record_mmio(uc: &mut UnicornContext, is_write: bool, addr: u64, size: usize, value: u64) {
+    let op = if is_write { "write" } else { "read" };
+    record(uc, |out, steps, pc| {
+        writeln!(
+            out,
+            r#"{{"step":{steps},"pc":"0x{pc:08x}","op":"{op}","addr":"0x{addr:08x}","size":{size},"value":"0x{value:08x}"}}"#,
+        )
+    });
+}
+
+pub fn record_unmapped(uc: &mut UnicornContext, addr: u64, size: usize, value: i64) {
+    record(uc, |out, steps, pc| {
+        writeln!(
+            out,
+            r#"{{"step":{steps},"pc":"0x{pc:08x}","op":"unmapped","addr":"0x{addr:08x}","size":{size},"value":"0x{value:08x}"}}"#,
+        )
+    });
+}
+
+/// Record an executed instruction. Installed as a code hook only when instruction tracing is requested.
+pub fn record_instruction(uc: &mut UnicornContext, addr: u64, _size: u32) {
+    record(uc, |out, steps, _pc| writeln!(out, r#"{{"step":{steps},"pc":"0x{addr:08x}","op":"exec"}}"#));
+}
+
+/// Flush the trace, if any. Called on exit and before bailing out on an emulator error.
+pub fn flush(uc: &mut UnicornContext) {
+    if let Some(tracer) = &mut uc.get_data_mut().tracer && let Err(err) = tracer.flush() {
+        error!("Failed to flush trace: {err:?}");
+    }
+}
+
+thread_local! {
+    /// Whether the register handler that is running logged an unsupported access.
+    static UNSUPPORTED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Flag the current register access as unsupported. Called by `log_unsupported_read!` and `log_unsupported_write!`.
+pub fn mark_unsupported() {
+    UNSUPPORTED.set(true);
+}
+
+/// Take the flag set by `mark_unsupported()`.
+pub fn take_unsupported() -> bool {
+    UNSUPPORTED.replace(false)
+}
+
+/// Accesses to one register.
+#[derive(Default)]
+struct RegisterCoverage {
+    reads: u64,
+    writes: u64,
+    unsupported_reads: u64,
+    unsupported_writes: u64,
+}
+
+/// Registers accessed by the guest, reported per peripheral on exit.
+#[derive(Default)]
+pub struct MmioCoverage {
+    pub enabled: bool,
+    /// Mapped peripherals as (name, base, size), in mapping order.
+    peripherals: Vec<(&'static str, u64, usize)>,
+    registers: BTreeMap<u64, RegisterCoverage>,
+}
+
+impl MmioCoverage {
+    pub fn add_peripheral(&mut self, name: &'static str, base: u64, size: usize) {
+        self.peripherals.push((name, base, size));
+    }
+
+    pub fn record(&mut self, is_write: bool, addr: u64, unsupported: bool) {
+        if !self.enabled {
+            return;
+        }
+        let register = self.registers.entry(addr).or_default();
+        let (count, unsupported_count) = if is_write {
+            (&mut register.writes, &mut register.unsupported_writes)
+        } else {
+            (&mut register.reads, &mut register.unsupported_reads)
+        };
+        *count += 1;
+        if unsupported {
+            *unsupported_count += 1;
+        }
+    }
+
+    /// Summary table of the accessed registers, grouped by peripheral.
+    pub fn report(&self) -> Vec<String> {
+        let mut lines = vec![];
+        for &(name, base, size) in &self.peripherals {
+            let registers: Vec<_> = self.registers.range(base..base + size as u64).collect();
+            if registers.is_empty() {
+                continue;
+            }
+            let unsupported = registers.iter()
+                .filter(|(_, reg)| reg.unsupported_reads + reg.unsupported_writes > 0)
+                .count();
+            lines.push(format!("{name} @ 0x{base:08x}: {} registers, {unsupported} unsupported", registers.len()));
+            for (addr, reg) in registers {
+                let status = match (reg.unsupported_reads + reg.unsupported_writes, reg.reads + reg.writes) {
+                    (0, _) => "handled",
+                    (some, all) if some == all => "unsupported",
+                    _ => "partly unsupported",
+                };
+                lines.push(format!(
+                    "  +0x{:03x} (0x{addr:08x})  reads {:>8}  writes {:>8}  {status}",
+                    addr - base, reg.reads, reg.writes,
+                ));
+            }
+        }
+        lines
+    }
+
+    /// Log the summary table, if enabled.
+    pub fn log_report(&self) {
+        if !self.enabled {
+            return;
+        }
+        info!("MMIO registers accessed:");
+        for line in self.report() {
+            info!("{line}");
+        }
+    }
+}
+
+#[test]
+fn test_mmio_coverage() {
+    let mut coverage = MmioCoverage { enabled: true, ..Default::default() };
+    coverage.add_peripheral("GPIO", 0xb8001000, 0x1000);
+    coverage.add_peripheral("I2C", 0xb8004000, 0x1000);
+    coverage.record(false, 0xb8001004, false);
+    coverage.record(true, 0xb8001004, false);
+    coverage.record(true, 0xb8001060, true);
+    coverage.record(false, 0xb8001000, true);
+    coverage.record(true, 0xb8001000, false);
+    assert_eq!(coverage.report(), [
+        "GPIO @ 0xb8001000: 3 registers, 2 unsupported",
+        "  +0x000 (0xb8001000)  reads        1  writes        1  partly unsupported",
+        "  +0x004 (0xb8001004)  reads        1  writes        1  handled",
+        "  +0x060 (0xb8001060)  reads        0  writes        1  unsupported",
+    ]);
+
+    coverage.enabled = false;
+    coverage.record(false, 0xb8004000, false);
+    assert_eq!(coverage.report().len(), 4);
+}
@@ -0,0 +1,134 @@
+use std::collections::BTreeMap;
+
+use log::error;
+use unicorn_engine::{UcHookId, uc_error, unicorn_const::{HookType, MemType}};
+
+use crate::device::{StopReason, UnicornContext, request_stop};
+
+/// Largest single guest access. Hooks start this much before a watched range so straddling accesses are seen.
+const MAX_ACCESS_SIZE: u64 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+impl WatchKind {
+    fn hook_type(self) -> HookType {
+        match self {
+            Self::Read => HookType::MEM_READ_AFTER,
+            Self::Write => HookType::MEM_WRITE,
+            Self::Access => HookType::MEM_READ_AFTER | HookType::MEM_WRITE,
+        }
+    }
+
+    fn matches(self, write: bool) -> bool {
+        match self {
+            Self::Read => !write,
+            Self::Write => write,
+            Self::Access => true,
+        }
+    }
+}
+
+pub struct Watchpoint {
+    pub addr: u64,
+    /// Length of the watched range in bytes.
+    pub len: u64,
+    pub kind: WatchKind,
+}
+
+/// An access that fired a watchpoint.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WatchHit {
+    pub id: usize,
+    pub addr: u64,
+}
+
+/// Registry of memory watchpoints. Each watchpoint installs its own memory hook over the watched range, so there is
+/// no cost when nothing is watched.
+#[derive(Default)]
+pub struct Watchpoints {
+    points: BTreeMap<usize, Watchpoint>,
+    hooks: BTreeMap<usize, UcHookId>,
+    next_id: usize,
+    /// Hits that stopped the emulator, oldest first.
+    hits: Vec<WatchHit>,
+}
+
+impl Watchpoint {
+    fn matches(&self, write: bool, addr: u64, size: usize) -> bool {
+        let end = addr + u64::try_from(size).unwrap();
+        self.kind.matches(write) && addr < self.addr + self.len && end > self.addr
+    }
+}
+
+impl Watchpoints {
+    /// Take the hits that stopped the emulator since the last call.
+    pub fn take_hits(&mut self) -> Vec<WatchHit> {
+        std::mem::take(&mut self.hits)
+    }
+}
+
+fn watch_hook(uc: &mut UnicornContext, id: usize, mem_type: MemType, addr: u64, size: usize) {
+    let write = mem_type == MemType::WRITE;
+    let Some(watchpoint) = uc.get_data().watch.points.get(&id) else {
+        return;
+    };
+    if !watchpoint.matches(write, addr, size) {
+        return;
+    }
+
+    uc.get_data_mut().watch.hits.push(WatchHit { id, addr });
+    request_stop(uc, StopReason::Watchpoint);
+    uc.emu_stop().unwrap_or_else(|err| {
+        error!("Failed to stop emulator: {err:?}");
+    });
+}
+
+/// Start watching a memory range. Returns the ID of the watchpoint.
+pub fn add_watchpoint(uc: &mut UnicornContext, watchpoint: Watchpoint) -> Result<usize, uc_error> {
+    if watchpoint.len == 0 {
+        return Err(uc_error::ARG);
+    }
+    let id = uc.get_data().watch.next_id;
+    let begin = watchpoint.addr.saturating_sub(MAX_ACCESS_SIZE - 1);
+    let end = watchpoint.addr + watchpoint.len - 1;
+    let hook = uc.add_mem_hook(watchpoint.kind.hook_type(), begin, end, move |uc, mem_type, addr, size, _value| {
+        watch_hook(uc, id, mem_type, addr, size);
+        true
+    })?;
+    let watch = &mut uc.get_data_mut().watch;
+    watch.next_id += 1;
+    watch.points.insert(id, watchpoint);
+    watch.hooks.insert(id, hook);
+    Ok(id)
+}
+
+/// Stop watching. Removing an unknown watchpoint does nothing.
+pub fn remove_watchpoint(uc: &mut UnicornContext, id: usize) -> Result<(), uc_error> {
+    let watch = &mut uc.get_data_mut().watch;
+    watch.points.remove(&id);
+    match watch.hooks.remove(&id) {
+        Some(hook) => uc.remove_hook(hook),
+        None => Ok(()),
+    }
+}
+
+#[test]
+fn test_watchpoint_matches() {
+    let watchpoint = Watchpoint { addr: 0x1000, len: 4, kind: WatchKind::Write };
+    assert!(watchpoint.matches(true, 0x1000, 4));
+    assert!(watchpoint.matches(true, 0x1003, 1));
+    // Straddles the start of the range.
+    assert!(watchpoint.matches(true, 0xffe, 4));
+    assert!(!watchpoint.matches(true, 0xffc, 4));
+    assert!(!watchpoint.matches(true, 0x1004, 4));
+    assert!(!watchpoint.matches(false, 0x1000, 4));
+
+    let watchpoint = Watchpoint { kind: WatchKind::Access, ..watchpoint };
+    assert!(watchpoint.matches(false, 0x1000, 2));
+    assert!(watchpoint.matches(true, 0x1000, 2));
+}